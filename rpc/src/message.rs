@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use chrono::{DateTime, Utc};
 
 use super::resource::{ResourceKind, CResourceUpdate, SResourceUpdate};
@@ -43,6 +45,23 @@ pub enum CMessage {
         path: String,
     },
     DownloadFile { serial: u64, id: String },
+    StreamFile { serial: u64, id: String },
+    DownloadTorrent { serial: u64, id: String },
+    CreateTorrent {
+        serial: u64,
+        path: String,
+        piece_size: Option<u32>,
+        #[serde(default)]
+        trackers: Vec<String>,
+        #[serde(default)]
+        webseeds: Vec<String>,
+        #[serde(default)]
+        private: bool,
+        comment: Option<String>,
+        #[serde(default)]
+        start: bool,
+    },
+    ReloadBlocklist { serial: u64 },
 }
 
 /// Server -> client message, serialize only
@@ -53,7 +72,7 @@ pub enum CMessage {
 pub enum SMessage<'a> {
     // Standard messages
     #[serde(skip_deserializing)]
-    ResourcesExtant { serial: u64, ids: Vec<&'a str> },
+    ResourcesExtant { serial: u64, ids: Vec<Cow<'a, str>> },
     #[serde(skip_serializing)]
     #[serde(rename = "RESOURCES_EXTANT")]
     OResourcesExtant { serial: u64, ids: Vec<String> },
@@ -76,7 +95,7 @@ pub enum SMessage<'a> {
     // InvalidRequest(Error),
     PermissionDenied(Error),
     TransferFailed(Error),
-    // ServerError(Error),
+    ServerError(Error),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -15,6 +15,7 @@ pub enum Resource {
     File(File),
     Peer(Peer),
     Tracker(Tracker),
+    Ban(Ban),
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -27,6 +28,7 @@ pub enum ResourceKind {
     File,
     Piece,
     Tracker,
+    Ban,
 }
 
 /// To increase server->client update efficiency, we
@@ -51,6 +53,12 @@ pub enum SResourceUpdate<'a> {
         rate_up: u64,
         rate_down: u64,
     },
+    /// Size of the IP blocklist and the connections it has blocked
+    Blocklist {
+        id: String,
+        blocklist_ranges: u64,
+        blocked: u64,
+    },
 
     TorrentStatus {
         id: String,
@@ -63,6 +71,7 @@ pub enum SResourceUpdate<'a> {
         rate_down: u64,
         transferred_up: u64,
         transferred_down: u64,
+        transferred_wasted: u64,
         progress: f32,
     },
     TorrentPeers {
@@ -71,12 +80,36 @@ pub enum SResourceUpdate<'a> {
         availability: f32,
     },
     TorrentPicker { id: String, sequential: bool },
+    TorrentTrackers { id: String, trackers: u8 },
+    /// Swarm statistics of a torrent or one of its trackers
+    Scrape {
+        id: String,
+        complete: u32,
+        incomplete: u32,
+        downloaded: u32,
+    },
     TorrentPriority { id: String, priority: u8 },
+    /// Location of a torrent's data, and the progress of moving it there
+    TorrentPath {
+        id: String,
+        path: String,
+        move_progress: Option<f32>,
+    },
+    TorrentMetadata {
+        id: String,
+        name: String,
+        size: u64,
+        pieces: u64,
+        piece_size: u32,
+        files: u32,
+    },
 
     TrackerStatus {
         id: String,
+        status: TrackerStatus,
         last_report: DateTime<Utc>,
         error: Option<String>,
+        warning: Option<String>,
     },
 
     FilePriority { id: String, priority: u8 },
@@ -98,6 +131,8 @@ pub struct CResourceUpdate {
     pub sequential: Option<bool>,
     pub throttle_up: Option<u32>,
     pub throttle_down: Option<u32>,
+    /// Trackers to add to a torrent, each in a new tier
+    pub add_trackers: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -109,6 +144,8 @@ pub struct Server {
     pub throttle_up: u32,
     pub throttle_down: u32,
     pub started: DateTime<Utc>,
+    pub blocklist_ranges: u64,
+    pub blocked: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -117,10 +154,13 @@ pub struct Torrent {
     pub id: String,
     pub name: String,
     pub path: String,
+    /// Progress of moving the torrent's data to path, if in progress
+    pub move_progress: Option<f32>,
     pub created: DateTime<Utc>,
     pub modified: DateTime<Utc>,
     pub status: Status,
     pub error: Option<String>,
+    pub size: Option<u64>,
     pub priority: u8,
     pub progress: f32,
     pub availability: f32,
@@ -131,8 +171,13 @@ pub struct Torrent {
     pub throttle_down: u32,
     pub transferred_up: u64,
     pub transferred_down: u64,
+    /// Bytes downloaded which were discarded as duplicates
+    pub transferred_wasted: u64,
     pub peers: u16,
     pub trackers: u8,
+    pub complete: u32,
+    pub incomplete: u32,
+    pub downloaded: u32,
     pub pieces: u64,
     pub piece_size: u32,
     pub files: u32,
@@ -148,6 +193,18 @@ pub enum Status {
     Idle,
     Seeding,
     Hashing,
+    Magnet,
+    Moving,
+    Error,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum TrackerStatus {
+    NotContacted,
+    Updating,
+    Working,
     Error,
 }
 
@@ -189,8 +246,23 @@ pub struct Tracker {
     pub id: String,
     pub torrent_id: String,
     pub url: String,
+    pub tier: u8,
+    pub status: TrackerStatus,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    pub warning: Option<String>,
+    pub complete: u32,
+    pub incomplete: u32,
+    pub downloaded: u32,
+}
+
+/// An address banned for sending corrupt data
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ban {
+    pub id: String,
+    pub ip: String,
+    pub created: DateTime<Utc>,
 }
 
 impl<'a> SResourceUpdate<'a> {
@@ -200,11 +272,16 @@ impl<'a> SResourceUpdate<'a> {
             &SResourceUpdate::OResource(ref r) => r.id(),
             &SResourceUpdate::Throttle { ref id, .. } |
             &SResourceUpdate::Rate { ref id, .. } |
+            &SResourceUpdate::Blocklist { ref id, .. } |
             &SResourceUpdate::TorrentStatus { ref id, .. } |
             &SResourceUpdate::TorrentTransfer { ref id, .. } |
             &SResourceUpdate::TorrentPeers { ref id, .. } |
             &SResourceUpdate::TorrentPicker { ref id, .. } |
+            &SResourceUpdate::TorrentTrackers { ref id, .. } |
+            &SResourceUpdate::Scrape { ref id, .. } |
             &SResourceUpdate::TorrentPriority { ref id, .. } |
+            &SResourceUpdate::TorrentPath { ref id, .. } |
+            &SResourceUpdate::TorrentMetadata { ref id, .. } |
             &SResourceUpdate::FilePriority { ref id, .. } |
             &SResourceUpdate::FileProgress { ref id, .. } |
             &SResourceUpdate::TrackerStatus { ref id, .. } |
@@ -223,6 +300,7 @@ impl Resource {
             &Resource::Piece(ref t) => &t.id,
             &Resource::Peer(ref t) => &t.id,
             &Resource::Tracker(ref t) => &t.id,
+            &Resource::Ban(ref b) => &b.id,
         }
     }
 
@@ -244,6 +322,7 @@ impl Resource {
             &Resource::Piece(_) => ResourceKind::Piece,
             &Resource::Peer(_) => ResourceKind::Peer,
             &Resource::Tracker(_) => ResourceKind::Tracker,
+            &Resource::Ban(_) => ResourceKind::Ban,
         }
     }
 
@@ -289,6 +368,13 @@ impl Resource {
         }
     }
 
+    pub fn as_ban(&self) -> &Ban {
+        match self {
+            &Resource::Ban(ref b) => b,
+            _ => panic!(),
+        }
+    }
+
     pub fn update(&mut self, update: SResourceUpdate) {
         match (self, update) {
             (&mut Resource::Torrent(ref mut t),
@@ -314,6 +400,15 @@ impl Resource {
                 s.rate_up = rate_up;
                 s.rate_down = rate_down;
             }
+            (&mut Resource::Server(ref mut s),
+             SResourceUpdate::Blocklist {
+                 blocklist_ranges,
+                 blocked,
+                 ..
+             }) => {
+                s.blocklist_ranges = blocklist_ranges;
+                s.blocked = blocked;
+            }
             (&mut Resource::Torrent(ref mut t),
              SResourceUpdate::TorrentStatus {
                  ref mut error,
@@ -329,6 +424,7 @@ impl Resource {
                  rate_down,
                  transferred_up,
                  transferred_down,
+                 transferred_wasted,
                  progress,
                  ..
              }) => {
@@ -336,6 +432,7 @@ impl Resource {
                 t.rate_down = rate_down;
                 t.transferred_up = transferred_up;
                 t.transferred_down = transferred_down;
+                t.transferred_wasted = transferred_wasted;
                 t.progress = progress;
             }
             (&mut Resource::Torrent(ref mut t),
@@ -351,6 +448,45 @@ impl Resource {
              SResourceUpdate::TorrentPicker { sequential, .. }) => {
                 t.sequential = sequential;
             }
+            (&mut Resource::Torrent(ref mut t),
+             SResourceUpdate::TorrentTrackers { trackers, .. }) => {
+                t.trackers = trackers;
+            }
+            (&mut Resource::Torrent(ref mut t),
+             SResourceUpdate::Scrape {
+                 complete,
+                 incomplete,
+                 downloaded,
+                 ..
+             }) => {
+                t.complete = complete;
+                t.incomplete = incomplete;
+                t.downloaded = downloaded;
+            }
+            (&mut Resource::Torrent(ref mut t),
+             SResourceUpdate::TorrentPath {
+                 ref mut path,
+                 move_progress,
+                 ..
+             }) => {
+                mem::swap(&mut t.path, path);
+                t.move_progress = move_progress;
+            }
+            (&mut Resource::Torrent(ref mut t),
+             SResourceUpdate::TorrentMetadata {
+                 ref mut name,
+                 size,
+                 pieces,
+                 piece_size,
+                 files,
+                 ..
+             }) => {
+                mem::swap(&mut t.name, name);
+                t.size = Some(size);
+                t.pieces = pieces;
+                t.piece_size = piece_size;
+                t.files = files;
+            }
             (&mut Resource::Peer(ref mut p), SResourceUpdate::Rate { rate_up, rate_down, .. }) => {
                 p.rate_up = rate_up;
                 p.rate_down = rate_down;
@@ -365,12 +501,27 @@ impl Resource {
             }
             (&mut Resource::Tracker(ref mut t),
              SResourceUpdate::TrackerStatus {
+                 status,
                  ref mut last_report,
                  ref mut error,
+                 ref mut warning,
                  ..
              }) => {
+                t.status = status;
                 mem::swap(&mut t.last_report, last_report);
                 mem::swap(&mut t.error, error);
+                mem::swap(&mut t.warning, warning);
+            }
+            (&mut Resource::Tracker(ref mut t),
+             SResourceUpdate::Scrape {
+                 complete,
+                 incomplete,
+                 downloaded,
+                 ..
+             }) => {
+                t.complete = complete;
+                t.incomplete = incomplete;
+                t.downloaded = downloaded;
             }
             (&mut Resource::File(ref mut f), SResourceUpdate::FilePriority {
                 priority,
@@ -402,6 +553,7 @@ impl Filter for Resource {
             &Resource::Piece(ref t) => t.matches(c),
             &Resource::Peer(ref t) => t.matches(c),
             &Resource::Tracker(ref t) => t.matches(c),
+            &Resource::Ban(ref b) => b.matches(c),
         }
     }
 }
@@ -415,6 +567,8 @@ impl Filter for Server {
             "rate_down" => match_n(self.rate_down as u64, c),
             "throttle_up" => match_n(self.throttle_up as u64, c),
             "throttle_down" => match_n(self.throttle_down as u64, c),
+            "blocklist_ranges" => match_n(self.blocklist_ranges, c),
+            "blocked" => match_n(self.blocked, c),
 
             _ => false,
         }
@@ -437,14 +591,20 @@ impl Filter for Torrent {
             "throttle_down" => match_n(self.throttle_down as u64, c),
             "transferred_up" => match_n(self.transferred_up as u64, c),
             "transferred_down" => match_n(self.transferred_down as u64, c),
+            "transferred_wasted" => match_n(self.transferred_wasted as u64, c),
             "peers" => match_n(self.peers as u64, c),
             "trackers" => match_n(self.trackers as u64, c),
+            "complete" => match_n(self.complete as u64, c),
+            "incomplete" => match_n(self.incomplete as u64, c),
+            "downloaded" => match_n(self.downloaded as u64, c),
             "pieces" => match_n(self.pieces as u64, c),
             "piece_size" => match_n(self.piece_size as u64, c),
             "files" => match_n(self.files as u64, c),
+            "size" => self.size.map(|s| match_n(s, c)).unwrap_or(false),
 
             "progress" => match_f(self.progress, c),
             "availability" => match_f(self.availability, c),
+            "move_progress" => self.move_progress.map(|p| match_f(p, c)).unwrap_or(false),
 
             "sequential" => match_b(self.sequential, c),
 
@@ -453,6 +613,17 @@ impl Filter for Torrent {
     }
 }
 
+impl Filter for Ban {
+    fn matches(&self, c: &Criterion) -> bool {
+        match &c.field[..] {
+            "id" => match_s(&self.id, c),
+            "ip" => match_s(&self.ip, c),
+
+            _ => false,
+        }
+    }
+}
+
 impl Filter for Piece {
     fn matches(&self, c: &Criterion) -> bool {
         match &c.field[..] {
@@ -509,7 +680,14 @@ impl Filter for Tracker {
             "id" => match_s(&self.id, c),
             "torrent_id" => match_s(&self.torrent_id, c),
             "url" => match_s(&self.url, c),
+            "status" => match_s(self.status.as_str(), c),
             "error" => match_s(self.error.as_ref().map(|s| s.as_str()).unwrap_or(""), c),
+            "warning" => match_s(self.warning.as_ref().map(|s| s.as_str()).unwrap_or(""), c),
+
+            "tier" => match_n(self.tier as u64, c),
+            "complete" => match_n(self.complete as u64, c),
+            "incomplete" => match_n(self.incomplete as u64, c),
+            "downloaded" => match_n(self.downloaded as u64, c),
 
             _ => false,
         }
@@ -525,7 +703,20 @@ impl Status {
             Status::Idle => "idle",
             Status::Seeding => "seeding",
             Status::Hashing => "hashing",
+            Status::Magnet => "magnet",
+            Status::Moving => "moving",
             Status::Error => "error",
         }
     }
 }
+
+impl TrackerStatus {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TrackerStatus::NotContacted => "not_contacted",
+            TrackerStatus::Updating => "updating",
+            TrackerStatus::Working => "working",
+            TrackerStatus::Error => "error",
+        }
+    }
+}
@@ -163,7 +163,23 @@ pub fn decode_buf(bytes: &[u8]) -> Result<BEncode, BError> {
     decode(&mut Cursor::new(bytes))
 }
 
+/// Decodes data whose dictionary keys may be binary, such as scrape
+/// responses keyed by info hash. Each key byte is mapped to the char
+/// of the same value, so ASCII keys are unaffected.
+pub fn decode_buf_binary_keys(bytes: &[u8]) -> Result<BEncode, BError> {
+    decode_keys(&mut Cursor::new(bytes), true)
+}
+
 pub fn decode<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
+    decode_keys(bytes, false)
+}
+
+/// Converts binary data to a dictionary key as produced by `decode_buf_binary_keys`.
+pub fn binary_key(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
+
+fn decode_keys<R: io::Read>(bytes: &mut R, binary: bool) -> Result<BEncode, BError> {
     match next_byte(bytes) {
         Ok(b'i') => {
             let s = read_until(bytes, b'e')?;
@@ -172,7 +188,7 @@ pub fn decode<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
         Ok(b'l') => {
             let mut l = vec![];
             loop {
-                match decode(bytes) {
+                match decode_keys(bytes, binary) {
                     Ok(val) => l.push(val),
                     Err(BError::EOF) => break,
                     e @ Err(_) => return e,
@@ -183,13 +199,14 @@ pub fn decode<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
         Ok(b'd') => {
             let mut d = BTreeMap::new();
             loop {
-                let key = match decode(bytes) {
+                let key = match decode_keys(bytes, binary) {
+                    Ok(BEncode::String(ref s)) if binary => binary_key(s),
                     Ok(BEncode::String(s)) => String::from_utf8(s).map_err(|_| BError::UTF8Decode)?,
                     Ok(_) => return Err(BError::InvalidDict),
                     Err(BError::EOF) => break,
                     Err(e) => return Err(e),
                 };
-                d.insert(key, decode(bytes)?);
+                d.insert(key, decode_keys(bytes, binary)?);
             }
             Ok(BEncode::Dict(d))
         }
@@ -1,5 +1,5 @@
 use std::env;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -10,6 +10,7 @@ pub struct Config {
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub proxy: ProxyConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -34,6 +35,8 @@ pub struct ConfigFile {
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfigFile,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,6 +55,10 @@ pub struct RpcConfig {
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    /// PEM files of certificate authorities trusted for
+    /// HTTPS trackers in addition to the built in roots
+    #[serde(default)]
+    pub ca_certs: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,6 +75,11 @@ pub struct DiskConfig {
     pub session: String,
     #[serde(default = "default_directory_dir")]
     pub directory: String,
+    #[serde(default = "default_allocation")]
+    pub allocation: Allocation,
+    /// Memory used to cache pieces being written and read, in MiB
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,12 +90,123 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    /// Local address all peer, tracker and DHT sockets are bound to
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    /// Network interface whose address sockets are bound to,
+    /// used if no bind address is given
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Whether or not torrents should be paused while the
+    /// bind address or interface is unavailable
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// eMule DAT, PeerGuardian P2P or CIDR lists of
+    /// address ranges which peers and DHT nodes may not use
+    #[serde(default)]
+    pub blocklists: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    #[serde(default = "default_encryption")]
+    pub encryption: Encryption,
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
+    #[serde(default = "default_fallback")]
+    pub fallback: bool,
+    /// Number of pieces failing their hash check because of data
+    /// sent by an address before it's banned, 0 to never ban
+    #[serde(default = "default_ban_threshold")]
+    pub ban_threshold: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// Address of the proxy, None if it couldn't be resolved
+    pub addr: Option<SocketAddr>,
+    /// Credentials for the proxy, if it requires authentication
+    pub auth: Option<(String, String)>,
+    pub peers: bool,
+    pub trackers: bool,
+    pub dht: bool,
+    pub webseeds: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfigFile {
+    #[serde(default = "default_proxy_kind")]
+    pub kind: ProxyKind,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_proxied")]
+    pub peers: bool,
+    #[serde(default = "default_proxied")]
+    pub trackers: bool,
+    #[serde(default = "default_proxied")]
+    pub dht: bool,
+    #[serde(default = "default_proxied")]
+    pub webseeds: bool,
+}
+
+/// Type of proxy outgoing connections are made through
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    None,
+    /// SOCKS5, which can also relay UDP traffic
+    Socks5,
+    /// HTTP CONNECT, which only supports TCP
+    Http,
+}
+
+/// Class of traffic which may be sent through the proxy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyClass {
+    Peers,
+    Trackers,
+    Dht,
+    WebSeeds,
+}
+
+/// How space for a torrent's files is allocated when it's added
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Allocation {
+    /// Files are only created once data is written to them
+    None,
+    /// Files are created at their full size, without reserving space
+    Sparse,
+    /// Space for files is reserved up front(posix_fallocate)
+    Full,
+}
+
+/// Transport used for outgoing peer connections
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Utp,
+}
+
+/// Policy for encrypting peer connections(MSE/PE)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    /// Only plaintext connections are made and accepted
+    Disabled,
+    /// Outgoing connections are encrypted, retrying in plaintext if the handshake
+    /// fails, and incoming connections may be either
+    Preferred,
+    /// Only encrypted connections are made and accepted
+    Required,
 }
 
 impl Config {
@@ -98,6 +221,32 @@ impl Config {
         };
         file.disk.session = expand_tilde(&file.disk.session);
         file.disk.directory = expand_tilde(&file.disk.directory);
+        file.net.blocklists = file.net
+            .blocklists
+            .iter()
+            .map(|p| expand_tilde(p))
+            .collect();
+        let proxy = ProxyConfig {
+            kind: file.proxy.kind,
+            addr: file.proxy
+                .address
+                .and_then(|a| a.to_socket_addrs().ok())
+                .and_then(|mut a| a.next()),
+            auth: match (file.proxy.username, file.proxy.password) {
+                (Some(u), Some(p)) => Some((u, p)),
+                (Some(u), None) => Some((u, String::new())),
+                _ => None,
+            },
+            peers: file.proxy.peers,
+            trackers: file.proxy.trackers,
+            dht: file.proxy.dht,
+            webseeds: file.proxy.webseeds,
+        };
+        file.tracker.ca_certs = file.tracker
+            .ca_certs
+            .iter()
+            .map(|p| expand_tilde(p))
+            .collect();
         Config {
             port: file.port,
             trk: file.tracker,
@@ -106,10 +255,24 @@ impl Config {
             net: file.net,
             peer: file.peer,
             dht,
+            proxy,
         }
     }
 }
 
+impl ProxyConfig {
+    /// Whether or not traffic of the given class goes through the proxy
+    pub fn enabled(&self, class: ProxyClass) -> bool {
+        self.kind != ProxyKind::None &&
+            match class {
+                ProxyClass::Peers => self.peers,
+                ProxyClass::Trackers => self.trackers,
+                ProxyClass::Dht => self.dht,
+                ProxyClass::WebSeeds => self.webseeds,
+            }
+    }
+}
+
 fn default_port() -> u16 {
     16384
 }
@@ -152,6 +315,30 @@ fn default_max_announces() -> usize {
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_encryption() -> Encryption {
+    Encryption::Disabled
+}
+fn default_allocation() -> Allocation {
+    Allocation::None
+}
+fn default_cache_size() -> usize {
+    16
+}
+fn default_transport() -> Transport {
+    Transport::Tcp
+}
+fn default_fallback() -> bool {
+    true
+}
+fn default_ban_threshold() -> u32 {
+    3
+}
+fn default_proxy_kind() -> ProxyKind {
+    ProxyKind::None
+}
+fn default_proxied() -> bool {
+    true
+}
 
 impl Default for Config {
     fn default() -> Self {
@@ -163,6 +350,7 @@ impl Default for Config {
             net: Default::default(),
             dht: Default::default(),
             peer: Default::default(),
+            proxy: Default::default(),
         }
     }
 }
@@ -180,7 +368,10 @@ impl Default for RpcConfig {
 
 impl Default for TrkConfig {
     fn default() -> TrkConfig {
-        TrkConfig { port: default_trk_port() }
+        TrkConfig {
+            port: default_trk_port(),
+            ca_certs: Vec::new(),
+        }
     }
 }
 
@@ -207,6 +398,8 @@ impl Default for DiskConfig {
         DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
+            allocation: default_allocation(),
+            cache_size: default_cache_size(),
         }
     }
 }
@@ -217,13 +410,52 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            bind_address: None,
+            interface: None,
+            kill_switch: false,
+            blocklists: Vec::new(),
+        }
+    }
+}
+
+impl Default for ProxyConfigFile {
+    fn default() -> ProxyConfigFile {
+        ProxyConfigFile {
+            kind: default_proxy_kind(),
+            address: None,
+            username: None,
+            password: None,
+            peers: default_proxied(),
+            trackers: default_proxied(),
+            dht: default_proxied(),
+            webseeds: default_proxied(),
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> ProxyConfig {
+        ProxyConfig {
+            kind: default_proxy_kind(),
+            addr: None,
+            auth: None,
+            peers: default_proxied(),
+            trackers: default_proxied(),
+            dht: default_proxied(),
+            webseeds: default_proxied(),
         }
     }
 }
 
 impl Default for PeerConfig {
     fn default() -> PeerConfig {
-        PeerConfig { prune_timeout: default_prune_timeout() }
+        PeerConfig {
+            prune_timeout: default_prune_timeout(),
+            encryption: default_encryption(),
+            transport: default_transport(),
+            fallback: default_fallback(),
+            ban_threshold: default_ban_threshold(),
+        }
     }
 }
 
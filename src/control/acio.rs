@@ -95,7 +95,7 @@ impl ACIO {
         if let Some(peer) = d.peers.get_mut(&not.id) {
             let ev = not.event;
             if ev.readable() {
-                while let Some(msg) = peer.readable().chain_err(|| ErrorKind::IO)? {
+                while let Some(msg) = peer.readable().chain_err(|| peer_err(peer))? {
                     events.push(cio::Event::Peer {
                         peer: not.id,
                         event: Ok(msg),
@@ -103,7 +103,7 @@ impl ACIO {
                 }
             }
             if ev.writable() {
-                peer.writable().chain_err(|| ErrorKind::IO)?;
+                peer.writable().chain_err(|| peer_err(peer))?;
             }
         }
         Ok(())
@@ -203,7 +203,7 @@ impl cio::CIO for ACIO {
     fn msg_peer(&mut self, pid: cio::PID, msg: torrent::Message) {
         let d = self.d();
         let err = if let Some(peer) = d.peers.get_mut(&pid) {
-            peer.write_message(msg).chain_err(|| ErrorKind::IO).err()
+            peer.write_message(msg).chain_err(|| peer_err(peer)).err()
         } else {
             // might happen if removed but still present in a torrent
             debug!(d.l, "Tried to message peer which has been removed!");
@@ -274,3 +274,12 @@ impl ACIOData {
         }
     }
 }
+
+/// Kind of error for a failed peer connection.
+fn peer_err(peer: &torrent::PeerConn) -> ErrorKind {
+    if peer.sock().mse_failed() {
+        ErrorKind::Handshake
+    } else {
+        ErrorKind::IO
+    }
+}
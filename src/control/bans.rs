@@ -0,0 +1,93 @@
+//! Strikes against addresses which have sent corrupt data,
+//! and the addresses banned because of them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+
+use rpc::resource::{Ban, Resource};
+use util::ban_rpc_id;
+
+pub struct Bans {
+    /// Number of strikes an address may get before it's banned, 0 to never ban
+    threshold: u32,
+    strikes: HashMap<IpAddr, u32>,
+    banned: HashMap<IpAddr, DateTime<Utc>>,
+}
+
+impl Bans {
+    pub fn new(threshold: u32) -> Bans {
+        Bans {
+            threshold,
+            strikes: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    /// Records a strike against an address, returning true if it
+    /// has now been banned.
+    pub fn strike(&mut self, ip: IpAddr) -> bool {
+        if self.threshold == 0 || self.banned.contains_key(&ip) {
+            return false;
+        }
+        let strikes = self.strikes.entry(ip).or_insert(0);
+        *strikes += 1;
+        if *strikes < self.threshold {
+            return false;
+        }
+        self.strikes.remove(&ip);
+        self.banned.insert(ip, Utc::now());
+        true
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains_key(ip)
+    }
+
+    /// Lifts the ban with the given RPC id, returning the address
+    /// which was banned.
+    pub fn unban(&mut self, id: &str) -> Option<IpAddr> {
+        let ip = self.banned.keys().find(|ip| ban_rpc_id(ip) == id).cloned();
+        if let Some(ref ip) = ip {
+            self.banned.remove(ip);
+        }
+        ip
+    }
+
+    pub fn resource(&self, ip: &IpAddr) -> Option<Resource> {
+        self.banned.get(ip).map(|created| {
+            Resource::Ban(Ban {
+                id: ban_rpc_id(ip),
+                ip: ip.to_string(),
+                created: *created,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bans;
+    use util::ban_rpc_id;
+
+    #[test]
+    fn test_bans() {
+        let mut b = Bans::new(2);
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(!b.strike(ip));
+        assert!(!b.is_banned(&ip));
+        assert!(b.strike(ip));
+        assert!(b.is_banned(&ip));
+        assert!(b.resource(&ip).is_some());
+
+        assert_eq!(b.unban("bad id"), None);
+        assert_eq!(b.unban(&ban_rpc_id(&ip)), Some(ip));
+        assert!(!b.is_banned(&ip));
+        // Strikes start over once unbanned
+        assert!(!b.strike(ip));
+
+        let mut b = Bans::new(0);
+        assert!(!b.strike(ip));
+    }
+}
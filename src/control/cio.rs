@@ -7,6 +7,11 @@ error_chain! {
                 display("IO error")
         }
 
+        Handshake {
+            description("encryption handshake failed")
+                display("Encryption handshake failed after connecting")
+        }
+
         Full {
             description("FD limit reached")
                 display("Too many existing open fd's, socket rejected")
@@ -1,7 +1,9 @@
 use torrent::Torrent;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time;
 use control::cio;
+use slog::Logger;
+use util;
 
 pub trait Job<T: cio::CIO> {
     fn update(&mut self, torrents: &mut HashMap<usize, Torrent<T>>);
@@ -50,6 +52,16 @@ impl<T: cio::CIO> Job<T> for TrackerUpdate {
     }
 }
 
+pub struct ScrapeUpdate;
+
+impl<T: cio::CIO> Job<T> for ScrapeUpdate {
+    fn update(&mut self, torrents: &mut HashMap<usize, Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.scrape();
+        }
+    }
+}
+
 pub struct UnchokeUpdate;
 
 impl<T: cio::CIO> Job<T> for UnchokeUpdate {
@@ -72,6 +84,91 @@ impl<T: cio::CIO> Job<T> for SessionUpdate {
     }
 }
 
+pub struct MetadataUpdate;
+
+impl<T: cio::CIO> Job<T> for MetadataUpdate {
+    fn update(&mut self, torrents: &mut HashMap<usize, Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_metadata();
+        }
+    }
+}
+
+pub struct PexUpdate;
+
+impl<T: cio::CIO> Job<T> for PexUpdate {
+    fn update(&mut self, torrents: &mut HashMap<usize, Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_pex();
+        }
+    }
+}
+
+pub struct WebSeedUpdate;
+
+impl<T: cio::CIO> Job<T> for WebSeedUpdate {
+    fn update(&mut self, torrents: &mut HashMap<usize, Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_webseeds();
+        }
+    }
+}
+
+/// Keeps the bind interface's address up to date and, if enabled,
+/// pauses all torrents while the bind address or interface is
+/// unavailable, resuming the ones it paused once it returns.
+pub struct KillSwitch {
+    enabled: bool,
+    paused: HashSet<usize>,
+    down: bool,
+    l: Logger,
+}
+
+impl KillSwitch {
+    /// Creates the kill switch, taking over torrents
+    /// that were held paused at startup.
+    pub fn new(enabled: bool, paused: HashSet<usize>, down: bool, l: Logger) -> KillSwitch {
+        KillSwitch {
+            enabled,
+            paused,
+            down,
+            l,
+        }
+    }
+}
+
+impl<T: cio::CIO> Job<T> for KillSwitch {
+    fn update(&mut self, torrents: &mut HashMap<usize, Torrent<T>>) {
+        util::refresh_bind_ip();
+        if !self.enabled {
+            return;
+        }
+        if util::bind_available() {
+            if self.down {
+                info!(self.l, "Bind interface available, resuming torrents");
+                self.down = false;
+            }
+            for id in self.paused.drain() {
+                if let Some(torrent) = torrents.get_mut(&id) {
+                    torrent.resume();
+                }
+            }
+            return;
+        }
+        if !self.down {
+            warn!(self.l, "Bind interface unavailable, pausing torrents");
+            self.down = true;
+        }
+        // Torrents added or resumed while down are paused as well
+        for (id, torrent) in torrents.iter_mut() {
+            if !torrent.paused() {
+                torrent.pause();
+                self.paused.insert(*id);
+            }
+        }
+    }
+}
+
 pub struct TorrentTxUpdate {
     speeds: HashMap<usize, Speed>,
 }
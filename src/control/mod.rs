@@ -1,18 +1,21 @@
 use std::{fs, io, time};
 use std::io::Read;
 use std::sync::atomic;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 
 use slog::Logger;
 use chrono::Utc;
 
-use {rpc, tracker, disk, listener, CONFIG, SHUTDOWN, PEER_ID};
-use util::{io_err, io_err_val, id_to_hash, hash_to_id};
+use {rpc, tracker, disk, listener, ipfilter, CONFIG, SHUTDOWN, PEER_ID};
+use util::{self, io_err, io_err_val, id_to_hash, hash_to_id};
 use torrent::{self, peer, Torrent};
 use throttle::Throttler;
 
 pub mod cio;
 pub mod acio;
+mod bans;
 mod job;
 
 /// Tracker update job interval
@@ -21,6 +24,16 @@ const TRK_JOB_SECS: u64 = 60;
 const UNCHK_JOB_SECS: u64 = 15;
 /// Session serialization job interval
 const SES_JOB_SECS: u64 = 10;
+/// Metadata request timeout check interval
+const MD_JOB_SECS: u64 = 5;
+/// PEX message job interval
+const PEX_JOB_SECS: u64 = 60;
+/// Tracker scrape job interval
+const SCRAPE_JOB_SECS: u64 = 900;
+/// Web seed retry check interval
+const WEBSEED_JOB_SECS: u64 = 5;
+/// Bind interface availability and address check interval
+const KILL_SWITCH_JOB_SECS: u64 = 1;
 /// Interval to update RPC of transfer stats
 const TX_JOB_MS: u64 = 500;
 
@@ -33,10 +46,16 @@ pub struct Control<T: cio::CIO> {
     tid_cnt: usize,
     job_timer: usize,
     tx_rates: Option<(u64, u64)>,
+    /// Blocked connection count last sent to RPC
+    blocked: usize,
+    /// Local address the listening sockets were last bound to
+    bind_ip: Option<IpAddr>,
+    bind_check: time::Instant,
     jobs: job::JobManager<T>,
     torrents: HashMap<usize, Torrent<T>>,
     peers: HashMap<usize, usize>,
     hash_idx: HashMap<[u8; 20], usize>,
+    bans: bans::Bans,
     l: Logger,
 }
 
@@ -53,6 +72,16 @@ impl<T: cio::CIO> Control<T> {
             time::Duration::from_secs(UNCHK_JOB_SECS),
         );
         jobs.add_job(job::SessionUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_job(job::MetadataUpdate, time::Duration::from_secs(MD_JOB_SECS));
+        jobs.add_job(job::PexUpdate, time::Duration::from_secs(PEX_JOB_SECS));
+        jobs.add_job(
+            job::WebSeedUpdate,
+            time::Duration::from_secs(WEBSEED_JOB_SECS),
+        );
+        jobs.add_job(
+            job::ScrapeUpdate,
+            time::Duration::from_secs(SCRAPE_JOB_SECS),
+        );
         jobs.add_job(
             job::TorrentTxUpdate::new(),
             time::Duration::from_millis(TX_JOB_MS),
@@ -70,15 +99,32 @@ impl<T: cio::CIO> Control<T> {
             torrents,
             peers,
             hash_idx,
+            bans: bans::Bans::new(CONFIG.peer.ban_threshold),
             tx_rates: None,
+            blocked: 0,
+            bind_ip: util::listen_ip(),
+            bind_check: time::Instant::now(),
             l,
         })
     }
 
     pub fn run(&mut self) {
-        if self.deserialize().is_err() {
+        let kill_switch = CONFIG.net.kill_switch && bind_configured();
+        // Without the bind interface torrents are held until it's back
+        let hold = kill_switch && !util::bind_available();
+        if hold {
+            warn!(self.l, "Bind interface unavailable, starting torrents paused");
+        }
+        let mut held = HashSet::new();
+        if self.deserialize(hold, &mut held).is_err() {
             warn!(self.l, "Session deserialization failed!");
         }
+        if bind_configured() {
+            self.jobs.add_job(
+                job::KillSwitch::new(kill_switch, held, hold, self.l.clone()),
+                time::Duration::from_secs(KILL_SWITCH_JOB_SECS),
+            );
+        }
         debug!(self.l, "Initialized!");
         self.send_rpc_info();
         let mut events = Vec::with_capacity(20);
@@ -91,6 +137,7 @@ impl<T: cio::CIO> Control<T> {
                 }
             }
             if SHUTDOWN.load(atomic::Ordering::SeqCst) == true {
+                self.serialize();
                 break;
             }
         }
@@ -103,18 +150,23 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
-    fn deserialize(&mut self) -> io::Result<()> {
+    fn deserialize(&mut self, hold: bool, held: &mut HashSet<usize>) -> io::Result<()> {
         debug!(self.l, "Deserializing torrents!");
         let sd = &CONFIG.disk.session;
         for entry in fs::read_dir(sd)? {
-            if let Err(e) = self.deserialize_torrent(entry) {
+            if let Err(e) = self.deserialize_torrent(entry, hold, held) {
                 warn!(self.l, "Failed to deserialize torrent file: {:?}!", e);
             }
         }
         Ok(())
     }
 
-    fn deserialize_torrent(&mut self, entry: io::Result<fs::DirEntry>) -> io::Result<()> {
+    fn deserialize_torrent(
+        &mut self,
+        entry: io::Result<fs::DirEntry>,
+        hold: bool,
+        held: &mut HashSet<usize>,
+    ) -> io::Result<()> {
         let dir = entry?;
         // TODO: We probably should improve this heuristic with and not rely
         // on directory entries, but this is good enough for now.
@@ -130,9 +182,14 @@ impl<T: cio::CIO> Control<T> {
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
         let log = self.l.new(o!("torrent" => tid));
-        if let Ok(t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle(), log) {
+        let res = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle(), log, hold);
+        if let Ok((t, h)) = res {
             trace!(self.l, "Succesfully parsed torrent file {:?}", dir.path());
+            if h {
+                held.insert(tid);
+            }
             self.hash_idx.insert(t.info().hash, tid);
+            self.cio.msg_listener(listener::Request::AddTorrent(t.info().hash));
             self.tid_cnt += 1;
             self.torrents.insert(tid, t);
         } else {
@@ -194,39 +251,183 @@ impl<T: cio::CIO> Control<T> {
 
     fn handle_trk_ev(&mut self, tr: tracker::Response) {
         debug!(self.l, "Handling tracker response");
-        let id = tr.0;
-        let resp = tr.1;
-        {
-            if let Some(torrent) = self.torrents.get_mut(&id) {
-                torrent.set_tracker_response(&resp);
-            } else {
-                return;
+        match tr {
+            tracker::Response::Tracker { tid, url, resp } => {
+                {
+                    if let Some(torrent) = self.torrents.get_mut(&tid) {
+                        torrent.set_tracker_response(&url, &resp);
+                    } else {
+                        return;
+                    }
+                }
+                if let Ok(r) = resp {
+                    self.add_peers(tid, &r.peers);
+                }
+            }
+            tracker::Response::Scrape { url, resp: Ok(stats) } => {
+                for (tid, s) in stats {
+                    if let Some(torrent) = self.torrents.get_mut(&tid) {
+                        torrent.set_scrape(&url, s);
+                    }
+                }
+            }
+            tracker::Response::Scrape { url, resp: Err(e) } => {
+                debug!(self.l, "Failed to scrape tracker {}: {:?}", url, e);
+            }
+            tracker::Response::DHT { tid, peers } => {
+                if self.torrents.contains_key(&tid) {
+                    self.add_peers(tid, &peers);
+                }
+            }
+            tracker::Response::WebSeed { tid, url, resp } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.set_webseed_response(&url, resp);
+                }
             }
         }
+    }
+
+    fn add_peers(&mut self, id: usize, peers: &[SocketAddr]) {
         trace!(self.l, "Adding peers!");
-        if let Ok(r) = resp {
-            for ip in r.peers.iter() {
-                trace!(self.l, "Adding peer({:?})!", ip);
-                if let Ok(peer) = peer::PeerConn::new_outgoing(ip) {
-                    trace!(self.l, "Added peer({:?})!", ip);
-                    self.add_peer(id, peer);
-                }
+        for ip in peers.iter() {
+            if self.bans.is_banned(&ip.ip()) || ipfilter::blocked(&ip.ip()) {
+                continue;
             }
-            if let Some(torrent) = self.torrents.get_mut(&id) {
-                torrent.update_rpc_peers();
+            trace!(self.l, "Adding peer({:?})!", ip);
+            let utp = match self.torrents.get(&id) {
+                Some(t) => t.use_utp(ip),
+                None => return,
+            };
+            let conn = if utp {
+                // The uTP connection itself is run by the listener
+                peer::PeerConn::new_utp(ip).map(|(peer, stream)| {
+                    self.cio.msg_listener(listener::Request::Connect(*ip, stream));
+                    peer
+                })
+            } else {
+                peer::PeerConn::new_outgoing(ip)
+            };
+            if let Ok(peer) = conn {
+                trace!(self.l, "Added peer({:?})!", ip);
+                self.add_peer(id, peer);
             }
         }
+        if let Some(torrent) = self.torrents.get_mut(&id) {
+            torrent.update_rpc_peers();
+        }
     }
 
     fn update_jobs(&mut self) {
         trace!(self.l, "Handling job timer");
+        // Jobs refresh the bind address before it's checked
         self.jobs.update(&mut self.torrents);
+        self.update_bind();
+    }
+
+    /// Rebinds the listening sockets if the local address changed,
+    /// e.g. when a VPN reconnects.
+    fn update_bind(&mut self) {
+        if !bind_configured() ||
+            self.bind_check.elapsed() < time::Duration::from_secs(KILL_SWITCH_JOB_SECS)
+        {
+            return;
+        }
+        self.bind_check = time::Instant::now();
+        let ip = util::listen_ip();
+        if ip != self.bind_ip {
+            info!(self.l, "Local address changed to {:?}, rebinding sockets", ip);
+            self.bind_ip = ip;
+            self.cio.msg_listener(listener::Request::Rebind);
+            self.cio.msg_trk(tracker::Request::Rebind);
+        }
     }
 
     fn handle_disk_ev(&mut self, resp: disk::Response) {
         trace!(self.l, "Got disk response {:?}!", resp);
-        if let Some(torrent) = self.torrents.get_mut(&resp.tid()) {
-            torrent.handle_disk_resp(resp);
+        match resp {
+            disk::Response::Created {
+                client,
+                serial,
+                start,
+                path,
+                info,
+            } => self.handle_created(client, serial, start, path, info),
+            resp => {
+                if !self.torrents.contains_key(&resp.tid()) {
+                    // Streams read as their torrent was removed still need a reply
+                    match resp {
+                        disk::Response::Stream { stream, .. } |
+                        disk::Response::StreamError { stream, .. } => {
+                            self.cio.msg_rpc(rpc::CtlMessage::StreamError {
+                                stream,
+                                reason: "Torrent was removed".to_owned(),
+                            });
+                        }
+                        _ => {}
+                    }
+                    return;
+                }
+                let offenders = match self.torrents.get_mut(&resp.tid()) {
+                    Some(torrent) => {
+                        torrent.handle_disk_resp(resp);
+                        torrent.take_offenders()
+                    }
+                    None => return,
+                };
+                for ip in offenders {
+                    self.strike(ip);
+                }
+            }
+        }
+    }
+
+    /// Records a strike against an address which sent corrupt data,
+    /// banning it and disconnecting its peers once it has too many.
+    fn strike(&mut self, ip: IpAddr) {
+        if !self.bans.strike(ip) {
+            return;
+        }
+        warn!(self.l, "Banning {} for sending corrupt data", ip);
+        for (_, torrent) in self.torrents.iter_mut() {
+            torrent.remove_peers_from(ip);
+        }
+        if let Some(r) = self.bans.resource(&ip) {
+            self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![r]));
+        }
+    }
+
+    fn handle_created(
+        &mut self,
+        client: usize,
+        serial: u64,
+        start: bool,
+        path: PathBuf,
+        info: io::Result<torrent::Info>,
+    ) {
+        match info {
+            Ok(info) => {
+                debug!(self.l, "Created torrent {:?}!", info);
+                let name = format!("{}.torrent", info.name);
+                let data = info.to_torrent().unwrap();
+                if start {
+                    let dir = path.parent().map(|p| p.to_string_lossy().into_owned());
+                    self.add_torrent(info, dir);
+                }
+                self.cio.msg_rpc(rpc::CtlMessage::TorrentFile {
+                    client,
+                    serial,
+                    name,
+                    data,
+                });
+            }
+            Err(e) => {
+                warn!(self.l, "Failed to create torrent: {}", e);
+                self.cio.msg_rpc(rpc::CtlMessage::Error {
+                    client,
+                    serial,
+                    reason: format!("Failed to create torrent: {}", e),
+                });
+            }
         }
     }
 
@@ -244,15 +445,24 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn handle_peer_ev(&mut self, peer: cio::PID, ev: cio::Result<torrent::Message>) {
-        let ref mut p = self.peers;
-        let ref mut t = self.torrents;
-
-        p.get(&peer).cloned().and_then(|id| t.get_mut(&id)).map(
-            |torrent| if torrent.peer_ev(peer, ev).is_err() {
-                p.remove(&peer);
-                torrent.update_rpc_peers();
-            },
-        );
+        let tid = match self.peers.get(&peer).cloned() {
+            Some(tid) => tid,
+            None => return,
+        };
+        let discovered = match self.torrents.get_mut(&tid) {
+            Some(torrent) => {
+                if torrent.peer_ev(peer, ev).is_err() {
+                    self.peers.remove(&peer);
+                    torrent.update_rpc_peers();
+                }
+                torrent.take_discovered()
+            }
+            None => return,
+        };
+        // Peers found via PEX are connected to just like tracker peers
+        if !discovered.is_empty() {
+            self.add_peers(tid, &discovered);
+        }
     }
 
     fn flush_blocked_peers(&mut self) {
@@ -272,6 +482,7 @@ impl<T: cio::CIO> Control<T> {
         let log = self.l.new(o!("torrent" => tid));
         let t = Torrent::new(tid, path, info, throttle, self.cio.new_handle(), log);
         self.hash_idx.insert(t.info().hash, tid);
+        self.cio.msg_listener(listener::Request::AddTorrent(t.info().hash));
         self.tid_cnt += 1;
         self.torrents.insert(tid, t);
     }
@@ -304,6 +515,36 @@ impl<T: cio::CIO> Control<T> {
                     t.rpc_update_file(id, priority);
                 }
             }
+            rpc::Message::ReadStream {
+                stream,
+                torrent_id,
+                file_id,
+                offset,
+                len,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i));
+                match res {
+                    Some(t) => t.rpc_read_stream(stream, file_id, offset, len),
+                    None => {
+                        self.cio.msg_rpc(rpc::CtlMessage::StreamError {
+                            stream,
+                            reason: format!("Unknown torrent {}", torrent_id),
+                        });
+                    }
+                }
+            }
+            rpc::Message::CloseStream { stream, torrent_id } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.rpc_close_stream(stream));
+            }
             rpc::Message::UpdateServer {
                 id,
                 throttle_up,
@@ -324,10 +565,14 @@ impl<T: cio::CIO> Control<T> {
             rpc::Message::RemoveTorrent(id) => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
+                let cio = &mut self.cio;
                 id_to_hash(&id)
                     .and_then(|d| hash_idx.remove(d.as_ref()))
                     .and_then(|i| torrents.remove(&i))
-                    .map(|mut t| t.delete());
+                    .map(|mut t| {
+                        cio.msg_listener(listener::Request::RemoveTorrent(t.info().hash));
+                        t.delete();
+                    });
             }
             rpc::Message::RemovePeer { id, torrent_id } => {
                 let hash_idx = &self.hash_idx;
@@ -345,6 +590,86 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|i| torrents.get_mut(i))
                     .map(|t| t.remove_tracker(&id));
             }
+            rpc::Message::RemoveBan(id) => {
+                if let Some(ip) = self.bans.unban(&id) {
+                    debug!(self.l, "Unbanned {}", ip);
+                    self.cio.msg_rpc(rpc::CtlMessage::Removed(vec![id]));
+                }
+            }
+            rpc::Message::ExportTorrent { client, serial, id } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &self.torrents;
+                let msg = match id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get(i))
+                    .map(|t| (t.info().name.clone(), t.info().to_torrent())) {
+                    Some((name, Some(data))) => {
+                        rpc::CtlMessage::TorrentFile {
+                            client,
+                            serial,
+                            name: format!("{}.torrent", name),
+                            data,
+                        }
+                    }
+                    Some((_, None)) => {
+                        rpc::CtlMessage::Error {
+                            client,
+                            serial,
+                            reason: "Torrent metadata is not available".to_owned(),
+                        }
+                    }
+                    None => {
+                        rpc::CtlMessage::Error {
+                            client,
+                            serial,
+                            reason: format!("unknown torrent id {}", id),
+                        }
+                    }
+                };
+                self.cio.msg_rpc(msg);
+            }
+            rpc::Message::CreateTorrent {
+                client,
+                serial,
+                start,
+                mut opts,
+            } => {
+                match create_path(&opts.path) {
+                    Ok(path) => {
+                        opts.path = path;
+                        self.cio.msg_disk(
+                            disk::Request::create(client, serial, start, opts),
+                        );
+                    }
+                    Err(e) => {
+                        warn!(self.l, "Refused to create torrent from {:?}: {}", opts.path, e);
+                        self.cio.msg_rpc(rpc::CtlMessage::Error {
+                            client,
+                            serial,
+                            reason: format!("Failed to create torrent: {}", e),
+                        });
+                    }
+                }
+            }
+            rpc::Message::ReloadBlocklist { client, serial } => {
+                match ipfilter::reload() {
+                    Ok(ranges) => {
+                        info!(self.l, "Reloaded blocklists, {} ranges", ranges);
+                        for (_, torrent) in self.torrents.iter_mut() {
+                            torrent.remove_blocked_peers();
+                        }
+                        self.update_rpc_blocklist();
+                    }
+                    Err(e) => {
+                        error!(self.l, "Failed to reload blocklists: {}", e);
+                        self.cio.msg_rpc(rpc::CtlMessage::Error {
+                            client,
+                            serial,
+                            reason: format!("Failed to reload blocklists: {}", e),
+                        });
+                    }
+                }
+            }
         }
         false
     }
@@ -359,6 +684,10 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn add_inc_peer(&mut self, id: usize, peer: peer::PeerConn, cid: [u8; 20], rsv: [u8; 8]) {
+        if self.bans.is_banned(&peer.sock().addr().ip()) {
+            debug!(self.l, "Rejecting banned peer {}", peer.sock().addr());
+            return;
+        }
         trace!(self.l, "Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
             if let Some(pid) = torrent.add_inc_peer(peer, cid, rsv) {
@@ -378,6 +707,20 @@ impl<T: cio::CIO> Control<T> {
             ]));
             self.tx_rates = None;
         }
+        if ipfilter::blocked_count() != self.blocked {
+            self.update_rpc_blocklist();
+        }
+    }
+
+    fn update_rpc_blocklist(&mut self) {
+        self.blocked = ipfilter::blocked_count();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::Blocklist {
+                id: hash_to_id(&PEER_ID[..]),
+                blocklist_ranges: ipfilter::ranges() as u64,
+                blocked: self.blocked as u64,
+            },
+        ]));
     }
 
     fn send_rpc_info(&mut self) {
@@ -388,11 +731,30 @@ impl<T: cio::CIO> Control<T> {
             throttle_up: 0,
             throttle_down: 0,
             started: Utc::now(),
+            blocklist_ranges: ipfilter::ranges() as u64,
+            blocked: ipfilter::blocked_count() as u64,
         });
         self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res]));
     }
 }
 
+/// Resolves the path of the data a torrent is created from. Relative paths
+/// are taken to be under the download directory, and the data must be
+/// within it so that clients can't read anything else on the server.
+fn create_path(path: &Path) -> io::Result<PathBuf> {
+    let dir = fs::canonicalize(&CONFIG.disk.directory)?;
+    let path = fs::canonicalize(dir.join(path))?;
+    if !path.starts_with(&dir) {
+        return io_err("Path is outside the download directory");
+    }
+    Ok(path)
+}
+
+/// Whether sockets are bound to a configured address or interface
+fn bind_configured() -> bool {
+    CONFIG.net.bind_address.is_some() || CONFIG.net.interface.is_some()
+}
+
 impl<T: cio::CIO> Drop for Control<T> {
     fn drop(&mut self) {
         debug!(self.l, "Triggering thread shutdown sequence!");
@@ -0,0 +1,315 @@
+//! Caches used by the disk thread: an LRU of open file handles, blocks
+//! of pieces being gathered in memory before they're written, and
+//! recently read pieces which are likely to be requested again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::{fs, io, mem, path};
+
+use super::Location;
+use CONFIG;
+
+/// Caches owned by the disk thread.
+pub struct Cache {
+    pub files: FileCache,
+    pub write: WriteCache,
+    pub read: ReadCache,
+}
+
+/// LRU cache of open files.
+pub struct FileCache {
+    files: HashMap<path::PathBuf, (fs::File, u64)>,
+    capacity: usize,
+    tick: u64,
+}
+
+/// A block waiting to be written, along with its file locations.
+pub struct Block {
+    pub data: Box<[u8; 16384]>,
+    pub locations: Vec<Location>,
+}
+
+/// A piece whose blocks are being gathered in memory.
+pub struct Piece {
+    pub tid: usize,
+    pub path: Option<String>,
+    /// Blocks of the piece, by offset
+    pub blocks: BTreeMap<u32, Block>,
+    len: u32,
+    received: u32,
+    used: u64,
+}
+
+/// Write-back cache which holds blocks until their piece is complete,
+/// so that it can be written in as few operations as possible.
+pub struct WriteCache {
+    pieces: HashMap<(usize, u32), Piece>,
+    size: usize,
+    tick: u64,
+}
+
+/// Cache of pieces read from disk, evicted least recently used first.
+pub struct ReadCache {
+    pieces: HashMap<(usize, u32), (Vec<u8>, u64)>,
+    size: usize,
+    tick: u64,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            files: FileCache::new(CONFIG.net.max_open_files),
+            write: WriteCache::new(),
+            read: ReadCache::new(),
+        }
+    }
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> FileCache {
+        FileCache {
+            files: HashMap::new(),
+            capacity,
+            tick: 0,
+        }
+    }
+
+    pub fn get_file<F: FnOnce(&mut fs::File) -> io::Result<()>>(
+        &mut self,
+        path: &path::Path,
+        f: F,
+    ) -> io::Result<()> {
+        self.tick += 1;
+        if let Some(&mut (ref mut file, ref mut used)) = self.files.get_mut(path) {
+            *used = self.tick;
+            return f(file);
+        }
+        if self.files.len() >= self.capacity {
+            let lru = self.files
+                .iter()
+                .min_by_key(|&(_, &(_, used))| used)
+                .map(|(p, _)| p.clone());
+            if let Some(p) = lru {
+                self.files.remove(&p);
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .read(true)
+            .open(path)?;
+        f(&mut file)?;
+        self.files.insert(path.to_path_buf(), (file, self.tick));
+        Ok(())
+    }
+
+    /// Closes a file if it's open.
+    pub fn remove(&mut self, path: &path::Path) {
+        self.files.remove(path);
+    }
+}
+
+impl Piece {
+    /// Number of bytes of the piece held in memory
+    fn size(&self) -> usize {
+        self.blocks.len() * 16384
+    }
+}
+
+impl WriteCache {
+    pub fn new() -> WriteCache {
+        WriteCache {
+            pieces: HashMap::new(),
+            size: 0,
+            tick: 0,
+        }
+    }
+
+    /// Adds a block to its piece, returning the piece once every
+    /// block of it is present.
+    pub fn add(
+        &mut self,
+        tid: usize,
+        index: u32,
+        begin: u32,
+        piece_len: u32,
+        block: Block,
+        path: Option<String>,
+    ) -> Option<Piece> {
+        self.tick += 1;
+        let len = block.locations.iter().map(|l| l.end - l.start).sum::<usize>() as u32;
+        let tick = self.tick;
+        let complete = {
+            let piece = self.pieces.entry((tid, index)).or_insert_with(|| {
+                Piece {
+                    tid,
+                    path,
+                    blocks: BTreeMap::new(),
+                    len: piece_len,
+                    received: 0,
+                    used: tick,
+                }
+            });
+            piece.used = tick;
+            match piece.blocks.insert(begin, block) {
+                // Blocks received more than once replace the original
+                Some(_) => {}
+                None => {
+                    piece.received += len;
+                    self.size += 16384;
+                }
+            }
+            piece.received >= piece.len
+        };
+        if complete {
+            self.remove(&(tid, index))
+        } else {
+            None
+        }
+    }
+
+    /// Removes the least recently written to piece.
+    pub fn evict(&mut self) -> Option<Piece> {
+        let lru = self.pieces
+            .iter()
+            .min_by_key(|&(_, p)| p.used)
+            .map(|(k, _)| *k);
+        lru.and_then(|k| self.remove(&k))
+    }
+
+    /// Removes a piece, whether or not all of its blocks are present.
+    pub fn remove_piece(&mut self, tid: usize, index: u32) -> Option<Piece> {
+        self.remove(&(tid, index))
+    }
+
+    /// Removes all of a torrent's pieces.
+    pub fn remove_torrent(&mut self, tid: usize) -> Vec<Piece> {
+        let keys: Vec<_> = self.pieces.keys().filter(|k| k.0 == tid).cloned().collect();
+        keys.iter().filter_map(|k| self.remove(k)).collect()
+    }
+
+    /// Removes every piece.
+    pub fn remove_all(&mut self) -> Vec<Piece> {
+        self.size = 0;
+        mem::replace(&mut self.pieces, HashMap::new())
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect()
+    }
+
+    /// Number of bytes held in memory
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn remove(&mut self, key: &(usize, u32)) -> Option<Piece> {
+        let piece = self.pieces.remove(key);
+        if let Some(ref p) = piece {
+            self.size -= p.size();
+        }
+        piece
+    }
+}
+
+impl ReadCache {
+    pub fn new() -> ReadCache {
+        ReadCache {
+            pieces: HashMap::new(),
+            size: 0,
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, tid: usize, index: u32) -> Option<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.pieces.get_mut(&(tid, index)).map(|&mut (ref data, ref mut used)| {
+            *used = tick;
+            &data[..]
+        })
+    }
+
+    /// Caches a piece, evicting others to keep the cache within capacity.
+    pub fn insert(&mut self, tid: usize, index: u32, data: Vec<u8>, capacity: usize) {
+        if data.len() > capacity {
+            return;
+        }
+        self.invalidate(tid, index);
+        while self.size + data.len() > capacity {
+            let lru = self.pieces
+                .iter()
+                .min_by_key(|&(_, &(_, used))| used)
+                .map(|(k, _)| *k);
+            match lru {
+                Some((t, i)) => self.invalidate(t, i),
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.size += data.len();
+        self.pieces.insert((tid, index), (data, self.tick));
+    }
+
+    /// Removes a piece whose data may have changed.
+    pub fn invalidate(&mut self, tid: usize, index: u32) {
+        if let Some((data, _)) = self.pieces.remove(&(tid, index)) {
+            self.size -= data.len();
+        }
+    }
+
+    pub fn remove_torrent(&mut self, tid: usize) {
+        let keys: Vec<_> = self.pieces.keys().filter(|k| k.0 == tid).cloned().collect();
+        for (t, i) in keys {
+            self.invalidate(t, i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{Block, ReadCache, WriteCache};
+    use disk::Location;
+
+    fn block(begin: usize, len: usize) -> Block {
+        Block {
+            data: Box::new([0u8; 16384]),
+            locations: vec![Location::new(PathBuf::from("a"), begin as u64, 0, len)],
+        }
+    }
+
+    #[test]
+    fn test_write_cache() {
+        let mut c = WriteCache::new();
+        assert!(c.add(0, 0, 0, 20000, block(0, 16384), None).is_none());
+        assert!(c.add(0, 1, 0, 20000, block(20000, 16384), None).is_none());
+        // Duplicate blocks don't count towards completion
+        assert!(c.add(0, 0, 0, 20000, block(0, 16384), None).is_none());
+        assert_eq!(c.size(), 32768);
+
+        let p = c.add(0, 0, 16384, 20000, block(16384, 3616), None).unwrap();
+        assert_eq!(p.blocks.keys().cloned().collect::<Vec<_>>(), vec![0, 16384]);
+        assert_eq!(c.size(), 16384);
+
+        assert_eq!(c.evict().map(|p| p.tid), Some(0));
+        assert!(c.evict().is_none());
+        assert_eq!(c.size(), 0);
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let mut c = ReadCache::new();
+        c.insert(0, 0, vec![0; 10], 25);
+        c.insert(0, 1, vec![1; 10], 25);
+        assert!(c.get(0, 0).is_some());
+        // Piece 1 is the least recently used
+        c.insert(1, 0, vec![2; 10], 25);
+        assert!(c.get(0, 1).is_none());
+        assert_eq!(c.get(0, 0), Some(&[0u8; 10][..]));
+
+        c.insert(1, 1, vec![3; 30], 25);
+        assert!(c.get(1, 1).is_none());
+        c.remove_torrent(0);
+        assert!(c.get(0, 0).is_none());
+        assert!(c.get(1, 0).is_some());
+    }
+}
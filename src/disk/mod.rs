@@ -0,0 +1,1113 @@
+mod cache;
+
+use std::sync::Arc;
+use std::{cmp, env, fs, fmt, path, thread};
+use std::io::{self, Seek, SeekFrom, Write, Read};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use torrent::Info;
+use torrent::info::CreateOpts;
+use slog::Logger;
+use util::hash_to_id;
+use ring::digest;
+use {amy, bincode, libc};
+use {handle, CONFIG};
+use config::Allocation;
+use self::cache::{Block, Cache, FileCache, Piece};
+
+const POLL_INT_MS: usize = 1000;
+/// Size of the chunks data is copied in when moved across filesystems
+const MOVE_BUF_LEN: usize = 16 * 1024 * 1024;
+/// Maximum number of buffers written in a single call(IOV_MAX)
+#[cfg(target_os = "linux")]
+const MAX_IOVECS: usize = 1024;
+
+pub struct Disk {
+    poll: amy::Poller,
+    ch: handle::Handle<Request, Response>,
+    l: Logger,
+    cache: Cache,
+}
+
+pub enum Request {
+    /// Writes a block, which is held in the write cache
+    /// until the rest of its piece arrives
+    Write {
+        tid: usize,
+        index: u32,
+        begin: u32,
+        piece_len: u32,
+        data: Box<[u8; 16384]>,
+        locations: Vec<Location>,
+        path: Option<String>,
+    },
+    /// Reads a block, using the locations of its whole piece
+    /// so that the piece may be read into the read cache
+    Read {
+        data: Box<[u8; 16384]>,
+        locations: Vec<Location>,
+        context: Ctx,
+        path: Option<String>,
+    },
+    /// Reads part of a file which is being streamed over RPC
+    ReadStream {
+        tid: usize,
+        stream: usize,
+        file: usize,
+        location: Location,
+        path: Option<String>,
+    },
+    /// Checks the hash of a downloaded piece, replying with
+    /// `Response::Verified`. The hash of each block is included if the piece
+    /// is invalid or blocks is set, so that the peers which sent bad blocks
+    /// can be found.
+    Verify {
+        tid: usize,
+        index: u32,
+        hash: Vec<u8>,
+        locations: Vec<Location>,
+        path: Option<String>,
+        blocks: bool,
+    },
+    /// Saves a torrent's session data, followed by the stats of its
+    /// files once all preceding writes have been performed.
+    Serialize {
+        tid: usize,
+        data: Vec<u8>,
+        info: Arc<Info>,
+        path: Option<String>,
+    },
+    Delete { tid: usize, hash: [u8; 20] },
+    /// Creates a torrent's wanted files according to the configured
+    /// allocation mode, replying with `Response::Allocated` on success
+    Allocate {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        skipped: Vec<bool>,
+    },
+    /// Validates every piece of a torrent, reading pieces which
+    /// are partly in skipped files from the part file
+    Validate {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        skipped: Vec<bool>,
+    },
+    /// Moves a torrent's files to a new directory, reporting progress
+    /// with `Response::Moving` and the result in a `Response::Moved`
+    Move {
+        tid: usize,
+        info: Arc<Info>,
+        from: Option<String>,
+        to: String,
+    },
+    /// Creates a new torrent for an RPC client, the result
+    /// is sent back in a `Response::Created`
+    Create {
+        client: usize,
+        serial: u64,
+        start: bool,
+        opts: CreateOpts,
+    },
+    Shutdown,
+}
+
+/// Size and modification time of a torrent file, used to determine
+/// if fast resume data still matches the data on disk.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileStat {
+    len: u64,
+    mtime: (u64, u32),
+}
+
+pub struct Ctx {
+    pub pid: usize,
+    pub tid: usize,
+    pub idx: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Ctx {
+    pub fn new(pid: usize, tid: usize, idx: u32, begin: u32, length: u32) -> Ctx {
+        Ctx {
+            pid,
+            tid,
+            idx,
+            begin,
+            length,
+        }
+    }
+}
+
+impl Request {
+    pub fn write(
+        tid: usize,
+        index: u32,
+        begin: u32,
+        piece_len: u32,
+        data: Box<[u8; 16384]>,
+        locations: Vec<Location>,
+        path: Option<String>,
+    ) -> Request {
+        Request::Write {
+            tid,
+            index,
+            begin,
+            piece_len,
+            data,
+            locations,
+            path,
+        }
+    }
+
+    pub fn read(
+        context: Ctx,
+        data: Box<[u8; 16384]>,
+        locations: Vec<Location>,
+        path: Option<String>,
+    ) -> Request {
+        Request::Read {
+            context,
+            data,
+            locations,
+            path,
+        }
+    }
+
+    pub fn read_stream(
+        tid: usize,
+        stream: usize,
+        file: usize,
+        location: Location,
+        path: Option<String>,
+    ) -> Request {
+        Request::ReadStream {
+            tid,
+            stream,
+            file,
+            location,
+            path,
+        }
+    }
+
+    pub fn verify(
+        tid: usize,
+        index: u32,
+        hash: Vec<u8>,
+        locations: Vec<Location>,
+        path: Option<String>,
+        blocks: bool,
+    ) -> Request {
+        Request::Verify {
+            tid,
+            index,
+            hash,
+            locations,
+            path,
+            blocks,
+        }
+    }
+
+    pub fn serialize(
+        tid: usize,
+        data: Vec<u8>,
+        info: Arc<Info>,
+        path: Option<String>,
+    ) -> Request {
+        Request::Serialize {
+            tid,
+            data,
+            info,
+            path,
+        }
+    }
+
+    pub fn validate(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        skipped: Vec<bool>,
+    ) -> Request {
+        Request::Validate {
+            tid,
+            info,
+            path,
+            skipped,
+        }
+    }
+
+    pub fn allocate(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        skipped: Vec<bool>,
+    ) -> Request {
+        Request::Allocate {
+            tid,
+            info,
+            path,
+            skipped,
+        }
+    }
+
+    pub fn move_data(tid: usize, info: Arc<Info>, from: Option<String>, to: String) -> Request {
+        Request::Move {
+            tid,
+            info,
+            from,
+            to,
+        }
+    }
+
+    pub fn create(client: usize, serial: u64, start: bool, opts: CreateOpts) -> Request {
+        Request::Create {
+            client,
+            serial,
+            start,
+            opts,
+        }
+    }
+
+    pub fn delete(tid: usize, hash: [u8; 20]) -> Request {
+        Request::Delete { tid, hash }
+    }
+
+    pub fn shutdown() -> Request {
+        Request::Shutdown
+    }
+
+    fn execute(self, c: &mut Cache) -> io::Result<Option<Response>> {
+        let sd = &CONFIG.disk.session;
+        let dd = &CONFIG.disk.directory;
+        match self {
+            Request::Write {
+                tid,
+                index,
+                begin,
+                piece_len,
+                data,
+                locations,
+                path,
+            } => {
+                c.read.invalidate(tid, index);
+                let block = Block { data, locations };
+                if let Some(piece) = c.write.add(tid, index, begin, piece_len, block, path) {
+                    flush(&mut c.files, piece)?;
+                }
+                // Stay within budget by writing out the least recently written to pieces
+                while c.write.size() > cache_budget() {
+                    if let Some(piece) = c.write.evict() {
+                        let ptid = piece.tid;
+                        if let Err(e) = flush(&mut c.files, piece) {
+                            return Ok(Some(Response::error(ptid, e)));
+                        }
+                    } else {
+                        break;
+                    }
+                }
+            }
+            Request::Read {
+                context,
+                mut data,
+                locations,
+                path,
+            } => {
+                let (begin, len) = (context.begin as usize, context.length as usize);
+                let hit = match c.read.get(context.tid, context.idx) {
+                    Some(piece) => {
+                        data[..len].copy_from_slice(&piece[begin..begin + len]);
+                        true
+                    }
+                    None => false,
+                };
+                if !hit {
+                    let base = path::Path::new(path.as_ref().unwrap_or(dd));
+                    let capacity = cache_budget().saturating_sub(c.write.size());
+                    let piece_len = locations.iter().map(|l| l.end).max().unwrap_or(0);
+                    if piece_len <= capacity {
+                        // Other blocks of the piece are likely to be requested soon
+                        let mut piece = vec![0u8; piece_len];
+                        read_locs(&mut c.files, base, &locations, &mut piece)?;
+                        data[..len].copy_from_slice(&piece[begin..begin + len]);
+                        c.read.insert(context.tid, context.idx, piece, capacity);
+                    } else {
+                        let locs = block_locs(&locations, begin, len);
+                        read_locs(&mut c.files, base, &locs, &mut data[..])?;
+                    }
+                }
+                let data = Arc::new(data);
+                return Ok(Some(Response::read(context, data)));
+            }
+            Request::ReadStream {
+                tid,
+                stream,
+                file,
+                location,
+                path,
+            } => {
+                let base = path::Path::new(path.as_ref().unwrap_or(dd));
+                let mut data = vec![0u8; location.end];
+                // Only the stream fails, the torrent itself may be fine
+                if let Err(err) = read_locs(&mut c.files, base, &[location], &mut data) {
+                    return Ok(Some(Response::StreamError { tid, stream, err }));
+                }
+                return Ok(Some(Response::Stream {
+                    tid,
+                    stream,
+                    file,
+                    data,
+                }));
+            }
+            Request::Verify {
+                tid,
+                index,
+                hash,
+                locations,
+                path,
+                blocks,
+            } => {
+                // Blocks of the piece may have been evicted and rewritten, so
+                // some can still be in the cache
+                if let Some(piece) = c.write.remove_piece(tid, index) {
+                    flush(&mut c.files, piece)?;
+                }
+                let base = path::Path::new(path.as_ref().unwrap_or(dd));
+                let piece_len = locations.iter().map(|l| l.end).max().unwrap_or(0);
+                let mut piece = vec![0u8; piece_len];
+                read_locs(&mut c.files, base, &locations, &mut piece)?;
+                let valid = digest::digest(&digest::SHA1, &piece).as_ref() == &hash[..];
+                let blocks = if !valid || blocks {
+                    piece
+                        .chunks(16384)
+                        .map(|b| {
+                            let mut hash = [0u8; 20];
+                            hash.copy_from_slice(digest::digest(&digest::SHA1, b).as_ref());
+                            hash
+                        })
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                if valid {
+                    // Newly downloaded pieces are likely to be requested by other peers
+                    let capacity = cache_budget().saturating_sub(c.write.size());
+                    c.read.insert(tid, index, piece, capacity);
+                }
+                return Ok(Some(Response::Verified {
+                    tid,
+                    index,
+                    valid,
+                    blocks,
+                }));
+            }
+            Request::Serialize {
+                tid,
+                data,
+                info,
+                path,
+            } => {
+                // Partially downloaded pieces in the resume data must be on disk
+                flush_torrent(c, tid)?;
+                let mut pb = path::PathBuf::from(sd);
+                pb.push(hash_to_id(&info.hash));
+                let stats = file_stats(&info, path.as_ref());
+                let stats = bincode::serialize(&stats, bincode::Infinite)
+                    .expect("Serialization failed!");
+                let mut f = fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&pb)?;
+                f.write_all(&data)?;
+                f.write_all(&stats)?;
+            }
+            Request::Delete { tid, hash } => {
+                flush_torrent(c, tid)?;
+                c.read.remove_torrent(tid);
+                let mut pb = path::PathBuf::from(sd);
+                pb.push(hash_to_id(&hash));
+                fs::remove_file(pb)?;
+                let part = part_file(&hash);
+                c.files.remove(&part);
+                // Only torrents with skipped files have a part file
+                fs::remove_file(part).ok();
+            }
+            Request::Allocate {
+                tid,
+                info,
+                path,
+                skipped,
+            } => {
+                let base = path::Path::new(path.as_ref().unwrap_or(dd));
+                for (file, _) in info.files.iter().zip(skipped).filter(|&(_, s)| !s) {
+                    let pb = base.join(&file.path);
+                    if let Some(dir) = pb.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    let f = fs::OpenOptions::new().write(true).create(true).open(&pb)?;
+                    let len = file.length as u64;
+                    match CONFIG.disk.allocation {
+                        Allocation::Full => fallocate(&f, len)?,
+                        Allocation::Sparse if f.metadata()?.len() < len => f.set_len(len)?,
+                        _ => {}
+                    }
+                }
+                return Ok(Some(Response::Allocated { tid }));
+            }
+            Request::Validate {
+                tid,
+                info,
+                path,
+                skipped,
+            } => {
+                flush_torrent(c, tid)?;
+                let mut invalid = Vec::new();
+                let mut buf = vec![0u8; info.piece_len as usize];
+                let base = path::Path::new(path.as_ref().unwrap_or(dd));
+                let mut cf = None;
+                let mut f = None;
+
+                for i in 0..info.pieces() {
+                    let mut valid = true;
+                    let locs = part_locs(&info, &skipped, i, 0, info.piece_disk_locs(i));
+                    for loc in locs {
+                        if cf.as_ref() != Some(&loc.file) {
+                            f = fs::OpenOptions::new().read(true).open(base.join(&loc.file)).ok();
+                            cf = Some(loc.file.clone());
+                        }
+                        let read = match f {
+                            Some(ref mut file) => {
+                                file.seek(SeekFrom::Start(loc.offset))
+                                    .and_then(|_| file.read_exact(&mut buf[loc.start..loc.end]))
+                                    .is_ok()
+                            }
+                            None => false,
+                        };
+                        if !read {
+                            valid = false;
+                        }
+                    }
+                    let mut ctx = digest::Context::new(&digest::SHA1);
+                    ctx.update(&buf[..info.piece_len(i) as usize]);
+                    let digest = ctx.finish();
+                    if !valid || digest.as_ref() != &info.hashes[i as usize][..] {
+                        invalid.push(i);
+                    }
+                }
+                return Ok(Some(Response::validation_complete(tid, invalid)));
+            }
+            Request::Create { .. } |
+            Request::Move { .. } |
+            Request::Shutdown => unreachable!(),
+        }
+        Ok(None)
+    }
+
+    pub fn tid(&self) -> usize {
+        match *self {
+            Request::Serialize { tid, .. } |
+            Request::Verify { tid, .. } |
+            Request::ReadStream { tid, .. } |
+            Request::Allocate { tid, .. } |
+            Request::Validate { tid, .. } |
+            Request::Move { tid, .. } |
+            Request::Delete { tid, .. } |
+            Request::Write { tid, .. } => tid,
+            Request::Read { ref context, .. } => context.tid,
+            // Creation errors are always returned in the response
+            Request::Create { .. } |
+            Request::Shutdown => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "disk::Request")
+    }
+}
+
+/// Path of the file storing the data of skipped files which
+/// share a piece with wanted files, so that skipped files aren't created.
+pub fn part_file(hash: &[u8; 20]) -> PathBuf {
+    let mut pb = PathBuf::from(&CONFIG.disk.session);
+    if pb.is_relative() {
+        if let Ok(dir) = env::current_dir() {
+            pb = dir.join(pb);
+        }
+    }
+    pb.push(format!("{}.parts", hash_to_id(hash)));
+    pb
+}
+
+/// Redirects locations in skipped files to the part file, for pieces
+/// which also lie in wanted files. Pieces of the part file are stored at
+/// their offset in the torrent, begin being the piece offset of locs.
+pub fn part_locs(
+    info: &Info,
+    skipped: &[bool],
+    index: u32,
+    begin: u32,
+    mut locs: Vec<Location>,
+) -> Vec<Location> {
+    if !skipped.contains(&true) {
+        return locs;
+    }
+    let is_skipped = |file: &PathBuf| {
+        info.files.iter().zip(skipped.iter()).any(
+            |(f, s)| *s && &f.path == file,
+        )
+    };
+    // Pieces which are only in skipped files aren't downloaded in the first place
+    if info.piece_disk_locs(index).iter().all(|l| is_skipped(&l.file)) {
+        return locs;
+    }
+    let part = part_file(&info.hash);
+    let start = index as u64 * info.piece_len as u64 + begin as u64;
+    for loc in locs.iter_mut() {
+        if is_skipped(&loc.file) {
+            loc.file = part.clone();
+            loc.offset = start + loc.start as u64;
+        }
+    }
+    locs
+}
+
+/// Returns the stats of each of a torrent's files, with None
+/// for those which can't be found.
+pub fn file_stats(info: &Info, path: Option<&String>) -> Vec<Option<FileStat>> {
+    let base = path::Path::new(path.unwrap_or(&CONFIG.disk.directory));
+    info.files
+        .iter()
+        .map(|f| {
+            fs::metadata(base.join(&f.path)).ok().and_then(|m| {
+                m.modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| {
+                        FileStat {
+                            len: m.len(),
+                            mtime: (d.as_secs(), d.subsec_nanos()),
+                        }
+                    })
+            })
+        })
+        .collect()
+}
+
+/// Memory available to the read and write caches
+fn cache_budget() -> usize {
+    CONFIG.disk.cache_size * 1024 * 1024
+}
+
+fn flush_torrent(c: &mut Cache, tid: usize) -> io::Result<()> {
+    for piece in c.write.remove_torrent(tid) {
+        flush(&mut c.files, piece)?;
+    }
+    Ok(())
+}
+
+/// Writes out a piece from the write cache, coalescing the
+/// contiguous parts of each file into a single write.
+fn flush(files: &mut FileCache, piece: Piece) -> io::Result<()> {
+    let base = path::PathBuf::from(piece.path.as_ref().unwrap_or(&CONFIG.disk.directory));
+    let mut segments: Vec<(&PathBuf, u64, &[u8])> = Vec::new();
+    for block in piece.blocks.values() {
+        for loc in block.locations.iter() {
+            segments.push((&loc.file, loc.offset, &block.data[loc.start..loc.end]));
+        }
+    }
+    segments.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    let mut i = 0;
+    while i < segments.len() {
+        let (file, offset) = (segments[i].0, segments[i].1);
+        let mut end = offset + segments[i].2.len() as u64;
+        let mut j = i + 1;
+        while j < segments.len() && segments[j].0 == file && segments[j].1 == end {
+            end += segments[j].2.len() as u64;
+            j += 1;
+        }
+        let bufs: Vec<&[u8]> = segments[i..j].iter().map(|s| s.2).collect();
+        files.get_file(&base.join(file), |f| write_vectored(f, offset, &bufs))?;
+        i = j;
+    }
+    Ok(())
+}
+
+/// Writes buffers to a file contiguously from offset.
+#[cfg(target_os = "linux")]
+fn write_vectored(f: &mut fs::File, mut offset: u64, bufs: &[&[u8]]) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut bufs = bufs.to_vec();
+    let mut i = 0;
+    while i < bufs.len() {
+        let iov: Vec<_> = bufs[i..cmp::min(bufs.len(), i + MAX_IOVECS)]
+            .iter()
+            .map(|b| {
+                libc::iovec {
+                    iov_base: b.as_ptr() as *mut libc::c_void,
+                    iov_len: b.len(),
+                }
+            })
+            .collect();
+        let res = unsafe {
+            libc::pwritev(
+                f.as_raw_fd(),
+                iov.as_ptr(),
+                iov.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        } else if res == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write whole buffer",
+            ));
+        }
+        let mut written = res as usize;
+        offset += written as u64;
+        // Skip the buffers which were written, trimming any partially written one
+        while written > 0 {
+            let buf = bufs[i];
+            if written >= buf.len() {
+                written -= buf.len();
+                i += 1;
+            } else {
+                bufs[i] = &buf[written..];
+                written = 0;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes buffers to a file contiguously from offset.
+#[cfg(not(target_os = "linux"))]
+fn write_vectored(f: &mut fs::File, offset: u64, bufs: &[&[u8]]) -> io::Result<()> {
+    f.seek(SeekFrom::Start(offset))?;
+    for buf in bufs {
+        f.write_all(buf)?;
+    }
+    Ok(())
+}
+
+/// Reads the data at each location into buf.
+fn read_locs(
+    files: &mut FileCache,
+    base: &path::Path,
+    locs: &[Location],
+    buf: &mut [u8],
+) -> io::Result<()> {
+    for loc in locs {
+        files.get_file(&base.join(&loc.file), |f| {
+            f.seek(SeekFrom::Start(loc.offset))?;
+            f.read(&mut buf[loc.start..loc.end])?;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Returns the locations of the len bytes at begin of a piece,
+/// relative to begin, given the locations of the whole piece.
+fn block_locs(piece: &[Location], begin: usize, len: usize) -> Vec<Location> {
+    let end = begin + len;
+    piece
+        .iter()
+        .filter(|l| l.start < end && l.end > begin)
+        .map(|l| {
+            let start = cmp::max(l.start, begin);
+            Location::new(
+                l.file.clone(),
+                l.offset + (start - l.start) as u64,
+                start - begin,
+                cmp::min(l.end, end) - begin,
+            )
+        })
+        .collect()
+}
+
+/// Reserves disk space for the first len bytes of a file.
+#[cfg(target_os = "linux")]
+fn fallocate(f: &fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    match unsafe { libc::posix_fallocate(f.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Reserves disk space for the first len bytes of a file by writing
+/// zeros past its current end, for platforms without posix_fallocate.
+#[cfg(not(target_os = "linux"))]
+fn fallocate(mut f: &fs::File, len: u64) -> io::Result<()> {
+    let buf = [0u8; 16384];
+    let mut pos = f.seek(SeekFrom::End(0))?;
+    while pos < len {
+        let amnt = cmp::min(len - pos, buf.len() as u64) as usize;
+        f.write_all(&buf[..amnt])?;
+        pos += amnt as u64;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub file: PathBuf,
+    pub offset: u64,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Location {
+    pub fn new(file: PathBuf, offset: u64, start: usize, end: usize) -> Location {
+        Location {
+            file,
+            offset,
+            start,
+            end,
+        }
+    }
+}
+
+pub enum Response {
+    Read {
+        context: Ctx,
+        data: Arc<Box<[u8; 16384]>>,
+    },
+    /// Data read for a stream, from the file at index file
+    Stream {
+        tid: usize,
+        stream: usize,
+        file: usize,
+        data: Vec<u8>,
+    },
+    /// Failure to read the data of a stream
+    StreamError {
+        tid: usize,
+        stream: usize,
+        err: io::Error,
+    },
+    /// Result of verifying a piece, with the hash of each of its blocks
+    /// if they were computed
+    Verified {
+        tid: usize,
+        index: u32,
+        valid: bool,
+        blocks: Vec<[u8; 20]>,
+    },
+    ValidationComplete { tid: usize, invalid: Vec<u32> },
+    Allocated { tid: usize },
+    Moving { tid: usize, progress: f32 },
+    /// Result of a move, with the new path if successful
+    Moved {
+        tid: usize,
+        path: io::Result<String>,
+    },
+    Created {
+        client: usize,
+        serial: u64,
+        start: bool,
+        path: PathBuf,
+        info: io::Result<Info>,
+    },
+    Error { tid: usize, err: io::Error },
+}
+
+impl Response {
+    pub fn read(context: Ctx, data: Arc<Box<[u8; 16384]>>) -> Response {
+        Response::Read { context, data }
+    }
+
+    pub fn error(tid: usize, err: io::Error) -> Response {
+        Response::Error { tid, err }
+    }
+
+    pub fn validation_complete(tid: usize, invalid: Vec<u32>) -> Response {
+        Response::ValidationComplete { tid, invalid }
+    }
+
+    pub fn tid(&self) -> usize {
+        match *self {
+            Response::Read { ref context, .. } => context.tid,
+            Response::ValidationComplete { tid, .. } |
+            Response::Verified { tid, .. } |
+            Response::Stream { tid, .. } |
+            Response::StreamError { tid, .. } |
+            Response::Allocated { tid } |
+            Response::Moving { tid, .. } |
+            Response::Moved { tid, .. } |
+            Response::Error { tid, .. } => tid,
+            Response::Created { .. } => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "disk::Response")
+    }
+}
+
+impl Disk {
+    pub fn new(poll: amy::Poller, ch: handle::Handle<Request, Response>, l: Logger) -> Disk {
+        Disk {
+            poll,
+            ch,
+            l,
+            cache: Cache::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        let sd = &CONFIG.disk.session;
+        fs::create_dir_all(sd).unwrap();
+
+        loop {
+            match self.poll.wait(POLL_INT_MS) {
+                Ok(_) => {
+                    if self.handle_events() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!(self.l, "Failed to poll for events: {:?}", e);
+                }
+            }
+        }
+
+    }
+
+    pub fn handle_events(&mut self) -> bool {
+        loop {
+            match self.ch.recv() {
+                Ok(Request::Shutdown) => {
+                    for piece in self.cache.write.remove_all() {
+                        if let Err(e) = flush(&mut self.cache.files, piece) {
+                            warn!(self.l, "Failed to write cached piece: {}", e);
+                        }
+                    }
+                    return true;
+                }
+                Ok(Request::Create { client, serial, start, opts }) => {
+                    trace!(self.l, "Creating torrent!");
+                    // Hashing large amounts of data would hold up every other
+                    // torrent's IO, so it's done on a separate thread
+                    let tx = self.ch.tx.clone();
+                    thread::spawn(move || {
+                        let info = Info::create(&opts);
+                        tx.send(Response::Created {
+                            client,
+                            serial,
+                            start,
+                            path: opts.path,
+                            info,
+                        }).ok();
+                    });
+                }
+                Ok(Request::Move { tid, info, from, to }) => {
+                    trace!(self.l, "Moving torrent data!");
+                    let dd = &CONFIG.disk.directory;
+                    let from = path::PathBuf::from(from.as_ref().unwrap_or(dd));
+                    let res = flush_torrent(&mut self.cache, tid)
+                        .and_then(|_| self.move_data(tid, &info, &from, path::Path::new(&to)));
+                    if let Err(ref e) = res {
+                        warn!(self.l, "Failed to move {:?} to {}: {}", from, to, e);
+                    }
+                    let path = res.map(|_| to);
+                    self.ch.send(Response::Moved { tid, path }).ok();
+                }
+                Ok(r) => {
+                    trace!(self.l, "Handling disk job!");
+                    let tid = r.tid();
+                    match r.execute(&mut self.cache) {
+                        Ok(Some(r)) => {
+                            self.ch.send(r).ok();
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            self.ch.send(Response::error(tid, e)).ok();
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+        false
+    }
+
+    /// Moves a torrent's files between directories. Files are renamed
+    /// where possible and otherwise copied, with the originals removed
+    /// only once every file has been moved. On failure, any files which
+    /// were moved are restored.
+    fn move_data(
+        &mut self,
+        tid: usize,
+        info: &Info,
+        from: &path::Path,
+        to: &path::Path,
+    ) -> io::Result<()> {
+        let mut renamed = Vec::new();
+        let mut copied = Vec::new();
+        let res = self.move_files(tid, info, from, to, &mut renamed, &mut copied);
+        if res.is_err() {
+            for (src, dst) in renamed {
+                fs::rename(dst, src).ok();
+            }
+            for (_, dst) in copied {
+                fs::remove_file(dst).ok();
+            }
+            return res;
+        }
+        for &(ref src, _) in copied.iter() {
+            if let Err(e) = fs::remove_file(src) {
+                warn!(self.l, "Failed to remove {:?} after copying: {}", src, e);
+            }
+        }
+        // Clean up directories left empty, remove_dir fails for any which aren't
+        for file in info.files.iter() {
+            let src = from.join(&file.path);
+            let mut dir = src.parent();
+            while let Some(d) = dir {
+                if d == from || fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+        Ok(())
+    }
+
+    fn move_files(
+        &mut self,
+        tid: usize,
+        info: &Info,
+        from: &path::Path,
+        to: &path::Path,
+        renamed: &mut Vec<(PathBuf, PathBuf)>,
+        copied: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> io::Result<()> {
+        let total = info.total_len as f32;
+        let mut moved = 0;
+        for file in info.files.iter() {
+            let src = from.join(&file.path);
+            let dst = to.join(&file.path);
+            // Open handles would keep referring to the original file
+            self.cache.files.remove(&src);
+            if !src.exists() {
+                moved += file.length as u64;
+                continue;
+            }
+            if dst.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", dst.display()),
+                ));
+            }
+            if let Some(dir) = dst.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            match fs::rename(&src, &dst) {
+                Ok(()) => renamed.push((src, dst)),
+                Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    copied.push((src.clone(), dst.clone()));
+                    let mut input = fs::File::open(&src)?;
+                    let mut output = fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&dst)?;
+                    let mut buf = vec![0u8; MOVE_BUF_LEN];
+                    loop {
+                        let amnt = input.read(&mut buf)?;
+                        if amnt == 0 {
+                            break;
+                        }
+                        output.write_all(&buf[..amnt])?;
+                        moved += amnt as u64;
+                        let progress = moved as f32 / total;
+                        self.ch.send(Response::Moving { tid, progress }).ok();
+                    }
+                    // The copy must be durable before the original is removed
+                    output.sync_all()?;
+                    fs::set_permissions(&dst, input.metadata()?.permissions())?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            moved += file.length as u64;
+            let progress = moved as f32 / total;
+            self.ch.send(Response::Moving { tid, progress }).ok();
+        }
+        Ok(())
+    }
+}
+
+pub fn start(creg: &mut amy::Registrar) -> io::Result<handle::Handle<Response, Request>> {
+    let poll = amy::Poller::new()?;
+    let mut reg = poll.get_registrar()?;
+    let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
+    dh.run("disk", move |h, l| Disk::new(poll, h, l).run());
+    Ok(ch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{block_locs, part_file, part_locs, Location};
+    use torrent::Info;
+    use torrent::info::File;
+
+    #[test]
+    fn test_block_locs() {
+        let piece = vec![
+            Location::new(PathBuf::from("a"), 100, 0, 10000),
+            Location::new(PathBuf::from("b"), 0, 10000, 32768),
+        ];
+        let locs = block_locs(&piece, 0, 16384);
+        assert_eq!(locs.len(), 2);
+        assert_eq!((locs[0].offset, locs[0].start, locs[0].end), (100, 0, 10000));
+        assert_eq!((locs[1].offset, locs[1].start, locs[1].end), (0, 10000, 16384));
+
+        let locs = block_locs(&piece, 16384, 16384);
+        assert_eq!(locs.len(), 1);
+        assert_eq!(locs[0].file, PathBuf::from("b"));
+        assert_eq!((locs[0].offset, locs[0].start, locs[0].end), (6384, 0, 16384));
+    }
+
+    #[test]
+    fn test_part_locs() {
+        let mut info = Info::with_pieces(2);
+        info.files = vec![
+            File {
+                path: PathBuf::from("a"),
+                length: 20000,
+            },
+            File {
+                path: PathBuf::from("b"),
+                length: 12768,
+            },
+        ];
+        let skipped = [true, false];
+        // Pieces entirely within skipped files are left as they are
+        let locs = part_locs(&info, &skipped, 0, 0, info.piece_disk_locs(0));
+        assert_eq!(locs[0].file, PathBuf::from("a"));
+
+        let locs = part_locs(&info, &skipped, 1, 0, info.piece_disk_locs(1));
+        assert_eq!(locs[0].file, part_file(&info.hash));
+        assert_eq!(locs[0].offset, 16384);
+        assert_eq!(locs[1].file, PathBuf::from("b"));
+        assert_eq!(locs[1].offset, 0);
+
+        let locs = part_locs(&info, &[false, false], 1, 0, info.piece_disk_locs(1));
+        assert_eq!(locs[0].file, PathBuf::from("a"));
+        assert_eq!(locs[0].offset, 16384);
+    }
+}
@@ -0,0 +1,262 @@
+//! Blocklist of address ranges which no connections are made to or accepted
+//! from, loaded from eMule DAT, PeerGuardian P2P and CIDR list files.
+
+use std::collections::{BTreeMap, Bound};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{atomic, RwLock};
+use std::{fs, io};
+use std::io::Read;
+
+use CONFIG;
+
+lazy_static! {
+    static ref FILTER: RwLock<IpFilter> = RwLock::new(IpFilter::new(Vec::new()));
+
+    /// Number of connection attempts which have been blocked
+    static ref BLOCKED: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+}
+
+/// Set of blocked address ranges.
+pub struct IpFilter {
+    /// Non overlapping ranges, keyed by their first address. The range which
+    /// could contain an address is the one with the greatest start not after it.
+    ranges: BTreeMap<IpAddr, IpAddr>,
+}
+
+impl IpFilter {
+    /// Creates a filter of inclusive ranges, merging those which overlap.
+    pub fn new(mut ranges: Vec<(IpAddr, IpAddr)>) -> IpFilter {
+        ranges.sort();
+        let mut merged: Vec<(IpAddr, IpAddr)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            if let Some(last) = merged.last_mut() {
+                if start <= last.1 {
+                    if end > last.1 {
+                        last.1 = end;
+                    }
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+        IpFilter { ranges: merged.into_iter().collect() }
+    }
+
+    /// Loads the ranges of each list file.
+    pub fn load(paths: &[String]) -> io::Result<IpFilter> {
+        let mut ranges = Vec::new();
+        for path in paths {
+            let mut data = Vec::new();
+            fs::File::open(path)
+                .and_then(|mut f| f.read_to_end(&mut data))
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            ranges.extend(parse_list(&String::from_utf8_lossy(&data)));
+        }
+        Ok(IpFilter::new(ranges))
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.ranges
+            .range((Bound::Unbounded, Bound::Included(*ip)))
+            .next_back()
+            .map(|(_, end)| ip <= end)
+            .unwrap_or(false)
+    }
+
+    /// Number of distinct ranges
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+/// Returns whether connections with an address are blocked,
+/// counting the attempt if so.
+pub fn blocked(ip: &IpAddr) -> bool {
+    let blocked = FILTER.read().unwrap().contains(ip);
+    if blocked {
+        BLOCKED.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+    blocked
+}
+
+/// Number of connection attempts which have been blocked
+pub fn blocked_count() -> usize {
+    BLOCKED.load(atomic::Ordering::Relaxed)
+}
+
+/// Number of ranges in the current blocklist
+pub fn ranges() -> usize {
+    FILTER.read().unwrap().len()
+}
+
+/// Reloads the configured lists, returning the number of ranges loaded.
+/// The current blocklist is kept if any list can't be read.
+pub fn reload() -> io::Result<usize> {
+    let filter = IpFilter::load(&CONFIG.net.blocklists)?;
+    let len = filter.len();
+    *FILTER.write().unwrap() = filter;
+    Ok(len)
+}
+
+/// Parses the ranges of a list, ignoring comments and invalid lines.
+pub fn parse_list(data: &str) -> Vec<(IpAddr, IpAddr)> {
+    data.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<(IpAddr, IpAddr)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return None;
+    }
+    // eMule DAT: "start - end , level , description", where ranges with
+    // an access level of 128 or more are allowed rather than blocked
+    if let Some(i) = line.find(',') {
+        if let Some(range) = parse_range(&line[..i]) {
+            let level = line[i + 1..].split(',').next().and_then(
+                |l| l.trim().parse::<u32>().ok(),
+            );
+            return match level {
+                Some(l) if l >= 128 => None,
+                _ => Some(range),
+            };
+        }
+    }
+    if line.contains('-') {
+        // PeerGuardian P2P: "description:start-end", where the description
+        // may itself contain colons
+        return parse_range(line).or_else(|| {
+            line.rfind(':').and_then(|i| parse_range(&line[i + 1..]))
+        });
+    }
+    parse_cidr(line)
+}
+
+/// Parses an inclusive range of the form "start-end".
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let mut addrs = s.splitn(2, '-');
+    let start = addrs.next().and_then(parse_addr);
+    let end = addrs.next().and_then(parse_addr);
+    match (start, end) {
+        (Some(s @ IpAddr::V4(_)), Some(e @ IpAddr::V4(_))) |
+        (Some(s @ IpAddr::V6(_)), Some(e @ IpAddr::V6(_))) if s <= e => Some((s, e)),
+        _ => None,
+    }
+}
+
+/// Parses a network of the form "addr/prefix", or a single address.
+fn parse_cidr(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let mut parts = s.splitn(2, '/');
+    let addr = match parts.next().and_then(parse_addr) {
+        Some(a) => a,
+        None => return None,
+    };
+    let prefix = match parts.next() {
+        Some(p) => {
+            match p.trim().parse::<u32>() {
+                Ok(p) => Some(p),
+                Err(_) => return None,
+            }
+        }
+        None => None,
+    };
+    match addr {
+        IpAddr::V4(a) => {
+            let prefix = prefix.unwrap_or(32);
+            if prefix > 32 {
+                return None;
+            }
+            let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+            let a = u32::from(a);
+            Some((
+                IpAddr::V4(Ipv4Addr::from(a & mask)),
+                IpAddr::V4(Ipv4Addr::from(a | !mask)),
+            ))
+        }
+        IpAddr::V6(a) => {
+            let prefix = prefix.unwrap_or(128);
+            if prefix > 128 {
+                return None;
+            }
+            let (mut start, mut end) = (a.octets(), a.octets());
+            for i in 0..16 {
+                let bits = prefix.saturating_sub(i as u32 * 8);
+                let mask = if bits >= 8 { 0xFF } else { !(0xFFu8 >> bits) };
+                start[i] &= mask;
+                end[i] |= !mask;
+            }
+            Some((IpAddr::V6(Ipv6Addr::from(start)), IpAddr::V6(Ipv6Addr::from(end))))
+        }
+    }
+}
+
+/// Parses an address, allowing the zero padded IPv4 octets used in DAT files.
+fn parse_addr(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let octets: Vec<_> = s.split('.').collect();
+    if octets.len() == 4 {
+        let mut addr = [0u8; 4];
+        for (i, o) in octets.iter().enumerate() {
+            match o.parse() {
+                Ok(o) => addr[i] = o,
+                Err(_) => return None,
+            }
+        }
+        return Some(IpAddr::V4(Ipv4Addr::from(addr)));
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpFilter, parse_list};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let list = "# comment\n\
+                    001.002.003.000 - 001.002.003.255 , 000 , DAT range\n\
+                    001.002.004.000 - 001.002.004.255 , 128 , DAT allowed range\n\
+                    Some: org:10.0.0.0-10.0.0.15\n\
+                    Foo, Inc:10.1.0.0-10.1.0.255\n\
+                    192.168.0.0/16\n\
+                    8.8.8.8\n\
+                    2001:db8::/32\n\
+                    garbage\n\
+                    5.5.5.5-1.1.1.1\n";
+        assert_eq!(
+            parse_list(list),
+            vec![
+                (ip("1.2.3.0"), ip("1.2.3.255")),
+                (ip("10.0.0.0"), ip("10.0.0.15")),
+                (ip("10.1.0.0"), ip("10.1.0.255")),
+                (ip("192.168.0.0"), ip("192.168.255.255")),
+                (ip("8.8.8.8"), ip("8.8.8.8")),
+                (ip("2001:db8::"), ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let f = IpFilter::new(vec![
+            (ip("10.0.0.0"), ip("10.0.0.15")),
+            (ip("10.0.0.8"), ip("10.0.1.0")),
+            (ip("1.1.1.1"), ip("1.1.1.1")),
+            (ip("2001:db8::"), ip("2001:db8::ff")),
+        ]);
+        assert_eq!(f.len(), 3);
+        assert!(f.contains(&ip("10.0.0.0")));
+        assert!(f.contains(&ip("10.0.0.200")));
+        assert!(f.contains(&ip("10.0.1.0")));
+        assert!(!f.contains(&ip("10.0.1.1")));
+        assert!(f.contains(&ip("1.1.1.1")));
+        assert!(!f.contains(&ip("1.1.1.2")));
+        assert!(!f.contains(&ip("0.0.0.1")));
+        assert!(f.contains(&ip("2001:db8::10")));
+        assert!(!f.contains(&ip("::1")));
+    }
+}
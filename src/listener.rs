@@ -1,16 +1,24 @@
 use std::fmt;
 use std::io::{self, ErrorKind};
-use std::net::{SocketAddrV4, Ipv4Addr, TcpListener};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixStream;
 use amy::{self, Poller, Registrar};
 use std::collections::HashMap;
 use slog::Logger;
+use socket::utp;
 use torrent::peer::PeerConn;
-use {handle, CONFIG};
+use util::{bind_tcp, bind_udp, canonical_addr, sha1_hash};
+use {handle, ipfilter, CONFIG};
 
 pub struct Listener {
     listener: TcpListener,
     lid: usize,
     incoming: HashMap<usize, PeerConn>,
+    utp: utp::Endpoint,
+    utp_timer: usize,
+    /// Torrent hashes indexed by HASH('req2', hash), used
+    /// to identify the torrent of encrypted connections
+    skeys: HashMap<[u8; 20], [u8; 20]>,
     poll: Poller,
     reg: Registrar,
     ch: handle::Handle<Request, Message>,
@@ -36,6 +44,12 @@ impl fmt::Debug for Message {
 
 #[derive(Debug)]
 pub enum Request {
+    /// Connect to a peer over uTP, using a stream from `Socket::new_utp`
+    Connect(SocketAddr, UnixStream),
+    AddTorrent([u8; 20]),
+    RemoveTorrent([u8; 20]),
+    /// Rebind the listening sockets after the local address changed
+    Rebind,
     Shutdown,
 }
 
@@ -47,14 +61,18 @@ impl Listener {
         reg: amy::Registrar,
         listener: TcpListener,
         lid: usize,
+        utp: utp::Endpoint,
+        utp_timer: usize,
         ch: handle::Handle<Request, Message>,
         l: Logger,
     ) -> Listener {
-
         Listener {
             listener,
             lid,
             incoming: HashMap::new(),
+            utp,
+            utp_timer,
+            skeys: HashMap::new(),
             poll,
             reg,
             ch,
@@ -68,9 +86,30 @@ impl Listener {
             for not in res {
                 match not.id {
                     id if id == self.lid => self.handle_conn(),
+                    id if id == self.utp.id() => self.handle_utp(),
+                    id if id == self.utp_timer => self.utp.tick(),
+                    id if self.utp.owns(id) => self.utp.stream_ready(id),
                     id if id == self.ch.rx.get_id() => {
-                        if let Ok(Request::Shutdown) = self.ch.recv() {
-                            return;
+                        while let Ok(r) = self.ch.recv() {
+                            match r {
+                                Request::Connect(addr, stream) => {
+                                    if self.utp.connect(addr, stream).is_err() {
+                                        debug!(self.l, "Failed to start uTP connection!");
+                                    }
+                                }
+                                Request::AddTorrent(hash) => {
+                                    self.skeys.insert(req2_hash(&hash), hash);
+                                }
+                                Request::RemoveTorrent(hash) => {
+                                    self.skeys.remove(&req2_hash(&hash));
+                                }
+                                Request::Rebind => {
+                                    if let Err(e) = self.rebind() {
+                                        error!(self.l, "Failed to rebind listener: {}", e);
+                                    }
+                                }
+                                Request::Shutdown => return,
+                            }
                         }
                     }
                     _ => self.handle_peer(not),
@@ -79,13 +118,31 @@ impl Listener {
         }
     }
 
+    fn rebind(&mut self) -> io::Result<()> {
+        let listener = bind_tcp(CONFIG.port)?;
+        listener.set_nonblocking(true)?;
+        self.utp.rebind(bind_udp(CONFIG.port)?)?;
+        let lid = self.reg.register(&listener, amy::Event::Both)?;
+        let _ = self.reg.deregister(&self.listener);
+        self.listener = listener;
+        self.lid = lid;
+        debug!(self.l, "Rebound listener to {:?}", self.listener.local_addr());
+        Ok(())
+    }
+
     fn handle_conn(&mut self) {
         loop {
             match self.listener.accept() {
-                Ok((conn, _ip)) => {
-                    debug!(self.l, "Accepted new connection from {:?}!", _ip);
+                Ok((conn, ip)) => {
+                    // IPv4 peers appear mapped on dual stack sockets
+                    let ip = canonical_addr(ip);
+                    if ipfilter::blocked(&ip.ip()) {
+                        debug!(self.l, "Rejected connection from blocked address {:?}", ip);
+                        continue;
+                    }
+                    debug!(self.l, "Accepted new connection from {:?}!", ip);
                     let peer = PeerConn::new_incoming(conn).unwrap();
-                    let pid = self.reg.register(peer.sock(), amy::Event::Read).unwrap();
+                    let pid = self.reg.register(peer.sock(), amy::Event::Both).unwrap();
                     self.incoming.insert(pid, peer);
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
@@ -98,9 +155,51 @@ impl Listener {
         }
     }
 
+    fn handle_utp(&mut self) {
+        for sock in self.utp.readable() {
+            if ipfilter::blocked(&sock.addr().ip()) {
+                debug!(self.l, "Rejected uTP connection from blocked address {:?}", sock.addr());
+                continue;
+            }
+            debug!(self.l, "Accepted new uTP connection from {:?}!", sock.addr());
+            let peer = PeerConn::new(sock);
+            match self.reg.register(peer.sock(), amy::Event::Both) {
+                Ok(pid) => {
+                    self.incoming.insert(pid, peer);
+                }
+                Err(_) => error!(self.l, "Failed to register uTP connection!"),
+            }
+        }
+    }
+
     fn handle_peer(&mut self, not: amy::Notification) {
         let pid = not.id;
-        match self.incoming.get_mut(&pid).unwrap().readable() {
+        let res = {
+            let peer = match self.incoming.get_mut(&pid) {
+                Some(p) => p,
+                None => return,
+            };
+            let mut res = peer.readable();
+            // Encrypted connections can only continue once we know
+            // which torrent they're for
+            let pending = match res {
+                Ok(None) => true,
+                _ => false,
+            };
+            if pending {
+                if let Some(req) = peer.sock().skey_request() {
+                    match self.skeys.get(&req) {
+                        Some(hash) => {
+                            peer.sock_mut().set_skey(hash);
+                            res = peer.readable();
+                        }
+                        None => res = Err(io::Error::new(ErrorKind::Other, "Unknown torrent")),
+                    }
+                }
+            }
+            res
+        };
+        match res {
             Ok(Some(hs)) => {
                 debug!(
                     self.l,
@@ -131,18 +230,27 @@ impl Listener {
     }
 }
 
+fn req2_hash(hash: &[u8; 20]) -> [u8; 20] {
+    let mut data = b"req2".to_vec();
+    data.extend_from_slice(hash);
+    let mut req2 = [0u8; 20];
+    req2.copy_from_slice(sha1_hash(&data).as_ref());
+    req2
+}
+
 pub fn start(creg: &mut amy::Registrar) -> io::Result<handle::Handle<Message, Request>> {
     let poll = Poller::new()?;
     let mut reg = poll.get_registrar()?;
-    let ip = Ipv4Addr::new(0, 0, 0, 0);
     let port = CONFIG.port;
-    let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
+    let listener = bind_tcp(port)?;
     listener.set_nonblocking(true)?;
     let lid = reg.register(&listener, amy::Event::Both)?;
+    let utp = utp::Endpoint::new(&reg, bind_udp(port)?)?;
+    let utp_timer = reg.set_interval(utp::TICK_MS)?;
 
     let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
     dh.run("listener", move |h, l| {
-        Listener::new(poll, reg, listener, lid, h, l).run()
+        Listener::new(poll, reg, listener, lid, utp, utp_timer, h, l).run()
     });
     Ok(ch)
 }
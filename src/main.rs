@@ -25,6 +25,9 @@ extern crate c_ares;
 extern crate httparse;
 extern crate base64;
 extern crate base32;
+extern crate libc;
+extern crate rustls;
+extern crate webpki_roots;
 
 extern crate chrono;
 // TODO: Get rid of this
@@ -42,6 +45,7 @@ mod listener;
 mod rpc;
 mod throttle;
 mod config;
+mod ipfilter;
 
 use std::{time, env, thread};
 use std::sync::{atomic, mpsc};
@@ -50,6 +54,8 @@ use slog::Drain;
 use control::acio;
 
 pub const DHT_EXT: (usize, u8) = (7, 1);
+pub const EXT_PROTO: (usize, u8) = (5, 0x10);
+pub const FAST_EXT: (usize, u8) = (7, 0x04);
 
 /// Throttler max token amount
 pub const THROT_TOKS: usize = 2 * 1024 * 1024;
@@ -127,6 +133,11 @@ lazy_static! {
 }
 
 fn init() -> io::Result<()> {
+    // Connections mustn't be made before the blocklists are in place
+    let ranges = ipfilter::reload()?;
+    if ranges != 0 {
+        info!(LOG, "Loaded {} blocked address ranges", ranges);
+    }
     let cpoll = amy::Poller::new()?;
     let mut creg = cpoll.get_registrar()?;
     let dh = disk::start(&mut creg)?;
@@ -8,6 +8,7 @@ use url::Url;
 
 use super::reader::Reader;
 use super::writer::Writer;
+use super::transfer::ByteRange;
 use super::proto::ws::{Message, Frame, Opcode};
 use super::{Result, ResultExt, ErrorKind};
 use util::{IOR, aread, sha1_hash};
@@ -32,7 +33,11 @@ pub struct Incoming {
 pub enum IncomingStatus {
     Incomplete,
     Upgrade,
-    Transfer { data: Vec<u8>, token: String },
+    Transfer {
+        data: Vec<u8>,
+        token: String,
+        range: Option<ByteRange>,
+    },
 }
 
 enum FragBuf {
@@ -223,6 +228,7 @@ impl Incoming {
                     return Ok(Some(IncomingStatus::Transfer {
                         data: self.buf[idx..self.pos].to_owned(),
                         token,
+                        range: parse_range(&req),
                     }));
                 } else {
                     // Probably some dumb CORS OPTION shit, just tell the client
@@ -296,6 +302,14 @@ impl FragBuf {
     }
 }
 
+fn parse_range(req: &httparse::Request) -> Option<ByteRange> {
+    req.headers
+        .iter()
+        .find(|header| header.name.to_lowercase() == "range")
+        .and_then(|header| str::from_utf8(header.value).ok())
+        .and_then(ByteRange::parse)
+}
+
 // TODO: We're not really checking HTTP semantics here, might be worth
 // considering.
 fn validate_tx(req: &httparse::Request) -> Option<String> {
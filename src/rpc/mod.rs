@@ -20,7 +20,7 @@ pub use self::errors::{Result, ResultExt, ErrorKind, Error};
 use self::proto::ws;
 use self::client::{Incoming, IncomingStatus, Client};
 use self::processor::{Processor, TransferKind};
-use self::transfer::{Transfers, TransferResult};
+use self::transfer::{Transfers, TransferResult, StreamResult};
 use util;
 use bencode;
 use handle;
@@ -35,6 +35,25 @@ pub enum CtlMessage {
     Extant(Vec<resource::Resource>),
     Update(Vec<resource::SResourceUpdate<'static>>),
     Removed(Vec<String>),
+    /// A generated .torrent file which should be offered to the client
+    TorrentFile {
+        client: usize,
+        serial: u64,
+        name: String,
+        data: Vec<u8>,
+    },
+    Error {
+        client: usize,
+        serial: u64,
+        reason: String,
+    },
+    /// Data read for a stream, from a file of size bytes
+    StreamData {
+        stream: usize,
+        size: u64,
+        data: Vec<u8>,
+    },
+    StreamError { stream: usize, reason: String },
     Shutdown,
 }
 
@@ -54,7 +73,32 @@ pub enum Message {
     RemoveTorrent(String),
     RemovePeer { id: String, torrent_id: String },
     RemoveTracker { id: String, torrent_id: String },
+    /// Lifts the ban of an address
+    RemoveBan(String),
     Torrent { info: torrent::Info, path: Option<String> },
+    ExportTorrent {
+        client: usize,
+        serial: u64,
+        id: String,
+    },
+    CreateTorrent {
+        client: usize,
+        serial: u64,
+        start: bool,
+        opts: torrent::info::CreateOpts,
+    },
+    /// Rereads the configured blocklists
+    ReloadBlocklist { client: usize, serial: u64 },
+    /// Reads up to len bytes at offset of a file for a stream,
+    /// once the pieces containing them are downloaded
+    ReadStream {
+        stream: usize,
+        torrent_id: String,
+        file_id: String,
+        offset: u64,
+        len: u64,
+    },
+    CloseStream { stream: usize, torrent_id: String },
 }
 
 #[allow(dead_code)]
@@ -120,6 +164,10 @@ impl RPC {
                     }
                     id if self.incoming.contains_key(&id) => self.handle_incoming(id),
                     id if id == self.cleanup => self.cleanup(),
+                    id if self.transfers.is_stream(id) => {
+                        let res = self.transfers.stream_ready(id);
+                        self.handle_stream(id, res);
+                    }
                     id if self.transfers.contains(id) => self.handle_transfer(id),
                     _ => self.handle_conn(not),
                 }
@@ -131,6 +179,15 @@ impl RPC {
         while let Ok(m) = self.ch.recv() {
             match m {
                 CtlMessage::Shutdown => return true,
+                CtlMessage::StreamData { stream, size, data } => {
+                    let res = self.transfers.stream_data(stream, size, data);
+                    self.handle_stream(stream, res);
+                }
+                CtlMessage::StreamError { stream, reason } => {
+                    debug!(self.l, "Stream failed: {}", reason);
+                    let res = self.transfers.stream_error(stream);
+                    self.handle_stream(stream, res);
+                }
                 m => {
                     let msgs: Vec<_> = {
                         self.processor
@@ -182,7 +239,7 @@ impl RPC {
                                 c.send(ws::Frame::Text(
                                     serde_json::to_string(&SMessage::ResourcesExtant {
                                         serial,
-                                        ids: vec![&tid],
+                                        ids: vec![tid.into()],
                                     }).unwrap(),
                                 ))
                             });
@@ -225,6 +282,39 @@ impl RPC {
         }
     }
 
+    fn handle_stream(&mut self, id: usize, res: StreamResult) {
+        match res {
+            StreamResult::Incomplete => {}
+            StreamResult::Read {
+                torrent_id,
+                file_id,
+                offset,
+                len,
+            } => {
+                let m = Message::ReadStream {
+                    stream: id,
+                    torrent_id,
+                    file_id,
+                    offset,
+                    len,
+                };
+                if self.ch.send(m).is_err() {
+                    crit!(self.l, "Failed to pass message to ctrl!");
+                }
+            }
+            StreamResult::Done { conn, torrent_id } => {
+                self.reg.deregister(&conn).is_ok();
+                let m = Message::CloseStream {
+                    stream: id,
+                    torrent_id,
+                };
+                if self.ch.send(m).is_err() {
+                    crit!(self.l, "Failed to pass message to ctrl!");
+                }
+            }
+        }
+    }
+
     fn handle_accept(&mut self) {
         loop {
             match self.listener.accept() {
@@ -253,7 +343,7 @@ impl RPC {
                 Ok(IncomingStatus::Incomplete) => {
                     self.incoming.insert(id, i);
                 }
-                Ok(IncomingStatus::Transfer { data, token }) => {
+                Ok(IncomingStatus::Transfer { data, token, range }) => {
                     debug!(self.l, "File transfer requested, validating");
                     match self.processor.get_transfer(token) {
                         Some((client, serial, TransferKind::UploadTorrent { path, size })) => {
@@ -280,6 +370,20 @@ impl RPC {
                             self.reg.deregister(&conn).is_ok();
                             self.transfers.add_download(conn, path);
                         }
+                        Some((_, _, TransferKind::StreamFile { torrent_id, file_id })) => {
+                            debug!(self.l, "File stream requested");
+                            // The connection stays registered, data is written as it's read
+                            let res =
+                                self.transfers.add_stream(id, i.into(), torrent_id, file_id, range);
+                            self.handle_stream(id, res);
+                        }
+                        Some((_, _, TransferKind::DownloadTorrent { name, data })) => {
+                            debug!(self.l, "Torrent download requested");
+                            let conn: TcpStream = i.into();
+                            conn.set_nonblocking(false).is_ok();
+                            self.reg.deregister(&conn).is_ok();
+                            self.transfers.add_data(conn, name, data);
+                        }
                         Some(_) => warn!(self.l, "Unimplemented transfer type ignored"),
                         None => {
                             warn!(self.l, "Transfer used invalid token");
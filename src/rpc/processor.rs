@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 use chrono::{DateTime, Utc, Duration};
 
@@ -6,7 +7,8 @@ use super::proto::message::{CMessage, SMessage, Error};
 use super::proto::criterion::{self, Criterion, Filter as FTrait};
 use super::proto::resource::{Resource, ResourceKind, SResourceUpdate};
 use super::{CtlMessage, Message};
-use util::random_string;
+use torrent::info::{Info, CreateOpts};
+use util::{random_string, hash_to_id};
 
 // TODO: Figure out a way to reduce allocations
 // in this entire file, ideally by taking pointers
@@ -41,6 +43,8 @@ pub enum TransferKind {
     UploadTorrent { size: u64, path: Option<String> },
     UploadFiles { size: u64, path: String },
     DownloadFile { path: String },
+    StreamFile { torrent_id: String, file_id: String },
+    DownloadTorrent { name: String, data: Vec<u8> },
 }
 
 const EXPIRATION_DUR: i64 = 120;
@@ -53,7 +57,7 @@ impl Processor {
             resources: HashMap::new(),
             tokens: HashMap::new(),
             torrent_idx: HashMap::new(),
-            kinds: vec![HashSet::new(); 6],
+            kinds: vec![HashSet::new(); 7],
         }
     }
 
@@ -66,7 +70,9 @@ impl Processor {
         let rem = match self.tokens.get(&tok) {
             Some(bt) => {
                 match &bt.kind {
-                    s @ &TransferKind::UploadTorrent { .. } => {
+                    // Players make many range requests over a stream
+                    s @ &TransferKind::UploadTorrent { .. } |
+                    s @ &TransferKind::StreamFile { .. } => {
                         res = Some((bt.client, bt.serial, s.clone()));
                         false
                     }
@@ -171,7 +177,7 @@ impl Processor {
                     Some(&Resource::Tracker(ref t)) => {
                         rmsg = Some(Message::RemoveTracker {
                             id,
-                            torrent_id: t.id.to_owned(),
+                            torrent_id: t.torrent_id.to_owned(),
                         });
                     }
                     Some(&Resource::Peer(ref p)) => {
@@ -180,10 +186,13 @@ impl Processor {
                             torrent_id: p.id.to_owned(),
                         });
                     }
+                    Some(&Resource::Ban(_)) => {
+                        rmsg = Some(Message::RemoveBan(id));
+                    }
                     Some(_) => {
                         resp.push(SMessage::InvalidResource(Error {
                             serial: Some(serial),
-                            reason: format!("Only torrents, trackers, and peers may be removed"),
+                            reason: format!("Only torrents, trackers, peers, and bans may be removed"),
                         }));
                     }
                     None => {
@@ -219,14 +228,14 @@ impl Processor {
                         for id in valid.intersection(t) {
                             let r = self.resources.get(id).unwrap();
                             if f.matches(r) {
-                                ids.push(r.id());
+                                ids.push(r.id().into());
                             }
                         }
                     } else {
                         for id in valid.iter() {
                             let r = self.resources.get(id).unwrap();
                             if f.matches(r) {
-                                ids.push(r.id());
+                                ids.push(r.id().into());
                             }
                         }
                     }
@@ -245,7 +254,23 @@ impl Processor {
                     TransferKind::UploadTorrent { size, path },
                 ));
             }
-            CMessage::UploadMagnet { serial, uri, path } => {}
+            CMessage::UploadMagnet { serial, uri, path } => {
+                match Info::from_magnet(&uri) {
+                    Ok(info) => {
+                        resp.push(SMessage::ResourcesExtant {
+                            serial,
+                            ids: vec![hash_to_id(&info.hash[..]).into()],
+                        });
+                        rmsg = Some(Message::Torrent { info, path });
+                    }
+                    Err(e) => {
+                        resp.push(SMessage::InvalidResource(Error {
+                            serial: Some(serial),
+                            reason: format!("Invalid magnet URI: {}", e),
+                        }));
+                    }
+                }
+            }
             CMessage::UploadFiles { serial, size, path } => {
                 resp.push(self.new_transfer(
                     client,
@@ -270,6 +295,67 @@ impl Processor {
                     TransferKind::DownloadFile { path },
                 ));
             }
+            CMessage::StreamFile { serial, id } => {
+                let torrent_id = match self.resources.get(&id) {
+                    Some(&Resource::File(ref f)) => f.torrent_id.clone(),
+                    _ => {
+                        resp.push(SMessage::UnknownResource(Error {
+                            serial: Some(serial),
+                            reason: format!("unknown file id {}", id),
+                        }));
+                        return (resp, rmsg);
+                    }
+                };
+                resp.push(self.new_transfer(
+                    client,
+                    serial,
+                    TransferKind::StreamFile {
+                        torrent_id,
+                        file_id: id,
+                    },
+                ));
+            }
+            CMessage::DownloadTorrent { serial, id } => {
+                match self.resources.get(&id) {
+                    Some(&Resource::Torrent(_)) => {
+                        rmsg = Some(Message::ExportTorrent { client, serial, id });
+                    }
+                    _ => {
+                        resp.push(SMessage::UnknownResource(Error {
+                            serial: Some(serial),
+                            reason: format!("unknown torrent id {}", id),
+                        }));
+                    }
+                }
+            }
+            CMessage::CreateTorrent {
+                serial,
+                path,
+                piece_size,
+                trackers,
+                webseeds,
+                private,
+                comment,
+                start,
+            } => {
+                let opts = CreateOpts {
+                    path: PathBuf::from(path),
+                    piece_len: piece_size,
+                    trackers,
+                    webseeds,
+                    private,
+                    comment,
+                };
+                rmsg = Some(Message::CreateTorrent {
+                    client,
+                    serial,
+                    start,
+                    opts,
+                });
+            }
+            CMessage::ReloadBlocklist { serial } => {
+                rmsg = Some(Message::ReloadBlocklist { client, serial });
+            }
         }
         (resp, rmsg)
     }
@@ -304,7 +390,13 @@ impl Processor {
                 }
 
                 for (serial, (c, ids)) in self.get_matching_filters(rids.into_iter()) {
-                    msgs.push((c, SMessage::ResourcesExtant { serial, ids }));
+                    msgs.push((
+                        c,
+                        SMessage::ResourcesExtant {
+                            serial,
+                            ids: ids.into_iter().map(|id| id.into()).collect(),
+                        },
+                    ));
                 }
             }
             CtlMessage::Update(updates) => {
@@ -350,6 +442,34 @@ impl Processor {
                     }
                 }
             }
+            CtlMessage::TorrentFile {
+                client,
+                serial,
+                name,
+                data,
+            } => {
+                let offer = self.new_transfer(
+                    client,
+                    serial,
+                    TransferKind::DownloadTorrent { name, data },
+                );
+                msgs.push((client, offer));
+            }
+            CtlMessage::Error {
+                client,
+                serial,
+                reason,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::ServerError(Error {
+                        serial: Some(serial),
+                        reason,
+                    }),
+                ));
+            }
+            CtlMessage::StreamData { .. } |
+            CtlMessage::StreamError { .. } |
             CtlMessage::Shutdown => unreachable!(),
         }
         msgs
@@ -385,6 +505,11 @@ impl Processor {
     }
 
     fn new_transfer(&mut self, client: usize, serial: u64, kind: TransferKind) -> SMessage {
+        let size = match kind {
+            TransferKind::DownloadTorrent { ref data, .. } => data.len() as u64,
+            // TODO: Get this for the other kinds
+            _ => 0,
+        };
         let expiration = Utc::now() + Duration::seconds(EXPIRATION_DUR);
         let tok = random_string(15);
         self.tokens.insert(
@@ -400,8 +525,7 @@ impl Processor {
             serial,
             expires: expiration,
             token: tok,
-            // TODO: Get this
-            size: 0,
+            size,
         }
     }
 }
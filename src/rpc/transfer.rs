@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::net::TcpStream;
-use std::io::{self, Write};
-use std::{time, thread, fs};
+use std::io::{self, Read, Write};
+use std::{cmp, time, thread, fs};
 use std::path::Path;
 
 use super::proto::message::Error;
@@ -9,6 +9,29 @@ use util::{aread, IOR};
 
 pub struct Transfers {
     torrents: HashMap<usize, TorrentTx>,
+    streams: HashMap<usize, StreamTx>,
+}
+
+/// A byte range given in a Range header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+    /// The first byte and optionally the last, inclusive
+    From(u64, Option<u64>),
+    /// The number of bytes at the end
+    Suffix(u64),
+}
+
+pub enum StreamResult {
+    /// More of the file should be read for the stream
+    Read {
+        torrent_id: String,
+        file_id: String,
+        offset: u64,
+        len: u64,
+    },
+    /// The stream is finished or failed and should be closed
+    Done { conn: TcpStream, torrent_id: String },
+    Incomplete,
 }
 
 pub enum TransferResult {
@@ -37,12 +60,38 @@ struct TorrentTx {
     last_action: time::Instant,
 }
 
+/// A file being streamed to a client, whose data is read in chunks as the
+/// connection is able to take it.
+struct StreamTx {
+    conn: TcpStream,
+    torrent_id: String,
+    file_id: String,
+    range: Option<ByteRange>,
+    /// Offset of the next data to be read
+    pos: u64,
+    /// End of the response, known once the size of the file is
+    end: Option<u64>,
+    /// Whether a read is in progress
+    reading: bool,
+    buf: Vec<u8>,
+    written: usize,
+}
+
+enum StreamStatus {
+    Read(u64, u64),
+    Waiting,
+    Done,
+}
+
 const CONN_TIMEOUT: u64 = 2;
+/// Maximum amount of a stream read at once
+const STREAM_CHUNK: u64 = 1024 * 1024;
 
 impl Transfers {
     pub fn new() -> Transfers {
         Transfers {
             torrents: HashMap::new(),
+            streams: HashMap::new(),
         }
     }
 
@@ -85,6 +134,97 @@ impl Transfers {
         });
     }
 
+    pub fn add_data(&self, conn: TcpStream, name: String, data: Vec<u8>) {
+        thread::spawn(move || {
+            match handle_data(conn, name, data) {
+                Ok(()) => {
+                }
+                Err(_) => {
+                    // TODO: ?
+                }
+            }
+        });
+    }
+
+    /// Starts streaming a file. Nothing is written until the file's size
+    /// is known, so an empty read is requested first.
+    pub fn add_stream(
+        &mut self,
+        id: usize,
+        conn: TcpStream,
+        torrent_id: String,
+        file_id: String,
+        range: Option<ByteRange>,
+    ) -> StreamResult {
+        self.streams.insert(
+            id,
+            StreamTx {
+                conn,
+                torrent_id,
+                file_id,
+                range,
+                pos: 0,
+                end: None,
+                reading: true,
+                buf: Vec::new(),
+                written: 0,
+            },
+        );
+        self.stream_result(id, Ok(StreamStatus::Read(0, 0)))
+    }
+
+    pub fn is_stream(&self, id: usize) -> bool {
+        self.streams.contains_key(&id)
+    }
+
+    /// Handles the connection of a stream becoming readable or writable
+    pub fn stream_ready(&mut self, id: usize) -> StreamResult {
+        let res = match self.streams.get_mut(&id) {
+            Some(s) => s.ready(),
+            None => return StreamResult::Incomplete,
+        };
+        self.stream_result(id, res)
+    }
+
+    /// Handles data read for a stream from a file of size bytes
+    pub fn stream_data(&mut self, id: usize, size: u64, data: Vec<u8>) -> StreamResult {
+        let res = match self.streams.get_mut(&id) {
+            Some(s) => s.data(size, data),
+            None => return StreamResult::Incomplete,
+        };
+        self.stream_result(id, res)
+    }
+
+    pub fn stream_error(&mut self, id: usize) -> StreamResult {
+        let res = match self.streams.get_mut(&id) {
+            Some(s) => s.error(),
+            None => return StreamResult::Incomplete,
+        };
+        self.stream_result(id, res)
+    }
+
+    fn stream_result(&mut self, id: usize, res: io::Result<StreamStatus>) -> StreamResult {
+        match res {
+            Ok(StreamStatus::Read(offset, len)) => {
+                let s = &self.streams[&id];
+                StreamResult::Read {
+                    torrent_id: s.torrent_id.clone(),
+                    file_id: s.file_id.clone(),
+                    offset,
+                    len,
+                }
+            }
+            Ok(StreamStatus::Waiting) => StreamResult::Incomplete,
+            Ok(StreamStatus::Done) | Err(_) => {
+                let s = self.streams.remove(&id).unwrap();
+                StreamResult::Done {
+                    conn: s.conn,
+                    torrent_id: s.torrent_id,
+                }
+            }
+        }
+    }
+
     pub fn contains(&self, id: usize) -> bool {
         self.torrents.contains_key(&id)
     }
@@ -175,11 +315,152 @@ impl TorrentTx {
     }
 }
 
+impl StreamTx {
+    fn ready(&mut self) -> io::Result<StreamStatus> {
+        // Anything sent by the client is ignored, but a closed connection ends the stream
+        let mut buf = [0u8; 512];
+        loop {
+            match self.conn.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.write()
+    }
+
+    fn data(&mut self, size: u64, data: Vec<u8>) -> io::Result<StreamStatus> {
+        self.reading = false;
+        match self.end {
+            None => {
+                let (status, start, end, range) = match self.range.map(|r| r.bounds(size)) {
+                    None => ("200 OK", 0, size, None),
+                    Some(Some((start, end))) => {
+                        let range = format!("Content-Range: bytes {}-{}/{}", start, end - 1, size);
+                        ("206 Partial Content", start, end, Some(range))
+                    }
+                    Some(None) => {
+                        let range = format!("Content-Range: bytes */{}", size);
+                        ("416 Range Not Satisfiable", 0, 0, Some(range))
+                    }
+                };
+                self.buf = stream_header(status, end - start, range).into_bytes();
+                self.pos = start;
+                self.end = Some(end);
+            }
+            Some(end) => {
+                let len = cmp::min(data.len() as u64, end - self.pos) as usize;
+                if len == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.buf.extend_from_slice(&data[..len]);
+                self.pos += len as u64;
+            }
+        }
+        self.write()
+    }
+
+    fn error(&mut self) -> io::Result<StreamStatus> {
+        self.reading = false;
+        if self.end.is_some() {
+            // The response can only be cut short
+            return Ok(StreamStatus::Done);
+        }
+        self.buf = stream_header("404 Not Found", 0, None).into_bytes();
+        self.end = Some(0);
+        self.write()
+    }
+
+    /// Writes out buffered data, requesting more once it's all written.
+    fn write(&mut self) -> io::Result<StreamStatus> {
+        while self.written < self.buf.len() {
+            match self.conn.write(&self.buf[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(a) => self.written += a,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(StreamStatus::Waiting)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.buf.clear();
+        self.written = 0;
+        match self.end {
+            Some(end) if self.pos < end => {
+                if self.reading {
+                    return Ok(StreamStatus::Waiting);
+                }
+                self.reading = true;
+                Ok(StreamStatus::Read(self.pos, cmp::min(end - self.pos, STREAM_CHUNK)))
+            }
+            Some(_) => Ok(StreamStatus::Done),
+            None => Ok(StreamStatus::Waiting),
+        }
+    }
+}
+
+impl ByteRange {
+    /// Parses the value of a Range header, only a single range is supported.
+    pub fn parse(value: &str) -> Option<ByteRange> {
+        let value = value.trim();
+        if !value.starts_with("bytes=") || value.contains(',') {
+            return None;
+        }
+        let mut bounds = value[6..].splitn(2, '-');
+        let start = bounds.next().unwrap_or("").trim();
+        let end = match bounds.next() {
+            Some(e) => e.trim(),
+            None => return None,
+        };
+        if start.is_empty() {
+            return end.parse().ok().map(ByteRange::Suffix);
+        }
+        let start = match start.parse() {
+            Ok(s) => s,
+            Err(_) => return None,
+        };
+        if end.is_empty() {
+            return Some(ByteRange::From(start, None));
+        }
+        end.parse().ok().and_then(|end| if end >= start {
+            Some(ByteRange::From(start, Some(end)))
+        } else {
+            None
+        })
+    }
+
+    /// Returns the start and end(exclusive) of the range within a file
+    /// of size bytes, or None if the range can't be satisfied.
+    pub fn bounds(&self, size: u64) -> Option<(u64, u64)> {
+        let (start, end) = match *self {
+            ByteRange::From(start, end) => {
+                (start, end.map(|e| cmp::min(e.saturating_add(1), size)).unwrap_or(size))
+            }
+            ByteRange::Suffix(len) => (size.saturating_sub(len), size),
+        };
+        // Empty ranges, e.g. of an empty file, can't be satisfied
+        if start >= end { None } else { Some((start, end)) }
+    }
+}
+
 fn handle_dl(mut conn: TcpStream, path: String) -> io::Result<()> {
     let mut f = fs::File::open(&path)?;
     let len = f.metadata()?.len();
 
     let p = Path::new(&path);
+    write_dl_header(&mut conn, &p.file_name().unwrap().to_string_lossy(), len)?;
+    io::copy(&mut f, &mut conn)?;
+    Ok(())
+}
+
+fn handle_data(mut conn: TcpStream, name: String, data: Vec<u8>) -> io::Result<()> {
+    write_dl_header(&mut conn, &name, data.len() as u64)?;
+    conn.write_all(&data)?;
+    Ok(())
+}
+
+fn write_dl_header(conn: &mut TcpStream, name: &str, len: u64) -> io::Result<()> {
     let lines = vec![
         format!("HTTP/1.1 200 OK"),
         format!("Access-Control-Allow-Origin: {}", "*"),
@@ -190,12 +471,47 @@ fn handle_dl(mut conn: TcpStream, path: String) -> io::Result<()> {
         ),
         format!("Content-Length: {}", len),
         format!("Content-Type: {}", "application/octet-stream"),
-        format!("Content-Disposition: attachment; filename=\"{}\"", p.file_name().unwrap().to_string_lossy()),
+        format!("Content-Disposition: attachment; filename=\"{}\"", name),
         format!("Connection: {}", "Close"),
         format!("\r\n"),
     ];
     let data = lines.join("\r\n");
-    conn.write_all(data.as_bytes())?;
-    io::copy(&mut f, &mut conn)?;
-    Ok(())
+    conn.write_all(data.as_bytes())
+}
+
+fn stream_header(status: &str, len: u64, range: Option<String>) -> String {
+    let mut lines = vec![
+        format!("HTTP/1.1 {}", status),
+        format!("Access-Control-Allow-Origin: {}", "*"),
+        format!("Accept-Ranges: {}", "bytes"),
+        format!("Content-Length: {}", len),
+        format!("Content-Type: {}", "application/octet-stream"),
+        format!("Connection: {}", "Close"),
+    ];
+    lines.extend(range);
+    lines.join("\r\n") + "\r\n\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ByteRange;
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(ByteRange::parse("bytes=0-499"), Some(ByteRange::From(0, Some(499))));
+        assert_eq!(ByteRange::parse("bytes=500-"), Some(ByteRange::From(500, None)));
+        assert_eq!(ByteRange::parse("bytes=-500"), Some(ByteRange::Suffix(500)));
+        assert_eq!(ByteRange::parse("bytes=5-1"), None);
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+
+        assert_eq!(ByteRange::From(0, Some(499)).bounds(1000), Some((0, 500)));
+        assert_eq!(ByteRange::From(500, Some(2000)).bounds(1000), Some((500, 1000)));
+        assert_eq!(ByteRange::From(1000, None).bounds(1000), None);
+        assert_eq!(ByteRange::Suffix(200).bounds(1000), Some((800, 1000)));
+        assert_eq!(ByteRange::Suffix(2000).bounds(1000), Some((0, 1000)));
+        assert_eq!(ByteRange::Suffix(0).bounds(1000), None);
+        assert_eq!(ByteRange::Suffix(200).bounds(0), None);
+        assert_eq!(ByteRange::From(0, None).bounds(0), None);
+    }
 }
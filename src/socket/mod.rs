@@ -0,0 +1,474 @@
+mod mse;
+pub mod proxy;
+pub mod utp;
+
+use std::cmp;
+use std::net::{TcpStream, SocketAddr};
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::net::UnixStream;
+use std::io::{self, ErrorKind, Read, Write};
+use throttle::Throttle;
+use net2::{TcpBuilder, TcpStreamExt};
+use util::{bind_device, bind_ip, canonical_addr, io_err, ip_tcp_builder, mapped_addr,
+           tcp_builder};
+use config::{Encryption, ProxyClass};
+use {amy, CONFIG};
+
+const EINPROGRESS: i32 = 115;
+/// Maximum amount of data buffered while the MSE handshake is in progress
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
+/// rate limiting, etc.
+pub struct Socket {
+    conn: Stream,
+    addr: SocketAddr,
+    pub throttle: Option<Throttle>,
+    /// Proxy handshake, which must complete before any other data is exchanged
+    proxy: Option<Box<proxy::Handshake>>,
+    /// MSE handshake, if one is in progress
+    mse: Option<Box<mse::Handshake>>,
+    crypt: Option<mse::Crypt>,
+    /// Whether the peer received part of the MSE handshake, so
+    /// that its failure isn't down to the connection itself
+    mse_sent: bool,
+    mse_failed: bool,
+    /// Decrypted data received during the MSE handshake
+    rbuf: Vec<u8>,
+    /// Data accepted by write which has not yet been sent.
+    /// Plaintext until the MSE handshake completes.
+    wbuf: Vec<u8>,
+}
+
+/// Underlying transport of a socket. uTP connections are run by the
+/// `utp::Endpoint`, which the socket communicates with over a unix stream.
+enum Stream {
+    Tcp(TcpStream),
+    Utp(UnixStream),
+}
+
+impl Socket {
+    pub fn new(addr: &SocketAddr) -> io::Result<Socket> {
+        let (dest, proxy) = if CONFIG.proxy.enabled(ProxyClass::Peers) {
+            let (dest, hs) = proxy::connect(proxy::Target::Addr(*addr))?;
+            (dest, Some(Box::new(hs)))
+        } else {
+            (*addr, None)
+        };
+        let conn = tcp_builder(&dest)?.to_tcp_stream()?;
+        conn.set_nonblocking(true)?;
+        match conn.connect(dest) {
+            Err(e) => {
+                if Some(EINPROGRESS) != e.raw_os_error() {
+                    return Err(e);
+                }
+            }
+            _ => {}
+        }
+        let mut sock = Socket::from_parts(Stream::Tcp(conn), addr.clone());
+        sock.proxy = proxy;
+        Ok(sock)
+    }
+
+    /// Creates an outgoing uTP socket. The returned stream must be
+    /// passed to the uTP endpoint, which makes the connection.
+    pub fn new_utp(addr: &SocketAddr) -> io::Result<(Socket, UnixStream)> {
+        let (conn, remote) = UnixStream::pair()?;
+        conn.set_nonblocking(true)?;
+        Ok((Socket::from_parts(Stream::Utp(conn), addr.clone()), remote))
+    }
+
+    #[cfg(test)]
+    pub fn empty() -> Socket {
+        let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
+        Socket::from_parts(Stream::Tcp(conn), "127.0.0.1:0".parse().unwrap())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Creates a socket from an accepted connection. Unless
+    /// encryption is disabled, the connection is checked for an
+    /// MSE handshake before any data is returned.
+    pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
+        conn.set_nonblocking(true)?;
+        let addr = canonical_addr(conn.peer_addr()?);
+        Ok(Socket::incoming(Stream::Tcp(conn), addr))
+    }
+
+    fn incoming(conn: Stream, addr: SocketAddr) -> Socket {
+        let mut sock = Socket::from_parts(conn, addr);
+        if CONFIG.peer.encryption != Encryption::Disabled {
+            sock.mse = Some(Box::new(mse::Handshake::receiver(CONFIG.peer.encryption)));
+        }
+        sock
+    }
+
+    fn from_parts(conn: Stream, addr: SocketAddr) -> Socket {
+        Socket {
+            conn,
+            addr,
+            throttle: None,
+            proxy: None,
+            mse: None,
+            crypt: None,
+            mse_sent: false,
+            mse_failed: false,
+            rbuf: Vec::new(),
+            wbuf: Vec::new(),
+        }
+    }
+
+    /// Starts an MSE handshake on an outgoing connection to a peer of
+    /// the torrent with the given hash, if encryption is enabled.
+    pub fn encrypt(&mut self, hash: &[u8; 20]) {
+        if CONFIG.peer.encryption != Encryption::Disabled {
+            self.mse = Some(Box::new(
+                mse::Handshake::initiator(hash, CONFIG.peer.encryption),
+            ));
+        }
+    }
+
+    /// Returns HASH('req2', SKEY) sent by an incoming peer, if the
+    /// matching torrent hash must be provided to continue the handshake.
+    pub fn skey_request(&self) -> Option<[u8; 20]> {
+        self.mse.as_ref().and_then(|hs| hs.skey_request())
+    }
+
+    pub fn set_skey(&mut self, hash: &[u8; 20]) {
+        if let Some(ref mut hs) = self.mse {
+            hs.set_skey(hash);
+        }
+    }
+
+    /// Progresses the proxy handshake as far as possible without blocking.
+    fn proxy_handshake(&mut self) -> io::Result<()> {
+        if self.proxy.as_mut().unwrap().drive(&mut self.conn)? {
+            let data = self.proxy.take().unwrap().finish();
+            match self.mse {
+                Some(ref mut hs) => hs.input(&data),
+                None => self.rbuf.extend_from_slice(&data),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the MSE handshake failed after connecting, e.g.
+    /// because the peer doesn't support encryption.
+    pub fn mse_failed(&self) -> bool {
+        self.mse_failed
+    }
+
+    /// Progresses the MSE handshake as far as possible without blocking.
+    fn handshake(&mut self) -> io::Result<()> {
+        let res = self.progress_handshake();
+        if res.is_err() && self.mse_sent {
+            self.mse_failed = true;
+        }
+        res
+    }
+
+    fn progress_handshake(&mut self) -> io::Result<()> {
+        loop {
+            let status = {
+                let hs = self.mse.as_mut().unwrap();
+                let status = hs.process().map_err(
+                    |e| io::Error::new(ErrorKind::InvalidData, e),
+                )?;
+                while !hs.out.is_empty() {
+                    match self.conn.write(&hs.out) {
+                        Ok(0) => return io_err("EOF"),
+                        Ok(amnt) => {
+                            hs.out.drain(..amnt);
+                            self.mse_sent = true;
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                status
+            };
+            match status {
+                mse::Status::Done => {
+                    let (crypt, payload, out) = self.mse.take().unwrap().finish();
+                    let mut pending = out;
+                    let mut data = self.wbuf.split_off(0);
+                    self.crypt = crypt;
+                    if let Some(ref mut c) = self.crypt {
+                        c.encrypt(&mut data);
+                    }
+                    pending.extend_from_slice(&data);
+                    self.wbuf = pending;
+                    self.rbuf = payload;
+                    return Ok(());
+                }
+                mse::Status::Skey(_) => return Ok(()),
+                mse::Status::Pending => {
+                    let mut buf = [0u8; 1024];
+                    match self.conn.read(&mut buf) {
+                        Ok(0) => return io_err("EOF"),
+                        Ok(amnt) => self.mse.as_mut().unwrap().input(&buf[..amnt]),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes out buffered data, returning WouldBlock if it cannot
+    /// all be written.
+    fn flush_wbuf(&mut self) -> io::Result<()> {
+        while !self.wbuf.is_empty() {
+            let amnt = write_throttled(&mut self.conn, &mut self.throttle, &self.wbuf)?;
+            if amnt == 0 {
+                return io_err("EOF");
+            }
+            self.wbuf.drain(..amnt);
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.proxy.is_some() {
+            self.proxy_handshake()?;
+            if self.proxy.is_some() {
+                return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+            }
+            if self.mse.is_none() {
+                if let Err(e) = self.flush_wbuf() {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        if self.mse.is_some() {
+            self.handshake()?;
+            if self.mse.is_some() {
+                return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+            }
+            // Any data buffered during the handshake can now be sent
+            if let Err(e) = self.flush_wbuf() {
+                if e.kind() != ErrorKind::WouldBlock {
+                    return Err(e);
+                }
+            }
+        }
+        if !self.rbuf.is_empty() {
+            let amnt = cmp::min(buf.len(), self.rbuf.len());
+            buf[..amnt].copy_from_slice(&self.rbuf[..amnt]);
+            self.rbuf.drain(..amnt);
+            return Ok(amnt);
+        }
+        let amnt = read_throttled(&mut self.conn, &mut self.throttle, buf)?;
+        if let Some(ref mut c) = self.crypt {
+            c.decrypt(&mut buf[..amnt]);
+        }
+        Ok(amnt)
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.proxy.is_some() {
+            self.proxy_handshake()?;
+        }
+        if self.proxy.is_none() && self.mse.is_some() {
+            self.handshake()?;
+        }
+        if self.proxy.is_some() || self.mse.is_some() {
+            // Buffer data until the handshake completes
+            if self.wbuf.len() >= MAX_PENDING {
+                return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+            }
+            self.wbuf.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        if self.crypt.is_none() && self.wbuf.is_empty() {
+            return write_throttled(&mut self.conn, &mut self.throttle, buf);
+        }
+        self.flush_wbuf()?;
+        // Encrypted data can't be partially written, so it's buffered
+        // and sent as the connection allows.
+        self.wbuf.extend_from_slice(buf);
+        if let Some(ref mut c) = self.crypt {
+            c.encrypt(&mut self.wbuf);
+        }
+        if let Err(e) = self.flush_wbuf() {
+            if e.kind() != ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.proxy.is_some() {
+            self.proxy_handshake()?;
+            if self.proxy.is_some() {
+                return Ok(());
+            }
+        }
+        if self.mse.is_some() {
+            return self.handshake();
+        }
+        self.flush_wbuf()?;
+        self.conn.flush()
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref s) => s.as_raw_fd(),
+            Stream::Utp(ref s) => s.as_raw_fd(),
+        }
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Utp(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Utp(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Utp(ref mut s) => s.flush(),
+        }
+    }
+}
+
+fn read_throttled(
+    conn: &mut Stream,
+    throttle: &mut Option<Throttle>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    // Don't bother rate limiting small requests
+    if buf.len() < 20 {
+        return conn.read(buf);
+    }
+    if let Some(ref mut t) = *throttle {
+        match t.get_bytes_dl(buf.len()) {
+            Ok(()) => {
+                match conn.read(buf) {
+                    Ok(amnt) => { t.restore_bytes_dl(buf.len() - amnt); Ok(amnt) }
+                    Err(e) => {
+                        t.restore_bytes_dl(buf.len());
+                        Err(e)
+                    }
+                }
+            }
+            Err(()) => {
+                Err(io::Error::new(ErrorKind::WouldBlock, ""))
+            }
+        }
+    } else {
+        conn.read(buf)
+    }
+}
+
+fn write_throttled(
+    conn: &mut Stream,
+    throttle: &mut Option<Throttle>,
+    buf: &[u8],
+) -> io::Result<usize> {
+    if buf.len() < 20 {
+        return conn.write(buf);
+    }
+    if let Some(ref mut t) = *throttle {
+        match t.get_bytes_ul(buf.len()) {
+            Ok(()) => {
+                match conn.write(buf) {
+                    Ok(amnt) => { t.restore_bytes_ul(buf.len() - amnt); Ok(amnt) }
+                    Err(e) => {
+                        t.restore_bytes_ul(buf.len());
+                        Err(e)
+                    }
+                }
+            }
+            Err(()) => {
+                Err(io::Error::new(ErrorKind::WouldBlock, ""))
+            }
+        }
+    } else {
+        conn.write(buf)
+    }
+}
+
+pub struct TSocket {
+    pub conn: TcpStream,
+    reg: amy::Registrar,
+}
+
+impl TSocket {
+    /// Creates a dual stack socket if possible, so that the
+    /// address can be either IPv4 or IPv6. If a local address is
+    /// configured the socket is bound to it instead.
+    pub fn new(r: &amy::Registrar) -> io::Result<(usize, TSocket)> {
+        let reg = r.try_clone()?;
+        let conn = if let Some(ip) = bind_ip()? {
+            let b = ip_tcp_builder(ip)?;
+            bind_device(&b)?;
+            b.bind((ip, 0))?;
+            b.to_tcp_stream()?
+        } else if let Ok(c) = TcpBuilder::new_v6().and_then(|b| {
+            b.only_v6(false)?;
+            b.to_tcp_stream()
+        })
+        {
+            c
+        } else {
+            TcpBuilder::new_v4()?.to_tcp_stream()?
+        };
+        conn.set_nonblocking(true)?;
+        let id = reg.register(&conn, amy::Event::Both)?;
+        Ok((id, TSocket { conn, reg }))
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        let addr = match self.conn.local_addr() {
+            Ok(SocketAddr::V6(_)) => mapped_addr(addr),
+            _ => addr,
+        };
+        match self.conn.connect(addr) {
+            Err(e) => {
+                if Some(EINPROGRESS) != e.raw_os_error() {
+                    return Err(e);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TSocket {
+    fn drop(&mut self) {
+        if let Err(_) = self.reg.deregister(&self.conn) {
+            // TODO: idk? does it matter?
+        }
+    }
+}
@@ -0,0 +1,520 @@
+//! Message Stream Encryption(MSE/PE). A Diffie-Hellman key exchange
+//! is performed before the BitTorrent handshake, after which the stream
+//! is either obfuscated with RC4 or continues in plaintext.
+
+use std::mem;
+
+use byteorder::{BigEndian, ByteOrder};
+use num::bigint::BigUint;
+use rand::{self, Rng};
+
+use config::Encryption;
+use util::sha1_hash;
+
+/// The 768 bit prime used for the key exchange, the generator is 2
+const PRIME: &'static [u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                               020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                               4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+/// Length of the public keys and shared secret
+const KEY_LEN: usize = 96;
+/// Length of the private key
+const PRIVATE_LEN: usize = 20;
+/// Verification constant
+const VC: [u8; 8] = [0; 8];
+/// Maximum amount of random padding which may follow each step
+const MAX_PAD: usize = 512;
+/// Amount of RC4 keystream discarded before use
+const RC4_DISCARD: usize = 1024;
+/// Prefix of a plaintext BitTorrent handshake
+const PROTOCOL: &'static [u8] = b"\x13BitTorrent protocol";
+
+pub const CRYPTO_PLAIN: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// RC4 stream cipher
+pub struct Rc4 {
+    s: Vec<u8>,
+    i: u8,
+    j: u8,
+}
+
+/// Ciphers used for an established RC4 connection.
+pub struct Crypt {
+    enc: Rc4,
+    dec: Rc4,
+}
+
+pub struct Handshake {
+    state: State,
+    policy: Encryption,
+    initiator: bool,
+    private: BigUint,
+    secret: Vec<u8>,
+    skey: [u8; 20],
+    select: u32,
+    enc: Option<Rc4>,
+    dec: Option<Rc4>,
+    /// Encrypted VC the initiator scans for
+    vc: [u8; 8],
+    /// Received data which has not yet been processed
+    inbuf: Vec<u8>,
+    /// Handshake data which must be sent to the remote
+    pub out: Vec<u8>,
+    /// Payload which was received during the handshake, decrypted
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// More data is needed to progress the handshake
+    Pending,
+    /// The torrent hash matching HASH('req2', SKEY) must be
+    /// provided via `set_skey` before the handshake can progress
+    Skey([u8; 20]),
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Incoming connection which may be plaintext
+    Detect,
+    /// Waiting for the remote public key
+    AwaitKey,
+    /// Initiator is scanning for the encrypted VC
+    AwaitVC,
+    /// Initiator is waiting for crypto_select and len(PadD)
+    AwaitSelect,
+    /// Initiator is skipping PadD
+    AwaitPadD(usize),
+    /// Receiver is scanning for HASH('req1', S)
+    AwaitReq1,
+    /// Receiver is waiting for HASH('req2', SKEY) xor HASH('req3', S)
+    AwaitReq2,
+    /// Receiver is waiting for the torrent hash to be resolved
+    AwaitSkey([u8; 20]),
+    /// Receiver is waiting for VC, crypto_provide and len(PadC)
+    AwaitProvide,
+    /// Receiver is skipping PadC and reading len(IA)
+    AwaitPadC(usize),
+    /// Receiver is reading the initial payload
+    AwaitIA(usize),
+    Done,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut s: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *b ^= self.s[k as usize];
+        }
+    }
+}
+
+impl Crypt {
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.enc.apply(data);
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.dec.apply(data);
+    }
+}
+
+impl Handshake {
+    /// Creates a handshake for an outgoing connection to a peer
+    /// of the torrent with the given info hash.
+    pub fn initiator(skey: &[u8; 20], policy: Encryption) -> Handshake {
+        let mut hs = Handshake::new(policy, true, State::AwaitKey);
+        hs.skey = *skey;
+        hs.send_key();
+        hs
+    }
+
+    /// Creates a handshake for an incoming connection, which
+    /// may turn out to be plaintext if the policy allows it.
+    pub fn receiver(policy: Encryption) -> Handshake {
+        Handshake::new(policy, false, State::Detect)
+    }
+
+    fn new(policy: Encryption, initiator: bool, state: State) -> Handshake {
+        let mut private = [0u8; PRIVATE_LEN];
+        rand::thread_rng().fill_bytes(&mut private);
+        Handshake {
+            state,
+            policy,
+            initiator,
+            private: BigUint::from_bytes_be(&private),
+            secret: Vec::new(),
+            skey: [0; 20],
+            select: 0,
+            enc: None,
+            dec: None,
+            vc: [0; 8],
+            inbuf: Vec::new(),
+            out: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        self.inbuf.extend_from_slice(data);
+    }
+
+    /// Returns the hash the remote used to identify the torrent,
+    /// if it is needed to continue.
+    pub fn skey_request(&self) -> Option<[u8; 20]> {
+        match self.state {
+            State::AwaitSkey(req) => Some(req),
+            _ => None,
+        }
+    }
+
+    pub fn set_skey(&mut self, skey: &[u8; 20]) {
+        if let State::AwaitSkey(_) = self.state {
+            self.skey = *skey;
+            self.dec = Some(self.cipher(b"keyA"));
+            self.enc = Some(self.cipher(b"keyB"));
+            self.state = State::AwaitProvide;
+        }
+    }
+
+    /// Processes as much buffered input as possible.
+    pub fn process(&mut self) -> Result<Status, &'static str> {
+        loop {
+            let next = match self.state {
+                State::Detect => {
+                    if self.inbuf.len() < PROTOCOL.len() {
+                        return Ok(Status::Pending);
+                    }
+                    if &self.inbuf[..PROTOCOL.len()] == PROTOCOL {
+                        if self.policy == Encryption::Required {
+                            return Err("Plaintext connections are not allowed");
+                        }
+                        self.select = CRYPTO_PLAIN;
+                        self.payload = mem::replace(&mut self.inbuf, Vec::new());
+                        State::Done
+                    } else {
+                        State::AwaitKey
+                    }
+                }
+                State::AwaitKey => {
+                    if self.inbuf.len() < KEY_LEN {
+                        return Ok(Status::Pending);
+                    }
+                    let remote = BigUint::from_bytes_be(&self.inbuf[..KEY_LEN]);
+                    self.inbuf.drain(..KEY_LEN);
+                    self.secret = pad_key(&modpow(&remote, &self.private, &prime()));
+                    if self.initiator {
+                        self.send_provide();
+                        State::AwaitVC
+                    } else {
+                        self.send_key();
+                        State::AwaitReq1
+                    }
+                }
+                State::AwaitVC => {
+                    match find(&self.inbuf, &self.vc) {
+                        Some(p) => {
+                            self.inbuf.drain(..p + VC.len());
+                            State::AwaitSelect
+                        }
+                        None if self.inbuf.len() >= MAX_PAD + VC.len() => {
+                            return Err("MSE verification constant not found");
+                        }
+                        None => return Ok(Status::Pending),
+                    }
+                }
+                State::AwaitSelect => {
+                    let b = match self.take(6) {
+                        Some(b) => b,
+                        None => return Ok(Status::Pending),
+                    };
+                    let select = BigEndian::read_u32(&b[..4]);
+                    let pad = BigEndian::read_u16(&b[4..]) as usize;
+                    if (select != CRYPTO_PLAIN && select != CRYPTO_RC4) ||
+                        select & self.provide() == 0
+                    {
+                        return Err("Invalid MSE crypto method selected");
+                    }
+                    if pad > MAX_PAD {
+                        return Err("Invalid MSE padding length");
+                    }
+                    self.select = select;
+                    State::AwaitPadD(pad)
+                }
+                State::AwaitPadD(len) => {
+                    if self.take(len).is_none() {
+                        return Ok(Status::Pending);
+                    }
+                    self.finish_payload();
+                    State::Done
+                }
+                State::AwaitReq1 => {
+                    let req1 = hash(&[b"req1", &self.secret]);
+                    match find(&self.inbuf, &req1) {
+                        Some(p) => {
+                            self.inbuf.drain(..p + req1.len());
+                            State::AwaitReq2
+                        }
+                        None if self.inbuf.len() >= MAX_PAD + req1.len() => {
+                            return Err("MSE synchronization hash not found");
+                        }
+                        None => return Ok(Status::Pending),
+                    }
+                }
+                State::AwaitReq2 => {
+                    if self.inbuf.len() < 20 {
+                        return Ok(Status::Pending);
+                    }
+                    let req3 = hash(&[b"req3", &self.secret]);
+                    let mut req2 = [0u8; 20];
+                    for i in 0..20 {
+                        req2[i] = self.inbuf[i] ^ req3[i];
+                    }
+                    self.inbuf.drain(..20);
+                    State::AwaitSkey(req2)
+                }
+                State::AwaitSkey(req2) => return Ok(Status::Skey(req2)),
+                State::AwaitProvide => {
+                    let b = match self.take(14) {
+                        Some(b) => b,
+                        None => return Ok(Status::Pending),
+                    };
+                    if b[..8] != VC {
+                        return Err("Invalid MSE verification constant");
+                    }
+                    let provide = BigEndian::read_u32(&b[8..12]);
+                    let pad = BigEndian::read_u16(&b[12..]) as usize;
+                    if pad > MAX_PAD {
+                        return Err("Invalid MSE padding length");
+                    }
+                    self.select = if provide & CRYPTO_RC4 != 0 {
+                        CRYPTO_RC4
+                    } else if provide & CRYPTO_PLAIN != 0 && self.policy != Encryption::Required {
+                        CRYPTO_PLAIN
+                    } else {
+                        return Err("No acceptable MSE crypto method provided");
+                    };
+                    State::AwaitPadC(pad)
+                }
+                State::AwaitPadC(len) => {
+                    let b = match self.take(len + 2) {
+                        Some(b) => b,
+                        None => return Ok(Status::Pending),
+                    };
+                    State::AwaitIA(BigEndian::read_u16(&b[len..]) as usize)
+                }
+                State::AwaitIA(len) => {
+                    self.payload = match self.take(len) {
+                        Some(b) => b,
+                        None => return Ok(Status::Pending),
+                    };
+                    // ENCRYPT(VC, crypto_select, len(PadD))
+                    let mut resp = [0u8; 14];
+                    BigEndian::write_u32(&mut resp[8..12], self.select);
+                    self.enc.as_mut().unwrap().apply(&mut resp);
+                    self.out.extend_from_slice(&resp);
+                    self.finish_payload();
+                    State::Done
+                }
+                State::Done => return Ok(Status::Done),
+            };
+            self.state = next;
+        }
+    }
+
+    /// Consumes the completed handshake, returning the ciphers if RC4 was
+    /// selected, payload which was received and any handshake data
+    /// which still must be sent.
+    pub fn finish(self) -> (Option<Crypt>, Vec<u8>, Vec<u8>) {
+        let crypt = match (self.select, self.enc, self.dec) {
+            (CRYPTO_RC4, Some(enc), Some(dec)) => Some(Crypt { enc, dec }),
+            _ => None,
+        };
+        (crypt, self.payload, self.out)
+    }
+
+    fn provide(&self) -> u32 {
+        match self.policy {
+            Encryption::Required => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAIN,
+        }
+    }
+
+    fn send_key(&mut self) {
+        let key = modpow(&BigUint::from(2u8), &self.private, &prime());
+        self.out.extend_from_slice(&pad_key(&key));
+        self.send_pad();
+    }
+
+    /// Sends the synchronization hashes and ENCRYPT(VC, crypto_provide,
+    /// len(PadC), PadC, len(IA)). The BitTorrent handshake is sent
+    /// afterwards rather than as IA.
+    fn send_provide(&mut self) {
+        self.out.extend_from_slice(&hash(&[b"req1", &self.secret]));
+        let req2 = hash(&[b"req2", &self.skey]);
+        let req3 = hash(&[b"req3", &self.secret]);
+        for i in 0..20 {
+            self.out.push(req2[i] ^ req3[i]);
+        }
+
+        let mut enc = self.cipher(b"keyA");
+        let mut dec = self.cipher(b"keyB");
+        let mut msg = [0u8; 16];
+        BigEndian::write_u32(&mut msg[8..12], self.provide());
+        enc.apply(&mut msg);
+        self.out.extend_from_slice(&msg);
+        self.vc = VC;
+        dec.apply(&mut self.vc);
+        self.enc = Some(enc);
+        self.dec = Some(dec);
+    }
+
+    fn send_pad(&mut self) {
+        let mut rng = rand::thread_rng();
+        let len = rng.gen_range(0, MAX_PAD + 1);
+        let mut pad = vec![0u8; len];
+        rng.fill_bytes(&mut pad);
+        self.out.extend_from_slice(&pad);
+    }
+
+    fn cipher(&self, name: &[u8]) -> Rc4 {
+        let mut c = Rc4::new(&hash(&[name, &self.secret, &self.skey]));
+        c.apply(&mut [0u8; RC4_DISCARD]);
+        c
+    }
+
+    /// Removes and decrypts the next len bytes of input, if available.
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        if self.inbuf.len() < len {
+            return None;
+        }
+        let mut b: Vec<u8> = self.inbuf.drain(..len).collect();
+        self.dec.as_mut().unwrap().apply(&mut b);
+        Some(b)
+    }
+
+    fn finish_payload(&mut self) {
+        let mut rest = mem::replace(&mut self.inbuf, Vec::new());
+        if self.select == CRYPTO_RC4 {
+            self.dec.as_mut().unwrap().apply(&mut rest);
+        }
+        self.payload.extend_from_slice(&rest);
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).unwrap()
+}
+
+fn modpow(base: &BigUint, exp: &BigUint, m: &BigUint) -> BigUint {
+    let mut res = BigUint::from(1u8);
+    for byte in exp.to_bytes_be() {
+        for i in (0..8).rev() {
+            res = &res * &res % m;
+            if (byte >> i) & 1 == 1 {
+                res = &res * base % m;
+            }
+        }
+    }
+    res
+}
+
+/// Left pads a key with zeroes to KEY_LEN bytes
+fn pad_key(key: &BigUint) -> Vec<u8> {
+    let b = key.to_bytes_be();
+    let mut k = vec![0u8; KEY_LEN - b.len()];
+    k.extend_from_slice(&b);
+    k
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let data: Vec<u8> = parts.iter().flat_map(|p| p.iter().cloned()).collect();
+    let mut h = [0u8; 20];
+    h.copy_from_slice(sha1_hash(&data).as_ref());
+    h
+}
+
+fn find(data: &[u8], pat: &[u8]) -> Option<usize> {
+    data.windows(pat.len()).position(|w| w == pat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, Rc4, Status, hash};
+    use config::Encryption;
+
+    /// Shuttles data between both sides until neither progresses.
+    fn exchange(a: &mut Handshake, b: &mut Handshake, skey: &[u8; 20]) -> Result<(), &'static str> {
+        for _ in 0..10 {
+            let out = a.out.split_off(0);
+            b.input(&out);
+            let out = b.out.split_off(0);
+            a.input(&out);
+            a.process()?;
+            if let Status::Skey(req) = b.process()? {
+                assert_eq!(req, hash(&[b"req2", skey]));
+                b.set_skey(skey);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut c = Rc4::new(b"Key");
+        let mut d = b"Plaintext".to_vec();
+        c.apply(&mut d);
+        assert_eq!(d, vec![0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let skey = [7u8; 20];
+        let mut a = Handshake::initiator(&skey, Encryption::Preferred);
+        let mut b = Handshake::receiver(Encryption::Required);
+        exchange(&mut a, &mut b, &skey).unwrap();
+        assert_eq!(a.process(), Ok(Status::Done));
+        assert_eq!(b.process(), Ok(Status::Done));
+
+        let (ac, _, aout) = a.finish();
+        let (bc, _, bout) = b.finish();
+        assert!(aout.is_empty() && bout.is_empty());
+        let (mut ac, mut bc) = (ac.unwrap(), bc.unwrap());
+        let mut data = b"\x13BitTorrent protocol".to_vec();
+        ac.encrypt(&mut data);
+        assert!(&data[..] != b"\x13BitTorrent protocol");
+        bc.decrypt(&mut data);
+        assert_eq!(&data[..], b"\x13BitTorrent protocol");
+        bc.encrypt(&mut data);
+        ac.decrypt(&mut data);
+        assert_eq!(&data[..], b"\x13BitTorrent protocol");
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let mut b = Handshake::receiver(Encryption::Preferred);
+        b.input(b"\x13BitTorrent protocol\x00\x00");
+        assert_eq!(b.process(), Ok(Status::Done));
+        let (crypt, payload, _) = b.finish();
+        assert!(crypt.is_none());
+        assert_eq!(&payload[..], b"\x13BitTorrent protocol\x00\x00");
+
+        let mut b = Handshake::receiver(Encryption::Required);
+        b.input(b"\x13BitTorrent protocol");
+        assert!(b.process().is_err());
+    }
+}
@@ -0,0 +1,591 @@
+//! Outgoing connections through SOCKS5(RFC 1928) and HTTP CONNECT
+//! proxies. Like the MSE handshake, the proxy handshake is performed
+//! on buffers: data to send is placed in `out` and received data is
+//! passed to `input`, so it can be driven over nonblocking sockets.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use base64;
+use byteorder::{BigEndian, ByteOrder};
+
+use config::{ProxyClass, ProxyKind};
+use util::{bind_udp, canonical_addr, io_err, tcp_builder, udp_addr};
+use CONFIG;
+
+const SOCKS_VERSION: u8 = 5;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+/// Largest HTTP proxy response header accepted
+const MAX_HTTP_HEADER: usize = 8192;
+/// Timeout for establishing the SOCKS5 UDP association
+const ASSOCIATE_TIMEOUT_SECS: u64 = 10;
+/// Delay before reestablishing a failed SOCKS5 UDP association
+const ASSOCIATE_RETRY_SECS: u64 = 60;
+/// Longest hostname, username or password a SOCKS5 proxy accepts
+const MAX_SOCKS_FIELD: usize = 255;
+
+/// Destination of a proxied connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Addr(SocketAddr),
+    /// Hostname which is resolved by the proxy
+    Host(String, u16),
+}
+
+pub enum Status {
+    Pending,
+    Done,
+}
+
+pub struct Handshake {
+    kind: ProxyKind,
+    auth: Option<(String, String)>,
+    cmd: u8,
+    target: Target,
+    state: State,
+    /// Data which must be sent to the proxy
+    pub out: Vec<u8>,
+    inp: Vec<u8>,
+    /// Address bound by a SOCKS5 proxy for the request
+    bound: Option<SocketAddr>,
+}
+
+enum State {
+    Greeting,
+    Auth,
+    Reply,
+    Done,
+}
+
+/// Starts a connection through the proxy, returning the address which
+/// should be connected to and the handshake which must complete before
+/// any data is exchanged with the target.
+pub fn connect(target: Target) -> io::Result<(SocketAddr, Handshake)> {
+    let addr = proxy_addr()?;
+    let hs = Handshake::new(CONFIG.proxy.kind, CONFIG.proxy.auth.clone(), CMD_CONNECT, target);
+    Ok((addr, hs))
+}
+
+impl Handshake {
+    fn new(kind: ProxyKind, auth: Option<(String, String)>, cmd: u8, target: Target) -> Handshake {
+        let mut hs = Handshake {
+            kind,
+            auth,
+            cmd,
+            target,
+            state: State::Greeting,
+            out: Vec::new(),
+            inp: Vec::new(),
+            bound: None,
+        };
+        match kind {
+            ProxyKind::Http => {
+                hs.http_request();
+                hs.state = State::Reply;
+            }
+            _ => {
+                let method = if hs.auth.is_some() {
+                    METHOD_PASSWORD
+                } else {
+                    METHOD_NONE
+                };
+                hs.out.extend_from_slice(&[SOCKS_VERSION, 1, method]);
+            }
+        }
+        hs
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        self.inp.extend_from_slice(data);
+    }
+
+    pub fn process(&mut self) -> Result<Status, &'static str> {
+        match self.kind {
+            ProxyKind::Http => self.process_http(),
+            _ => self.process_socks(),
+        }
+    }
+
+    /// Progresses the handshake over a connection to the proxy as far
+    /// as possible without blocking, returning whether it has completed.
+    pub fn drive<S: Read + Write>(&mut self, conn: &mut S) -> io::Result<bool> {
+        loop {
+            let status = self.process().map_err(
+                |e| io::Error::new(ErrorKind::InvalidData, e),
+            )?;
+            while !self.out.is_empty() {
+                match conn.write(&self.out) {
+                    Ok(0) => return io_err("EOF"),
+                    Ok(amnt) => {
+                        self.out.drain(..amnt);
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) => return Err(e),
+                }
+            }
+            if let Status::Done = status {
+                return Ok(true);
+            }
+            let mut buf = [0u8; 512];
+            match conn.read(&mut buf) {
+                Ok(0) => return io_err("EOF"),
+                Ok(amnt) => self.input(&buf[..amnt]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns any data received from the target after the handshake.
+    pub fn finish(self) -> Vec<u8> {
+        self.inp
+    }
+
+    fn process_socks(&mut self) -> Result<Status, &'static str> {
+        loop {
+            match self.state {
+                State::Greeting => {
+                    if self.inp.len() < 2 {
+                        return Ok(Status::Pending);
+                    }
+                    if self.inp[0] != SOCKS_VERSION {
+                        return Err("Invalid SOCKS version in proxy response");
+                    }
+                    let method = self.inp[1];
+                    self.inp.drain(..2);
+                    match (method, self.auth.take()) {
+                        (METHOD_NONE, _) => {
+                            self.socks_request()?;
+                            self.state = State::Reply;
+                        }
+                        (METHOD_PASSWORD, Some((user, pass))) => {
+                            // RFC 1929 username/password authentication
+                            if user.len() > MAX_SOCKS_FIELD || pass.len() > MAX_SOCKS_FIELD {
+                                return Err("Proxy username or password is too long");
+                            }
+                            self.out.push(1);
+                            self.out.push(user.len() as u8);
+                            self.out.extend_from_slice(user.as_bytes());
+                            self.out.push(pass.len() as u8);
+                            self.out.extend_from_slice(pass.as_bytes());
+                            self.state = State::Auth;
+                        }
+                        _ => return Err("Proxy rejected the authentication methods"),
+                    }
+                }
+                State::Auth => {
+                    if self.inp.len() < 2 {
+                        return Ok(Status::Pending);
+                    }
+                    if self.inp[1] != 0 {
+                        return Err("Proxy authentication failed");
+                    }
+                    self.inp.drain(..2);
+                    self.socks_request()?;
+                    self.state = State::Reply;
+                }
+                State::Reply => {
+                    if self.inp.len() < 5 {
+                        return Ok(Status::Pending);
+                    }
+                    let len = match self.inp[3] {
+                        ATYP_V4 => 10,
+                        ATYP_V6 => 22,
+                        ATYP_DOMAIN => 7 + self.inp[4] as usize,
+                        _ => return Err("Invalid address type in proxy response"),
+                    };
+                    if self.inp.len() < len {
+                        return Ok(Status::Pending);
+                    }
+                    if self.inp[1] != 0 {
+                        return Err(socks_error(self.inp[1]));
+                    }
+                    self.bound = parse_socks_addr(&self.inp[3..len]).map(|(a, _)| a);
+                    self.inp.drain(..len);
+                    self.state = State::Done;
+                }
+                State::Done => return Ok(Status::Done),
+            }
+        }
+    }
+
+    fn process_http(&mut self) -> Result<Status, &'static str> {
+        if let State::Done = self.state {
+            return Ok(Status::Done);
+        }
+        let end = match self.inp.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if self.inp.len() > MAX_HTTP_HEADER => {
+                return Err("Proxy response header is too large")
+            }
+            None => return Ok(Status::Pending),
+        };
+        let ok = {
+            let mut status = self.inp[..end].split(|&b| b == b' ' || b == b'\r').skip(1);
+            status.next().map(|c| c == b"200").unwrap_or(false)
+        };
+        if !ok {
+            return Err("Proxy refused the connection");
+        }
+        self.inp.drain(..end);
+        self.state = State::Done;
+        Ok(Status::Done)
+    }
+
+    fn socks_request(&mut self) -> Result<(), &'static str> {
+        self.out.extend_from_slice(&[SOCKS_VERSION, self.cmd, 0]);
+        let target = self.target.clone();
+        encode_socks_addr(&mut self.out, &target)
+    }
+
+    fn http_request(&mut self) {
+        let dest = match self.target {
+            Target::Addr(addr) => addr.to_string(),
+            Target::Host(ref host, port) => format!("{}:{}", host, port),
+        };
+        let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", dest, dest);
+        if let Some((ref user, ref pass)) = self.auth {
+            let creds = base64::encode(format!("{}:{}", user, pass).as_bytes());
+            req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", creds));
+        }
+        req.push_str("\r\n");
+        self.out.extend_from_slice(req.as_bytes());
+    }
+}
+
+/// UDP socket whose traffic may be relayed through a SOCKS5 proxy.
+pub struct UdpSocket {
+    sock: net::UdpSocket,
+    class: ProxyClass,
+    relay: Relay,
+    /// Time before which a lost association isn't reestablished
+    retry: Instant,
+}
+
+enum Relay {
+    Direct,
+    /// Relay address of the association, which lasts as long
+    /// as the nonblocking control connection is open
+    Socks(SocketAddr, TcpStream),
+    /// The traffic should be proxied but can't be
+    Unavailable,
+}
+
+impl UdpSocket {
+    /// Binds a UDP socket on all interfaces, associating it with
+    /// the proxy if traffic of the class should be proxied. If this
+    /// fails nothing is sent, rather than sending outside the proxy.
+    pub fn bind(port: u16, class: ProxyClass) -> io::Result<UdpSocket> {
+        let sock = bind_udp(port)?;
+        let relay = relay(class);
+        let retry = Instant::now() + Duration::from_secs(ASSOCIATE_RETRY_SECS);
+        Ok(UdpSocket {
+            sock,
+            class,
+            relay,
+            retry,
+        })
+    }
+
+    /// Reestablishes the proxy association if it couldn't be
+    /// established or the proxy closed it. Should be called periodically.
+    pub fn tick(&mut self) {
+        let lost = match self.relay {
+            Relay::Direct => false,
+            Relay::Socks(_, ref ctrl) => closed(ctrl),
+            Relay::Unavailable => true,
+        };
+        if !lost || Instant::now() < self.retry {
+            return;
+        }
+        self.relay = relay(self.class);
+        self.retry = Instant::now() + Duration::from_secs(ASSOCIATE_RETRY_SECS);
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.sock.set_nonblocking(nonblocking)
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self.relay {
+            Relay::Direct => self.sock.send_to(buf, udp_addr(&self.sock, addr)),
+            Relay::Socks(relay, _) => {
+                let mut data = vec![0, 0, 0];
+                if let Err(e) = encode_socks_addr(&mut data, &Target::Addr(addr)) {
+                    return io_err(e);
+                }
+                data.extend_from_slice(buf);
+                self.sock.send_to(&data, udp_addr(&self.sock, relay)).map(
+                    |_| buf.len(),
+                )
+            }
+            Relay::Unavailable => io_err("UDP traffic can't be sent through the proxy"),
+        }
+    }
+
+    /// Receives a datagram, returning the canonical address of its sender.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (amnt, from) = self.sock.recv_from(buf)?;
+            let relay = match self.relay {
+                Relay::Socks(relay, _) => canonical_addr(relay),
+                _ => return Ok((amnt, canonical_addr(from))),
+            };
+            if canonical_addr(from) != relay || amnt < 4 || buf[2] != 0 {
+                // Only unfragmented datagrams from the relay are accepted
+                continue;
+            }
+            if let Some((addr, len)) = parse_socks_addr(&buf[3..amnt]) {
+                let start = 3 + len;
+                let data = buf[start..amnt].to_vec();
+                buf[..data.len()].copy_from_slice(&data);
+                return Ok((data.len(), canonical_addr(addr)));
+            }
+        }
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+fn proxy_addr() -> io::Result<SocketAddr> {
+    match CONFIG.proxy.addr {
+        Some(addr) => Ok(addr),
+        None => io_err("Proxy address could not be resolved"),
+    }
+}
+
+/// Determines how UDP traffic of the class is sent.
+fn relay(class: ProxyClass) -> Relay {
+    if !CONFIG.proxy.enabled(class) {
+        Relay::Direct
+    } else if CONFIG.proxy.kind != ProxyKind::Socks5 {
+        Relay::Unavailable
+    } else {
+        match associate() {
+            Ok((addr, ctrl)) => Relay::Socks(addr, ctrl),
+            Err(_) => Relay::Unavailable,
+        }
+    }
+}
+
+/// Establishes a SOCKS5 UDP association, returning the relay address
+/// and the control connection which keeps it alive.
+fn associate() -> io::Result<(SocketAddr, TcpStream)> {
+    let proxy = proxy_addr()?;
+    let mut ctrl = tcp_builder(&proxy)?.connect(proxy)?;
+    let timeout = Some(Duration::from_secs(ASSOCIATE_TIMEOUT_SECS));
+    ctrl.set_read_timeout(timeout)?;
+    ctrl.set_write_timeout(timeout)?;
+    let any = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+    let mut hs = Handshake::new(
+        ProxyKind::Socks5,
+        CONFIG.proxy.auth.clone(),
+        CMD_UDP_ASSOCIATE,
+        Target::Addr(any),
+    );
+    if !hs.drive(&mut ctrl)? {
+        return io_err("Proxy timed out");
+    }
+    let mut relay = match hs.bound {
+        Some(addr) => addr,
+        None => return io_err("Proxy did not provide a relay address"),
+    };
+    if relay.ip().is_unspecified() {
+        relay.set_ip(proxy.ip());
+    }
+    ctrl.set_nonblocking(true)?;
+    Ok((relay, ctrl))
+}
+
+/// Whether or not the proxy closed an association's control connection.
+fn closed(mut ctrl: &TcpStream) -> bool {
+    let mut buf = [0u8; 64];
+    loop {
+        match ctrl.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return false,
+            Err(_) => return true,
+        }
+    }
+}
+
+fn encode_socks_addr(buf: &mut Vec<u8>, target: &Target) -> Result<(), &'static str> {
+    let port = match *target {
+        Target::Addr(SocketAddr::V4(ref a)) => {
+            buf.push(ATYP_V4);
+            buf.extend_from_slice(&a.ip().octets());
+            a.port()
+        }
+        Target::Addr(SocketAddr::V6(ref a)) => {
+            buf.push(ATYP_V6);
+            buf.extend_from_slice(&a.ip().octets());
+            a.port()
+        }
+        Target::Host(ref host, _) if host.len() > MAX_SOCKS_FIELD => {
+            return Err("Hostname is too long to be sent to the proxy")
+        }
+        Target::Host(ref host, port) => {
+            buf.push(ATYP_DOMAIN);
+            buf.push(host.len() as u8);
+            buf.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    let mut p = [0u8; 2];
+    BigEndian::write_u16(&mut p, port);
+    buf.extend_from_slice(&p);
+    Ok(())
+}
+
+/// Parses a SOCKS5 address starting with its type, returning it and its
+/// encoded length. Domain addresses aren't resolved and are ignored.
+fn parse_socks_addr(data: &[u8]) -> Option<(SocketAddr, usize)> {
+    match data.first() {
+        Some(&ATYP_V4) if data.len() >= 7 => {
+            let ip = Ipv4Addr::new(data[1], data[2], data[3], data[4]);
+            let port = BigEndian::read_u16(&data[5..7]);
+            Some((SocketAddr::new(IpAddr::V4(ip), port), 7))
+        }
+        Some(&ATYP_V6) if data.len() >= 19 => {
+            let mut segments = [0u16; 8];
+            for (i, s) in segments.iter_mut().enumerate() {
+                *s = BigEndian::read_u16(&data[1 + i * 2..3 + i * 2]);
+            }
+            let ip = Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                segments[3],
+                segments[4],
+                segments[5],
+                segments[6],
+                segments[7],
+            );
+            let port = BigEndian::read_u16(&data[17..19]);
+            Some((SocketAddr::new(IpAddr::V6(ip), port), 19))
+        }
+        _ => None,
+    }
+}
+
+fn socks_error(code: u8) -> &'static str {
+    match code {
+        1 => "Proxy server failure",
+        2 => "Connection not allowed by proxy ruleset",
+        3 => "Network unreachable through proxy",
+        4 => "Host unreachable through proxy",
+        5 => "Connection refused through proxy",
+        6 => "TTL expired through proxy",
+        7 => "Command not supported by proxy",
+        8 => "Address type not supported by proxy",
+        _ => "Unknown proxy error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, Status, Target, CMD_CONNECT, parse_socks_addr, encode_socks_addr};
+    use config::ProxyKind;
+
+    fn done(hs: &mut Handshake) -> bool {
+        match hs.process().unwrap() {
+            Status::Done => true,
+            Status::Pending => false,
+        }
+    }
+
+    #[test]
+    fn test_socks_auth() {
+        let target = Target::Host("tracker.example".to_owned(), 80);
+        let auth = Some(("user".to_owned(), "pw".to_owned()));
+        let mut hs = Handshake::new(ProxyKind::Socks5, auth, CMD_CONNECT, target);
+        assert_eq!(hs.out, vec![5u8, 1, 2]);
+        hs.out.clear();
+        assert!(!done(&mut hs));
+
+        hs.input(&[5, 2]);
+        assert!(!done(&mut hs));
+        assert_eq!(hs.out, b"\x01\x04user\x02pw");
+        hs.out.clear();
+
+        hs.input(&[1, 0]);
+        assert!(!done(&mut hs));
+        assert_eq!(hs.out, b"\x05\x01\x00\x03\x0ftracker.example\x00\x50");
+
+        hs.input(&[5, 0, 0, 1, 10, 0, 0, 1, 0x1a, 0xe1, 1, 2]);
+        assert!(done(&mut hs));
+        assert_eq!(hs.bound, Some("10.0.0.1:6881".parse().unwrap()));
+        assert_eq!(hs.finish(), vec![1u8, 2]);
+    }
+
+    #[test]
+    fn test_socks_failure() {
+        let target = Target::Addr("1.2.3.4:6881".parse().unwrap());
+        let mut hs = Handshake::new(ProxyKind::Socks5, None, CMD_CONNECT, target);
+        hs.input(&[5, 0]);
+        assert!(!done(&mut hs));
+        assert_eq!(&hs.out[3..], &[5u8, 1, 0, 1, 1, 2, 3, 4, 0x1a, 0xe1][..]);
+        hs.input(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(hs.process().is_err());
+
+        // Password authentication can't be used without credentials
+        let target = Target::Addr("1.2.3.4:6881".parse().unwrap());
+        let mut hs = Handshake::new(ProxyKind::Socks5, None, CMD_CONNECT, target);
+        hs.input(&[5, 2]);
+        assert!(hs.process().is_err());
+
+        // Credentials which can't be encoded are rejected
+        let target = Target::Addr("1.2.3.4:6881".parse().unwrap());
+        let auth = Some(("u".repeat(256), "pw".to_owned()));
+        let mut hs = Handshake::new(ProxyKind::Socks5, auth, CMD_CONNECT, target);
+        hs.input(&[5, 2]);
+        assert!(hs.process().is_err());
+    }
+
+    #[test]
+    fn test_http_connect() {
+        let target = Target::Addr("[2001:db8::1]:6881".parse().unwrap());
+        let auth = Some(("user".to_owned(), "pw".to_owned()));
+        let mut hs = Handshake::new(ProxyKind::Http, auth, CMD_CONNECT, target);
+        assert_eq!(
+            String::from_utf8(hs.out.clone()).unwrap(),
+            "CONNECT [2001:db8::1]:6881 HTTP/1.1\r\nHost: [2001:db8::1]:6881\r\n\
+             Proxy-Authorization: Basic dXNlcjpwdw==\r\n\r\n"
+        );
+        hs.input(b"HTTP/1.1 200 Connection established\r\n");
+        assert!(!done(&mut hs));
+        hs.input(b"\r\nabc");
+        assert!(done(&mut hs));
+        assert_eq!(hs.finish(), b"abc");
+
+        let target = Target::Host("tracker.example".to_owned(), 443);
+        let mut hs = Handshake::new(ProxyKind::Http, None, CMD_CONNECT, target);
+        hs.input(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        assert!(hs.process().is_err());
+    }
+
+    #[test]
+    fn test_socks_addr() {
+        for addr in &["1.2.3.4:80", "[2001:db8::1]:6881"] {
+            let addr = addr.parse().unwrap();
+            let mut buf = Vec::new();
+            encode_socks_addr(&mut buf, &Target::Addr(addr)).unwrap();
+            assert_eq!(parse_socks_addr(&buf), Some((addr, buf.len())));
+        }
+        let host = Target::Host("a".repeat(256), 80);
+        assert!(encode_socks_addr(&mut Vec::new(), &host).is_err());
+    }
+}
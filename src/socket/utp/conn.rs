@@ -0,0 +1,601 @@
+use std::{cmp, mem, u32};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use super::packet::{Packet, Type, HEADER_LEN};
+
+/// Maximum payload of a single packet
+pub const MAX_PAYLOAD: usize = 1400 - HEADER_LEN;
+/// Amount of unacked and unsent data which may be buffered
+const SEND_BUF: usize = 256 * 1024;
+/// Receive window advertised when no received data is buffered
+const RECV_BUF: usize = 1024 * 1024;
+/// LEDBAT target queuing delay, in microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+/// Maximum increase of the congestion window per RTT
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const MIN_CWND: f64 = MAX_PAYLOAD as f64;
+const INIT_CWND: f64 = (MAX_PAYLOAD * 2) as f64;
+const INIT_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 500;
+const MAX_RTO_MS: u64 = 60_000;
+/// Transmissions of a packet before the connection is considered dead
+const MAX_TRANSMISSIONS: u32 = 5;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+/// Interval after which an idle connection sends a keepalive
+const KEEPALIVE_SECS: u64 = 29;
+/// Duration of each base delay history bucket
+const DELAY_BUCKET_SECS: u64 = 60;
+/// Number of previous buckets used for the base delay
+const DELAY_HISTORY: usize = 2;
+/// Amount of packets ahead of the current ack which are buffered
+const MAX_OOO: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    FinSent,
+    Closed,
+}
+
+/// A sent packet which hasn't been acked yet.
+struct Sent {
+    seq: u16,
+    ty: Type,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    fast_resent: bool,
+}
+
+/// A single uTP connection, independent of the underlying
+/// UDP socket. Incoming packets are passed to `handle`, and the
+/// packets it produces are taken with `packets`.
+pub struct Conn {
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet sent
+    seq: u16,
+    /// Last in order sequence number received
+    ack: u16,
+    last_ack_recv: u16,
+    sent: VecDeque<Sent>,
+    /// Payload bytes in flight
+    flight: usize,
+    cwnd: f64,
+    peer_wnd: usize,
+    /// Packets received ahead of ack
+    ooo: HashMap<u16, Packet>,
+    /// In order data which has been received
+    recv: Vec<u8>,
+    /// Data waiting to be packetized
+    send: Vec<u8>,
+    /// Encoded packets which should be transmitted
+    out: Vec<Vec<u8>>,
+    need_ack: bool,
+    /// Set when the local side wants to close the connection
+    closing: bool,
+    /// Sequence number of the remote's FIN, once received in order
+    eof: Option<u16>,
+    epoch: Instant,
+    reply_diff: u32,
+    rtt: u64,
+    rtt_var: u64,
+    rto: Duration,
+    timeout: Instant,
+    dup_acks: u32,
+    last_sent: Instant,
+    cur_delay: Option<u32>,
+    delay_min: u32,
+    delay_start: Instant,
+    delays: VecDeque<u32>,
+}
+
+impl Conn {
+    /// Creates an outgoing connection, queueing a SYN.
+    pub fn connect() -> Conn {
+        let id = rand::thread_rng().gen::<u16>();
+        let mut c = Conn::new(State::SynSent, id, id.wrapping_add(1), 1, 0);
+        c.send_packet(Type::Syn, Vec::new());
+        c
+    }
+
+    /// Creates a connection in response to a SYN.
+    pub fn accept(syn: &Packet) -> Conn {
+        let seq = rand::thread_rng().gen::<u16>();
+        let mut c = Conn::new(
+            State::Connected,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+            seq,
+            syn.seq,
+        );
+        c.peer_wnd = syn.wnd as usize;
+        c.reply_diff = c.timestamp().wrapping_sub(syn.ts);
+        c.send_state();
+        c
+    }
+
+    fn new(state: State, recv_id: u16, send_id: u16, seq: u16, ack: u16) -> Conn {
+        let now = Instant::now();
+        Conn {
+            state,
+            recv_id,
+            send_id,
+            seq,
+            ack,
+            last_ack_recv: 0,
+            sent: VecDeque::new(),
+            flight: 0,
+            cwnd: INIT_CWND,
+            peer_wnd: MAX_PAYLOAD,
+            ooo: HashMap::new(),
+            recv: Vec::new(),
+            send: Vec::new(),
+            out: Vec::new(),
+            need_ack: false,
+            closing: false,
+            eof: None,
+            epoch: now,
+            reply_diff: 0,
+            rtt: 0,
+            rtt_var: 0,
+            rto: Duration::from_millis(INIT_RTO_MS),
+            timeout: now,
+            dup_acks: 0,
+            last_sent: now,
+            cur_delay: None,
+            delay_min: u32::MAX,
+            delay_start: now,
+            delays: VecDeque::new(),
+        }
+    }
+
+    /// Connection id the remote uses when sending to us
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub fn connected(&self) -> bool {
+        self.state != State::SynSent
+    }
+
+    /// Whether or not the connection is finished. Any received data
+    /// should still be read.
+    pub fn closed(&self) -> bool {
+        self.state == State::Closed || (self.eof.is_some() && self.out.is_empty())
+    }
+
+    /// Amount of data which can currently be written
+    pub fn send_capacity(&self) -> usize {
+        if self.closing || self.state == State::Closed {
+            return 0;
+        }
+        SEND_BUF.saturating_sub(self.send.len() + self.flight)
+    }
+
+    /// Buffers data to be sent, returning the amount accepted.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let amnt = cmp::min(data.len(), self.send_capacity());
+        self.send.extend_from_slice(&data[..amnt]);
+        self.flush();
+        amnt
+    }
+
+    /// In order data which has been received
+    pub fn readable(&self) -> &[u8] {
+        &self.recv
+    }
+
+    /// Marks amnt bytes of received data as read
+    pub fn consume(&mut self, amnt: usize) {
+        let before = self.recv_window();
+        self.recv.drain(..amnt);
+        // Let the remote know if its window reopened
+        if before < MAX_PAYLOAD && self.recv_window() >= MAX_PAYLOAD && self.connected() {
+            self.send_state();
+        }
+    }
+
+    /// Closes the connection once all buffered data is sent.
+    pub fn close(&mut self) {
+        self.closing = true;
+        self.flush();
+    }
+
+    /// Takes the packets which should be sent to the remote.
+    pub fn packets(&mut self) -> Vec<Vec<u8>> {
+        mem::replace(&mut self.out, Vec::new())
+    }
+
+    pub fn handle(&mut self, p: Packet) {
+        match (self.state, p.ty) {
+            (State::Closed, _) => return,
+            (_, Type::Reset) => {
+                self.state = State::Closed;
+                return;
+            }
+            (_, Type::Syn) => {
+                // Our ack was lost, resend it
+                self.send_state();
+                return;
+            }
+            (State::SynSent, Type::State) => {
+                self.state = State::Connected;
+                self.ack = p.seq.wrapping_sub(1);
+            }
+            (State::SynSent, _) => return,
+            _ => {}
+        }
+        let now = Instant::now();
+        self.peer_wnd = p.wnd as usize;
+        self.reply_diff = self.timestamp().wrapping_sub(p.ts);
+        if p.ts_diff != 0 {
+            self.delay_sample(p.ts_diff, now);
+        }
+        self.process_ack(&p, now);
+        match p.ty {
+            Type::Data | Type::Fin => self.recv_data(p),
+            _ => {}
+        }
+        self.flush();
+    }
+
+    /// Handles retransmission timeouts and keepalives, should
+    /// be called periodically.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        if self.state == State::Closed {
+            return;
+        }
+        if !self.sent.is_empty() && now >= self.timeout {
+            let max = if self.state == State::SynSent {
+                MAX_SYN_TRANSMISSIONS
+            } else {
+                MAX_TRANSMISSIONS
+            };
+            if self.sent[0].transmissions >= max {
+                self.state = State::Closed;
+                return;
+            }
+            // A timeout indicates severe congestion
+            self.cwnd = MIN_CWND;
+            self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MS));
+            self.timeout = now + self.rto;
+            self.resend(0);
+        } else if self.sent.is_empty() && self.state == State::Connected &&
+                   now.duration_since(self.last_sent) >= Duration::from_secs(KEEPALIVE_SECS)
+        {
+            self.send_state();
+        }
+        self.flush();
+    }
+
+    fn process_ack(&mut self, p: &Packet, now: Instant) {
+        let mut acked = 0;
+        let mut sample = None;
+        while self.sent.front().map(|s| !seq_lt(p.ack, s.seq)).unwrap_or(false) {
+            let s = self.sent.pop_front().unwrap();
+            if s.transmissions == 1 {
+                sample = Some(now.duration_since(s.sent_at));
+            }
+            acked += s.payload.len();
+        }
+        if let Some(ref sack) = p.sack {
+            let mut sacked = 0;
+            for i in 0..sack.len() * 8 {
+                if sack[i / 8] >> (i % 8) & 1 == 0 {
+                    continue;
+                }
+                sacked += 1;
+                let seq = p.ack.wrapping_add(2 + i as u16);
+                if let Some(pos) = self.sent.iter().position(|s| s.seq == seq) {
+                    acked += self.sent.remove(pos).unwrap().payload.len();
+                }
+            }
+            // Packets past the first unacked one arrived, so it was lost
+            if sacked >= 3 {
+                self.lost();
+            }
+        }
+
+        if acked > 0 || p.ack != self.last_ack_recv {
+            self.flight -= acked;
+            self.dup_acks = 0;
+            if let Some(s) = sample {
+                self.update_rtt(s);
+            }
+            self.update_cwnd(acked);
+            self.timeout = now + self.rto;
+        } else if p.ty == Type::State && !self.sent.is_empty() {
+            self.dup_acks += 1;
+            if self.dup_acks == 3 {
+                self.lost();
+            }
+        }
+        self.last_ack_recv = p.ack;
+        if self.state == State::FinSent && self.sent.is_empty() {
+            self.state = State::Closed;
+        }
+    }
+
+    fn recv_data(&mut self, p: Packet) {
+        self.need_ack = true;
+        if self.eof.is_some() {
+            return;
+        }
+        if p.seq == self.ack.wrapping_add(1) {
+            self.accept_packet(p);
+            loop {
+                let next = self.ack.wrapping_add(1);
+                match self.ooo.remove(&next) {
+                    Some(p) => self.accept_packet(p),
+                    None => break,
+                }
+            }
+        } else if seq_lt(self.ack, p.seq) && p.seq.wrapping_sub(self.ack) < MAX_OOO {
+            self.ooo.insert(p.seq, p);
+        }
+    }
+
+    fn accept_packet(&mut self, p: Packet) {
+        self.ack = p.seq;
+        if p.ty == Type::Fin {
+            self.eof = Some(p.seq);
+            self.ooo.clear();
+        } else {
+            self.recv.extend_from_slice(&p.payload);
+        }
+    }
+
+    /// Handles the loss of the first unacked packet
+    fn lost(&mut self) {
+        let resend = match self.sent.front() {
+            Some(s) => !s.fast_resent,
+            None => false,
+        };
+        if resend {
+            self.sent[0].fast_resent = true;
+            self.cwnd = (self.cwnd / 2.).max(MIN_CWND);
+            self.resend(0);
+        }
+    }
+
+    /// Sends as much buffered data as the congestion and receive
+    /// windows allow, then an ack if one is still needed.
+    fn flush(&mut self) {
+        match self.state {
+            State::SynSent | State::Closed => return,
+            _ => {}
+        }
+        while !self.send.is_empty() {
+            let wnd = cmp::min(self.cwnd as usize, self.peer_wnd);
+            let len = cmp::min(self.send.len(), MAX_PAYLOAD);
+            // Always allow a single packet, which probes a closed window
+            if self.flight > 0 && self.flight + len > wnd {
+                break;
+            }
+            let payload: Vec<u8> = self.send.drain(..len).collect();
+            self.send_packet(Type::Data, payload);
+        }
+        if self.closing && self.send.is_empty() && self.state == State::Connected {
+            self.send_packet(Type::Fin, Vec::new());
+            self.state = State::FinSent;
+        }
+        if self.need_ack {
+            self.send_state();
+        }
+    }
+
+    fn send_packet(&mut self, ty: Type, payload: Vec<u8>) {
+        let now = Instant::now();
+        if self.sent.is_empty() {
+            self.timeout = now + self.rto;
+        }
+        self.flight += payload.len();
+        self.sent.push_back(Sent {
+            seq: self.seq,
+            ty,
+            payload,
+            sent_at: now,
+            transmissions: 0,
+            fast_resent: false,
+        });
+        self.seq = self.seq.wrapping_add(1);
+        let idx = self.sent.len() - 1;
+        self.resend(idx);
+    }
+
+    fn resend(&mut self, idx: usize) {
+        let ty = self.sent[idx].ty;
+        let mut p = self.packet(ty);
+        p.seq = self.sent[idx].seq;
+        p.payload = self.sent[idx].payload.clone();
+        self.out.push(p.encode());
+        let s = &mut self.sent[idx];
+        s.transmissions += 1;
+        s.sent_at = Instant::now();
+    }
+
+    fn send_state(&mut self) {
+        let mut p = self.packet(Type::State);
+        p.seq = self.seq;
+        self.out.push(p.encode());
+    }
+
+    /// Creates a packet with the current connection state, every
+    /// packet acks the data we've received.
+    fn packet(&mut self, ty: Type) -> Packet {
+        self.need_ack = false;
+        self.last_sent = Instant::now();
+        let id = if ty == Type::Syn {
+            self.recv_id
+        } else {
+            self.send_id
+        };
+        let mut p = Packet::new(ty, id);
+        p.ts = self.timestamp();
+        p.ts_diff = self.reply_diff;
+        p.wnd = self.recv_window() as u32;
+        p.ack = self.ack;
+        p.sack = self.sack();
+        p
+    }
+
+    fn sack(&self) -> Option<Vec<u8>> {
+        if self.ooo.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; 4];
+        for i in 0..32 {
+            if self.ooo.contains_key(&self.ack.wrapping_add(2 + i as u16)) {
+                mask[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn recv_window(&self) -> usize {
+        let buffered: usize = self.ooo.values().map(|p| p.payload.len()).sum();
+        RECV_BUF.saturating_sub(self.recv.len() + buffered)
+    }
+
+    /// Microsecond timestamp, relative to the connection's creation
+    fn timestamp(&self) -> u32 {
+        let d = self.epoch.elapsed();
+        (d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000) as u32
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let ms = sample.as_secs() * 1000 + sample.subsec_nanos() as u64 / 1_000_000;
+        if self.rtt == 0 {
+            self.rtt = ms;
+            self.rtt_var = ms / 2;
+        } else {
+            let delta = if self.rtt > ms {
+                self.rtt - ms
+            } else {
+                ms - self.rtt
+            };
+            self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+            self.rtt = (self.rtt * 7 + ms) / 8;
+        }
+        let rto = cmp::max(self.rtt + 4 * self.rtt_var, MIN_RTO_MS);
+        self.rto = Duration::from_millis(rto);
+    }
+
+    fn delay_sample(&mut self, delay: u32, now: Instant) {
+        self.cur_delay = Some(delay);
+        if now.duration_since(self.delay_start) >= Duration::from_secs(DELAY_BUCKET_SECS) {
+            self.delays.push_back(self.delay_min);
+            if self.delays.len() > DELAY_HISTORY {
+                self.delays.pop_front();
+            }
+            self.delay_start = now;
+            self.delay_min = delay;
+        } else {
+            self.delay_min = cmp::min(self.delay_min, delay);
+        }
+    }
+
+    /// Adjusts the congestion window according to LEDBAT, growing it
+    /// while the queuing delay is below target and shrinking it otherwise.
+    fn update_cwnd(&mut self, acked: usize) {
+        let off_target = match self.cur_delay {
+            Some(d) => {
+                let base = self.delays.iter().cloned().fold(self.delay_min, cmp::min);
+                (TARGET_DELAY - d.saturating_sub(base) as f64) / TARGET_DELAY
+            }
+            None => 1.,
+        };
+        let gain = MAX_CWND_INCREASE * off_target * acked as f64 / self.cwnd;
+        self.cwnd = (self.cwnd + gain).max(MIN_CWND).min(RECV_BUF as f64);
+    }
+}
+
+/// Whether a comes before b, accounting for wrapping
+fn seq_lt(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Conn, MAX_PAYLOAD};
+    use super::super::packet::Packet;
+
+    fn deliver(from: &mut Conn, to: &mut Conn) -> usize {
+        let pkts = from.packets();
+        let n = pkts.len();
+        for p in pkts {
+            to.handle(Packet::decode(&p).unwrap());
+        }
+        n
+    }
+
+    fn pair() -> (Conn, Conn) {
+        let mut a = Conn::connect();
+        let syn = a.packets();
+        assert_eq!(syn.len(), 1);
+        let mut b = Conn::accept(&Packet::decode(&syn[0]).unwrap());
+        assert_eq!(b.recv_id(), Packet::decode(&syn[0]).unwrap().conn_id + 1);
+        assert!(!a.connected());
+        deliver(&mut b, &mut a);
+        assert!(a.connected());
+        (a, b)
+    }
+
+    #[test]
+    fn test_transfer() {
+        let (mut a, mut b) = pair();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut written = 0;
+        while written < data.len() || b.readable().len() < data.len() {
+            written += a.write(&data[written..]);
+            while deliver(&mut a, &mut b) + deliver(&mut b, &mut a) > 0 {}
+        }
+        assert_eq!(b.readable(), &data[..]);
+        b.consume(data.len());
+        assert!(b.readable().is_empty());
+
+        b.write(b"reply");
+        deliver(&mut b, &mut a);
+        assert_eq!(a.readable(), b"reply");
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let (mut a, mut b) = pair();
+        let data = vec![1u8; MAX_PAYLOAD * 4];
+        a.write(&data);
+        let mut pkts = a.packets();
+        assert_eq!(pkts.len(), 2);
+        // Deliver the second packet first
+        let first = pkts.remove(0);
+        b.handle(Packet::decode(&pkts[0]).unwrap());
+        assert!(b.readable().is_empty());
+        let ack = Packet::decode(&b.packets()[0]).unwrap();
+        assert_eq!(ack.sack, Some(vec![1, 0, 0, 0]));
+        a.handle(ack);
+        b.handle(Packet::decode(&first).unwrap());
+        assert_eq!(b.readable().len(), MAX_PAYLOAD * 2);
+        while deliver(&mut a, &mut b) + deliver(&mut b, &mut a) > 0 {}
+        assert_eq!(b.readable(), &data[..]);
+    }
+
+    #[test]
+    fn test_close() {
+        let (mut a, mut b) = pair();
+        a.write(b"bye");
+        a.close();
+        deliver(&mut a, &mut b);
+        assert_eq!(b.readable(), b"bye");
+        assert!(!b.closed());
+        deliver(&mut b, &mut a);
+        assert!(a.closed());
+        assert!(b.closed());
+    }
+}
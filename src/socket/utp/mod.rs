@@ -0,0 +1,218 @@
+//! uTP(BEP 29) transport. A single UDP socket on the peer port
+//! multiplexes every uTP connection, each of which is bridged to its
+//! `Socket` through a unix stream, so peers can use it like TCP.
+
+mod conn;
+mod packet;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixStream;
+
+use amy;
+use self::conn::Conn;
+use self::packet::{Packet, Type};
+use super::{Socket, Stream};
+use util::{canonical_addr, udp_addr};
+
+/// Interval at which connection timers should be processed
+pub const TICK_MS: usize = 100;
+
+pub struct Endpoint {
+    sock: UdpSocket,
+    id: usize,
+    reg: amy::Registrar,
+    /// Connections indexed by the registration id of their stream
+    conns: HashMap<usize, Entry>,
+    /// Stream ids indexed by remote address and receiving connection id
+    ids: HashMap<(SocketAddr, u16), usize>,
+}
+
+struct Entry {
+    conn: Conn,
+    addr: SocketAddr,
+    stream: UnixStream,
+    /// Set once the local socket has been closed
+    eof: bool,
+}
+
+impl Endpoint {
+    pub fn new(reg: &amy::Registrar, sock: UdpSocket) -> io::Result<Endpoint> {
+        sock.set_nonblocking(true)?;
+        let reg = reg.try_clone()?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        Ok(Endpoint {
+            sock,
+            id,
+            reg,
+            conns: HashMap::new(),
+            ids: HashMap::new(),
+        })
+    }
+
+    /// Replaces the UDP socket, e.g. after the local address changed.
+    /// Existing connections time out unless their peers follow.
+    pub fn rebind(&mut self, sock: UdpSocket) -> io::Result<()> {
+        sock.set_nonblocking(true)?;
+        let id = self.reg.register(&sock, amy::Event::Read)?;
+        let _ = self.reg.deregister(&self.sock);
+        self.sock = sock;
+        self.id = id;
+        Ok(())
+    }
+
+    /// Registration id of the UDP socket
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Whether or not the registration id belongs to a connection's stream
+    pub fn owns(&self, id: usize) -> bool {
+        self.conns.contains_key(&id)
+    }
+
+    /// Starts an outgoing connection for a socket created by `Socket::new_utp`.
+    pub fn connect(&mut self, addr: SocketAddr, stream: UnixStream) -> io::Result<()> {
+        self.add_conn(addr, Conn::connect(), stream).map(|_| ())
+    }
+
+    /// Processes incoming packets, returning sockets for
+    /// any newly accepted connections.
+    pub fn readable(&mut self) -> Vec<Socket> {
+        let mut accepted = Vec::new();
+        let mut buf = [0u8; 2048];
+        loop {
+            let (amnt, addr) = match self.sock.recv_from(&mut buf) {
+                Ok((amnt, addr)) => (amnt, canonical_addr(addr)),
+                // ICMP errors from previous sends can surface here
+                Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                Err(_) => break,
+            };
+            let p = match Packet::decode(&buf[..amnt]) {
+                Some(p) => p,
+                None => continue,
+            };
+            let key = if p.ty == Type::Syn {
+                (addr, p.conn_id.wrapping_add(1))
+            } else {
+                (addr, p.conn_id)
+            };
+            match self.ids.get(&key).cloned() {
+                Some(id) => {
+                    if let Some(e) = self.conns.get_mut(&id) {
+                        e.conn.handle(p);
+                    }
+                    self.pump(id);
+                }
+                None if p.ty == Type::Syn => {
+                    if let Ok(s) = self.accept(addr, &p) {
+                        accepted.push(s);
+                    }
+                }
+                None if p.ty != Type::Reset => self.reset(addr, &p),
+                None => {}
+            }
+        }
+        accepted
+    }
+
+    /// Handles an event on a connection's stream.
+    pub fn stream_ready(&mut self, id: usize) {
+        self.pump(id);
+    }
+
+    /// Processes connection timeouts, should be called every TICK_MS.
+    pub fn tick(&mut self) {
+        let ids: Vec<_> = self.conns.keys().cloned().collect();
+        for id in ids {
+            if let Some(e) = self.conns.get_mut(&id) {
+                e.conn.tick();
+            }
+            self.pump(id);
+        }
+    }
+
+    fn accept(&mut self, addr: SocketAddr, syn: &Packet) -> io::Result<Socket> {
+        let (conn, remote) = UnixStream::pair()?;
+        conn.set_nonblocking(true)?;
+        self.add_conn(addr, Conn::accept(syn), remote)?;
+        Ok(Socket::incoming(Stream::Utp(conn), addr))
+    }
+
+    fn add_conn(&mut self, addr: SocketAddr, conn: Conn, stream: UnixStream) -> io::Result<usize> {
+        stream.set_nonblocking(true)?;
+        let id = self.reg.register(&stream, amy::Event::Both)?;
+        self.ids.insert((addr, conn.recv_id()), id);
+        self.conns.insert(
+            id,
+            Entry {
+                conn,
+                addr,
+                stream,
+                eof: false,
+            },
+        );
+        self.pump(id);
+        Ok(id)
+    }
+
+    /// Moves data between a connection and its stream, sends any
+    /// resulting packets, and removes the connection once it's finished.
+    fn pump(&mut self, id: usize) {
+        let done = {
+            let e = match self.conns.get_mut(&id) {
+                Some(e) => e,
+                None => return,
+            };
+            let mut failed = false;
+            while !e.conn.readable().is_empty() {
+                match e.stream.write(e.conn.readable()) {
+                    Ok(amnt) => e.conn.consume(amnt),
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            let mut buf = [0u8; 16384];
+            while !e.eof && !failed && e.conn.connected() && e.conn.send_capacity() > 0 {
+                let max = cmp::min(buf.len(), e.conn.send_capacity());
+                match e.stream.read(&mut buf[..max]) {
+                    Ok(0) => {
+                        e.eof = true;
+                        e.conn.close();
+                    }
+                    Ok(amnt) => {
+                        e.conn.write(&buf[..amnt]);
+                    }
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => failed = true,
+                }
+            }
+            for pkt in e.conn.packets() {
+                // Lost packets are handled by retransmission
+                self.sock.send_to(&pkt, udp_addr(&self.sock, e.addr)).ok();
+            }
+            failed || (e.conn.closed() && (e.eof || e.conn.readable().is_empty()))
+        };
+        if done {
+            self.remove(id);
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        if let Some(e) = self.conns.remove(&id) {
+            self.ids.remove(&(e.addr, e.conn.recv_id()));
+            self.reg.deregister(&e.stream).ok();
+        }
+    }
+
+    fn reset(&self, addr: SocketAddr, p: &Packet) {
+        let mut r = Packet::new(Type::Reset, p.conn_id);
+        r.ack = p.seq;
+        self.sock.send_to(&r.encode(), udp_addr(&self.sock, addr)).ok();
+    }
+}
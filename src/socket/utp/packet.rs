@@ -0,0 +1,143 @@
+use byteorder::{BigEndian, ByteOrder};
+
+/// Size of the fixed uTP header
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const EXT_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub ty: Type,
+    pub conn_id: u16,
+    pub ts: u32,
+    pub ts_diff: u32,
+    pub wnd: u32,
+    pub seq: u16,
+    pub ack: u16,
+    /// Selective ack bitmask, where bit i acks ack + 2 + i
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Type {
+    fn from_u8(t: u8) -> Option<Type> {
+        match t {
+            0 => Some(Type::Data),
+            1 => Some(Type::Fin),
+            2 => Some(Type::State),
+            3 => Some(Type::Reset),
+            4 => Some(Type::Syn),
+            _ => None,
+        }
+    }
+}
+
+impl Packet {
+    pub fn new(ty: Type, conn_id: u16) -> Packet {
+        Packet {
+            ty,
+            conn_id,
+            ts: 0,
+            ts_diff: 0,
+            wnd: 0,
+            seq: 0,
+            ack: 0,
+            sack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Packet> {
+        if data.len() < HEADER_LEN || data[0] & 0x0F != VERSION {
+            return None;
+        }
+        let ty = match Type::from_u8(data[0] >> 4) {
+            Some(t) => t,
+            None => return None,
+        };
+        let mut p = Packet {
+            ty,
+            conn_id: BigEndian::read_u16(&data[2..4]),
+            ts: BigEndian::read_u32(&data[4..8]),
+            ts_diff: BigEndian::read_u32(&data[8..12]),
+            wnd: BigEndian::read_u32(&data[12..16]),
+            seq: BigEndian::read_u16(&data[16..18]),
+            ack: BigEndian::read_u16(&data[18..20]),
+            sack: None,
+            payload: Vec::new(),
+        };
+        let mut ext = data[1];
+        let mut pos = HEADER_LEN;
+        while ext != 0 {
+            if data.len() < pos + 2 {
+                return None;
+            }
+            let next = data[pos];
+            let len = data[pos + 1] as usize;
+            pos += 2;
+            if data.len() < pos + len {
+                return None;
+            }
+            if ext == EXT_SACK {
+                p.sack = Some(data[pos..pos + len].to_vec());
+            }
+            pos += len;
+            ext = next;
+        }
+        p.payload = data[pos..].to_vec();
+        Some(p)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let sack_len = self.sack.as_ref().map(|s| s.len() + 2).unwrap_or(0);
+        let mut buf = vec![0u8; HEADER_LEN + sack_len];
+        buf[0] = (self.ty as u8) << 4 | VERSION;
+        BigEndian::write_u16(&mut buf[2..4], self.conn_id);
+        BigEndian::write_u32(&mut buf[4..8], self.ts);
+        BigEndian::write_u32(&mut buf[8..12], self.ts_diff);
+        BigEndian::write_u32(&mut buf[12..16], self.wnd);
+        BigEndian::write_u16(&mut buf[16..18], self.seq);
+        BigEndian::write_u16(&mut buf[18..20], self.ack);
+        if let Some(ref sack) = self.sack {
+            buf[1] = EXT_SACK;
+            buf[HEADER_LEN + 1] = sack.len() as u8;
+            buf[HEADER_LEN + 2..].copy_from_slice(sack);
+        }
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Packet, Type};
+
+    #[test]
+    fn test_encode_decode() {
+        let mut p = Packet::new(Type::Data, 1234);
+        p.ts = 100;
+        p.ts_diff = 20;
+        p.wnd = 1 << 20;
+        p.seq = 65535;
+        p.ack = 7;
+        p.sack = Some(vec![0b101, 0, 0, 0]);
+        p.payload = b"hello".to_vec();
+        let data = p.encode();
+        assert_eq!(data[0], 0x01);
+        assert_eq!(Packet::decode(&data).unwrap(), p);
+
+        let s = Packet::new(Type::Syn, 1);
+        assert_eq!(Packet::decode(&s.encode()).unwrap(), s);
+        assert!(Packet::decode(&[0x51; 20]).is_none());
+        assert!(Packet::decode(&data[..10]).is_none());
+    }
+}
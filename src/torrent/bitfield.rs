@@ -40,6 +40,9 @@ impl Bitfield {
     }
 
     pub fn complete(&self) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
         for i in 0..self.data.len() - 1 {
             if !(self.data[i]) != 0 {
                 return false;
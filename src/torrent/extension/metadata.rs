@@ -0,0 +1,256 @@
+//! Implementation of the ut_metadata extension(BEP 9), used to fetch
+//! the info dictionary of torrents added via magnet links.
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use bencode::{self, BEncode};
+
+/// The extended message id we advertise for ut_metadata
+pub const UT_METADATA_ID: u8 = 1;
+/// Size of a metadata piece, all but the last must be this length
+pub const METADATA_PIECE_LEN: usize = 16384;
+/// Largest info dictionary we're willing to download
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// Time after which a requested piece may be requested from another peer
+const REQUEST_TIMEOUT_SECS: u64 = 15;
+
+#[derive(Debug, PartialEq)]
+pub enum Msg {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(u32),
+}
+
+/// Download state of a torrent's metadata.
+pub struct Fetcher {
+    data: Vec<u8>,
+    pieces: Vec<PieceStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PieceStatus {
+    Missing,
+    Requested { peer: usize, at: Instant },
+    Received,
+}
+
+impl Msg {
+    pub fn decode(payload: &[u8]) -> Option<Msg> {
+        let mut c = Cursor::new(payload);
+        let mut d = match bencode::decode(&mut c).ok().and_then(|b| b.to_dict()) {
+            Some(d) => d,
+            None => return None,
+        };
+        let piece = match d.remove("piece").and_then(|p| p.to_int()) {
+            Some(p) => p as u32,
+            None => return None,
+        };
+        match d.remove("msg_type").and_then(|t| t.to_int()) {
+            Some(0) => Some(Msg::Request(piece)),
+            Some(1) => {
+                let pos = c.position() as usize;
+                d.remove("total_size").and_then(|t| t.to_int()).map(|total_size| {
+                    Msg::Data {
+                        piece,
+                        total_size: total_size as usize,
+                        data: payload[pos..].to_vec(),
+                    }
+                })
+            }
+            Some(2) => Some(Msg::Reject(piece)),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        let (msg_type, piece) = match *self {
+            Msg::Request(p) => (0, p),
+            Msg::Data { piece, total_size, .. } => {
+                d.insert("total_size".to_owned(), BEncode::Int(total_size as i64));
+                (1, piece)
+            }
+            Msg::Reject(p) => (2, p),
+        };
+        d.insert("msg_type".to_owned(), BEncode::Int(msg_type));
+        d.insert("piece".to_owned(), BEncode::Int(piece as i64));
+        let mut buf = BEncode::Dict(d).encode_to_buf();
+        if let Msg::Data { ref data, .. } = *self {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    /// Creates a data message for the given piece of metadata,
+    /// or a reject if the piece doesn't exist.
+    pub fn data(metadata: &[u8], piece: u32) -> Msg {
+        let start = piece as usize * METADATA_PIECE_LEN;
+        if start >= metadata.len() {
+            return Msg::Reject(piece);
+        }
+        let end = ::std::cmp::min(start + METADATA_PIECE_LEN, metadata.len());
+        Msg::Data {
+            piece,
+            total_size: metadata.len(),
+            data: metadata[start..end].to_vec(),
+        }
+    }
+}
+
+impl Fetcher {
+    /// Creates a new fetcher for metadata of the given size,
+    /// returning None if the size is unreasonable.
+    pub fn new(size: usize) -> Option<Fetcher> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return None;
+        }
+        let num_pieces = (size + METADATA_PIECE_LEN - 1) / METADATA_PIECE_LEN;
+        Some(Fetcher {
+            data: vec![0u8; size],
+            pieces: vec![PieceStatus::Missing; num_pieces],
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Selects the next piece which should be requested from a peer.
+    /// Pieces whose requests have timed out will be rerequested.
+    pub fn pick(&mut self, peer: usize) -> Option<u32> {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        match self.pieces.iter().position(|p| match *p {
+            PieceStatus::Missing => true,
+            PieceStatus::Requested { at, .. } => at.elapsed() > timeout,
+            PieceStatus::Received => false,
+        }) {
+            Some(idx) => {
+                self.pieces[idx] = PieceStatus::Requested {
+                    peer,
+                    at: Instant::now(),
+                };
+                Some(idx as u32)
+            }
+            None => None,
+        }
+    }
+
+    /// Whether or not the peer has an outstanding request.
+    pub fn requested_from(&self, peer: usize) -> bool {
+        self.pieces.iter().any(|p| match *p {
+            PieceStatus::Requested { peer: p, .. } => p == peer,
+            _ => false,
+        })
+    }
+
+    /// Stores a received piece, returning an error if it was invalid.
+    pub fn received(&mut self, piece: u32, data: &[u8]) -> Result<(), ()> {
+        let idx = piece as usize;
+        if idx >= self.pieces.len() {
+            return Err(());
+        }
+        let start = idx * METADATA_PIECE_LEN;
+        let len = ::std::cmp::min(METADATA_PIECE_LEN, self.data.len() - start);
+        if data.len() != len {
+            return Err(());
+        }
+        self.data[start..start + len].copy_from_slice(data);
+        self.pieces[idx] = PieceStatus::Received;
+        Ok(())
+    }
+
+    pub fn rejected(&mut self, piece: u32) {
+        if let Some(p) = self.pieces.get_mut(piece as usize) {
+            if *p != PieceStatus::Received {
+                *p = PieceStatus::Missing;
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: usize) {
+        for p in self.pieces.iter_mut() {
+            let requested = match *p {
+                PieceStatus::Requested { peer: id, .. } => id == peer,
+                _ => false,
+            };
+            if requested {
+                *p = PieceStatus::Missing;
+            }
+        }
+    }
+
+    pub fn complete(&self) -> bool {
+        self.pieces.iter().all(|p| *p == PieceStatus::Received)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Discards all received data, used if the metadata failed validation.
+    pub fn reset(&mut self) {
+        for p in self.pieces.iter_mut() {
+            *p = PieceStatus::Missing;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Msg, Fetcher, METADATA_PIECE_LEN};
+
+    #[test]
+    fn test_encode_decode() {
+        let r = Msg::Request(2);
+        assert_eq!(Msg::decode(&r.encode()).unwrap(), r);
+        let d = Msg::Data {
+            piece: 1,
+            total_size: 20000,
+            data: vec![1u8; 3616],
+        };
+        assert_eq!(Msg::decode(&d.encode()).unwrap(), d);
+        let rj = Msg::Reject(0);
+        assert_eq!(Msg::decode(&rj.encode()).unwrap(), rj);
+        assert!(Msg::decode(b"d8:msg_typei5e5:piecei0ee").is_none());
+    }
+
+    #[test]
+    fn test_data() {
+        let md = vec![5u8; METADATA_PIECE_LEN + 10];
+        match Msg::data(&md, 1) {
+            Msg::Data { data, total_size, .. } => {
+                assert_eq!(data.len(), 10);
+                assert_eq!(total_size, METADATA_PIECE_LEN + 10);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(Msg::data(&md, 2), Msg::Reject(2));
+    }
+
+    #[test]
+    fn test_fetcher() {
+        assert!(Fetcher::new(0).is_none());
+        let mut f = Fetcher::new(METADATA_PIECE_LEN + 10).unwrap();
+        assert_eq!(f.pick(0), Some(0));
+        assert_eq!(f.pick(1), Some(1));
+        assert_eq!(f.pick(2), None);
+        assert!(f.requested_from(1));
+        f.remove_peer(1);
+        assert!(!f.requested_from(1));
+        assert_eq!(f.pick(2), Some(1));
+        assert!(f.received(1, &[0u8; 11]).is_err());
+        f.received(1, &[1u8; 10]).unwrap();
+        f.rejected(0);
+        assert!(!f.complete());
+        assert_eq!(f.pick(2), Some(0));
+        f.received(0, &[0u8; METADATA_PIECE_LEN]).unwrap();
+        assert!(f.complete());
+        assert_eq!(f.data()[METADATA_PIECE_LEN], 1);
+    }
+}
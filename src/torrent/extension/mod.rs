@@ -0,0 +1,195 @@
+//! Extension protocol(BEP 10) support. Every extension synapse
+//! understands is registered in `EXTENSIONS`, along with the
+//! extended message id peers should use when sending it to us.
+
+pub mod metadata;
+pub mod pex;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use bencode::{self, BEncode};
+use CONFIG;
+
+/// Extended message id reserved for the extension handshake
+pub const HANDSHAKE_ID: u8 = 0;
+/// Number of outstanding requests we allow peers to queue
+pub const REQQ: u32 = 250;
+/// Client name and version sent in the handshake
+const CLIENT: &'static str = concat!("Synapse ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Extension {
+    Metadata,
+    Pex,
+}
+
+/// All extensions which are advertised to peers
+pub const EXTENSIONS: &'static [Extension] = &[Extension::Metadata, Extension::Pex];
+
+impl Extension {
+    /// Name of the extension, as used in the handshake's m dictionary
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Extension::Metadata => "ut_metadata",
+            Extension::Pex => "ut_pex",
+        }
+    }
+
+    /// Id which peers use to send us messages of this extension
+    pub fn id(&self) -> u8 {
+        match *self {
+            Extension::Metadata => metadata::UT_METADATA_ID,
+            Extension::Pex => pex::UT_PEX_ID,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Extension> {
+        EXTENSIONS.iter().find(|e| e.id() == id).cloned()
+    }
+
+    pub fn from_name(name: &str) -> Option<Extension> {
+        EXTENSIONS.iter().find(|e| e.name() == name).cloned()
+    }
+}
+
+/// The extension handshake, containing the supported extensions
+/// and miscellaneous information about the client.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Handshake {
+    /// Extensions supported by the client, mapped to their message ids
+    pub ids: HashMap<Extension, u8>,
+    /// Client name and version
+    pub client: Option<String>,
+    /// Local TCP listen port
+    pub port: Option<u16>,
+    /// Number of outstanding requests the client allows
+    pub reqq: Option<u32>,
+    /// Our IP address as seen by the client
+    pub yourip: Option<IpAddr>,
+    /// Size of the torrent's info dictionary, if known(BEP 9)
+    pub metadata_size: Option<usize>,
+}
+
+impl Handshake {
+    /// Creates the handshake synapse sends to peers.
+    pub fn local() -> Handshake {
+        Handshake {
+            ids: EXTENSIONS.iter().map(|e| (*e, e.id())).collect(),
+            client: Some(CLIENT.to_owned()),
+            port: Some(CONFIG.port),
+            reqq: Some(REQQ),
+            yourip: None,
+            metadata_size: None,
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Handshake> {
+        let mut d = match bencode::decode_buf(payload).ok().and_then(|b| b.to_dict()) {
+            Some(d) => d,
+            None => return None,
+        };
+        let mut hs = Handshake::default();
+        if let Some(m) = d.remove("m").and_then(|m| m.to_dict()) {
+            for (name, id) in m {
+                let ext = Extension::from_name(&name);
+                let id = id.to_int();
+                match (ext, id) {
+                    // An id of 0 indicates that the extension is disabled
+                    (Some(e), Some(i)) if i > 0 && i <= 255 => {
+                        hs.ids.insert(e, i as u8);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        hs.client = d.remove("v").and_then(|v| v.to_string());
+        hs.port = match d.remove("p").and_then(|p| p.to_int()) {
+            Some(p) if p > 0 && p <= 65535 => Some(p as u16),
+            _ => None,
+        };
+        hs.reqq = match d.remove("reqq").and_then(|r| r.to_int()) {
+            Some(r) if r > 0 => Some(r as u32),
+            _ => None,
+        };
+        hs.yourip = d.remove("yourip").and_then(|i| i.to_bytes()).and_then(
+            |b| decode_ip(&b),
+        );
+        hs.metadata_size = match d.remove("metadata_size").and_then(|s| s.to_int()) {
+            Some(s) if s > 0 => Some(s as usize),
+            _ => None,
+        };
+        Some(hs)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut m = BTreeMap::new();
+        for (ext, id) in self.ids.iter() {
+            m.insert(ext.name().to_owned(), BEncode::Int(*id as i64));
+        }
+        let mut d = BTreeMap::new();
+        d.insert("m".to_owned(), BEncode::Dict(m));
+        if let Some(ref v) = self.client {
+            d.insert("v".to_owned(), BEncode::from_str(v));
+        }
+        if let Some(p) = self.port {
+            d.insert("p".to_owned(), BEncode::Int(p as i64));
+        }
+        if let Some(r) = self.reqq {
+            d.insert("reqq".to_owned(), BEncode::Int(r as i64));
+        }
+        if let Some(ip) = self.yourip {
+            d.insert("yourip".to_owned(), BEncode::String(encode_ip(&ip)));
+        }
+        if let Some(s) = self.metadata_size {
+            d.insert("metadata_size".to_owned(), BEncode::Int(s as i64));
+        }
+        BEncode::Dict(d).encode_to_buf()
+    }
+}
+
+fn encode_ip(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ref i) => i.octets().to_vec(),
+        IpAddr::V6(ref i) => i.octets().to_vec(),
+    }
+}
+
+fn decode_ip(b: &[u8]) -> Option<IpAddr> {
+    if b.len() == 4 {
+        let mut o = [0u8; 4];
+        o.copy_from_slice(b);
+        Some(IpAddr::from(o))
+    } else if b.len() == 16 {
+        let mut o = [0u8; 16];
+        o.copy_from_slice(b);
+        Some(IpAddr::from(o))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, Extension, metadata};
+
+    #[test]
+    fn test_handshake() {
+        let mut hs = Handshake::local();
+        hs.yourip = Some("127.0.0.1".parse().unwrap());
+        hs.metadata_size = Some(1000);
+        assert_eq!(Handshake::decode(&hs.encode()).unwrap(), hs);
+        assert_eq!(hs.ids.get(&Extension::Metadata), Some(&metadata::UT_METADATA_ID));
+    }
+
+    #[test]
+    fn test_handshake_decode() {
+        let hs = Handshake::decode(b"d1:md11:ut_metadatai3e6:ut_fooi2e6:ut_pexi0ee1:pi0e4:reqqi-1ee")
+            .unwrap();
+        assert_eq!(hs.ids.len(), 1);
+        assert_eq!(hs.ids.get(&Extension::Metadata), Some(&3));
+        assert_eq!(hs.port, None);
+        assert_eq!(hs.reqq, None);
+        assert!(Handshake::decode(b"li1ee").is_none());
+    }
+}
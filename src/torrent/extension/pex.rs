@@ -0,0 +1,174 @@
+//! Implementation of the peer exchange extension(ut_pex, BEP 11),
+//! used to discover peers from the peers we're already connected to.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bencode::{self, BEncode};
+use util::{addr_to_bytes, bytes_to_addr};
+
+/// The extended message id we advertise for ut_pex
+pub const UT_PEX_ID: u8 = 2;
+/// Maximum amount of added or dropped peers in a single message
+pub const MAX_PEERS: usize = 50;
+/// Minimum time between PEX messages sent to or accepted from a peer
+pub const PEX_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Msg {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+/// Per peer PEX state, tracking which peers we've advertised
+/// and when the peer last sent us a message.
+pub struct State {
+    sent: HashSet<SocketAddr>,
+    last_recv: Option<Instant>,
+}
+
+impl Msg {
+    pub fn decode(payload: &[u8]) -> Option<Msg> {
+        let mut d = match bencode::decode_buf(payload).ok().and_then(|b| b.to_dict()) {
+            Some(d) => d,
+            None => return None,
+        };
+        let mut added = decode_peers(&mut d, "added", 6);
+        added.extend(decode_peers(&mut d, "added6", 18));
+        let mut dropped = decode_peers(&mut d, "dropped", 6);
+        dropped.extend(decode_peers(&mut d, "dropped6", 18));
+        Some(Msg { added, dropped })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        let (added, added6) = encode_peers(&self.added);
+        let (dropped, dropped6) = encode_peers(&self.dropped);
+        // We don't track any of the flags, so leave them empty
+        let flags = vec![0u8; added.len() / 6];
+        let flags6 = vec![0u8; added6.len() / 18];
+        d.insert("added".to_owned(), BEncode::String(added));
+        d.insert("added.f".to_owned(), BEncode::String(flags));
+        d.insert("dropped".to_owned(), BEncode::String(dropped));
+        if !added6.is_empty() || !dropped6.is_empty() {
+            d.insert("added6".to_owned(), BEncode::String(added6));
+            d.insert("added6.f".to_owned(), BEncode::String(flags6));
+            d.insert("dropped6".to_owned(), BEncode::String(dropped6));
+        }
+        BEncode::Dict(d).encode_to_buf()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            sent: HashSet::new(),
+            last_recv: None,
+        }
+    }
+
+    /// Produces a message containing the changes in the connected
+    /// peer set since the last message, if there are any.
+    pub fn update(&mut self, peers: &HashSet<SocketAddr>) -> Option<Msg> {
+        let added: Vec<_> = peers
+            .difference(&self.sent)
+            .take(MAX_PEERS)
+            .cloned()
+            .collect();
+        let dropped: Vec<_> = self.sent
+            .difference(peers)
+            .take(MAX_PEERS)
+            .cloned()
+            .collect();
+        for a in &added {
+            self.sent.insert(*a);
+        }
+        for d in &dropped {
+            self.sent.remove(d);
+        }
+        let msg = Msg { added, dropped };
+        if msg.is_empty() { None } else { Some(msg) }
+    }
+
+    /// Checks whether or not a received message should be processed,
+    /// peers which send messages too frequently are ignored.
+    pub fn recv_allowed(&mut self) -> bool {
+        // Allow a bit of leeway for timing differences
+        let min = Duration::from_secs(PEX_INTERVAL_SECS / 2);
+        match self.last_recv {
+            Some(t) if t.elapsed() < min => false,
+            _ => {
+                self.last_recv = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+fn decode_peers(d: &mut BTreeMap<String, BEncode>, key: &str, len: usize) -> Vec<SocketAddr> {
+    let data = d.remove(key).and_then(|b| b.to_bytes()).unwrap_or_default();
+    data.chunks(len)
+        .filter(|p| p.len() == len)
+        .take(MAX_PEERS)
+        .map(bytes_to_addr)
+        .collect()
+}
+
+/// Encodes peers in compact form, split into IPv4 and IPv6 peers.
+fn encode_peers(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut data = Vec::with_capacity(peers.len() * 6);
+    let mut data6 = Vec::new();
+    for p in peers {
+        if p.is_ipv4() {
+            data.extend_from_slice(&addr_to_bytes(p));
+        } else {
+            data6.extend_from_slice(&addr_to_bytes(p));
+        }
+    }
+    (data, data6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Msg, State, MAX_PEERS};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_encode_decode() {
+        let m = Msg {
+            added: vec!["127.0.0.1:6881".parse().unwrap(), "10.0.0.1:80".parse().unwrap()],
+            dropped: vec!["192.168.1.1:1000".parse().unwrap()],
+        };
+        assert_eq!(Msg::decode(&m.encode()).unwrap(), m);
+        let m = Msg {
+            added: vec!["10.0.0.1:80".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap()],
+            dropped: vec!["[2001:db8::2]:6881".parse().unwrap()],
+        };
+        assert_eq!(Msg::decode(&m.encode()).unwrap(), m);
+        assert_eq!(Msg::decode(b"de").unwrap(), Msg::default());
+        assert!(Msg::decode(b"le").is_none());
+    }
+
+    #[test]
+    fn test_state() {
+        let mut s = State::new();
+        let mut peers: HashSet<SocketAddr> = (0..MAX_PEERS + 10)
+            .map(|i| SocketAddr::from(([10, 0, 0, 1], i as u16 + 1)))
+            .collect();
+        assert_eq!(s.update(&peers).unwrap().added.len(), MAX_PEERS);
+        assert_eq!(s.update(&peers).unwrap().added.len(), 10);
+        assert!(s.update(&peers).is_none());
+        let a = "10.0.0.1:1".parse().unwrap();
+        peers.remove(&a);
+        assert_eq!(s.update(&peers).unwrap().dropped, vec![a]);
+
+        assert!(s.recv_allowed());
+        assert!(!s.recv_allowed());
+    }
+}
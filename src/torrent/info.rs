@@ -1,10 +1,13 @@
-use bencode::BEncode;
-use std::path::PathBuf;
+use bencode::{self, BEncode};
+use std::path::{Path, PathBuf};
 use std::collections::BTreeMap;
-use std::{fmt, cmp};
+use std::io::{self, Read};
+use std::{fmt, fs, cmp, ops};
+use chrono::Utc;
 use url::Url;
 use ring::digest;
-use util::hash_to_id;
+use util::{hash_to_id, id_to_hash, io_err, io_err_val, sha1_hash};
+use base32;
 use disk;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -17,8 +20,36 @@ pub struct Info {
     pub hash: [u8; 20],
     pub files: Vec<File>,
     pub private: bool,
+    /// Tiers of trackers from the announce-list(BEP 12)
+    pub announce_list: Vec<Vec<String>>,
+    /// Web seed URLs from the url-list(BEP 19)
+    pub url_list: Vec<String>,
+    pub comment: Option<String>,
+    pub creator: Option<String>,
+    pub creation_date: Option<i64>,
+    /// Encoded info dictionary the infohash was computed from,
+    /// empty if the metadata isn't known
+    pub raw_info: Vec<u8>,
 }
 
+/// Options used to create a torrent from local data
+#[derive(Clone, Debug)]
+pub struct CreateOpts {
+    /// File or directory to create the torrent from
+    pub path: PathBuf,
+    /// Piece length, chosen based on the size of the data if not given
+    pub piece_len: Option<u32>,
+    pub trackers: Vec<String>,
+    pub webseeds: Vec<String>,
+    pub private: bool,
+    pub comment: Option<String>,
+}
+
+const MIN_PIECE_LEN: u32 = 16384;
+const MAX_PIECE_LEN: u32 = 16 * 1024 * 1024;
+/// Number of pieces automatically chosen piece lengths aim for
+const TARGET_PIECES: u64 = 1500;
+
 impl fmt::Debug for Info {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -77,82 +108,427 @@ impl Info {
         if url.scheme() != "magnet" {
             return Err("magnet URL must use magnet scheme");
         };
-        // TODO: Actually implmeent this
-        unimplemented!();
+
+        let mut hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut url_list = Vec::new();
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "xt" => {
+                    if !v.starts_with("urn:btih:") {
+                        continue;
+                    }
+                    hash = Some(parse_btih(&v[9..]).ok_or("Invalid infohash provided in xt")?);
+                }
+                "dn" => name = Some(v.into_owned()),
+                "tr" => trackers.push(v.into_owned()),
+                "ws" => url_list.push(v.into_owned()),
+                _ => {}
+            }
+        }
+
+        let hash = hash.ok_or("magnet URL must contain a urn:btih xt field")?;
+        // Each tracker of a magnet is tried in turn
+        let announce_list = if trackers.len() > 1 {
+            trackers.iter().map(|t| vec![t.clone()]).collect()
+        } else {
+            vec![]
+        };
+        Ok(Info {
+            name: name.unwrap_or_else(|| hash_to_id(&hash)),
+            announce: trackers.into_iter().next().unwrap_or_else(String::new),
+            piece_len: 0,
+            total_len: 0,
+            hashes: vec![],
+            hash,
+            files: vec![],
+            private: false,
+            announce_list,
+            url_list,
+            comment: None,
+            creator: None,
+            creation_date: None,
+            raw_info: vec![],
+        })
     }
 
     pub fn from_bencode(data: BEncode) -> Result<Info, &'static str> {
-        data.to_dict()
-            .and_then(|mut d| {
-                d.remove("info").and_then(|i| i.to_dict()).map(|i| (d, i))
+        let mut d = data.to_dict().ok_or("invalid info field")?;
+        let i = d.remove("info").and_then(|i| i.to_dict()).ok_or(
+            "invalid info field",
+        )?;
+        let announce_list: Vec<Vec<String>> = d.remove("announce-list")
+            .and_then(|l| l.to_list())
+            .map(|tiers| {
+                tiers
+                    .into_iter()
+                    .filter_map(|t| t.to_list())
+                    .map(|t| t.into_iter().filter_map(|u| u.to_string()).collect())
+                    .filter(|t: &Vec<String>| !t.is_empty())
+                    .collect()
             })
-            .ok_or("invalid info field")
-            .and_then(|(mut d, mut i)| {
-                let mut info_bytes = Vec::new();
-                BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
-                let mut ctx = digest::Context::new(&digest::SHA1);
-                ctx.update(&info_bytes[..]);
-                let digest = ctx.finish();
-                let mut hash = [0u8; 20];
-                hash.copy_from_slice(digest.as_ref());
-
-                let a = d.remove("announce").and_then(|a| a.to_string()).ok_or(
-                    "Info must have announce url",
-                )?;
-                let pl = i.remove("piece length").and_then(|i| i.to_int()).ok_or(
-                    "Info must specify piece length",
-                )?;
-                let hashes = i.remove("pieces")
-                    .and_then(|p| p.to_bytes())
-                    .map(|mut p| {
-                        let mut v = Vec::new();
-                        while !p.is_empty() {
-                            let remaining = p.split_off(20);
-                            v.push(p);
-                            p = remaining;
-                        }
-                        v
-                    })
-                    .ok_or("Info must provide valid hashes")?;
-
-                let private = i.remove("private")
-                    .and_then(|v| v.to_int())
-                    .map(|p| p == 1)
-                    .unwrap_or(false);
-
-                let files = parse_bencode_files(i)?;
-                let name = if files.is_empty() {
-                    files[0]
-                        .path
-                        .clone()
-                        .into_os_string()
-                        .into_string()
-                        .unwrap()
-                } else if !files[0].path.has_root() {
-                    let mut piter = files[0].path.components();
-                    piter
-                        .next()
-                        .unwrap()
-                        .as_os_str()
-                        .to_os_string()
-                        .into_string()
-                        .unwrap()
-                } else {
-                    unreachable!();
-                };
+            .unwrap_or_default();
+        // Trackerless torrents may only have an announce-list, or neither
+        let a = d.remove("announce")
+            .and_then(|a| a.to_string())
+            .or_else(|| announce_list.iter().flat_map(|t| t.iter()).next().cloned())
+            .unwrap_or_default();
+        let mut info = Info::from_info_dict(i, a)?;
+        info.announce_list = announce_list;
+        info.url_list = match d.remove("url-list") {
+            Some(BEncode::List(l)) => l.into_iter().filter_map(|u| u.to_string()).collect(),
+            Some(u) => u.to_string().into_iter().collect(),
+            None => vec![],
+        };
+        info.comment = d.remove("comment").and_then(|c| c.to_string());
+        info.creator = d.remove("created by").and_then(|c| c.to_string());
+        info.creation_date = d.remove("creation date").and_then(|c| c.to_int());
+        Ok(info)
+    }
 
-                let total_len = files.iter().map(|f| f.length as u64).sum();
-                Ok(Info {
-                    name,
-                    announce: a,
-                    piece_len: pl as u32,
-                    hashes,
-                    hash,
-                    files,
-                    total_len,
-                    private,
-                })
+    /// Creates a torrent from the file or directory given in the options,
+    /// hashing all of its data.
+    pub fn create(opts: &CreateOpts) -> io::Result<Info> {
+        let name = opts.path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .ok_or_else(|| io_err_val("Path must name a file or directory"))?;
+        let mut paths = Vec::new();
+        if fs::metadata(&opts.path)?.is_dir() {
+            collect_files(&opts.path, &mut paths)?;
+        } else {
+            paths.push(opts.path.clone());
+        }
+        let total_len: u64 = paths
+            .iter()
+            .map(|p| fs::metadata(p).map(|m| m.len()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .sum();
+        if total_len == 0 {
+            return io_err("Torrent must contain some data");
+        }
+        let piece_len = match opts.piece_len {
+            Some(l) if l < MIN_PIECE_LEN || l > MAX_PIECE_LEN || !l.is_power_of_two() => {
+                return io_err("Piece length must be a power of two between 16 KiB and 16 MiB");
+            }
+            Some(l) => l,
+            None => auto_piece_len(total_len),
+        };
+
+        let mut hashes = Vec::new();
+        let mut files = Vec::new();
+        let mut buf = vec![0u8; piece_len as usize];
+        let mut pos = 0;
+        for p in paths.iter() {
+            let mut f = fs::File::open(p)?;
+            // Use the amount actually read, in case the file changed
+            let mut length = 0;
+            loop {
+                let amnt = f.read(&mut buf[pos..])?;
+                if amnt == 0 {
+                    break;
+                }
+                pos += amnt;
+                length += amnt;
+                if pos == buf.len() {
+                    hashes.push(sha1_hash(&buf).as_ref().to_vec());
+                    pos = 0;
+                }
+            }
+            let path = match p.strip_prefix(&opts.path) {
+                Ok(rel) if rel.components().next().is_some() => Path::new(&name).join(rel),
+                _ => PathBuf::from(&name),
+            };
+            files.push(File { path, length });
+        }
+        if pos > 0 {
+            hashes.push(sha1_hash(&buf[..pos]).as_ref().to_vec());
+        }
+
+        let mut info = Info {
+            name,
+            announce: opts.trackers.first().cloned().unwrap_or_else(String::new),
+            piece_len,
+            total_len: files.iter().map(|f| f.length as u64).sum(),
+            hashes,
+            hash: [0u8; 20],
+            files,
+            private: opts.private,
+            announce_list: if opts.trackers.len() > 1 {
+                opts.trackers.iter().map(|t| vec![t.clone()]).collect()
+            } else {
+                vec![]
+            },
+            url_list: opts.webseeds.clone(),
+            comment: opts.comment.clone(),
+            creator: Some(format!("synapse {}", env!("CARGO_PKG_VERSION"))),
+            creation_date: Some(Utc::now().timestamp()),
+            raw_info: vec![],
+        };
+        info.raw_info = info.to_bencode().encode_to_buf();
+        info.hash.copy_from_slice(sha1_hash(&info.raw_info).as_ref());
+        Ok(info)
+    }
+
+    /// Constructs a complete Info from the raw metadata of a magnet link,
+    /// verifying it against the infohash.
+    pub fn with_metadata(&self, data: &[u8]) -> Result<Info, &'static str> {
+        if sha1_hash(data).as_ref() != &self.hash[..] {
+            return Err("Metadata does not match infohash");
+        }
+        let i = bencode::decode_buf(data)
+            .ok()
+            .and_then(|b| b.to_dict())
+            .ok_or("Metadata must be a valid dictionary")?;
+        let mut info = Info::from_info_dict(i, self.announce.clone())?;
+        info.announce_list = self.announce_list.clone();
+        info.url_list = self.url_list.clone();
+        // Keep the data as verified, rather than its reencoding
+        info.hash = self.hash;
+        info.raw_info = data.to_vec();
+        Ok(info)
+    }
+
+    fn from_info_dict(
+        mut i: BTreeMap<String, BEncode>,
+        announce: String,
+    ) -> Result<Info, &'static str> {
+        let mut info_bytes = Vec::new();
+        BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
+        let mut ctx = digest::Context::new(&digest::SHA1);
+        ctx.update(&info_bytes[..]);
+        let digest = ctx.finish();
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(digest.as_ref());
+
+        let pl = i.remove("piece length").and_then(|i| i.to_int()).ok_or(
+            "Info must specify piece length",
+        )?;
+        let hashes = i.remove("pieces")
+            .and_then(|p| p.to_bytes())
+            .map(|mut p| {
+                let mut v = Vec::new();
+                while !p.is_empty() {
+                    let remaining = p.split_off(20);
+                    v.push(p);
+                    p = remaining;
+                }
+                v
             })
+            .ok_or("Info must provide valid hashes")?;
+
+        let private = i.remove("private")
+            .and_then(|v| v.to_int())
+            .map(|p| p == 1)
+            .unwrap_or(false);
+
+        let files = parse_bencode_files(i)?;
+        let name = if files.is_empty() {
+            files[0]
+                .path
+                .clone()
+                .into_os_string()
+                .into_string()
+                .unwrap()
+        } else if !files[0].path.has_root() {
+            let mut piter = files[0].path.components();
+            piter
+                .next()
+                .unwrap()
+                .as_os_str()
+                .to_os_string()
+                .into_string()
+                .unwrap()
+        } else {
+            unreachable!();
+        };
+
+        let total_len = files.iter().map(|f| f.length as u64).sum();
+        Ok(Info {
+            name,
+            announce,
+            piece_len: pl as u32,
+            hashes,
+            hash,
+            files,
+            total_len,
+            private,
+            announce_list: vec![],
+            url_list: vec![],
+            comment: None,
+            creator: None,
+            creation_date: None,
+            raw_info: info_bytes,
+        })
+    }
+
+    /// Reconstructs the info dictionary. This is only guaranteed to
+    /// match the infohash if the original dictionary contained no
+    /// fields which are discarded during parsing.
+    pub fn to_bencode(&self) -> BEncode {
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::from_str(&self.name));
+        info.insert(
+            "piece length".to_owned(),
+            BEncode::Int(self.piece_len as i64),
+        );
+        let mut pieces = Vec::with_capacity(self.hashes.len() * 20);
+        for h in self.hashes.iter() {
+            pieces.extend_from_slice(h);
+        }
+        info.insert("pieces".to_owned(), BEncode::String(pieces));
+        if self.private {
+            info.insert("private".to_owned(), BEncode::Int(1));
+        }
+        if self.files.len() == 1 && self.files[0].path == Path::new(&self.name) {
+            info.insert(
+                "length".to_owned(),
+                BEncode::Int(self.files[0].length as i64),
+            );
+        } else {
+            let files = self.files
+                .iter()
+                .map(|f| {
+                    let mut fd = BTreeMap::new();
+                    let path = f.path
+                        .strip_prefix(&self.name)
+                        .unwrap_or(&f.path)
+                        .components()
+                        .map(|c| BEncode::from_str(&c.as_os_str().to_string_lossy()))
+                        .collect();
+                    fd.insert("length".to_owned(), BEncode::Int(f.length as i64));
+                    fd.insert("path".to_owned(), BEncode::List(path));
+                    BEncode::Dict(fd)
+                })
+                .collect();
+            info.insert("files".to_owned(), BEncode::List(files));
+        }
+        BEncode::Dict(info)
+    }
+
+    /// Encodes the complete metainfo, as would be found in a .torrent file,
+    /// with the info dictionary unchanged so it matches the infohash.
+    /// Returns None if the info dictionary isn't known.
+    pub fn to_torrent(&self) -> Option<Vec<u8>> {
+        let mut info = self.metadata();
+        if info.is_none() {
+            return None;
+        }
+        let mut data = vec![b'd'];
+        for (k, v) in self.torrent_fields() {
+            // Dictionary keys are sorted, so the info dict is spliced in order
+            if k.as_str() > "info" {
+                if let Some(i) = info.take() {
+                    BEncode::from_str("info").encode(&mut data).unwrap();
+                    data.extend_from_slice(&i);
+                }
+            }
+            BEncode::from_str(&k).encode(&mut data).unwrap();
+            v.encode(&mut data).unwrap();
+        }
+        if let Some(i) = info.take() {
+            BEncode::from_str("info").encode(&mut data).unwrap();
+            data.extend_from_slice(&i);
+        }
+        data.push(b'e');
+        Some(data)
+    }
+
+    /// Returns the fields of the metainfo other than the info dictionary.
+    fn torrent_fields(&self) -> BTreeMap<String, BEncode> {
+        let mut t = BTreeMap::new();
+        // Trackerless torrents, e.g. magnets without trackers, omit announce
+        if !self.announce.is_empty() {
+            t.insert("announce".to_owned(), BEncode::from_str(&self.announce));
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self.announce_list
+                .iter()
+                .map(|tier| {
+                    BEncode::List(tier.iter().map(|u| BEncode::from_str(u)).collect())
+                })
+                .collect();
+            t.insert("announce-list".to_owned(), BEncode::List(tiers));
+        }
+        if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(|u| BEncode::from_str(u)).collect();
+            t.insert("url-list".to_owned(), BEncode::List(urls));
+        }
+        if let Some(ref c) = self.comment {
+            t.insert("comment".to_owned(), BEncode::from_str(c));
+        }
+        if let Some(ref c) = self.creator {
+            t.insert("created by".to_owned(), BEncode::from_str(c));
+        }
+        if let Some(d) = self.creation_date {
+            t.insert("creation date".to_owned(), BEncode::Int(d));
+        }
+        t
+    }
+
+    /// Returns the encoded info dictionary, if it can be reconstructed
+    /// such that it matches the infohash.
+    pub fn metadata(&self) -> Option<Vec<u8>> {
+        if !self.complete() {
+            return None;
+        }
+        if !self.raw_info.is_empty() {
+            return Some(self.raw_info.clone());
+        }
+        // Sessions saved before the raw info dictionary was kept
+        // can only be exported if it's reproducible
+        let data = self.to_bencode().encode_to_buf();
+        if sha1_hash(&data).as_ref() == &self.hash[..] {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the tiers of trackers to announce to. As per BEP 12
+    /// the announce-list takes precedence over announce if present.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        if !self.announce_list.is_empty() {
+            self.announce_list.clone()
+        } else if !self.announce.is_empty() {
+            vec![vec![self.announce.clone()]]
+        } else {
+            vec![]
+        }
+    }
+
+    /// Adds a tracker in a new, lowest priority tier.
+    pub fn add_tracker(&mut self, url: String) {
+        let mut tiers = self.tiers();
+        if self.announce.is_empty() {
+            self.announce = url.clone();
+        }
+        tiers.push(vec![url]);
+        self.announce_list = tiers;
+    }
+
+    /// Removes a tracker from every tier it's in.
+    pub fn remove_tracker(&mut self, url: &str) {
+        for tier in self.announce_list.iter_mut() {
+            tier.retain(|u| u != url);
+        }
+        self.announce_list.retain(|tier| !tier.is_empty());
+        if self.announce == url {
+            self.announce = self.announce_list
+                .iter()
+                .flat_map(|t| t.iter())
+                .next()
+                .cloned()
+                .unwrap_or_default();
+        }
+    }
+
+    /// Returns whether or not the info dictionary is known, this
+    /// is false for magnet links whose metadata is still being fetched.
+    pub fn complete(&self) -> bool {
+        !self.hashes.is_empty()
     }
 
     #[cfg(test)]
@@ -166,6 +542,12 @@ impl Info {
             hash: [0u8; 20],
             files: vec![],
             private: false,
+            announce_list: vec![],
+            url_list: vec![],
+            comment: None,
+            creator: None,
+            creation_date: None,
+            raw_info: vec![],
         }
     }
 
@@ -210,6 +592,40 @@ impl Info {
         self.calc_disk_locs(index, 0, len)
     }
 
+    /// Calculates the priority of each piece from the priorities of the
+    /// files, a piece taking the highest priority of the files it overlaps.
+    pub fn piece_priorities(&self, files: &[u8]) -> Vec<u8> {
+        let mut priorities = vec![0; self.pieces() as usize];
+        let mut start = 0;
+        for (f, p) in self.files.iter().zip(files.iter()) {
+            for pri in &mut priorities[self.pieces_in(start, f.length as u64)] {
+                *pri = cmp::max(*pri, *p);
+            }
+            start += f.length as u64;
+        }
+        priorities
+    }
+
+    /// Returns the range of pieces which overlap the file at idx.
+    pub fn file_pieces(&self, idx: usize) -> ops::Range<usize> {
+        self.pieces_in(self.file_offset(idx), self.files[idx].length as u64)
+    }
+
+    /// Returns the offset of the file at idx within the torrent.
+    pub fn file_offset(&self, idx: usize) -> u64 {
+        self.files[..idx].iter().map(|f| f.length as u64).sum()
+    }
+
+    /// Returns the range of pieces which overlap len bytes at offset start.
+    pub fn pieces_in(&self, start: u64, len: u64) -> ops::Range<usize> {
+        if len == 0 {
+            return 0..0;
+        }
+        let first = start / self.piece_len as u64;
+        let last = (start + len - 1) / self.piece_len as u64;
+        first as usize..last as usize + 1
+    }
+
     /// Calculates the file offsets for a given index, begin, and block length.
     fn calc_disk_locs(&self, index: u32, begin: u32, mut len: u32) -> Vec<disk::Location> {
         // The absolute byte offset where we start processing data.
@@ -273,3 +689,275 @@ fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>,
         None => File::from_bencode(BEncode::Dict(data)).map(|f| vec![f]),
     }
 }
+
+/// Recursively collects all files in a directory, in a consistent order.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for p in entries {
+        if fs::metadata(&p)?.is_dir() {
+            collect_files(&p, files)?;
+        } else {
+            files.push(p);
+        }
+    }
+    Ok(())
+}
+
+/// Picks the smallest power of two piece length which
+/// keeps the piece count near TARGET_PIECES.
+fn auto_piece_len(total_len: u64) -> u32 {
+    let mut len = MIN_PIECE_LEN;
+    while len < MAX_PIECE_LEN && total_len / len as u64 > TARGET_PIECES {
+        len *= 2;
+    }
+    len
+}
+
+/// Parses the hex or base32 encoded infohash of a magnet link
+fn parse_btih(s: &str) -> Option<[u8; 20]> {
+    match s.len() {
+        40 => id_to_hash(s),
+        32 => {
+            let alphabet = base32::Alphabet::RFC4648 { padding: false };
+            base32::decode(alphabet, &s.to_uppercase()).and_then(|d| if d.len() == 20 {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&d);
+                Some(hash)
+            } else {
+                None
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Info, CreateOpts, auto_piece_len};
+    use bencode::{self, BEncode};
+    use std::collections::BTreeMap;
+    use std::{env, fs};
+    use std::io::Write;
+    use std::path::Path;
+    use util::random_string;
+
+    fn test_info() -> Info {
+        let mut f1 = BTreeMap::new();
+        f1.insert("length".to_owned(), BEncode::Int(20000));
+        f1.insert("path".to_owned(), BEncode::List(vec![BEncode::from_str("a")]));
+        let mut f2 = BTreeMap::new();
+        f2.insert("length".to_owned(), BEncode::Int(12768));
+        f2.insert(
+            "path".to_owned(),
+            BEncode::List(vec![BEncode::from_str("b"), BEncode::from_str("c")]),
+        );
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::from_str("test"));
+        info.insert("piece length".to_owned(), BEncode::Int(16384));
+        info.insert("pieces".to_owned(), BEncode::String(vec![1u8; 40]));
+        info.insert(
+            "files".to_owned(),
+            BEncode::List(vec![BEncode::Dict(f1), BEncode::Dict(f2)]),
+        );
+        let mut t = BTreeMap::new();
+        t.insert("announce".to_owned(), BEncode::from_str("http://localhost"));
+        t.insert("info".to_owned(), BEncode::Dict(info));
+        Info::from_bencode(BEncode::Dict(t)).unwrap()
+    }
+
+    #[test]
+    fn test_magnet_hex() {
+        let i = Info::from_magnet(
+            "magnet:?xt=urn:btih:0102030405060708090a0b0c0d0e0f1011121314&dn=test&tr=udp%3A%2F%2Flocalhost%3A1337&ws=http%3A%2F%2Flocalhost%2Ftest",
+        ).unwrap();
+        let mut hash = [0u8; 20];
+        for j in 0..20 {
+            hash[j] = j as u8 + 1;
+        }
+        assert_eq!(i.hash, hash);
+        assert_eq!(i.name, "test");
+        assert_eq!(i.announce, "udp://localhost:1337");
+        assert_eq!(i.url_list, vec!["http://localhost/test".to_owned()]);
+        assert!(!i.complete());
+    }
+
+    #[test]
+    fn test_magnet_base32() {
+        let i = Info::from_magnet("magnet:?xt=urn:btih:AEBAGBAFAYDQQCIKBMGA2DQPCAIREEYU")
+            .unwrap();
+        let mut hash = [0u8; 20];
+        for j in 0..20 {
+            hash[j] = j as u8 + 1;
+        }
+        assert_eq!(i.hash, hash);
+        assert_eq!(i.announce, "");
+    }
+
+    #[test]
+    fn test_magnet_invalid() {
+        assert!(Info::from_magnet("http://localhost").is_err());
+        assert!(Info::from_magnet("magnet:?dn=test").is_err());
+        assert!(Info::from_magnet("magnet:?xt=urn:btih:0102").is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let info = test_info();
+        let data = info.to_bencode().encode_to_buf();
+        let magnet = Info::from_magnet(&format!(
+            "magnet:?xt=urn:btih:{}",
+            ::util::hash_to_id(&info.hash)
+        )).unwrap();
+        assert_eq!(info.metadata().unwrap(), data);
+        assert!(magnet.metadata().is_none());
+        let ni = magnet.with_metadata(&data).unwrap();
+        assert!(ni.complete());
+        assert_eq!(ni.hash, info.hash);
+        assert_eq!(ni.total_len, 32768);
+        assert_eq!(ni.files.len(), 2);
+
+        let mut bad = bencode::decode_buf(&data).unwrap().to_dict().unwrap();
+        bad.insert("private".to_owned(), BEncode::Int(1));
+        assert!(
+            magnet
+                .with_metadata(&BEncode::Dict(bad).encode_to_buf())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_export_magnet() {
+        let info = test_info();
+        let magnet = Info::from_magnet(&format!(
+            "magnet:?xt=urn:btih:{}",
+            ::util::hash_to_id(&info.hash)
+        )).unwrap();
+        let ni = magnet
+            .with_metadata(&info.metadata().unwrap())
+            .unwrap();
+        let data = ni.to_torrent().unwrap();
+        let d = bencode::decode_buf(&data).unwrap().to_dict().unwrap();
+        assert!(!d.contains_key("announce"));
+        let ei = Info::from_bencode(BEncode::Dict(d)).unwrap();
+        assert_eq!(ei.hash, info.hash);
+        assert_eq!(ei.announce, "");
+        assert_eq!(ei.files.len(), 2);
+    }
+
+    #[test]
+    fn test_export_unknown_keys() {
+        let mut i = test_info().to_bencode().to_dict().unwrap();
+        i.insert("source".to_owned(), BEncode::from_str("tracker"));
+        let raw = BEncode::Dict(i.clone()).encode_to_buf();
+        let mut t = BTreeMap::new();
+        t.insert("announce".to_owned(), BEncode::from_str("http://localhost"));
+        t.insert("info".to_owned(), BEncode::Dict(i));
+        t.insert(
+            "url-list".to_owned(),
+            BEncode::List(vec![BEncode::from_str("http://localhost/")]),
+        );
+        let info = Info::from_bencode(BEncode::Dict(t.clone())).unwrap();
+        assert_eq!(info.metadata().unwrap(), raw);
+        assert!(info.to_bencode().encode_to_buf() != raw);
+        assert_eq!(info.to_torrent().unwrap(), BEncode::Dict(t).encode_to_buf());
+
+        let magnet = Info::from_magnet(&format!(
+            "magnet:?xt=urn:btih:{}",
+            ::util::hash_to_id(&info.hash)
+        )).unwrap();
+        assert!(magnet.to_torrent().is_none());
+        assert_eq!(magnet.with_metadata(&raw).unwrap().metadata().unwrap(), raw);
+    }
+
+    #[test]
+    fn test_piece_priorities() {
+        let info = test_info();
+        assert_eq!(info.piece_priorities(&[0, 5]), vec![0, 5]);
+        assert_eq!(info.piece_priorities(&[4, 0]), vec![4, 4]);
+        assert_eq!(info.piece_priorities(&[1, 2]), vec![1, 2]);
+        assert_eq!(info.file_pieces(0), 0..2);
+        assert_eq!(info.file_pieces(1), 1..2);
+    }
+
+    #[test]
+    fn test_tiers() {
+        let mut info = test_info();
+        assert_eq!(info.tiers(), vec![vec!["http://localhost".to_owned()]]);
+        info.add_tracker("udp://localhost:1337".to_owned());
+        assert_eq!(
+            info.tiers(),
+            vec![
+                vec!["http://localhost".to_owned()],
+                vec!["udp://localhost:1337".to_owned()],
+            ]
+        );
+        info.remove_tracker("http://localhost");
+        assert_eq!(info.announce, "udp://localhost:1337");
+        assert_eq!(info.tiers(), vec![vec!["udp://localhost:1337".to_owned()]]);
+        info.remove_tracker("udp://localhost:1337");
+        assert_eq!(info.announce, "");
+        assert!(info.tiers().is_empty());
+
+        let m = Info::from_magnet(
+            "magnet:?xt=urn:btih:AEBAGBAFAYDQQCIKBMGA2DQPCAIREEYU&tr=http%3A%2F%2Fa&tr=http%3A%2F%2Fb",
+        ).unwrap();
+        assert_eq!(m.announce, "http://a");
+        assert_eq!(m.tiers().len(), 2);
+    }
+
+    #[test]
+    fn test_auto_piece_len() {
+        assert_eq!(auto_piece_len(1), 16384);
+        assert_eq!(auto_piece_len(1500 * 16384), 16384);
+        assert_eq!(auto_piece_len(1500 * 16384 + 1), 32768);
+        assert_eq!(auto_piece_len(1 << 50), 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_create() {
+        let dir = env::temp_dir().join(format!("synapse-{}", random_string(10)));
+        fs::create_dir_all(dir.join("test/b")).unwrap();
+        fs::File::create(dir.join("test/a"))
+            .and_then(|mut f| f.write_all(&[1u8; 20000]))
+            .unwrap();
+        fs::File::create(dir.join("test/b/c"))
+            .and_then(|mut f| f.write_all(&[2u8; 12768]))
+            .unwrap();
+
+        let mut opts = CreateOpts {
+            path: dir.join("test"),
+            piece_len: Some(16384),
+            trackers: vec!["http://localhost".to_owned()],
+            webseeds: vec!["http://localhost/files/".to_owned()],
+            private: false,
+            comment: Some("comment".to_owned()),
+        };
+        let info = Info::create(&opts).unwrap();
+        assert_eq!(info.pieces(), 2);
+        assert_eq!(info.total_len, 32768);
+        assert_eq!(info.files[0].path, Path::new("test/a"));
+        assert_eq!(info.files[1].path, Path::new("test/b/c"));
+        assert_eq!(info.metadata().unwrap(), info.to_bencode().encode_to_buf());
+
+        let data = info.to_torrent().unwrap();
+        let ni = Info::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(ni.hash, info.hash);
+        assert_eq!(ni.announce, "http://localhost");
+        assert_eq!(ni.url_list, opts.webseeds);
+        assert_eq!(ni.comment, opts.comment);
+        assert_eq!(ni.creation_date, info.creation_date);
+
+        opts.piece_len = Some(1000);
+        assert!(Info::create(&opts).is_err());
+        opts.path = dir.join("test/a");
+        opts.piece_len = None;
+        let info = Info::create(&opts).unwrap();
+        assert_eq!(info.files.len(), 1);
+        assert_eq!(info.name, "a");
+        assert_eq!(info.files[0].path, Path::new("a"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
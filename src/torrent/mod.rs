@@ -3,47 +3,99 @@ pub mod peer;
 pub mod bitfield;
 mod picker;
 mod choker;
+mod extension;
+mod trackers;
+mod webseed;
+mod session;
+mod retries;
 
-use std::fmt;
+use std::{cmp, fmt, io, mem};
 use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
 use slog::Logger;
+use url::Url;
 
 pub use self::bitfield::Bitfield;
 pub use self::info::Info;
 pub use self::peer::{Peer, PeerConn};
 pub use self::peer::Message;
 
-use self::picker::Picker;
-use {bincode, rpc, disk, util, RAREST_PKR, CONFIG};
+use self::picker::{Picker, DEFAULT_PRIORITY};
+use self::extension::{metadata, pex, Extension};
+use self::trackers::Trackers;
+use self::webseed::WebSeed;
+use self::retries::Retries;
+use {bincode, rpc, disk, ipfilter, util, RAREST_PKR, CONFIG};
+use config::{Allocation, Encryption, ProxyClass, Transport};
 use control::cio;
 use rpc::resource::{self, Resource, SResourceUpdate};
 use throttle::Throttle;
-use tracker::{self, TrackerResponse};
+use tracker::{self, ScrapeResponse, TrackerResponse};
+
+/// Number of pieces past a stream's read position which are prioritized
+const STREAM_READAHEAD: usize = 8;
+/// Number of failed copies of a piece whose blocks are kept to find the corrupt ones
+const MAX_SUSPECT_COPIES: usize = 4;
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum TrackerStatus {
+    NotContacted,
     Updating,
     Ok {
         seeders: u32,
         leechers: u32,
         interval: u32,
+        warning: Option<String>,
     },
     Failure(String),
     Error,
 }
 
+impl TrackerStatus {
+    pub fn from_response(resp: &tracker::Result<TrackerResponse>) -> TrackerStatus {
+        match *resp {
+            Ok(ref r) => {
+                TrackerStatus::Ok {
+                    seeders: r.seeders,
+                    leechers: r.leechers,
+                    interval: r.interval,
+                    warning: r.warning.clone(),
+                }
+            }
+            Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
+                TrackerStatus::Failure(s.clone())
+            }
+            Err(_) => TrackerStatus::Error,
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        match *self {
+            TrackerStatus::Ok { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Session data of a torrent. Changes to its layout, including that of
+/// `Info`, need a new version and migration in `session`.
 #[derive(Serialize, Deserialize)]
 struct TorrentData {
     info: Info,
     pieces: Bitfield,
     uploaded: u64,
     downloaded: u64,
+    /// Bytes received which were already downloaded
+    wasted: u64,
     status: Status,
     path: Option<String>,
+    /// Completed blocks of partially downloaded pieces
+    partial: Vec<(u32, Vec<u32>)>,
+    /// Priority of each file
+    priorities: Vec<u8>,
 }
 
 pub struct Torrent<T: cio::CIO> {
@@ -53,13 +105,13 @@ pub struct Torrent<T: cio::CIO> {
     cio: T,
     uploaded: u64,
     downloaded: u64,
+    wasted: u64,
     last_ul: u64,
     last_dl: u64,
     priority: u8,
     last_clear: DateTime<Utc>,
     throttle: Throttle,
-    tracker: TrackerStatus,
-    tracker_update: Option<Instant>,
+    trackers: Trackers,
     peers: HashMap<usize, Peer<T>>,
     leechers: HashSet<usize>,
     picker: Picker,
@@ -68,6 +120,40 @@ pub struct Torrent<T: cio::CIO> {
     l: Logger,
     dirty: bool,
     path: Option<String>,
+    /// Encoded info dictionary, served to peers via ut_metadata
+    metadata: Option<Vec<u8>>,
+    /// Metadata download state for magnet links
+    fetcher: Option<metadata::Fetcher>,
+    /// PEX state of peers which support ut_pex
+    pex: HashMap<usize, pex::State>,
+    /// Peers discovered through PEX, pending connection
+    discovered: Vec<SocketAddr>,
+    /// Peers which failed to connect over the preferred transport
+    fallback: Retries,
+    /// Peers whose encryption handshake failed, retried in plaintext
+    /// when encryption is only preferred
+    plaintext: Retries,
+    /// Blocks of pieces which failed their hash check with data from more
+    /// than one address, kept until a valid copy shows which were corrupt
+    suspects: HashMap<u32, Vec<(u32, IpAddr, [u8; 20])>>,
+    /// Addresses which sent corrupt data, pending a strike
+    offenders: Vec<IpAddr>,
+    webseeds: Vec<WebSeed>,
+    /// Cause of the last disk error, reported over RPC
+    disk_error: Option<String>,
+    /// Status to restore once the torrent's data has been moved
+    pre_move: Option<Status>,
+    /// Priority of each file, 0 meaning the file is skipped
+    priorities: Vec<u8>,
+    /// Reads of files streamed over RPC which are waiting on pieces
+    streams: HashMap<usize, StreamRead>,
+}
+
+/// A read of len bytes at offset of a file being streamed
+struct StreamRead {
+    file: usize,
+    offset: u64,
+    len: u64,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -79,6 +165,8 @@ pub enum Status {
     Seeding,
     Validating,
     DiskError,
+    Magnet,
+    Moving,
 }
 
 impl Status {
@@ -91,7 +179,7 @@ impl Status {
 
     pub fn stopped(&self) -> bool {
         match *self {
-            Status::Paused | Status::DiskError => true,
+            Status::Paused | Status::DiskError | Status::Moving => true,
             _ => false,
         }
     }
@@ -115,7 +203,15 @@ impl<T: cio::CIO> Torrent<T> {
             Picker::new_sequential(&info, &pieces)
         };
         let leechers = HashSet::new();
-        let status = Status::Pending;
+        let status = if info.complete() {
+            Status::Pending
+        } else {
+            Status::Magnet
+        };
+        let metadata = info.metadata();
+        let trackers = Trackers::new(&info);
+        let webseeds = webseeds(&info);
+        let priorities = vec![DEFAULT_PRIORITY; info.files.len()];
         let mut t = Torrent {
             id,
             info: Arc::new(info),
@@ -126,37 +222,65 @@ impl<T: cio::CIO> Torrent<T> {
             priority: 3,
             uploaded: 0,
             downloaded: 0,
+            wasted: 0,
             last_ul: 0,
             last_dl: 0,
             last_clear: Utc::now(),
             cio,
             leechers,
             throttle,
-            tracker: TrackerStatus::Updating,
-            tracker_update: None,
+            trackers,
             choker: choker::Choker::new(),
             l: l.clone(),
             dirty: true,
             status,
+            metadata,
+            fetcher: None,
+            pex: HashMap::new(),
+            discovered: Vec::new(),
+            fallback: Retries::new(),
+            plaintext: Retries::new(),
+            suspects: HashMap::new(),
+            offenders: Vec::new(),
+            webseeds,
+            disk_error: None,
+            pre_move: None,
+            priorities,
+            streams: HashMap::new(),
         };
         t.start();
-        t.validate();
+        if t.info.complete() {
+            t.allocate();
+        } else {
+            t.announce_start();
+        }
 
         t
     }
 
+    /// Loads a torrent from its session data. If `hold` is set it starts
+    /// paused without announcing, the returned flag tells whether it was
+    /// held rather than paused already.
     pub fn deserialize(
         id: usize,
         data: &[u8],
         throttle: Throttle,
         cio: T,
         l: Logger,
-    ) -> Result<Torrent<T>, bincode::Error> {
-        let d: TorrentData = bincode::deserialize(data)?;
+        hold: bool,
+    ) -> Result<(Torrent<T>, bool), bincode::Error> {
+        let mut data = data;
+        let d = session::decode(&mut data)?;
+        // The disk thread appends file stats, which are missing from older sessions
+        let stats: Option<Vec<Option<disk::FileStat>>> =
+            bincode::deserialize_from(&mut data, bincode::Infinite).ok();
         debug!(l, "Torrent data deserialized!");
         let peers = HashMap::new();
         let leechers = HashSet::new();
         let picker = picker::Picker::new_rarest(&d.info, &d.pieces);
+        let metadata = d.info.metadata();
+        let trackers = Trackers::new(&d.info);
+        let webseeds = webseeds(&d.info);
         let mut t = Torrent {
             id,
             info: Arc::new(d.info),
@@ -165,6 +289,7 @@ impl<T: cio::CIO> Torrent<T> {
             picker,
             uploaded: d.uploaded,
             downloaded: d.downloaded,
+            wasted: d.wasted,
             last_ul: 0,
             last_dl: 0,
             priority: 3,
@@ -172,30 +297,62 @@ impl<T: cio::CIO> Torrent<T> {
             cio,
             leechers,
             throttle,
-            tracker: TrackerStatus::Updating,
-            tracker_update: None,
+            trackers,
             choker: choker::Choker::new(),
             l: l.clone(),
             dirty: false,
             status: d.status,
             path: d.path,
+            metadata,
+            fetcher: None,
+            pex: HashMap::new(),
+            discovered: Vec::new(),
+            fallback: Retries::new(),
+            plaintext: Retries::new(),
+            suspects: HashMap::new(),
+            offenders: Vec::new(),
+            webseeds,
+            disk_error: None,
+            pre_move: None,
+            priorities: d.priorities,
+            streams: HashMap::new(),
         };
+        let priorities = t.info.piece_priorities(&t.priorities);
+        t.picker.set_priorities(priorities);
+        // Fast resume data is only trusted if the files are as they were when it was saved
+        let resumable = stats
+            .map(|s| s == disk::file_stats(&t.info, t.path.as_ref()))
+            .unwrap_or(false);
         match t.status {
+            // An interrupted move may have left files in either location
+            Status::Validating | Status::Moving => {
+                t.recheck();
+            }
+            _ if t.info.complete() && !resumable => {
+                debug!(t.l, "Fast resume data is stale, rechecking");
+                t.recheck();
+            }
             Status::DiskError | Status::Seeding | Status::Leeching => {
-                if t.pieces.complete() {
+                if t.wanted_complete() {
                     t.status = Status::Idle;
                 } else {
                     t.status = Status::Pending;
                 }
             }
-            Status::Validating => {
-                t.validate();
-            }
             _ => {}
         };
+        if t.status != Status::Validating {
+            t.picker.restore_partial(&d.partial);
+        }
+        let held = hold && t.status != Status::Paused;
+        if held {
+            t.status = Status::Paused;
+        }
         t.start();
-        t.announce_start();
-        Ok(t)
+        if !held {
+            t.announce_start();
+        }
+        Ok((t, held))
     }
 
     pub fn serialize(&mut self) {
@@ -204,15 +361,19 @@ impl<T: cio::CIO> Torrent<T> {
             pieces: self.pieces.clone(),
             uploaded: self.uploaded,
             downloaded: self.downloaded,
+            wasted: self.wasted,
             status: self.status,
             path: self.path.clone(),
+            partial: self.picker.partial(),
+            priorities: self.priorities.clone(),
         };
-        let data = bincode::serialize(&d, bincode::Infinite).expect("Serialization failed!");
+        let data = session::encode(&d).expect("Serialization failed!");
         debug!(self.l, "Sending serialization request!");
         self.cio.msg_disk(disk::Request::serialize(
             self.id,
             data,
-            self.info.hash,
+            self.info.clone(),
+            self.path.clone(),
         ));
         self.dirty = false;
     }
@@ -223,47 +384,75 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn delete(&mut self) {
         debug!(self.l, "Sending file deletion request!");
+        for (stream, _) in self.streams.drain() {
+            self.cio.msg_rpc(rpc::CtlMessage::StreamError {
+                stream,
+                reason: "Torrent was removed".to_owned(),
+            });
+        }
         self.cio.msg_disk(
             disk::Request::delete(self.id, self.info.hash),
         );
     }
 
-    pub fn set_tracker_response(&mut self, resp: &tracker::Result<TrackerResponse>) {
+    pub fn set_tracker_response(&mut self, url: &str, resp: &tracker::Result<TrackerResponse>) {
         debug!(self.l, "Processing tracker response");
         match *resp {
-            Ok(ref r) => {
-                let mut time = Instant::now();
-                time += Duration::from_secs(r.interval as u64);
-                self.tracker = TrackerStatus::Ok {
-                    seeders: r.seeders,
-                    leechers: r.leechers,
-                    interval: r.interval,
-                };
-                self.tracker_update = Some(time);
-            }
-            Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
-                self.tracker = TrackerStatus::Failure(s.clone());
-            }
-            Err(ref e) => {
-                warn!(self.l, "Failed to query tracker: {:?}", e);
-                self.tracker = TrackerStatus::Error;
+            Err(ref e) => warn!(self.l, "Failed to query tracker {}: {:?}", url, e),
+            Ok(TrackerResponse { warning: Some(ref w), .. }) => {
+                warn!(self.l, "Tracker {} warning: {}", url, w)
             }
+            Ok(_) => {}
+        }
+        let next = self.trackers.handle_response(url, resp);
+        self.update_rpc_tracker(url);
+        if resp.is_ok() {
+            self.update_rpc_stats(url);
+        }
+        if let Some((url, event)) = next {
+            debug!(self.l, "Failing over to tracker {}", url);
+            self.send_announce(url, event);
+        }
+    }
+
+    pub fn set_scrape(&mut self, url: &str, stats: ScrapeResponse) {
+        if self.trackers.set_stats(url, stats) {
+            self.update_rpc_stats(url);
+        }
+    }
+
+    /// Requests swarm statistics from every tracker, the tracker
+    /// subsystem batching them with other torrents' requests.
+    pub fn scrape(&mut self) {
+        let urls: Vec<_> = self.trackers.scrapable().map(|t| t.url.clone()).collect();
+        for url in urls {
+            let req = tracker::Request::scrape(self, url);
+            self.cio.msg_trk(req);
         }
-        self.update_rpc_tracker();
     }
 
     pub fn update_tracker(&mut self) {
-        if let Some(end) = self.tracker_update {
+        if self.trackers.due() {
             debug!(self.l, "Updating tracker at inteval!");
-            let cur = Instant::now();
-            if cur >= end {
-                let req = tracker::Request::interval(self);
-                self.cio.msg_trk(req);
-            }
+            self.announce(None);
         }
     }
 
     pub fn remove_peer(&mut self, rpc_id: &str) {
+        let ws = self.webseeds
+            .iter()
+            .position(|ws| util::webseed_rpc_id(&self.info.hash, &ws.url) == rpc_id);
+        if let Some(i) = ws {
+            let mut ws = self.webseeds.remove(i);
+            debug!(self.l, "Removing web seed {}", ws.url);
+            if let Some(idx) = ws.reset() {
+                self.picker.abandon_whole(idx);
+            }
+            Arc::make_mut(&mut self.info).url_list.retain(|u| *u != ws.url);
+            self.dirty = true;
+            self.cio.msg_rpc(rpc::CtlMessage::Removed(vec![rpc_id.to_owned()]));
+            return;
+        }
         let ih = &self.info.hash;
         let cio = &mut self.cio;
         self.peers
@@ -272,8 +461,50 @@ impl<T: cio::CIO> Torrent<T> {
             .map(|(id, _)| cio.remove_peer(*id));
     }
 
-    // TODO: Implement once mutlitracker support is in
-    pub fn remove_tracker(&mut self, rpc_id: &str) {}
+    pub fn remove_tracker(&mut self, rpc_id: &str) {
+        let url = match self.trackers
+            .iter()
+            .find(|t| util::trk_rpc_id(&self.info.hash, &t.url) == rpc_id)
+        {
+            Some(t) => t.url.clone(),
+            None => return,
+        };
+        debug!(self.l, "Removing tracker {}", url);
+        self.trackers.remove(&url);
+        Arc::make_mut(&mut self.info).remove_tracker(&url);
+        self.dirty = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Removed(vec![rpc_id.to_owned()]));
+        self.update_rpc_trackers();
+    }
+
+    fn add_trackers(&mut self, urls: Vec<String>) {
+        let mut added = Vec::new();
+        for url in urls {
+            if Url::parse(&url).is_err() {
+                warn!(self.l, "Ignoring invalid tracker url {}", url);
+                continue;
+            }
+            if self.trackers.add(url.clone()) {
+                Arc::make_mut(&mut self.info).add_tracker(url.clone());
+                added.push(url);
+            }
+        }
+        if added.is_empty() {
+            return;
+        }
+        self.dirty = true;
+        let resources = added
+            .iter()
+            .filter_map(|url| self.trackers.get(url))
+            .map(|t| self.tracker_resource(t))
+            .collect();
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
+        self.update_rpc_trackers();
+        // If every other tracker is down, try the new ones right away
+        if !self.status.stopped() && !self.trackers.active() {
+            self.announce(Some(tracker::Event::Started));
+        }
+    }
 
     pub fn get_throttle(&self, id: usize) -> Throttle {
         self.throttle.new_sibling(id)
@@ -304,6 +535,15 @@ impl<T: cio::CIO> Torrent<T> {
             disk::Response::Read { context, data } => {
                 trace!(self.l, "Received piece from disk, uploading!");
                 if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    // The peer may have been choked while the read was in progress
+                    if !peer.request_allowed(context.idx) && peer.fast() {
+                        peer.send_message(Message::reject(
+                            context.idx,
+                            context.begin,
+                            context.length,
+                        ));
+                        return;
+                    }
                     let p = Message::s_piece(context.idx, context.begin, context.length, data);
                     // This may not be 100% accurate, but close enough for now.
                     self.uploaded += context.length as u64;
@@ -312,21 +552,37 @@ impl<T: cio::CIO> Torrent<T> {
                     peer.send_message(p);
                 }
             }
+            disk::Response::Verified {
+                index,
+                valid,
+                blocks,
+                ..
+            } => {
+                if valid {
+                    self.piece_verified(index, blocks);
+                } else {
+                    self.piece_failed(index, blocks);
+                }
+            }
             disk::Response::ValidationComplete { invalid, .. } => {
                 debug!(self.l, "Validation completed!");
-                if invalid.is_empty() {
+                // Pieces only in skipped files needn't be present
+                if invalid.iter().all(|p| !self.picker.wanted(*p)) {
                     if !self.pieces.complete() {
                         for i in 0..self.pieces.len() {
                             self.pieces.set_bit(i);
                         }
+                        for piece in invalid {
+                            self.pieces.unset_bit(piece as u64);
+                        }
+                        self.picker.refresh_picker(&self.pieces);
                     }
                     info!(self.l, "Torrent succesfully downloaded!");
                     // TOOD: Consider if we should store this result
                     if !self.status.stopped() {
                         self.set_status(Status::Idle);
                     }
-                    let req = tracker::Request::completed(self);
-                    self.cio.msg_trk(req);
+                    self.announce(Some(tracker::Event::Completed));
                     // Remove all seeding peers.
                     let leechers = &self.leechers;
                     let seeders = self.peers
@@ -353,6 +609,7 @@ impl<T: cio::CIO> Torrent<T> {
                             self.picker.refresh_picker(&self.pieces);
                         }
                         self.announce_start();
+                        self.request_all();
                     } else {
                         for piece in invalid {
                             self.picker.invalidate_piece(piece);
@@ -365,16 +622,44 @@ impl<T: cio::CIO> Torrent<T> {
                 // update the RPC stats once done
                 self.update_rpc_transfer();
             }
+            disk::Response::Stream { stream, file, data, .. } => {
+                let size = self.info.files[file].length as u64;
+                self.cio.msg_rpc(rpc::CtlMessage::StreamData { stream, size, data });
+            }
+            disk::Response::StreamError { stream, err, .. } => {
+                warn!(self.l, "Failed to read stream: {:?}", err);
+                self.cio.msg_rpc(rpc::CtlMessage::StreamError {
+                    stream,
+                    reason: format!("Failed to read file: {}", err),
+                });
+            }
+            disk::Response::Moving { progress, .. } => {
+                self.update_rpc_path(Some(progress));
+            }
+            disk::Response::Moved { path, .. } => {
+                self.moved(path);
+            }
+            disk::Response::Allocated { .. } => {
+                debug!(self.l, "Files allocated!");
+                self.validate();
+            }
             disk::Response::Error { err, .. } => {
                 warn!(self.l, "Disk error: {:?}", err);
+                self.disk_error = Some(format!("Disk error: {}", err));
                 self.set_status(Status::DiskError);
             }
+            disk::Response::Created { .. } => unreachable!(),
         }
     }
 
     pub fn peer_ev(&mut self, pid: cio::PID, evt: cio::Result<Message>) -> Result<(), ()> {
         // TODO: Consider Boxing peers so it's just pointer insert/removal
         let mut peer = self.peers.remove(&pid).ok_or(())?;
+        // Peers which don't support encryption drop the connection during its handshake
+        let mse_failed = match evt {
+            Err(cio::Error(cio::ErrorKind::Handshake, _)) => true,
+            _ => false,
+        };
         if let Ok(mut msg) = evt {
             if peer.handle_msg(&mut msg).is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
                 self.peers.insert(pid, peer);
@@ -388,16 +673,39 @@ impl<T: cio::CIO> Torrent<T> {
                 self.cio.remove_peer(self.id);
             }
         } else {
-            self.cleanup_peer(&mut peer);
+            self.cleanup_peer(&mut peer, mse_failed);
         }
         Err(())
     }
 
     pub fn handle_msg(&mut self, msg: Message, peer: &mut Peer<T>) -> Result<(), ()> {
         trace!(self.l, "Received {:?} from peer", msg);
+        if let Message::Handshake { .. } = msg {
+            // Later connections start over with the preferred settings
+            self.plaintext.remove(&peer.addr());
+            self.fallback.remove(&peer.addr());
+        }
+        // Until metadata is received, only extension messages are relevant
+        if !self.info.complete() {
+            match msg {
+                Message::Handshake { .. } => {
+                    self.send_pieces(peer);
+                    self.send_ext_handshake(peer);
+                }
+                Message::Extended { id, payload } => self.handle_ext(id, payload, peer)?,
+                _ => {}
+            }
+            return Ok(());
+        }
         match msg {
-            Message::Handshake { .. } => { }
-            Message::Bitfield(_) => {
+            Message::Handshake { .. } => {
+                self.send_pieces(peer);
+                self.send_ext_handshake(peer);
+            }
+            Message::Extended { id, payload } => self.handle_ext(id, payload, peer)?,
+            Message::Bitfield(_) |
+            Message::HaveAll |
+            Message::HaveNone => {
                 if self.pieces.usable(peer.pieces()) {
                     peer.interested();
                 }
@@ -425,6 +733,17 @@ impl<T: cio::CIO> Torrent<T> {
             Message::Unchoke => {
                 self.make_requests(peer);
             }
+            Message::AllowedFast(_) => {
+                if peer.remote_status().choked {
+                    self.make_requests(peer);
+                }
+            }
+            Message::RejectRequest { index, begin, .. } => {
+                if self.info.complete() {
+                    self.picker.rejected(picker::Block::new(index, begin), peer.id());
+                    self.make_requests(peer);
+                }
+            }
             Message::Piece {
                 index,
                 begin,
@@ -433,6 +752,7 @@ impl<T: cio::CIO> Torrent<T> {
             } => {
                 // Ignore a piece we already have, this could happen from endgame
                 if self.pieces.has_bit(index as u64) {
+                    self.wasted += length as u64;
                     return Ok(());
                 }
 
@@ -448,10 +768,13 @@ impl<T: cio::CIO> Torrent<T> {
                     return Err(());
                 }
 
-                let pr = self.picker.completed(picker::Block::new(index, begin));
+                let pr = self.picker.completed(picker::Block::new(index, begin), peer.addr().ip());
                 let (piece_done, peers) = if let Ok(r) = pr {
                     r
                 } else {
+                    // Either a duplicate of a block which has already arrived, or one
+                    // we never asked for
+                    self.wasted += length as u64;
                     return Ok(());
                 };
 
@@ -463,59 +786,25 @@ impl<T: cio::CIO> Torrent<T> {
                 self.downloaded += length as u64;
                 self.last_dl += length as u64;
                 if piece_done {
-                    self.pieces.set_bit(index as u64);
-                    self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                        resource::SResourceUpdate::PieceDownloaded {
-                            id: util::piece_rpc_id(&self.info.hash, index as u64),
-                            downloaded: true,
-                        },
-                    ]));
-
-                    // Begin validation, and save state if the torrent is done
-                    if self.pieces.complete() {
-                        debug!(self.l, "Beginning validation");
-                        self.serialize();
-                        self.validate();
-                    }
-
-                    // Tell all relevant peers we got the piece
-                    let m = Message::Have(index);
-                    for pid in self.leechers.iter() {
-                        if let Some(peer) = self.peers.get_mut(pid) {
-                            if !peer.pieces().has_bit(index as u64) {
-                                peer.send_message(m.clone());
-                            }
-                        } else {
-                            // This situation can occur when a torrent itself is a leecher
-                            // and the piece download causes a "self notification", while it
-                            // has been removed. Ignore for now.
-                        }
-                    }
-
-                    // Mark uninteresting peers
-                    for (_, peer) in self.peers.iter_mut() {
-                        if !self.pieces.usable(peer.pieces()) {
-                            peer.uninterested();
-                        }
-                    }
+                    self.verify_piece(index);
                 }
 
                 // If there are any peers we've asked duplicate pieces for,
                 // cancel them, though we should still assume they'll probably send it anyways
-                let m = Message::Cancel {
-                    index,
-                    begin,
-                    length,
-                };
-
+                let mut cancelled = Vec::new();
                 for pid in peers.into_iter().filter(|p| *p != peer.id()) {
                     if let Some(peer) = self.peers.get_mut(&pid) {
-                        peer.send_message(m.clone());
+                        peer.cancel_request(index, begin, length);
+                        cancelled.push(pid);
                     }
                 }
 
-                if !self.pieces.complete() {
+                if !self.wanted_complete() {
                     self.make_requests(peer);
+                    // Cancelled requests free up space in other peers' queues
+                    for pid in cancelled {
+                        self.make_requests_pid(pid);
+                    }
                 }
             }
             Message::Request {
@@ -523,6 +812,10 @@ impl<T: cio::CIO> Torrent<T> {
                 begin,
                 length,
             } => {
+                // Disallowed requests are rejected at the peer level
+                if !peer.request_allowed(index) {
+                    return Ok(());
+                }
                 if !self.status.stopped() && !self.status.leeching() {
                     self.set_status(Status::Seeding);
                     // TODO get this from some sort of allocator.
@@ -531,6 +824,8 @@ impl<T: cio::CIO> Torrent<T> {
                     } else {
                         self.request_read(peer.id(), index, begin, Box::new([0u8; 16384]));
                     }
+                } else if peer.fast() {
+                    peer.send_message(Message::reject(index, begin, length));
                 } else {
                     // TODO: add this to a queue to fulfill later
                 }
@@ -547,6 +842,7 @@ impl<T: cio::CIO> Torrent<T> {
             Message::KeepAlive |
             Message::Choke |
             Message::Cancel { .. } |
+            Message::SuggestPiece(_) |
             Message::Port(_) => {}
 
             Message::SharedPiece { .. } => unreachable!(),
@@ -554,6 +850,503 @@ impl<T: cio::CIO> Torrent<T> {
         Ok(())
     }
 
+    /// Checks the hash of a piece downloaded from peers once it's been written.
+    fn verify_piece(&mut self, index: u32) {
+        let locs = self.info.piece_disk_locs(index);
+        let locs = disk::part_locs(&self.info, &self.skipped(), index, 0, locs);
+        let hash = self.info.hashes[index as usize].clone();
+        // Block hashes of a valid copy are needed to find who sent corrupt blocks
+        let blocks = self.suspects.contains_key(&index);
+        self.cio.msg_disk(disk::Request::verify(
+            self.id,
+            index,
+            hash,
+            locs,
+            self.path.clone(),
+            blocks,
+        ));
+    }
+
+    fn piece_verified(&mut self, index: u32, blocks: Vec<[u8; 20]>) {
+        self.picker.verified(index);
+        // Blocks of an earlier failure which differ from the valid copy were corrupt
+        if let Some(suspects) = self.suspects.remove(&index) {
+            let mut offenders: Vec<_> = suspects
+                .into_iter()
+                .filter(|&(offset, _, hash)| {
+                    blocks.get(offset as usize / 16384).map(|h| *h != hash).unwrap_or(false)
+                })
+                .map(|(_, ip, _)| ip)
+                .collect();
+            offenders.sort();
+            offenders.dedup();
+            for ip in offenders {
+                warn!(self.l, "Peer {} sent corrupt data for piece {}", ip, index);
+                self.offenders.push(ip);
+            }
+        }
+        if !self.pieces.has_bit(index as u64) {
+            self.piece_completed(index);
+        }
+    }
+
+    fn piece_failed(&mut self, index: u32, blocks: Vec<[u8; 20]>) {
+        // A web seed may have completed the piece in the meantime
+        if self.pieces.has_bit(index as u64) {
+            self.picker.verified(index);
+            return;
+        }
+        let sources = self.picker.hash_failed(index);
+        warn!(self.l, "Piece {} failed its hash check", index);
+        let mut ips: Vec<_> = sources.iter().map(|&(_, ip)| ip).collect();
+        ips.sort();
+        ips.dedup();
+        match (ips.len(), ips.get(0).cloned()) {
+            // Every block came from the same peer, so it's clearly at fault
+            (1, Some(Some(ip))) => {
+                warn!(self.l, "Peer {} sent corrupt data for piece {}", ip, index);
+                self.offenders.push(ip);
+            }
+            // Otherwise remember what each peer sent, to be compared against
+            // the piece once it's been downloaded again from a single peer
+            _ => {
+                let suspects = self.suspects.entry(index).or_insert_with(Vec::new);
+                for (offset, ip) in sources {
+                    let hash = blocks.get(offset as usize / 16384);
+                    if let (Some(ip), Some(hash)) = (ip, hash) {
+                        suspects.push((offset, ip, *hash));
+                    }
+                }
+                let max = blocks.len() * MAX_SUSPECT_COPIES;
+                if suspects.len() > max {
+                    let excess = suspects.len() - max;
+                    suspects.drain(..excess);
+                }
+                self.picker.pin(index);
+            }
+        }
+        self.request_all();
+    }
+
+    /// Returns the addresses of peers which have sent corrupt data,
+    /// so that they may be banned.
+    pub fn take_offenders(&mut self) -> Vec<IpAddr> {
+        mem::replace(&mut self.offenders, Vec::new())
+    }
+
+    /// Disconnects every peer with the given address.
+    pub fn remove_peers_from(&mut self, ip: IpAddr) {
+        let cio = &mut self.cio;
+        for (id, _) in self.peers.iter().filter(|&(_, p)| p.addr().ip() == ip) {
+            cio.remove_peer(*id);
+        }
+    }
+
+    /// Disconnects peers whose addresses are blocked.
+    pub fn remove_blocked_peers(&mut self) {
+        let cio = &mut self.cio;
+        let blocked = self.peers.iter().filter(|&(_, p)| ipfilter::blocked(&p.addr().ip()));
+        for (id, _) in blocked {
+            cio.remove_peer(*id);
+        }
+    }
+
+    /// Handles a piece which has been completely downloaded.
+    fn piece_completed(&mut self, index: u32) {
+        self.pieces.set_bit(index as u64);
+        if !self.streams.is_empty() {
+            self.update_streams();
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::PieceDownloaded {
+                id: util::piece_rpc_id(&self.info.hash, index as u64),
+                downloaded: true,
+            },
+        ]));
+
+        // Begin validation, and save state if the torrent is done
+        if self.wanted_complete() {
+            debug!(self.l, "Beginning validation");
+            self.serialize();
+            self.validate();
+        }
+
+        // Tell all relevant peers we got the piece
+        let m = Message::Have(index);
+        for pid in self.leechers.iter() {
+            if let Some(peer) = self.peers.get_mut(pid) {
+                if !peer.pieces().has_bit(index as u64) {
+                    peer.send_message(m.clone());
+                }
+            } else {
+                // This situation can occur when a torrent itself is a leecher
+                // and the piece download causes a "self notification", while it
+                // has been removed. Ignore for now.
+            }
+        }
+
+        // Mark uninteresting peers
+        for (_, peer) in self.peers.iter_mut() {
+            if !self.pieces.usable(peer.pieces()) {
+                peer.uninterested();
+            }
+        }
+    }
+
+    /// Starts downloading a piece from each web seed which isn't busy
+    /// or backing off.
+    pub fn update_webseeds(&mut self) {
+        if self.status.stopped() || !self.info.complete() || self.wanted_complete() {
+            return;
+        }
+        for ws in self.webseeds.iter_mut() {
+            if !ws.idle() {
+                continue;
+            }
+            match self.picker.pick_whole(ws.id) {
+                Some(idx) => {
+                    let req = ws.start(self.id, idx, &self.info);
+                    self.cio.msg_trk(tracker::Request::WebSeed(req));
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn set_webseed_response(&mut self, url: &str, resp: tracker::Result<Vec<u8>>) {
+        let i = match self.webseeds.iter().position(|ws| ws.url == url) {
+            Some(i) => i,
+            None => return,
+        };
+        match resp {
+            // The disk shouldn't be used while stopped, so start over once resumed
+            Ok(_) if self.status.stopped() => {
+                if let Some(idx) = self.webseeds[i].reset() {
+                    self.picker.abandon_whole(idx);
+                }
+            }
+            Ok(data) => {
+                if let Some((idx, data)) = self.webseeds[i].received(data) {
+                    self.webseed_piece(i, idx, data);
+                } else if let Some(req) = self.webseeds[i].next_request(self.id) {
+                    self.cio.msg_trk(tracker::Request::WebSeed(req));
+                }
+            }
+            Err(e) => {
+                warn!(self.l, "Web seed {} failed: {}", url, e);
+                if let Some(idx) = self.webseeds[i].failed() {
+                    self.picker.abandon_whole(idx);
+                }
+            }
+        }
+        self.update_webseeds();
+    }
+
+    /// Verifies and writes a piece downloaded from a web seed.
+    fn webseed_piece(&mut self, i: usize, index: u32, data: Vec<u8>) {
+        if util::sha1_hash(&data).as_ref() != &self.info.hashes[index as usize][..] {
+            warn!(self.l, "Web seed {} sent an invalid piece", self.webseeds[i].url);
+            self.webseeds[i].failed();
+            self.picker.abandon_whole(index);
+            return;
+        }
+        self.webseeds[i].succeeded();
+        // Peers may have completed the piece in the meantime
+        if !self.picker.completed_whole(index) || self.pieces.has_bit(index as u64) {
+            return;
+        }
+        self.set_status(Status::Leeching);
+        self.dirty = true;
+        let mut begin = 0;
+        while begin < data.len() {
+            let len = cmp::min(16384, data.len() - begin);
+            let mut block = Box::new([0u8; 16384]);
+            block[..len].copy_from_slice(&data[begin..begin + len]);
+            self.write_piece(index, begin as u32, block);
+            begin += len;
+        }
+        self.downloaded += data.len() as u64;
+        self.last_dl += data.len() as u64;
+        self.piece_completed(index);
+    }
+
+    fn webseed_resource(&self, ws: &WebSeed) -> resource::Resource {
+        resource::Resource::Peer(resource::Peer {
+            id: util::webseed_rpc_id(&self.info.hash, &ws.url),
+            torrent_id: self.rpc_id(),
+            client_id: [0u8; 20],
+            ip: ws.url.clone(),
+            rate_up: 0,
+            rate_down: 0,
+            availability: 1.,
+        })
+    }
+
+    /// Informs the peer of the pieces we have, this is done once the peer's
+    /// handshake is received so that the fast extension may be used.
+    fn send_pieces(&mut self, peer: &mut Peer<T>) {
+        if peer.fast() {
+            if self.info.complete() && self.pieces.complete() {
+                peer.send_message(Message::HaveAll);
+            } else if self.pieces.iter().next().is_none() {
+                peer.send_message(Message::HaveNone);
+            } else {
+                peer.send_message(Message::Bitfield(self.pieces.clone()));
+            }
+            peer.send_allowed_fast(&self.info.hash, self.info.pieces());
+        } else if self.info.complete() {
+            // Without metadata we don't know how many pieces there are
+            peer.send_message(Message::Bitfield(self.pieces.clone()));
+        }
+    }
+
+    fn send_ext_handshake(&mut self, peer: &mut Peer<T>) {
+        let mut hs = extension::Handshake::local();
+        hs.metadata_size = self.metadata.as_ref().map(|md| md.len());
+        // Private torrents must only obtain peers from their trackers
+        if self.info.private {
+            hs.ids.remove(&Extension::Pex);
+        }
+        peer.send_ext_handshake(hs);
+    }
+
+    /// Dispatches extension messages to their handlers, the
+    /// handshake itself is processed at the peer level.
+    fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
+        if id == extension::HANDSHAKE_ID {
+            for ext in extension::EXTENSIONS {
+                if peer.ext_id(*ext).is_some() {
+                    self.ext_enabled(*ext, peer);
+                }
+            }
+            return Ok(());
+        }
+        match Extension::from_id(id) {
+            Some(Extension::Metadata) => self.handle_metadata(payload, peer),
+            Some(Extension::Pex) => self.handle_pex(payload, peer),
+            // Peers may only use ids we've advertised, but be lenient
+            None => Ok(()),
+        }
+    }
+
+    /// Called when a peer indicates support for an extension
+    fn ext_enabled(&mut self, ext: Extension, peer: &mut Peer<T>) {
+        match ext {
+            Extension::Metadata => {
+                let size = peer.ext_handshake().metadata_size;
+                if let Some(s) = size {
+                    if !self.info.complete() && self.fetcher.is_none() {
+                        debug!(self.l, "Fetching metadata of size {}", s);
+                        self.fetcher = metadata::Fetcher::new(s);
+                    }
+                }
+                if let Some(ref mut f) = self.fetcher {
+                    request_metadata(f, peer);
+                }
+            }
+            Extension::Pex => {
+                if !self.info.private && !self.pex.contains_key(&peer.id()) {
+                    let mut state = pex::State::new();
+                    let peers = self.pex_peers(peer.id());
+                    if let Some(msg) = state.update(&peers) {
+                        peer.send_ext(Extension::Pex, msg.encode());
+                    }
+                    self.pex.insert(peer.id(), state);
+                }
+            }
+        }
+    }
+
+    fn handle_metadata(&mut self, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
+        match metadata::Msg::decode(&payload) {
+            Some(metadata::Msg::Request(piece)) => {
+                let resp = match self.metadata {
+                    Some(ref md) => metadata::Msg::data(md, piece),
+                    None => metadata::Msg::Reject(piece),
+                };
+                peer.send_ext(Extension::Metadata, resp.encode());
+            }
+            Some(metadata::Msg::Data {
+                     piece,
+                     total_size,
+                     data,
+                 }) => {
+                let done = match self.fetcher {
+                    Some(ref mut f) if f.size() == total_size => {
+                        if f.received(piece, &data).is_err() {
+                            return Err(());
+                        }
+                        f.complete()
+                    }
+                    _ => false,
+                };
+                if done {
+                    self.metadata_fetched(peer);
+                } else if let Some(ref mut f) = self.fetcher {
+                    request_metadata(f, peer);
+                }
+            }
+            Some(metadata::Msg::Reject(piece)) => {
+                if let Some(ref mut f) = self.fetcher {
+                    f.rejected(piece);
+                }
+            }
+            None => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn handle_pex(&mut self, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
+        if self.info.private {
+            return Ok(());
+        }
+        match self.pex.get_mut(&peer.id()) {
+            Some(s) => {
+                if !s.recv_allowed() {
+                    debug!(self.l, "Ignoring PEX message, peer is sending too frequently");
+                    return Ok(());
+                }
+            }
+            None => return Ok(()),
+        }
+        let msg = pex::Msg::decode(&payload).ok_or(())?;
+        let known: HashSet<_> = self.peers
+            .values()
+            .chain(Some(&*peer))
+            .flat_map(|p| Some(p.addr()).into_iter().chain(p.listen_addr()))
+            .collect();
+        for addr in msg.added {
+            if !known.contains(&addr) && !self.discovered.contains(&addr) {
+                self.discovered.push(addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the listen addresses of all connected peers,
+    /// excluding the peer the set will be sent to.
+    fn pex_peers(&self, exclude: usize) -> HashSet<SocketAddr> {
+        self.peers
+            .values()
+            .filter(|p| p.id() != exclude)
+            .filter_map(|p| p.listen_addr())
+            .collect()
+    }
+
+    /// Periodically called to send PEX messages containing
+    /// peers which have connected or disconnected since the last update.
+    pub fn update_pex(&mut self) {
+        if self.info.private {
+            return;
+        }
+        let pids: Vec<_> = self.pex.keys().cloned().collect();
+        for pid in pids {
+            let peers = self.pex_peers(pid);
+            let msg = self.pex.get_mut(&pid).and_then(|s| s.update(&peers));
+            if let (Some(m), Some(peer)) = (msg, self.peers.get_mut(&pid)) {
+                peer.send_ext(Extension::Pex, m.encode());
+            }
+        }
+    }
+
+    /// Returns whether or not a connection to the peer should use uTP,
+    /// which is never the case when peers are proxied.
+    pub fn use_utp(&self, addr: &SocketAddr) -> bool {
+        if CONFIG.proxy.enabled(ProxyClass::Peers) {
+            return false;
+        }
+        (CONFIG.peer.transport == Transport::Utp) != self.fallback.contains(addr)
+    }
+
+    /// Returns whether or not an outgoing connection to the peer should
+    /// start with an MSE handshake.
+    pub fn use_mse(&self, addr: &SocketAddr) -> bool {
+        match CONFIG.peer.encryption {
+            Encryption::Disabled => false,
+            Encryption::Preferred => !self.plaintext.contains(addr),
+            Encryption::Required => true,
+        }
+    }
+
+    /// Returns peers discovered through PEX, so that
+    /// they may be connected to.
+    pub fn take_discovered(&mut self) -> Vec<SocketAddr> {
+        mem::replace(&mut self.discovered, Vec::new())
+    }
+
+    /// Validates fetched metadata and if correct, begins
+    /// downloading the torrent normally.
+    fn metadata_fetched(&mut self, peer: &mut Peer<T>) {
+        let res = match self.fetcher {
+            Some(ref f) => self.info.with_metadata(f.data()).map(|i| (i, f.data().to_vec())),
+            None => return,
+        };
+        match res {
+            Ok((info, data)) => {
+                info!(self.l, "Metadata fetched for {}", info.name);
+                self.fetcher = None;
+                self.metadata = Some(data);
+                self.set_info(info, peer);
+            }
+            Err(e) => {
+                warn!(self.l, "Invalid metadata received: {}", e);
+                if let Some(ref mut f) = self.fetcher {
+                    f.reset();
+                    request_metadata(f, peer);
+                }
+            }
+        }
+    }
+
+    /// Replaces the torrent's placeholder info with the full info,
+    /// `peer` is the currently active peer, which is not in the peer map.
+    fn set_info(&mut self, info: Info, peer: &mut Peer<T>) {
+        let len = info.pieces() as u64;
+        self.info = Arc::new(info);
+        self.pieces = Bitfield::new(len);
+        self.picker = if self.picker.is_sequential() {
+            Picker::new_sequential(&self.info, &self.pieces)
+        } else {
+            Picker::new_rarest(&self.info, &self.pieces)
+        };
+        self.priorities = vec![DEFAULT_PRIORITY; self.info.files.len()];
+        for peer in self.peers.values_mut().chain(Some(peer)) {
+            peer.resize_pieces(len);
+            self.picker.add_peer(peer);
+            if !peer.pieces().complete() {
+                self.leechers.insert(peer.id());
+            }
+            if self.pieces.usable(peer.pieces()) {
+                peer.interested();
+            }
+        }
+
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentMetadata {
+                id,
+                name: self.info.name.clone(),
+                size: self.info.total_len,
+                pieces: self.info.pieces() as u64,
+                piece_size: self.info.piece_len,
+                files: self.info.files.len() as u32,
+            },
+        ]));
+        let resources = self.rpc_info_content();
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
+        self.serialize();
+        self.allocate();
+    }
+
+    /// Periodically called to rerequest metadata pieces which have timed out
+    pub fn update_metadata(&mut self) {
+        if let Some(ref mut f) = self.fetcher {
+            for (_, peer) in self.peers.iter_mut() {
+                request_metadata(f, peer);
+            }
+        }
+    }
+
     /// Periodically called to update peers, choking the slowest one and
     /// optimistically unchoking a new peer
     pub fn update_unchoked(&mut self) {
@@ -573,7 +1366,9 @@ impl<T: cio::CIO> Torrent<T> {
                 (resource::Status::Paused, _) => {
                     self.pause();
                 }
-                (resource::Status::Hashing, Status::Validating) => {}
+                (_, Status::Moving) |
+                (resource::Status::Hashing, Status::Validating) |
+                (resource::Status::Hashing, Status::Magnet) => {}
                 (resource::Status::Hashing, _) => {
                     self.validate();
                 }
@@ -599,6 +1394,10 @@ impl<T: cio::CIO> Torrent<T> {
         if let Some(s) = u.sequential {
             self.change_picker(s);
         }
+
+        if let Some(t) = u.add_trackers {
+            self.add_trackers(t);
+        }
     }
 
     pub fn rpc_update_file(&mut self, id: String, priority: u8) {
@@ -613,9 +1412,29 @@ impl<T: cio::CIO> Torrent<T> {
         self.serialize();
     }
 
-    fn announce_start(&mut self) {
-        let req = tracker::Request::started(self);
+    /// Announces to the first tracker, failing over
+    /// to the rest as responses come in.
+    fn announce(&mut self, event: Option<tracker::Event>) {
+        if let Some(url) = self.trackers.start(event) {
+            self.send_announce(url, event);
+        }
+    }
+
+    fn send_announce(&mut self, url: String, event: Option<tracker::Event>) {
+        let req = tracker::Request::new_announce(self, url.clone(), event);
         self.cio.msg_trk(req);
+        self.update_rpc_tracker(&url);
+    }
+
+    fn announce_stopped(&mut self) {
+        for url in self.trackers.stop() {
+            let req = tracker::Request::stopped(self, url);
+            self.cio.msg_trk(req);
+        }
+    }
+
+    fn announce_start(&mut self) {
+        self.announce(Some(tracker::Event::Started));
         // TODO: Consider repeatedly sending out these during annoucne intervals
         if !self.info.private {
             let mut req = tracker::Request::DHTAnnounce(self.info.hash);
@@ -630,12 +1449,24 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn complete(&self) -> bool {
         match self.status {
-            Status::Leeching | Status::Validating | Status::Pending => false,
+            Status::Leeching | Status::Validating | Status::Pending | Status::Magnet => false,
             Status::Idle | Status::Seeding | Status::Paused => true,
-            Status::DiskError => self.pieces.complete(),
+            Status::DiskError | Status::Moving => self.wanted_complete(),
         }
     }
 
+    /// Returns whether every piece of the wanted files has been downloaded
+    fn wanted_complete(&self) -> bool {
+        (0..self.info.pieces()).all(|i| {
+            self.pieces.has_bit(i as u64) || !self.picker.wanted(i)
+        })
+    }
+
+    /// Returns whether each file is skipped
+    fn skipped(&self) -> Vec<bool> {
+        self.priorities.iter().map(|p| *p == 0).collect()
+    }
+
     fn set_throttle(&mut self, ul: u32, dl: u32) {
         self.throttle.set_ul_rate(ul as usize);
         self.throttle.set_dl_rate(dl as usize);
@@ -649,8 +1480,80 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Moves the torrent's data to a new directory, stopping the
+    /// torrent until it's done.
     fn set_path(&mut self, path: String) {
-        // TODO: IMplement
+        if self.path.as_ref().unwrap_or(&CONFIG.disk.directory) == &path {
+            return;
+        }
+        // Without metadata there's no data to move yet
+        if !self.info.complete() {
+            self.path = Some(path);
+            self.dirty = true;
+            self.update_rpc_path(None);
+            return;
+        }
+        match self.status {
+            Status::Validating | Status::Moving => {
+                warn!(self.l, "Can't move data while {:?}", self.status);
+                return;
+            }
+            _ => {}
+        }
+        debug!(self.l, "Moving data to {}", path);
+        if !self.status.stopped() {
+            self.announce_stopped();
+        }
+        self.pre_move = Some(self.status);
+        self.set_status(Status::Moving);
+        self.update_rpc_path(Some(0.));
+        self.cio.msg_disk(disk::Request::move_data(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+            path,
+        ));
+    }
+
+    /// Restores the torrent once its data has been moved, or
+    /// enters an error state if the move failed.
+    fn moved(&mut self, path: io::Result<String>) {
+        let status = self.pre_move.take().unwrap_or(Status::Paused);
+        let res = path.map(|p| {
+            debug!(self.l, "Moved data to {}", p);
+            self.path = Some(p);
+        });
+        self.update_rpc_path(None);
+        if let Err(e) = res {
+            self.disk_error = Some(format!("Failed to move data: {}", e));
+            self.set_status(Status::DiskError);
+            return;
+        }
+        self.serialize();
+        match status {
+            Status::Paused | Status::DiskError => self.set_status(status),
+            // Validation was deferred until the data was moved
+            Status::Validating => {
+                self.announce(Some(tracker::Event::Started));
+                self.validate();
+            }
+            _ => {
+                self.status = Status::Paused;
+                self.resume();
+            }
+        }
+    }
+
+    fn update_rpc_path(&mut self, move_progress: Option<f32>) {
+        let id = self.rpc_id();
+        let path = self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentPath {
+                id,
+                path,
+                move_progress,
+            },
+        ]));
     }
 
     fn set_priority(&mut self, priority: u8) {
@@ -665,8 +1568,138 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Reads part of a file for an RPC stream once its pieces have
+    /// been downloaded, prioritizing those pieces until then.
+    pub fn rpc_read_stream(&mut self, stream: usize, file_id: String, offset: u64, len: u64) {
+        let file = match self.file_idx(&file_id) {
+            Some(f) if self.priorities[f] != 0 => f,
+            res => {
+                let reason = if res.is_some() {
+                    "File is skipped"
+                } else {
+                    "Unknown file"
+                };
+                self.cio.msg_rpc(rpc::CtlMessage::StreamError {
+                    stream,
+                    reason: reason.to_owned(),
+                });
+                return;
+            }
+        };
+        let size = self.info.files[file].length as u64;
+        let len = cmp::min(len, size.saturating_sub(offset));
+        if len == 0 {
+            self.cio.msg_rpc(rpc::CtlMessage::StreamData {
+                stream,
+                size,
+                data: Vec::new(),
+            });
+            return;
+        }
+        self.streams.insert(stream, StreamRead { file, offset, len });
+        self.update_streams();
+    }
+
+    pub fn rpc_close_stream(&mut self, stream: usize) {
+        if self.streams.remove(&stream).is_some() {
+            self.update_streams();
+        }
+    }
+
+    /// Reads the data of streams whose pieces have been downloaded, and makes
+    /// the pieces at and just past the read position of the others critical.
+    fn update_streams(&mut self) {
+        let mut ready = Vec::new();
+        let mut critical = Vec::new();
+        for (id, s) in self.streams.iter() {
+            let start = self.info.file_offset(s.file) + s.offset;
+            let pieces = self.info.pieces_in(start, s.len);
+            if pieces.clone().all(|p| self.pieces.has_bit(p as u64)) {
+                ready.push(*id);
+                continue;
+            }
+            // Pieces nearer the read position are needed sooner
+            let end = cmp::min(pieces.end + STREAM_READAHEAD, self.info.pieces() as usize);
+            for (rank, p) in (pieces.start..end).enumerate() {
+                if !self.pieces.has_bit(p as u64) {
+                    critical.push((rank, p as u32));
+                }
+            }
+        }
+        critical.sort();
+        self.picker.set_critical(critical.into_iter().map(|(_, p)| p).collect());
+
+        for id in ready {
+            if let Some(s) = self.streams.remove(&id) {
+                let file = self.info.files[s.file].path.clone();
+                let loc = disk::Location::new(file, s.offset, 0, s.len as usize);
+                self.cio.msg_disk(disk::Request::read_stream(
+                    self.id,
+                    id,
+                    s.file,
+                    loc,
+                    self.path.clone(),
+                ));
+            }
+        }
+    }
+
+    fn file_idx(&self, id: &str) -> Option<usize> {
+        let hash = &self.info.hash;
+        self.info.files.iter().position(|f| {
+            util::file_rpc_id(hash, f.path.as_path().to_string_lossy().as_ref()) == id
+        })
+    }
+
     fn set_file_priority(&mut self, id: String, priority: u8) {
-        // TODO: Implement file priority in picker
+        let idx = match self.file_idx(&id) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let prev = self.skipped();
+        self.priorities[idx] = priority;
+        let skipped = self.skipped();
+        // Pieces shared with other files are kept in the part file while a file is
+        // skipped, so those which are stored elsewhere now have to be downloaded again
+        let mut invalid = Vec::new();
+        for i in self.info.file_pieces(idx) {
+            let i = i as u32;
+            if !self.pieces.has_bit(i as u64) {
+                continue;
+            }
+            let locs = self.info.piece_disk_locs(i);
+            let before = disk::part_locs(&self.info, &prev, i, 0, locs.clone());
+            let after = disk::part_locs(&self.info, &skipped, i, 0, locs);
+            if before.iter().zip(after.iter()).any(|(a, b)| a.file != b.file) {
+                invalid.push(i);
+            }
+        }
+        for i in invalid {
+            self.pieces.unset_bit(i as u64);
+            self.picker.invalidate_piece(i);
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                resource::SResourceUpdate::PieceDownloaded {
+                    id: util::piece_rpc_id(&self.info.hash, i as u64),
+                    downloaded: false,
+                },
+            ]));
+        }
+        let priorities = self.info.piece_priorities(&self.priorities);
+        self.picker.set_priorities(priorities);
+        self.dirty = true;
+
+        let status = self.status;
+        match status {
+            Status::Idle | Status::Seeding if !self.wanted_complete() => {
+                self.set_status(Status::Pending);
+                self.request_all();
+            }
+            Status::Pending | Status::Leeching if self.wanted_complete() => {
+                self.serialize();
+                self.validate();
+            }
+            _ => {}
+        }
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::FilePriority { id, priority },
         ]));
@@ -674,15 +1707,22 @@ impl<T: cio::CIO> Torrent<T> {
 
     fn rpc_info(&self) -> Vec<resource::Resource> {
         let mut r = Vec::new();
+        let stats = self.trackers.stats();
         r.push(Resource::Torrent(resource::Torrent {
             id: self.rpc_id(),
             name: self.info.name.clone(),
             // TODO: Properly add this
             path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
+            move_progress: None,
             created: Utc::now(),
             modified: Utc::now(),
             status: self.status.into(),
             error: self.error(),
+            size: if self.info.complete() {
+                Some(self.info.total_len)
+            } else {
+                None
+            },
             priority: 3,
             progress: self.progress(),
             availability: self.availability(),
@@ -694,14 +1734,45 @@ impl<T: cio::CIO> Torrent<T> {
             throttle_down: self.throttle.dl_rate() as u32,
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
+            transferred_wasted: self.wasted,
             peers: 0,
-            // TODO: Alter when mutlitracker support hits
-            trackers: 1,
+            trackers: cmp::min(self.trackers.len(), u8::max_value() as usize) as u8,
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            downloaded: stats.downloaded,
             pieces: self.info.pieces() as u64,
             piece_size: self.info.piece_len,
             files: self.info.files.len() as u32,
         }));
+        r.extend(self.rpc_info_content());
+        for t in self.trackers.iter() {
+            r.push(self.tracker_resource(t));
+        }
+        for ws in self.webseeds.iter() {
+            r.push(self.webseed_resource(ws));
+        }
+        r
+    }
 
+    fn tracker_resource(&self, t: &trackers::Tracker) -> resource::Resource {
+        resource::Resource::Tracker(resource::Tracker {
+            id: util::trk_rpc_id(&self.info.hash, &t.url),
+            torrent_id: self.rpc_id(),
+            url: t.url.clone(),
+            tier: cmp::min(t.tier, u8::max_value() as usize) as u8,
+            status: t.status.clone().into(),
+            last_report: t.last_report,
+            error: t.error(),
+            warning: t.warning(),
+            complete: t.stats.complete,
+            incomplete: t.stats.incomplete,
+            downloaded: t.stats.downloaded,
+        })
+    }
+
+    /// Produces the piece and file resources of the torrent
+    fn rpc_info_content(&self) -> Vec<resource::Resource> {
+        let mut r = Vec::new();
         for i in 0..self.info.pieces() {
             let id = util::piece_rpc_id(&self.info.hash, i as u64);
             if self.pieces.has_bit(i as u64) {
@@ -721,7 +1792,7 @@ impl<T: cio::CIO> Torrent<T> {
             }
         }
 
-        for f in self.info.files.iter() {
+        for (f, priority) in self.info.files.iter().zip(self.priorities.iter()) {
             let id =
                 util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
             r.push(resource::Resource::File(resource::File {
@@ -729,19 +1800,10 @@ impl<T: cio::CIO> Torrent<T> {
                 torrent_id: self.rpc_id(),
                 availability: 0.,
                 progress: 0.,
-                priority: 3,
+                priority: *priority,
                 path: f.path.as_path().to_string_lossy().into_owned(),
             }))
         }
-
-        r.push(resource::Resource::Tracker(resource::Tracker {
-            id: util::trk_rpc_id(&self.info.hash, &self.info.announce),
-            torrent_id: self.rpc_id(),
-            url: self.info.announce.clone(),
-            last_report: Utc::now(),
-            error: None,
-        }));
-
         r
     }
 
@@ -757,14 +1819,22 @@ impl<T: cio::CIO> Torrent<T> {
                 util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
             r.push(id)
         }
-        r.push(util::trk_rpc_id(&self.info.hash, &self.info.announce));
-        // TOOD: Tracker removal too
+        for t in self.trackers.iter() {
+            r.push(util::trk_rpc_id(&self.info.hash, &t.url));
+        }
+        for ws in self.webseeds.iter() {
+            r.push(util::webseed_rpc_id(&self.info.hash, &ws.url));
+        }
         self.cio.msg_rpc(rpc::CtlMessage::Removed(r));
     }
 
     fn error(&self) -> Option<String> {
         match self.status {
-            Status::DiskError => Some("Disk error!".to_owned()),
+            Status::DiskError => {
+                Some(self.disk_error.clone().unwrap_or_else(
+                    || "Disk error!".to_owned(),
+                ))
+            }
             _ => None,
         }
     }
@@ -774,6 +1844,9 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn progress(&self) -> f32 {
+        if !self.info.complete() {
+            return 0.;
+        }
         self.pieces.iter().count() as f32 / self.info.pieces() as f32
     }
 
@@ -805,12 +1878,23 @@ impl<T: cio::CIO> Torrent<T> {
     /// The disk send handle is also provided.
     fn write_piece(&mut self, index: u32, begin: u32, data: Box<[u8; 16384]>) {
         let locs = self.info.block_disk_locs(index, begin);
-        self.cio.msg_disk(disk::Request::write(self.id, data, locs, self.path.clone()));
+        let locs = disk::part_locs(&self.info, &self.skipped(), index, begin, locs);
+        let piece_len = self.info.piece_len(index);
+        self.cio.msg_disk(disk::Request::write(
+            self.id,
+            index,
+            begin,
+            piece_len,
+            data,
+            locs,
+            self.path.clone(),
+        ));
     }
 
     /// Issues a read request of the given torrent
     fn request_read(&mut self, id: usize, index: u32, begin: u32, data: Box<[u8; 16384]>) {
-        let locs = self.info.block_disk_locs(index, begin);
+        let locs = self.info.piece_disk_locs(index);
+        let locs = disk::part_locs(&self.info, &self.skipped(), index, 0, locs);
         let len = self.info.block_len(index, begin);
         let ctx = disk::Ctx::new(id, self.id, index, begin, len);
         self.cio.msg_disk(disk::Request::read(ctx, data, locs, self.path.clone()));
@@ -824,7 +1908,12 @@ impl<T: cio::CIO> Torrent<T> {
             return;
         }
         while peer.can_queue_req() {
-            if let Some(block) = self.picker.pick(peer) {
+            let block = if peer.remote_status().choked {
+                self.picker.pick_allowed_fast(peer)
+            } else {
+                self.picker.pick(peer)
+            };
+            if let Some(block) = block {
                 peer.request_piece(block.index, block.offset, self.info.block_len(block.index, block.offset));
             } else {
                 break;
@@ -837,7 +1926,12 @@ impl<T: cio::CIO> Torrent<T> {
             return;
         }
         while peer.can_queue_req() {
-            if let Some(block) = self.picker.pick(peer) {
+            let block = if peer.remote_status().choked {
+                self.picker.pick_allowed_fast(peer)
+            } else {
+                self.picker.pick(peer)
+            };
+            if let Some(block) = block {
                 peer.request_piece(block.index, block.offset, self.info.block_len(block.index, block.offset));
             } else {
                 break;
@@ -849,7 +1943,9 @@ impl<T: cio::CIO> Torrent<T> {
         if let Ok(p) = Peer::new(conn, self, None, None) {
             let pid = p.id();
             trace!(self.l, "Adding peer {:?}!", pid);
-            self.picker.add_peer(&p);
+            if self.info.complete() {
+                self.picker.add_peer(&p);
+            }
             self.peers.insert(pid, p);
             Some(pid)
         } else {
@@ -858,10 +1954,14 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn add_inc_peer(&mut self, conn: PeerConn, id: [u8; 20], rsv: [u8; 8]) -> Option<usize> {
-        if let Ok(p) = Peer::new(conn, self, Some(id), Some(rsv)) {
+        if let Ok(mut p) = Peer::new(conn, self, Some(id), Some(rsv)) {
             let pid = p.id();
             debug!(self.l, "Adding peer {:?}!", pid);
-            self.picker.add_peer(&p);
+            if self.info.complete() {
+                self.picker.add_peer(&p);
+            }
+            self.send_pieces(&mut p);
+            self.send_ext_handshake(&mut p);
             self.peers.insert(pid, p);
             Some(pid)
         } else {
@@ -875,13 +1975,11 @@ impl<T: cio::CIO> Torrent<T> {
         }
         self.status = status;
         let id = self.rpc_id();
+        let error = self.error();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             SResourceUpdate::TorrentStatus {
                 id,
-                error: match status {
-                    Status::DiskError => Some("Disk error".to_owned()),
-                    _ => None,
-                },
+                error,
                 status: status.into(),
             },
         ]));
@@ -899,22 +1997,51 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
-    pub fn update_rpc_tracker(&mut self) {
-        let id = util::trk_rpc_id(&self.info.hash, &self.info.announce);
-        let error = match &self.tracker {
-            &TrackerStatus::Failure(ref r) => Some(r.clone()),
-            &TrackerStatus::Error => Some(
-                "Failed to query tracker for an unknown reason.".to_owned(),
-            ),
-            _ => None,
+    fn update_rpc_tracker(&mut self, url: &str) {
+        let update = match self.trackers.get(url) {
+            Some(t) => {
+                SResourceUpdate::TrackerStatus {
+                    id: util::trk_rpc_id(&self.info.hash, &t.url),
+                    status: t.status.clone().into(),
+                    last_report: t.last_report,
+                    error: t.error(),
+                    warning: t.warning(),
+                }
+            }
+            None => return,
         };
-        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-            SResourceUpdate::TrackerStatus {
-                id,
-                last_report: Utc::now(),
-                error,
-            },
-        ]));
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![update]));
+    }
+
+    /// Updates the swarm statistics of a tracker and the torrent.
+    fn update_rpc_stats(&mut self, url: &str) {
+        let tracker = match self.trackers.get(url) {
+            Some(t) => {
+                SResourceUpdate::Scrape {
+                    id: util::trk_rpc_id(&self.info.hash, &t.url),
+                    complete: t.stats.complete,
+                    incomplete: t.stats.incomplete,
+                    downloaded: t.stats.downloaded,
+                }
+            }
+            None => return,
+        };
+        let stats = self.trackers.stats();
+        let torrent = SResourceUpdate::Scrape {
+            id: self.rpc_id(),
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            downloaded: stats.downloaded,
+        };
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![tracker, torrent]));
+    }
+
+    fn update_rpc_trackers(&mut self) {
+        let id = self.rpc_id();
+        let trackers = cmp::min(self.trackers.len(), u8::max_value() as usize) as u8;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(
+            vec![SResourceUpdate::TorrentTrackers { id, trackers }],
+        ));
     }
 
     pub fn update_rpc_transfer(&mut self) {
@@ -928,6 +2055,7 @@ impl<T: cio::CIO> Torrent<T> {
             rate_down,
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
+            transferred_wasted: self.wasted,
             progress,
         });
         if !self.status.leeching() {
@@ -955,6 +2083,13 @@ impl<T: cio::CIO> Torrent<T> {
                 });
             }
         }
+        for ws in self.webseeds.iter_mut() {
+            updates.push(SResourceUpdate::Rate {
+                id: util::webseed_rpc_id(&self.info.hash, &ws.url),
+                rate_up: 0,
+                rate_down: ws.get_rate(),
+            });
+        }
         let mut files = HashMap::new();
         for p in self.pieces.iter() {
             for loc in self.info.piece_disk_locs(p as u32) {
@@ -975,21 +2110,48 @@ impl<T: cio::CIO> Torrent<T> {
         self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
     }
 
-    fn cleanup_peer(&mut self, peer: &mut Peer<T>) {
+    fn cleanup_peer(&mut self, peer: &mut Peer<T>, mse_failed: bool) {
         trace!(self.l, "Removing {:?}!", peer);
         self.choker.remove_peer(peer, &mut self.peers);
         self.leechers.remove(&peer.id());
-        self.picker.remove_peer(&peer);
+        if self.info.complete() {
+            self.picker.remove_peer(&peer);
+            self.picker.release_pinned(peer.id());
+        }
+        if let Some(ref mut f) = self.fetcher {
+            f.remove_peer(peer.id());
+        }
+        self.pex.remove(&peer.id());
+        // Retry connections whose encryption handshake failed in plaintext if
+        // encryption is only preferred, otherwise over the other transport
+        if peer.outgoing() && !peer.ready() {
+            let addr = peer.addr();
+            let retry = if mse_failed && CONFIG.peer.encryption == Encryption::Preferred &&
+                self.plaintext.insert(addr)
+            {
+                true
+            } else {
+                CONFIG.peer.fallback && self.fallback.insert(addr)
+            };
+            if retry {
+                self.discovered.push(addr);
+            }
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.status == Status::Paused
     }
 
     pub fn pause(&mut self) {
         debug!(self.l, "Pausing torrent!");
         match self.status {
+            // The torrent is restored to its prior state once moved
+            Status::Moving => return,
             Status::Paused => {}
             _ => {
                 debug!(self.l, "Sending stopped request to trk");
-                let req = tracker::Request::stopped(self);
-                self.cio.msg_trk(req);
+                self.announce_stopped();
             }
         }
         self.set_status(Status::Paused);
@@ -997,15 +2159,24 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn resume(&mut self) {
         debug!(self.l, "Resuming torrent!");
+        if self.status == Status::Moving {
+            return;
+        }
+        if !self.info.complete() {
+            if self.status == Status::Paused {
+                self.announce_start();
+            }
+            self.set_status(Status::Magnet);
+            return;
+        }
         match self.status {
             Status::Paused => {
                 debug!(self.l, "Sending started request to trk");
-                let req = tracker::Request::started(self);
-                self.cio.msg_trk(req);
+                self.announce(Some(tracker::Event::Started));
                 self.request_all();
             }
             Status::DiskError => {
-                if self.pieces.complete() {
+                if self.wanted_complete() {
                     self.validate();
                 } else {
                     self.request_all();
@@ -1014,7 +2185,7 @@ impl<T: cio::CIO> Torrent<T> {
             }
             _ => {}
         }
-        if self.pieces.complete() {
+        if self.wanted_complete() {
             self.set_status(Status::Idle);
         } else {
             self.set_status(Status::Pending);
@@ -1022,16 +2193,49 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn validate(&mut self) {
-        self.cio.msg_disk(
-            disk::Request::validate(self.id, self.info.clone()),
-        );
+        // The data is validated at its new location once moved
+        if self.status == Status::Moving {
+            self.pre_move = Some(Status::Validating);
+            return;
+        }
+        let skipped = self.skipped();
+        self.cio.msg_disk(disk::Request::validate(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+            skipped,
+        ));
+        self.set_status(Status::Validating);
+    }
+
+    /// Allocates the torrent's files, validating them once done.
+    fn allocate(&mut self) {
+        if CONFIG.disk.allocation == Allocation::None {
+            self.validate();
+            return;
+        }
+        let skipped = self.skipped();
+        self.cio.msg_disk(disk::Request::allocate(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+            skipped,
+        ));
         self.set_status(Status::Validating);
     }
 
+    /// Discards the pieces we have and validates them from disk.
+    fn recheck(&mut self) {
+        self.pieces = Bitfield::new(self.info.pieces() as u64);
+        self.picker.refresh_picker(&self.pieces);
+        self.validate();
+    }
+
     fn request_all(&mut self) {
         for pid in self.pids() {
             self.make_requests_pid(pid);
         }
+        self.update_webseeds();
     }
 
     fn pids(&self) -> Vec<usize> {
@@ -1073,15 +2277,24 @@ impl<T: cio::CIO> Drop for Torrent<T> {
         }
         match self.status {
             Status::Paused => {}
-            _ => {
-                let req = tracker::Request::stopped(self);
-                self.cio.msg_trk(req);
-            }
+            _ => self.announce_stopped(),
         }
         self.send_rpc_removal();
     }
 }
 
+impl Into<rpc::resource::TrackerStatus> for TrackerStatus {
+    fn into(self) -> rpc::resource::TrackerStatus {
+        match self {
+            TrackerStatus::NotContacted => rpc::resource::TrackerStatus::NotContacted,
+            TrackerStatus::Updating => rpc::resource::TrackerStatus::Updating,
+            TrackerStatus::Ok { .. } => rpc::resource::TrackerStatus::Working,
+            TrackerStatus::Failure(_) |
+            TrackerStatus::Error => rpc::resource::TrackerStatus::Error,
+        }
+    }
+}
+
 impl Into<rpc::resource::Status> for Status {
     fn into(self) -> rpc::resource::Status {
         match self {
@@ -1092,6 +2305,38 @@ impl Into<rpc::resource::Status> for Status {
             Status::Seeding => rpc::resource::Status::Seeding,
             Status::Validating => rpc::resource::Status::Hashing,
             Status::DiskError => rpc::resource::Status::Error,
+            Status::Magnet => rpc::resource::Status::Magnet,
+            Status::Moving => rpc::resource::Status::Moving,
         }
     }
 }
+
+/// Creates the web seeds of a torrent from its url-list, ignoring
+/// any which aren't HTTP(S) URLs.
+fn webseeds(info: &Info) -> Vec<WebSeed> {
+    info.url_list
+        .iter()
+        .filter(|u| {
+            Url::parse(u)
+                .map(|u| u.scheme() == "http" || u.scheme() == "https")
+                .unwrap_or(false)
+        })
+        .enumerate()
+        // Ids are allocated from the top so they never collide with peers
+        .map(|(i, u)| WebSeed::new(u.clone(), usize::max_value() - i))
+        .collect()
+}
+
+/// Requests a piece of metadata from the peer if it supports ut_metadata
+/// and has no outstanding requests.
+fn request_metadata<T: cio::CIO>(fetcher: &mut metadata::Fetcher, peer: &mut Peer<T>) {
+    if peer.ext_id(Extension::Metadata).is_none() || fetcher.requested_from(peer.id()) {
+        return;
+    }
+    if let Some(piece) = fetcher.pick(peer.id()) {
+        peer.send_ext(
+            Extension::Metadata,
+            metadata::Msg::Request(piece).encode(),
+        );
+    }
+}
@@ -34,6 +34,12 @@ pub enum Message {
     },
     Cancel { index: u32, begin: u32, length: u32 },
     Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+    Extended { id: u8, payload: Vec<u8> },
 }
 
 impl fmt::Debug for Message {
@@ -82,6 +88,26 @@ impl fmt::Debug for Message {
                 )
             }
             Message::Port(port) => write!(f, "Message::Port({:?})", port),
+            Message::SuggestPiece(p) => write!(f, "Message::SuggestPiece({})", p),
+            Message::HaveAll => write!(f, "Message::HaveAll"),
+            Message::HaveNone => write!(f, "Message::HaveNone"),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                write!(
+                    f,
+                    "Message::RejectRequest {{ idx: {}, begin: {}, len: {} }}",
+                    index,
+                    begin,
+                    length
+                )
+            }
+            Message::AllowedFast(p) => write!(f, "Message::AllowedFast({})", p),
+            Message::Extended { id, ref payload } => {
+                write!(f, "Message::Extended {{ id: {}, len: {} }}", id, payload.len())
+            }
         }
     }
 }
@@ -144,6 +170,23 @@ impl Clone for Message {
                 length,
             },
             Message::Port(port) => Message::Port(port),
+            Message::SuggestPiece(p) => Message::SuggestPiece(p),
+            Message::HaveAll => Message::HaveAll,
+            Message::HaveNone => Message::HaveNone,
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => Message::RejectRequest {
+                index,
+                begin,
+                length,
+            },
+            Message::AllowedFast(p) => Message::AllowedFast(p),
+            Message::Extended { id, ref payload } => Message::Extended {
+                id,
+                payload: payload.clone(),
+            },
         }
     }
 }
@@ -161,9 +204,18 @@ impl PartialEq for Message {
             (&Message::Choke, &Message::Choke) |
             (&Message::Unchoke, &Message::Unchoke) |
             (&Message::Interested, &Message::Interested) |
-            (&Message::Uninterested, &Message::Uninterested) => true,
-            (&Message::Have(p), &Message::Have(p_)) => p == p_,
+            (&Message::Uninterested, &Message::Uninterested) |
+            (&Message::HaveAll, &Message::HaveAll) |
+            (&Message::HaveNone, &Message::HaveNone) => true,
+            (&Message::Have(p), &Message::Have(p_)) |
+            (&Message::SuggestPiece(p), &Message::SuggestPiece(p_)) |
+            (&Message::AllowedFast(p), &Message::AllowedFast(p_)) => p == p_,
             (&Message::Port(p), &Message::Port(p_)) => p == p_,
+            (&Message::Extended { id, ref payload },
+             &Message::Extended {
+                 id: id_,
+                 payload: ref payload_,
+             }) => id == id_ && payload == payload_,
             (&Message::Request {
                  index,
                  begin,
@@ -195,6 +247,16 @@ impl PartialEq for Message {
                  index: i,
                  begin: b,
                  length: l,
+             }) |
+            (&Message::RejectRequest {
+                 index,
+                 begin,
+                 length,
+             },
+             &Message::RejectRequest {
+                 index: i,
+                 begin: b,
+                 length: l,
              }) => index == i && begin == b && length == l,
             _ => false,
         }
@@ -203,10 +265,12 @@ impl PartialEq for Message {
 
 impl Message {
     pub fn handshake(torrent: &TorrentInfo) -> Message {
-        use {PEER_ID, DHT_EXT};
+        use {PEER_ID, DHT_EXT, EXT_PROTO, FAST_EXT};
         let mut rsv = [0u8; 8];
+        // Indicate DHT, extension protocol, and fast extension support
         rsv[DHT_EXT.0] |= DHT_EXT.1;
-        // Indicate DHT support
+        rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
         Message::Handshake {
             rsv,
             hash: torrent.hash,
@@ -222,6 +286,14 @@ impl Message {
         }
     }
 
+    pub fn reject(idx: u32, offset: u32, len: u32) -> Message {
+        Message::RejectRequest {
+            index: idx,
+            begin: offset,
+            length: len,
+        }
+    }
+
     pub fn s_piece(index: u32, begin: u32, length: u32, data: Arc<Box<[u8; 16384]>>) -> Message {
         Message::SharedPiece {
             index,
@@ -249,7 +321,8 @@ impl Message {
     pub fn is_special(&self) -> bool {
         match *self {
             Message::Handshake { .. } |
-            Message::Bitfield(_) => true,
+            Message::Bitfield(_) |
+            Message::Extended { .. } => true,
             _ => false,
         }
     }
@@ -258,14 +331,17 @@ impl Message {
         match *self {
             Message::Handshake { .. } => 68,
             Message::KeepAlive => 4,
-            Message::Choke | Message::Unchoke | Message::Interested | Message::Uninterested => 5,
+            Message::Choke | Message::Unchoke | Message::Interested | Message::Uninterested |
+            Message::HaveAll | Message::HaveNone => 5,
             Message::Port(_) => 7,
-            Message::Have(_) => 9,
+            Message::Have(_) | Message::SuggestPiece(_) | Message::AllowedFast(_) => 9,
             Message::Bitfield(ref pf) => 5 + pf.bytes(),
             Message::Request { .. } |
-            Message::Cancel { .. } => 17,
+            Message::Cancel { .. } |
+            Message::RejectRequest { .. } => 17,
             Message::Piece { ref data, .. } => 13 + data.len(),
             Message::SharedPiece { ref data, .. } => 13 + data.len(),
+            Message::Extended { ref payload, .. } => 6 + payload.len(),
         }
     }
 
@@ -359,6 +435,41 @@ impl Message {
                 buf.write_u32::<BigEndian>(begin)?;
                 buf.write_u32::<BigEndian>(length)?;
             }
+            Message::SuggestPiece(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x0D)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
+            Message::HaveAll => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0E)?;
+            }
+            Message::HaveNone => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0F)?;
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.write_u32::<BigEndian>(13)?;
+                buf.write_u8(0x10)?;
+                buf.write_u32::<BigEndian>(index)?;
+                buf.write_u32::<BigEndian>(begin)?;
+                buf.write_u32::<BigEndian>(length)?;
+            }
+            Message::AllowedFast(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x11)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
+            Message::Extended { id, ref payload } => {
+                buf.write_u32::<BigEndian>(2 + payload.len() as u32)?;
+                buf.write_u8(20)?;
+                buf.write_u8(id)?;
+                buf.write_all(payload)?;
+            }
         };
         Ok(())
     }
@@ -2,10 +2,12 @@ mod reader;
 mod writer;
 mod message;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::{io, fmt, mem, time};
 use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
 
+use byteorder::{BigEndian, ReadBytesExt};
 use chrono::{DateTime, Utc};
 
 pub use self::message::Message;
@@ -13,12 +15,20 @@ use self::reader::Reader;
 use self::writer::Writer;
 use socket::Socket;
 use torrent::{Torrent, Bitfield};
+use torrent::extension::{self, Extension};
 use throttle::Throttle;
 use control::cio;
 use rpc::{self, resource};
 use tracker;
 use util;
-use {DHT_EXT, CONFIG};
+use {DHT_EXT, EXT_PROTO, FAST_EXT, CONFIG};
+
+/// Number of pieces in the allowed fast set we give to peers
+const ALLOWED_FAST_K: usize = 10;
+/// Maximum number of suggested pieces to remember
+const MAX_SUGGESTED: usize = 16;
+/// Maximum number of allowed fast pieces to remember
+const MAX_ALLOWED_FAST: usize = 32;
 
 error_chain! {
     errors {
@@ -47,6 +57,16 @@ pub struct Peer<T: cio::CIO> {
     t_hash: [u8; 20],
     cid: Option<[u8; 20]>,
     rsv: Option<[u8; 8]>,
+    ext: extension::Handshake,
+    outgoing: bool,
+    /// Pieces the peer allows us to request while choked
+    allowed_fast: Vec<u32>,
+    /// Pieces we allow the peer to request while choked
+    granted_fast: Vec<u32>,
+    /// Pieces the peer has suggested we download
+    suggested: Vec<u32>,
+    /// Whether a HaveAll was received before the piece count was known
+    have_all: bool,
 }
 
 #[derive(Debug)]
@@ -104,6 +124,13 @@ impl PeerConn {
         Ok(PeerConn::new(Socket::new(ip)?))
     }
 
+    /// Creates a new "outgoing" uTP peer. The returned stream must be
+    /// sent to the listener, which runs the uTP connection.
+    pub fn new_utp(ip: &SocketAddr) -> io::Result<(PeerConn, UnixStream)> {
+        let (sock, stream) = Socket::new_utp(ip)?;
+        Ok((PeerConn::new(sock), stream))
+    }
+
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
     pub fn new_incoming(sock: TcpStream) -> io::Result<PeerConn> {
@@ -163,10 +190,20 @@ impl Peer<cio::test::TCIO> {
             t_hash: [0u8; 20],
             rsv: None,
             cid: None,
+            ext: Default::default(),
+            outgoing: true,
+            allowed_fast: Vec::new(),
+            granted_fast: Vec::new(),
+            suggested: Vec::new(),
+            have_all: false,
             last_flush: Utc::now(),
         }
     }
 
+    pub fn suggested_mut(&mut self) -> &mut Vec<u32> {
+        &mut self.suggested
+    }
+
     pub fn test_from_pieces(id: usize, pieces: Bitfield) -> Peer<cio::test::TCIO> {
         Peer::test(id, 0, 0, 0, pieces)
     }
@@ -195,6 +232,9 @@ impl<T: cio::CIO> Peer<T> {
     ) -> cio::Result<Peer<T>> {
         let addr = conn.sock().addr();
         conn.set_throttle(t.get_throttle(0));
+        if cid.is_none() && t.use_mse(&addr) {
+            conn.sock_mut().encrypt(&t.info.hash);
+        }
         let id = t.cio.add_peer(conn)?;
         let mut p = Peer {
             id,
@@ -212,10 +252,15 @@ impl<T: cio::CIO> Peer<T> {
             t_hash: t.info.hash,
             rsv,
             cid,
+            ext: Default::default(),
+            outgoing: cid.is_none(),
+            allowed_fast: Vec::new(),
+            granted_fast: Vec::new(),
+            suggested: Vec::new(),
+            have_all: false,
             last_flush: Utc::now(),
         };
         p.send_message(Message::handshake(&t.info));
-        p.send_message(Message::Bitfield(t.pieces.clone()));
         p.send_rpc_info();
         Ok(p)
     }
@@ -225,14 +270,111 @@ impl<T: cio::CIO> Peer<T> {
         self.cid.is_some()
     }
 
+    /// Returns whether or not we initiated the connection
+    pub fn outgoing(&self) -> bool {
+        self.outgoing
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
 
+    /// Returns the address the peer accepts connections on, if known
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        match self.ext.port {
+            Some(p) => {
+                let mut addr = self.addr;
+                addr.set_port(p);
+                Some(addr)
+            }
+            None if self.outgoing => Some(self.addr),
+            None => None,
+        }
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
 
+    /// Resizes the peer's bitfield once a torrent's metadata
+    /// has been acquired, discarding it if the sizes mismatch.
+    pub fn resize_pieces(&mut self, len: u64) {
+        let mut pieces = Bitfield::new(len);
+        if self.have_all {
+            for i in 0..len {
+                pieces.set_bit(i);
+            }
+        } else if self.pieces.bytes() == pieces.bytes() {
+            for i in self.pieces.iter().filter(|i| *i < len) {
+                pieces.set_bit(i);
+            }
+        }
+        self.pieces = pieces;
+    }
+
+    /// Whether or not both sides support the fast extension(BEP 6)
+    pub fn fast(&self) -> bool {
+        match self.rsv {
+            Some(rsv) => (rsv[FAST_EXT.0] & FAST_EXT.1) != 0,
+            None => false,
+        }
+    }
+
+    pub fn allowed_fast(&self) -> &[u32] {
+        &self.allowed_fast
+    }
+
+    pub fn suggested(&self) -> &[u32] {
+        &self.suggested
+    }
+
+    /// Whether or not the peer may currently request the given piece
+    pub fn request_allowed(&self, index: u32) -> bool {
+        !self.local_status.choked || self.granted_fast.contains(&index)
+    }
+
+    /// Computes and sends the peer's allowed fast set
+    pub fn send_allowed_fast(&mut self, hash: &[u8; 20], pieces: u32) {
+        if !self.fast() {
+            return;
+        }
+        self.granted_fast = allowed_fast_set(&self.addr.ip(), hash, pieces, ALLOWED_FAST_K);
+        for i in 0..self.granted_fast.len() {
+            let m = Message::AllowedFast(self.granted_fast[i]);
+            self.send_message(m);
+        }
+    }
+
+    /// Returns the extended message id the peer uses for an extension,
+    /// or None if it's unsupported.
+    pub fn ext_id(&self, ext: Extension) -> Option<u8> {
+        self.ext.ids.get(&ext).cloned()
+    }
+
+    pub fn ext_handshake(&self) -> &extension::Handshake {
+        &self.ext
+    }
+
+    /// Sends the extension handshake if the peer supports the extension protocol
+    pub fn send_ext_handshake(&mut self, mut hs: extension::Handshake) {
+        match self.rsv {
+            Some(rsv) if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 => {}
+            _ => return,
+        }
+        hs.yourip = Some(self.addr.ip());
+        self.send_message(Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload: hs.encode(),
+        });
+    }
+
+    /// Sends an extension message, if the peer supports the extension
+    pub fn send_ext(&mut self, ext: Extension, payload: Vec<u8>) {
+        if let Some(id) = self.ext_id(ext) {
+            self.send_message(Message::Extended { id, payload });
+        }
+    }
+
     #[cfg(test)]
     pub fn pieces_mut(&mut self) -> &mut Bitfield {
         &mut self.pieces
@@ -266,7 +408,7 @@ impl<T: cio::CIO> Peer<T> {
     }
 
     pub fn can_queue_req(&mut self) -> bool {
-        !self.remote_status.choked && self.queued < 5
+        (!self.remote_status.choked || !self.allowed_fast.is_empty()) && self.queued < 5
     }
 
     pub fn handle_msg(&mut self, msg: &mut Message) -> Result<()> {
@@ -283,13 +425,21 @@ impl<T: cio::CIO> Peer<T> {
             Message::SharedPiece { length, .. } => {
                 self.downloaded_bytes += length as u64;
                 self.downloaded += 1;
-                self.queued -= 1;
+                // A piece we've cancelled may still arrive
+                self.queued = self.queued.saturating_sub(1);
             }
-            Message::Request { .. } => {
-                if self.local_status.choked {
-                    return Err(
-                        ErrorKind::ProtocolError("Peer requested while choked!").into(),
-                    );
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if !self.request_allowed(index) {
+                    if !self.fast() {
+                        return Err(
+                            ErrorKind::ProtocolError("Peer requested while choked!").into(),
+                        );
+                    }
+                    self.send_message(Message::reject(index, begin, length));
                 }
             }
             Message::Choke => {
@@ -305,6 +455,10 @@ impl<T: cio::CIO> Peer<T> {
                 self.remote_status.interested = false;
             }
             Message::Have(idx) => {
+                // Without metadata or a bitfield, there's nothing to validate against
+                if self.pieces.len() == 0 {
+                    return Ok(());
+                }
                 if idx >= self.pieces.len() as u32 {
                     return Err(
                         ErrorKind::ProtocolError("Invalid piece provided in HAVE!").into(),
@@ -313,8 +467,11 @@ impl<T: cio::CIO> Peer<T> {
                 self.pieces.set_bit(idx as u64);
             }
             Message::Bitfield(ref mut pieces) => {
-                // Set the correct length, then swap the pieces
-                pieces.cap(self.pieces.len());
+                // Set the correct length, then swap the pieces. If we don't
+                // know the length yet, it'll be set once metadata is received.
+                if self.pieces.len() != 0 {
+                    pieces.cap(self.pieces.len());
+                }
                 mem::swap(pieces, &mut self.pieces);
             }
             Message::KeepAlive => {
@@ -335,6 +492,59 @@ impl<T: cio::CIO> Peer<T> {
                 s.set_port(p);
                 self.cio.msg_trk(tracker::Request::AddNode(s));
             }
+            Message::HaveAll |
+            Message::HaveNone |
+            Message::SuggestPiece(_) |
+            Message::RejectRequest { .. } |
+            Message::AllowedFast(_) if !self.fast() => {
+                return Err(
+                    ErrorKind::ProtocolError("Fast extension message sent without support!")
+                        .into(),
+                );
+            }
+            Message::HaveAll => {
+                let len = self.pieces.len();
+                if len == 0 {
+                    self.have_all = true;
+                }
+                for i in 0..len {
+                    self.pieces.set_bit(i);
+                }
+            }
+            Message::HaveNone => {
+                self.pieces = Bitfield::new(self.pieces.len());
+            }
+            Message::SuggestPiece(idx) => {
+                if (idx as u64) < self.pieces.len() && !self.suggested.contains(&idx) {
+                    if self.suggested.len() == MAX_SUGGESTED {
+                        self.suggested.remove(0);
+                    }
+                    self.suggested.push(idx);
+                }
+            }
+            Message::RejectRequest { .. } => {
+                if self.queued == 0 {
+                    return Err(
+                        ErrorKind::ProtocolError("Peer rejected an unknown request!").into(),
+                    );
+                }
+                self.queued -= 1;
+            }
+            Message::AllowedFast(idx) => {
+                if (idx as u64) < self.pieces.len() && !self.allowed_fast.contains(&idx) {
+                    if self.allowed_fast.len() == MAX_ALLOWED_FAST {
+                        self.allowed_fast.remove(0);
+                    }
+                    self.allowed_fast.push(idx);
+                }
+            }
+            Message::Extended { id: extension::HANDSHAKE_ID, ref payload } => {
+                self.ext = extension::Handshake::decode(payload).ok_or_else(|| {
+                    Error::from(ErrorKind::ProtocolError("Invalid extension handshake!"))
+                })?;
+            }
+            // All other extension messages are handled at the torrent level
+            Message::Extended { .. } => {}
         }
         Ok(())
     }
@@ -345,10 +555,60 @@ impl<T: cio::CIO> Peer<T> {
         self.send_message(m);
     }
 
+    /// Cancels a request, which will no longer count towards the queue.
+    /// Peers supporting the fast extension must reject the request
+    /// instead(BEP 6), which dequeues it then.
+    pub fn cancel_request(&mut self, idx: u32, offset: u32, len: u32) {
+        if !self.fast() {
+            self.queued = self.queued.saturating_sub(1);
+        }
+        self.send_message(Message::Cancel {
+            index: idx,
+            begin: offset,
+            length: len,
+        });
+    }
+
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
             self.send_message(Message::Choke);
+            if self.fast() {
+                self.reject_queued();
+            }
+        }
+    }
+
+    /// Removes queued pieces which the peer is no longer allowed to
+    /// request, explicitly rejecting them(BEP 6).
+    fn reject_queued(&mut self) {
+        let granted = &self.granted_fast;
+        let rejected = self.cio
+            .get_peer(self.id, |conn| {
+                let mut rejected = Vec::new();
+                conn.writer.write_queue.retain(|m| match *m {
+                    Message::Piece {
+                        index,
+                        begin,
+                        length,
+                        ..
+                    } |
+                    Message::SharedPiece {
+                        index,
+                        begin,
+                        length,
+                        ..
+                    } if !granted.contains(&index) => {
+                        rejected.push(Message::reject(index, begin, length));
+                        false
+                    }
+                    _ => true,
+                });
+                rejected
+            })
+            .unwrap_or_default();
+        for m in rejected {
+            self.send_message(m);
         }
     }
 
@@ -411,6 +671,33 @@ impl<T: cio::CIO> Peer<T> {
     }
 }
 
+/// Computes the allowed fast set for a peer, as described in BEP 6.
+fn allowed_fast_set(ip: &IpAddr, hash: &[u8; 20], pieces: u32, k: usize) -> Vec<u32> {
+    let mut set = Vec::new();
+    // The algorithm is only defined for IPv4 addresses
+    let ip = match *ip {
+        IpAddr::V4(ref ip) => ip.octets(),
+        IpAddr::V6(_) => return set,
+    };
+    let k = ::std::cmp::min(k, pieces as usize);
+    let mut x = vec![ip[0], ip[1], ip[2], 0];
+    x.extend_from_slice(&hash[..]);
+    while set.len() < k {
+        x = util::sha1_hash(&x).as_ref().to_vec();
+        for i in 0..5 {
+            if set.len() >= k {
+                break;
+            }
+            let y = (&x[i * 4..i * 4 + 4]).read_u32::<BigEndian>().unwrap();
+            let index = y % pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
 impl<T: cio::CIO> Drop for Peer<T> {
     fn drop(&mut self) {
         self.send_rpc_removal();
@@ -432,9 +719,11 @@ impl<T: cio::CIO> fmt::Debug for Peer<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::Peer;
+    use super::{Peer, allowed_fast_set, MAX_ALLOWED_FAST};
     use control::cio::{CIO, test};
-    use torrent::Message;
+    use torrent::{Bitfield, Message};
+    use torrent::extension::{Extension, Handshake, HANDSHAKE_ID};
+    use FAST_EXT;
 
     #[test]
     fn test_cancel() {
@@ -475,4 +764,94 @@ mod tests {
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
     }
+
+    #[test]
+    fn test_ext_handshake() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        assert_eq!(peer.ext_id(Extension::Metadata), None);
+        let mut rsv = [0u8; 8];
+        rsv[5] = 0x10;
+        peer.rsv = Some(rsv);
+        peer.send_ext_handshake(Handshake::local());
+        let hs = match tcio.data().peer_msgs[0].1 {
+            Message::Extended { id: HANDSHAKE_ID, ref payload } => Handshake::decode(payload),
+            _ => None,
+        }.unwrap();
+        assert_eq!(hs.yourip, Some(peer.addr().ip()));
+
+        let mut m = Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: hs.encode(),
+        };
+        peer.handle_msg(&mut m).unwrap();
+        assert_eq!(peer.ext_id(Extension::Metadata), Some(Extension::Metadata.id()));
+        let mut bad = Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: b"i1e".to_vec(),
+        };
+        assert!(peer.handle_msg(&mut bad).is_err());
+    }
+
+    #[test]
+    fn test_allowed_fast() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let mut rsv = [0u8; 8];
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
+        peer.rsv = Some(rsv);
+        *peer.pieces_mut() = Bitfield::new(100);
+
+        peer.handle_msg(&mut Message::AllowedFast(100)).unwrap();
+        assert!(peer.allowed_fast().is_empty());
+        for i in 0..(MAX_ALLOWED_FAST as u32 + 4) {
+            peer.handle_msg(&mut Message::AllowedFast(i)).unwrap();
+        }
+        assert_eq!(peer.allowed_fast().len(), MAX_ALLOWED_FAST);
+        assert_eq!(peer.allowed_fast()[0], 4);
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        let ip = "80.4.4.200".parse().unwrap();
+        let hash = [0xAAu8; 20];
+        assert_eq!(
+            allowed_fast_set(&ip, &hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(&ip, &hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn test_reject_on_choke() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let mut rsv = [0u8; 8];
+        rsv[7] = 0x04;
+        peer.rsv = Some(rsv);
+        peer.unchoke();
+        let p1 = Message::Piece {
+            index: 1,
+            begin: 0,
+            data: Box::new([0u8; 16384]),
+            length: 16384,
+        };
+        peer.send_message(p1);
+        peer.choke();
+        let wq = tcio.get_peer(peer.id, |p| p.writer.write_queue.clone())
+            .unwrap();
+        assert_eq!(wq.len(), 2);
+        assert_eq!(wq[0], Message::Choke);
+        assert_eq!(wq[1], Message::reject(1, 0, 16384));
+
+        // A non fast peer requesting while choked is a protocol error,
+        // but a fast peer should just be rejected.
+        let mut r = Message::request(2, 0, 16384);
+        assert!(peer.handle_msg(&mut r).is_ok());
+        peer.rsv = None;
+        assert!(peer.handle_msg(&mut r).is_err());
+    }
 }
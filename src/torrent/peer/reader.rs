@@ -10,6 +10,9 @@ pub(super) struct Reader {
     blocks_read: usize,
 }
 
+/// Largest extended message we'll accept
+const MAX_EXT_LEN: u32 = 1024 * 1024;
+
 impl Reader {
     pub fn new() -> Reader {
         Reader {
@@ -56,6 +59,7 @@ enum ReadState {
         idx: usize,
     },
     ReadingBitfield { data: Vec<u8>, idx: usize },
+    ReadingExtended { data: Vec<u8>, idx: usize },
 }
 
 enum ReadRes {
@@ -266,6 +270,24 @@ impl ReadState {
                     Err(e) => ReadRes::Err(e),
                 }
             }
+            ReadState::ReadingExtended { mut data, mut idx } => {
+                let len = data.len();
+                match conn.read(&mut data[idx as usize..]) {
+                    Ok(0) => ReadRes::EOF,
+                    Ok(amnt) if idx + amnt == len => {
+                        let id = data.remove(0);
+                        ReadRes::Message(Message::Extended { id, payload: data })
+                    }
+                    Ok(amnt) => {
+                        idx += amnt;
+                        ReadState::ReadingExtended { data, idx }.next_state(conn)
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        ReadRes::Incomplete(ReadState::ReadingExtended { data, idx })
+                    }
+                    Err(e) => ReadRes::Err(e),
+                }
+            }
         }
     }
 
@@ -327,9 +349,31 @@ impl ReadState {
                     len: len,
                 }.next_state(conn)
             }
-            6 | 8 => {
+            0x0E => {
+                if len != 1 {
+                    return ReadRes::Err(io_err_val("Invalid HaveAll message length"));
+                }
+                ReadRes::Message(Message::HaveAll)
+            }
+            0x0F => {
+                if len != 1 {
+                    return ReadRes::Err(io_err_val("Invalid HaveNone message length"));
+                }
+                ReadRes::Message(Message::HaveNone)
+            }
+            0x0D | 0x11 => {
+                if len != 5 {
+                    return ReadRes::Err(io_err_val("Invalid Suggest/AllowedFast message length"));
+                }
+                ReadState::ReadingMsg {
+                    data: buf,
+                    idx: 5,
+                    len: len,
+                }.next_state(conn)
+            }
+            6 | 8 | 0x10 => {
                 if len != 13 {
-                    return ReadRes::Err(io_err_val("Invalid Request/Cancel message length"));
+                    return ReadRes::Err(io_err_val("Invalid Request/Cancel/Reject message length"));
                 }
                 ReadState::ReadingMsg {
                     data: buf,
@@ -337,6 +381,15 @@ impl ReadState {
                     len: len,
                 }.next_state(conn)
             }
+            20 => {
+                if len < 2 || len > MAX_EXT_LEN {
+                    return ReadRes::Err(io_err_val("Invalid Extended message length"));
+                }
+                ReadState::ReadingExtended {
+                    data: vec![0; len as usize - 1],
+                    idx: 0,
+                }.next_state(conn)
+            }
             _ => ReadRes::Err(io_err_val("Invalid ID provided!")),
         }
     }
@@ -381,6 +434,33 @@ impl ReadState {
                 }
                 ReadRes::Message(Message::Port((&buf[5..7]).read_u16::<BigEndian>().unwrap()))
             }
+            0x0D => {
+                if len != 5 {
+                    return ReadRes::Err(io_err_val("Suggest message must be of len 5"));
+                }
+                let idx = (&buf[5..9]).read_u32::<BigEndian>().unwrap();
+                ReadRes::Message(Message::SuggestPiece(idx))
+            }
+            0x10 => {
+                if len != 13 {
+                    return ReadRes::Err(io_err_val("Reject message must be of len 13"));
+                }
+                let idx = (&buf[5..9]).read_u32::<BigEndian>().unwrap();
+                let beg = (&buf[9..13]).read_u32::<BigEndian>().unwrap();
+                let len = (&buf[13..17]).read_u32::<BigEndian>().unwrap();
+                ReadRes::Message(Message::RejectRequest {
+                    index: idx,
+                    begin: beg,
+                    length: len,
+                })
+            }
+            0x11 => {
+                if len != 5 {
+                    return ReadRes::Err(io_err_val("AllowedFast message must be of len 5"));
+                }
+                let idx = (&buf[5..9]).read_u32::<BigEndian>().unwrap();
+                ReadRes::Message(Message::AllowedFast(idx))
+            }
             _ => ReadRes::Err(io_err_val("Invalid message ID")),
         }
     }
@@ -575,6 +655,34 @@ mod tests {
         test_message(data, Message::Port(6881));
     }
 
+    #[test]
+    fn test_read_fast() {
+        test_message(vec![0u8, 0, 0, 5, 0x0D, 0, 0, 0, 3], Message::SuggestPiece(3));
+        test_message(vec![0u8, 0, 0, 1, 0x0E], Message::HaveAll);
+        test_message(vec![0u8, 0, 0, 1, 0x0F], Message::HaveNone);
+        test_message(
+            vec![0u8, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3],
+            Message::RejectRequest {
+                index: 1,
+                begin: 2,
+                length: 3,
+            },
+        );
+        test_message(vec![0u8, 0, 0, 5, 0x11, 0, 0, 0, 7], Message::AllowedFast(7));
+    }
+
+    #[test]
+    fn test_read_extended() {
+        let data = vec![0u8, 0, 0, 5, 20, 1, b'd', b'e', 0xff];
+        test_message(
+            data,
+            Message::Extended {
+                id: 1,
+                payload: vec![b'd', b'e', 0xff],
+            },
+        );
+    }
+
     #[test]
     fn test_read_handshake() {
         use PEER_ID;
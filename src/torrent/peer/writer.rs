@@ -37,6 +37,15 @@ impl Writer {
 
     pub fn writable<W: Write>(&mut self, conn: &mut W) -> io::Result<()> {
         self.writable = true;
+        // Encrypted connections may have buffered data which must be sent first
+        match conn.flush() {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                self.writable = false;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
         self.write(conn)
     }
 
@@ -305,6 +314,41 @@ mod tests {
         assert_eq!(buf, [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
     }
 
+    #[test]
+    fn test_write_reject() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 17];
+        let m = Message::RejectRequest {
+            index: 1,
+            begin: 1,
+            length: 1,
+        };
+        w.write_message(m, &mut &mut buf[..]).unwrap();
+        assert_eq!(buf, [0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
+    }
+
+    #[test]
+    fn test_write_have_all() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 5];
+        w.write_message(Message::HaveAll, &mut &mut buf[..]).unwrap();
+        w.writable(&mut &mut buf[..]).unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 0x0E])
+    }
+
+    #[test]
+    fn test_write_extended() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 9];
+        let m = Message::Extended {
+            id: 1,
+            payload: vec![b'd', b'e', 0xff],
+        };
+        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.writable(&mut &mut buf[..]).unwrap();
+        assert_eq!(buf, [0, 0, 0, 5, 20, 1, b'd', b'e', 0xff])
+    }
+
     #[test]
     fn test_write_handshake() {
         use PEER_ID;
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::{mem, time};
 use torrent::{Info, Peer, Bitfield};
 use control::cio;
@@ -17,11 +18,28 @@ pub struct Picker {
     seeders: u16,
     /// Set of pieces which have blocks waiting. These should be prioritized.
     downloading: HashMap<u32, Vec<Downloading>>,
+    /// Sources of the blocks of completed pieces whose hash is being checked
+    verifying: HashMap<u32, Vec<(u32, Option<IpAddr>)>>,
     /// Bitfield of unpicked pieces, not in progress or
     /// completed yet. A set bit is picked, unset is unpicked.
     unpicked: Bitfield,
     /// The current picker in use
     picker: PickerKind,
+    /// Priority of each piece, 0 meaning the piece isn't wanted
+    priorities: Vec<u8>,
+    /// Distinct priorities of wanted pieces, highest first
+    levels: Vec<u8>,
+    /// Time critical pieces, such as those being streamed, most urgent first
+    critical: Vec<u32>,
+    /// Whether every remaining block has been requested, in which case
+    /// blocks are requested from every peer which has them.
+    endgame: bool,
+    /// Number of wanted pieces which haven't been picked yet
+    remaining: u32,
+    /// Pieces which must be downloaded from a single peer, such as those
+    /// which failed their hash check after coming from several, and the
+    /// peer currently downloading each of them
+    pinned: HashMap<u32, Option<usize>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -46,6 +64,8 @@ struct Downloading {
     offset: u32,
     completed: bool,
     requested: Vec<Request>,
+    /// Address of the peer which sent the block, if known
+    from: Option<IpAddr>,
 }
 
 /// A request to a peer and the time it was initiated.
@@ -56,6 +76,8 @@ struct Request {
 }
 
 const MAX_DUP_REQS: usize = 3;
+/// Priority of pieces unless otherwise set
+pub const DEFAULT_PRIORITY: u8 = 3;
 
 impl Picker {
     /// Creates a new rarest picker, which will select over
@@ -63,13 +85,22 @@ impl Picker {
     pub fn new_rarest(info: &Info, pieces: &Bitfield) -> Picker {
         let scale = info.piece_len / 16384;
         let picker = rarest::Picker::new(pieces);
-        Picker {
+        let mut p = Picker {
             picker: PickerKind::Rarest(picker),
             scale,
             seeders: 0,
             unpicked: pieces.clone(),
             downloading: HashMap::new(),
-        }
+            verifying: HashMap::new(),
+            priorities: vec![DEFAULT_PRIORITY; pieces.len() as usize],
+            levels: vec![DEFAULT_PRIORITY],
+            critical: Vec::new(),
+            endgame: false,
+            remaining: 0,
+            pinned: HashMap::new(),
+        };
+        p.remaining = p.count_remaining();
+        p
     }
 
     /// Creates a new sequential picker, which will select over
@@ -77,13 +108,22 @@ impl Picker {
     pub fn new_sequential(info: &Info, pieces: &Bitfield) -> Picker {
         let scale = info.piece_len / 16384;
         let picker = sequential::Picker::new(pieces);
-        Picker {
+        let mut p = Picker {
             picker: PickerKind::Sequential(picker),
             scale,
             seeders: 0,
             unpicked: pieces.clone(),
             downloading: HashMap::new(),
-        }
+            verifying: HashMap::new(),
+            priorities: vec![DEFAULT_PRIORITY; pieces.len() as usize],
+            levels: vec![DEFAULT_PRIORITY],
+            critical: Vec::new(),
+            endgame: false,
+            remaining: 0,
+            pinned: HashMap::new(),
+        };
+        p.remaining = p.count_remaining();
+        p
     }
 
     /// Returns true if the current picker algorithm is sequential
@@ -94,18 +134,123 @@ impl Picker {
         }
     }
 
+    /// Sets the priority of each piece. Pieces of higher priority are
+    /// picked first, and pieces with a priority of 0 aren't picked at all.
+    pub fn set_priorities(&mut self, priorities: Vec<u8>) {
+        let mut levels: Vec<_> = priorities.iter().cloned().filter(|p| *p != 0).collect();
+        levels.sort_by(|a, b| b.cmp(a));
+        levels.dedup();
+        self.priorities = priorities;
+        self.levels = levels;
+        self.endgame = false;
+        self.remaining = self.count_remaining();
+    }
+
+    /// Sets the pieces which are needed before any others, most urgent first.
+    pub fn set_critical(&mut self, pieces: Vec<u32>) {
+        self.critical = pieces;
+    }
+
+    /// Returns whether a piece is to be downloaded
+    pub fn wanted(&self, idx: u32) -> bool {
+        self.priorities[idx as usize] != 0
+    }
+
     /// Attempts to select a block for a peer.
     pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
         if let Some(b) = self.pick_expired(peer) {
             return Some(b);
         }
 
-        let piece = match self.picker {
-            PickerKind::Sequential(ref mut p) => p.pick(peer),
-            PickerKind::Rarest(ref mut p) => p.pick(peer),
-        };
+        if let Some(b) = self.pick_critical(peer) {
+            return Some(b);
+        }
+
+        // Honor any pieces the peer has suggested(BEP 6)
+        if let Some(b) = self.pick_from(peer, peer.suggested()) {
+            return Some(b);
+        }
+
+        if let Some(b) = self.pick_unrequested(peer) {
+            return Some(b);
+        }
+
+        let mut piece = None;
+        for level in self.levels.iter().cloned() {
+            let priorities = &self.priorities;
+            let pinned = &self.pinned;
+            let id = peer.id();
+            let wanted = |p: u32| priorities[p as usize] == level && pickable_by(pinned, p, id);
+            piece = match self.picker {
+                PickerKind::Sequential(ref mut p) => p.pick(peer, wanted),
+                PickerKind::Rarest(ref mut p) => p.pick(peer, wanted),
+            };
+            if piece.is_some() {
+                break;
+            }
+        }
+        if let Some(b) = piece.and_then(|p| self.pick_piece(p, peer.id())) {
+            return Some(b);
+        }
+
+        if !self.endgame {
+            self.endgame = self.check_endgame();
+        }
+        if self.endgame {
+            self.pick_endgame(peer)
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to select a block for a peer which has choked us,
+    /// using only pieces in the peer's allowed fast set.
+    pub fn pick_allowed_fast<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        self.pick_from(peer, peer.allowed_fast())
+    }
+
+    /// Picks a block from the first of the given pieces which
+    /// the peer has and hasn't been completely picked.
+    fn pick_from<T: cio::CIO>(&mut self, peer: &Peer<T>, pieces: &[u32]) -> Option<Block> {
+        let piece = pieces.iter().cloned().find(|p| {
+            peer.pieces().has_bit(*p as u64) && !self.unpicked.has_bit(*p as u64) &&
+                self.wanted(*p) && pickable_by(&self.pinned, *p, peer.id())
+        });
         piece.and_then(|p| self.pick_piece(p, peer.id()))
-            .or_else(|| self.pick_downloading(peer))
+    }
+
+    /// Attempts to pick a block of a time critical piece. Blocks which
+    /// have already been requested are requested from other peers too,
+    /// so that a slow peer doesn't hold up the piece.
+    fn pick_critical<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        for i in 0..self.critical.len() {
+            let piece = self.critical[i];
+            if !peer.pieces().has_bit(piece as u64) || !self.wanted(piece) ||
+                !pickable_by(&self.pinned, piece, peer.id())
+            {
+                continue;
+            }
+            if !self.unpicked.has_bit(piece as u64) {
+                return self.pick_piece(piece, peer.id());
+            }
+            if let Some(dl) = self.downloading.get_mut(&piece) {
+                let id = peer.id();
+                let r = dl.iter_mut()
+                    .find(|r| {
+                        !r.completed && r.requested.len() < MAX_DUP_REQS &&
+                            r.requested.iter().all(|req| req.peer != id)
+                    })
+                    .map(|r| {
+                        r.requested.push(Request::new(id));
+                        Block::new(piece, r.offset)
+                    });
+                if r.is_some() {
+                    pin(&mut self.pinned, piece, id);
+                    return r;
+                }
+            }
+        }
+        None
     }
 
     /// Attempts to pick an expired block
@@ -118,6 +263,7 @@ impl Picker {
 
     /// Picks a block from a given piece for a peer
     fn pick_piece(&mut self, piece: u32, id: usize) -> Option<Block> {
+        pin(&mut self.pinned, piece, id);
         if !self.downloading.contains_key(&piece) {
             self.downloading.insert(piece, vec![]);
         }
@@ -128,6 +274,7 @@ impl Picker {
                 offset,
                 completed: false,
                 requested: vec![Request::new(id)],
+                from: None,
             });
 
         if dl.len() == self.scale as usize {
@@ -135,7 +282,7 @@ impl Picker {
                 PickerKind::Sequential(ref mut p) => p.completed(piece),
                 PickerKind::Rarest(ref mut p) => p.completed(piece),
             }
-            self.unpicked.set_bit(piece as u64);
+            set_picked(&mut self.unpicked, &mut self.remaining, &self.priorities, piece);
         }
         Some(Block {
             index: piece,
@@ -143,17 +290,66 @@ impl Picker {
         })
     }
 
-    /// Attempts to pick an already requested block
-    fn pick_downloading<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+    /// Picks a whole piece for a peer which downloads entire pieces at once,
+    /// such as a web seed, requesting every block of it from the peer.
+    /// Pieces which already have blocks being downloaded aren't picked.
+    pub fn pick_whole(&mut self, id: usize) -> Option<u32> {
+        let piece = {
+            let priorities = &self.priorities;
+            let mut pieces = (0..self.unpicked.len() as u32).filter(|p| {
+                !self.unpicked.has_bit(*p as u64) && !self.downloading.contains_key(p) &&
+                    priorities[*p as usize] != 0 && pickable_by(&self.pinned, *p, id)
+            });
+            // Take the first of the highest priority pieces
+            pieces.next().map(|first| {
+                pieces.fold(first, |best, p| if priorities[p as usize] >
+                    priorities[best as usize]
+                {
+                    p
+                } else {
+                    best
+                })
+            })
+        };
+        let piece = match piece {
+            Some(p) => p,
+            None => return None,
+        };
+        pin(&mut self.pinned, piece, id);
+        let blocks = (0..self.scale)
+            .map(|i| {
+                Downloading {
+                    offset: i * 16384,
+                    completed: false,
+                    requested: vec![Request::new(id)],
+                    from: None,
+                }
+            })
+            .collect();
+        self.downloading.insert(piece, blocks);
+        match self.picker {
+            PickerKind::Sequential(ref mut p) => p.completed(piece),
+            PickerKind::Rarest(ref mut p) => p.completed(piece),
+        }
+        set_picked(&mut self.unpicked, &mut self.remaining, &self.priorities, piece);
+        Some(piece)
+    }
+
+    /// Attempts to pick a block of a downloading piece which nobody has
+    /// been asked for, such as those of restored pieces.
+    fn pick_unrequested<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
         for (idx, dl) in self.downloading.iter_mut() {
-            if peer.pieces().has_bit(*idx as u64) {
+            if peer.pieces().has_bit(*idx as u64) && self.priorities[*idx as usize] != 0 &&
+                pickable_by(&self.pinned, *idx, peer.id())
+            {
                 let r = dl.iter_mut()
-                    .find(|r| !r.completed && r.requested.len() < MAX_DUP_REQS)
+                    .find(|r| !r.completed && r.requested.is_empty())
                     .map(|r| {
                         r.requested.push(Request::new(peer.id()));
                         Block::new(*idx, r.offset)
                     });
                 if r.is_some() {
+                    pin(&mut self.pinned, *idx, peer.id());
                     return r;
                 }
             }
@@ -161,17 +357,68 @@ impl Picker {
         None
     }
 
-    /// Marks a block as completed. Returns a result indicating if the block
-    /// was actually requested, the success value containing a bool indicating
-    /// if the block is complete, and a vector of peers from which the block
-    /// was requested(for cancellation).
-    pub fn completed(&mut self, b: Block) -> Result<(bool, Vec<usize>), ()> {
+    /// Returns true once every wanted piece has been picked and every block
+    /// of the downloading pieces has been requested from some peer.
+    fn check_endgame(&self) -> bool {
+        self.remaining == 0 &&
+            self.downloading.iter().all(|(idx, dl)| {
+                !self.wanted(*idx) ||
+                    dl.iter().all(|r| r.completed || !r.requested.is_empty())
+            })
+    }
+
+    fn count_remaining(&self) -> u32 {
+        (0..self.unpicked.len() as u32)
+            .filter(|p| !self.unpicked.has_bit(*p as u64) && self.wanted(*p))
+            .count() as u32
+    }
+
+    /// Picks an outstanding block which hasn't been requested from the peer,
+    /// preferring those with the fewest requests, then the lowest index.
+    fn pick_endgame<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let id = peer.id();
+        let mut best: Option<(usize, Block)> = None;
+        for (idx, dl) in self.downloading.iter() {
+            if !peer.pieces().has_bit(*idx as u64) || self.priorities[*idx as usize] == 0 ||
+                !pickable_by(&self.pinned, *idx, id)
+            {
+                continue;
+            }
+            for r in dl.iter() {
+                if r.completed || r.requested.iter().any(|req| req.peer == id) {
+                    continue;
+                }
+                let reqs = r.requested.len();
+                let better = best.as_ref()
+                    .map(|b| (reqs, *idx) < (b.0, b.1.index))
+                    .unwrap_or(true);
+                if better {
+                    best = Some((reqs, Block::new(*idx, r.offset)));
+                }
+            }
+        }
+        best.map(|(_, b)| {
+            pin(&mut self.pinned, b.index, id);
+            let dl = self.downloading.get_mut(&b.index).unwrap();
+            let r = dl.iter_mut().find(|r| r.offset == b.offset).unwrap();
+            r.requested.push(Request::new(id));
+            b
+        })
+    }
+
+    /// Marks a block sent by the peer at from as completed. Returns a result
+    /// indicating if the block was actually requested and not already received,
+    /// the success value containing a bool indicating if the piece is complete,
+    /// and a vector of peers from which the block was requested(for cancellation).
+    /// Completed pieces should have their hash checked, then be passed to
+    /// `verified` or `hash_failed`.
+    pub fn completed(&mut self, b: Block, from: IpAddr) -> Result<(bool, Vec<usize>), ()> {
         // Find the block in our downloading blocks, mark as true,
         // and extract the current peer list for return.
         let res = self.downloading.get_mut(&b.index)
             .and_then(|dl| dl.iter_mut()
-                             .find(|r| r.offset == b.offset)
-                             .map(|r| r.complete()))
+                             .find(|r| r.offset == b.offset && !r.completed)
+                             .map(|r| r.complete(from)))
             .map(|r| r.into_iter().map(|e| e.peer).collect());
 
         // If we've requested every single block for this piece and they're all complete, remove it
@@ -181,19 +428,144 @@ impl Picker {
             .map(|r| r.len() as u32 == scale && r.iter().all(|d| d.completed)).unwrap_or(false);
 
         if complete {
-            self.downloading.remove(&b.index);
+            let sources = self.downloading
+                .remove(&b.index)
+                .unwrap()
+                .into_iter()
+                .map(|d| (d.offset, d.from))
+                .collect();
+            self.verifying.insert(b.index, sources);
         }
 
         res.map(|r| (complete, r)).ok_or(())
     }
 
+    /// Marks a completed piece as having a valid hash, returning the offset
+    /// and source of each of its blocks.
+    pub fn verified(&mut self, idx: u32) -> Vec<(u32, Option<IpAddr>)> {
+        self.pinned.remove(&idx);
+        self.verifying.remove(&idx).unwrap_or_default()
+    }
+
+    /// Marks a completed piece as having an invalid hash, so it'll be
+    /// downloaded again. Returns the offset and source of each of its blocks.
+    pub fn hash_failed(&mut self, idx: u32) -> Vec<(u32, Option<IpAddr>)> {
+        let sources = self.verifying.remove(&idx).unwrap_or_default();
+        // A pinned piece may be downloaded again from any one peer
+        if let Some(p) = self.pinned.get_mut(&idx) {
+            *p = None;
+        }
+        self.invalidate_piece(idx);
+        sources
+    }
+
+    /// Pins a piece, so that it's downloaded entirely from whichever
+    /// peer is picked for it first, until its hash is valid.
+    pub fn pin(&mut self, idx: u32) {
+        self.pinned.entry(idx).or_insert(None);
+    }
+
+    /// Unpins the pieces a peer was downloading once it's gone, so that
+    /// another peer may be picked for their remaining blocks.
+    pub fn release_pinned(&mut self, id: usize) {
+        for (idx, p) in self.pinned.iter_mut() {
+            if *p != Some(id) {
+                continue;
+            }
+            *p = None;
+            if let Some(dl) = self.downloading.get_mut(idx) {
+                for d in dl.iter_mut() {
+                    d.requested.retain(|r| r.peer != id);
+                }
+            }
+            self.endgame = false;
+        }
+    }
+
+    /// Marks a piece picked with `pick_whole` as completed. Returns false
+    /// if the piece was already completed or abandoned.
+    pub fn completed_whole(&mut self, idx: u32) -> bool {
+        self.downloading.remove(&idx).is_some()
+    }
+
+    /// Abandons a piece picked with `pick_whole`, allowing it to be picked again.
+    pub fn abandon_whole(&mut self, idx: u32) {
+        if self.downloading.remove(&idx).is_some() {
+            self.invalidate_piece(idx);
+        }
+    }
+
+    /// Returns the offsets of the completed blocks of each partially
+    /// downloaded piece.
+    pub fn partial(&self) -> Vec<(u32, Vec<u32>)> {
+        self.downloading
+            .iter()
+            .map(|(idx, dl)| {
+                let done = dl.iter().filter(|d| d.completed).map(|d| d.offset).collect();
+                (*idx, done)
+            })
+            .filter(|p| !p.1.is_empty())
+            .collect()
+    }
+
+    /// Restores partially downloaded pieces, so that their completed
+    /// blocks won't be requested again. The remaining blocks are left
+    /// unrequested, to be picked before any new pieces.
+    pub fn restore_partial(&mut self, partial: &[(u32, Vec<u32>)]) {
+        for &(idx, ref done) in partial {
+            if idx as u64 >= self.unpicked.len() || self.unpicked.has_bit(idx as u64) {
+                continue;
+            }
+            let dl: Vec<_> = (0..self.scale)
+                .map(|i| {
+                    Downloading {
+                        offset: i * 16384,
+                        completed: done.contains(&(i * 16384)),
+                        requested: vec![],
+                        from: None,
+                    }
+                })
+                .collect();
+            // A fully completed piece would never be reported, so fetch it again
+            if dl.iter().all(|d| d.completed) || !dl.iter().any(|d| d.completed) {
+                continue;
+            }
+            match self.picker {
+                PickerKind::Sequential(ref mut p) => p.completed(idx),
+                PickerKind::Rarest(ref mut p) => p.completed(idx),
+            }
+            set_picked(&mut self.unpicked, &mut self.remaining, &self.priorities, idx);
+            self.downloading.insert(idx, dl);
+        }
+    }
+
+    /// Removes a peer's request for a block after it has been rejected,
+    /// allowing the block to be picked again.
+    pub fn rejected(&mut self, b: Block, peer: usize) {
+        let dl = self.downloading.get_mut(&b.index).and_then(|dl| {
+            dl.iter_mut().find(|r| r.offset == b.offset)
+        });
+        if let Some(d) = dl {
+            d.requested.retain(|r| r.peer != peer);
+            if d.requested.is_empty() && !d.completed {
+                self.endgame = false;
+            }
+        }
+    }
+
     /// Invalidates a piece
     pub fn invalidate_piece(&mut self, idx: u32) {
         match self.picker {
             PickerKind::Sequential(ref mut p) => p.incomplete(idx),
             PickerKind::Rarest(ref mut p) => p.incomplete(idx),
         }
-        self.unpicked.unset_bit(idx as u64);
+        if self.unpicked.has_bit(idx as u64) {
+            self.unpicked.unset_bit(idx as u64);
+            if self.wanted(idx) {
+                self.remaining += 1;
+            }
+        }
+        self.endgame = false;
     }
 
     pub fn piece_available(&mut self, idx: u32) {
@@ -236,6 +608,8 @@ impl Picker {
 
     pub fn refresh_picker(&mut self, pieces: &Bitfield) {
         self.unpicked = pieces.clone();
+        self.endgame = false;
+        self.remaining = self.count_remaining();
         self.picker = if self.is_sequential() {
             PickerKind::Sequential(sequential::Picker::new(&self.unpicked))
         } else {
@@ -244,6 +618,32 @@ impl Picker {
     }
 }
 
+/// Returns whether a piece may be picked for the peer, i.e. it
+/// isn't pinned to another peer.
+fn pickable_by(pinned: &HashMap<u32, Option<usize>>, piece: u32, id: usize) -> bool {
+    match pinned.get(&piece) {
+        Some(&Some(p)) => p == id,
+        _ => true,
+    }
+}
+
+/// Assigns a pinned piece to the peer it's been picked for.
+fn pin(pinned: &mut HashMap<u32, Option<usize>>, piece: u32, id: usize) {
+    if let Some(p) = pinned.get_mut(&piece) {
+        *p = Some(id);
+    }
+}
+
+/// Marks a piece as picked, keeping count of the wanted pieces left.
+fn set_picked(unpicked: &mut Bitfield, remaining: &mut u32, priorities: &[u8], idx: u32) {
+    if !unpicked.has_bit(idx as u64) {
+        unpicked.set_bit(idx as u64);
+        if priorities[idx as usize] != 0 {
+            *remaining -= 1;
+        }
+    }
+}
+
 impl Block {
     pub fn new(index: u32, offset: u32) -> Block {
         Block { index, offset }
@@ -260,8 +660,9 @@ impl Request {
 }
 
 impl Downloading {
-    fn complete(&mut self) -> Vec<Request> {
+    fn complete(&mut self, from: IpAddr) -> Vec<Request> {
         self.completed = true;
+        self.from = Some(from);
         mem::replace(&mut self.requested, Vec::with_capacity(0))
     }
 }
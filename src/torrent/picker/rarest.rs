@@ -107,12 +107,17 @@ impl Picker {
         self.swap_piece(idx, swap_idx);
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
+    pub fn pick<T: cio::CIO, F: Fn(u32) -> bool>(
+        &mut self,
+        peer: &Peer<T>,
+        wanted: F,
+    ) -> Option<u32> {
         // Find the first matching piece which is not complete,
         // and that the peer also has
         self.pieces.iter()
             .cloned()
             .filter(|p| self.piece_idx[*p as usize].status == PieceStatus::Incomplete)
+            .filter(|p| wanted(*p))
             .find(|p| peer.pieces().has_bit(*p as u64))
             .map(|p| {
                 if (self.piece_idx[p as usize].availability % 2) == 0 {
@@ -209,7 +214,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&peers[0]), None);
+        assert_eq!(picker.pick(&peers[0], |_| true), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[1].pieces_mut().set_bit(0);
@@ -219,13 +224,13 @@ mod tests {
         for peer in peers.iter() {
             picker.add_peer(peer);
         }
-        assert_eq!(picker.pick(&peers[1]), Some(2));
+        assert_eq!(picker.pick(&peers[1], |_| true), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&peers[1]), Some(0));
+        assert_eq!(picker.pick(&peers[1], |_| true), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&peers[1]), None);
-        assert_eq!(picker.pick(&peers[0]), None);
-        assert_eq!(picker.pick(&peers[2]), Some(1));
+        assert_eq!(picker.pick(&peers[1], |_| true), None);
+        assert_eq!(picker.pick(&peers[0], |_| true), None);
+        assert_eq!(picker.pick(&peers[2], |_| true), Some(1));
         picker.completed(1);
     }
 
@@ -239,7 +244,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&peers[0]), None);
+        assert_eq!(picker.pick(&peers[0], |_| true), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[0].pieces_mut().set_bit(1);
@@ -253,15 +258,15 @@ mod tests {
         }
         picker.remove_peer(&peers[0]);
 
-        assert_eq!(picker.pick(&peers[1]), Some(2));
+        assert_eq!(picker.pick(&peers[1], |_| true), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&peers[2]), Some(0));
+        assert_eq!(picker.pick(&peers[2], |_| true), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&peers[2]), Some(1));
+        assert_eq!(picker.pick(&peers[2], |_| true), Some(1));
         picker.completed(1);
 
-        assert_eq!(picker.pick(&peers[1]), None);
+        assert_eq!(picker.pick(&peers[1], |_| true), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&peers[1]), Some(1));
+        assert_eq!(picker.pick(&peers[1], |_| true), Some(1));
     }
 }
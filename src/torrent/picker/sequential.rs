@@ -37,9 +37,13 @@ impl Picker {
         }
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
+    pub fn pick<T: cio::CIO, F: Fn(u32) -> bool>(
+        &mut self,
+        peer: &Peer<T>,
+        wanted: F,
+    ) -> Option<u32> {
         self.pieces[self.piece_idx..].iter()
-            .find(|p| peer.pieces().has_bit(p.pos as u64))
+            .find(|p| wanted(p.pos) && peer.pieces().has_bit(p.pos as u64))
             .map(|p| p.pos)
 
             /*
@@ -144,19 +148,19 @@ mod tests {
         let b = Bitfield::new(3);
         let mut picker = Picker::new(&b);
         let mut peer = Peer::test_from_pieces(0, b);
-        assert_eq!(picker.pick(&peer), None);
+        assert_eq!(picker.pick(&peer, |_| true), None);
         peer.pieces_mut().set_bit(1);
-        assert_eq!(picker.pick(&peer), Some(1));
+        assert_eq!(picker.pick(&peer, |_| true), Some(1));
         peer.pieces_mut().set_bit(0);
-        assert_eq!(picker.pick(&peer), Some(0));
+        assert_eq!(picker.pick(&peer, |_| true), Some(0));
         picker.completed(0);
         picker.completed(1);
         peer.pieces_mut().set_bit(2);
-        assert_eq!(picker.pick(&peer), Some(2));
+        assert_eq!(picker.pick(&peer, |_| true), Some(2));
 
         picker.completed(2);
-        assert_eq!(picker.pick(&peer), None);
+        assert_eq!(picker.pick(&peer, |_| true), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&peer), Some(1));
+        assert_eq!(picker.pick(&peer, |_| true), Some(1));
     }
 }
@@ -1,12 +1,17 @@
 use super::{Block, Picker};
 use std::collections::HashMap;
 use std::cell::UnsafeCell;
+use std::net::IpAddr;
 use torrent::{Bitfield, Peer as TGPeer, Info};
 use rand::distributions::{IndependentSample, Range};
 use {rand, control};
 
 type TPeer = TGPeer<control::cio::test::TCIO>;
 
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
 struct Simulation {
     cfg: TestCfg,
     ticks: usize,
@@ -89,7 +94,7 @@ impl Simulation {
                         peer.requests.remove(b.ind_sample(&mut rng))
                     };
                     let ref mut received = self.peers()[req.peer];
-                    received.picker.completed(Block::new(req.piece, 0));
+                    received.picker.completed(Block::new(req.piece, 0), localhost());
                     received.data.pieces_mut().set_bit(req.piece as u64);
                     if received.data.pieces().complete() {
                         received.compl = Some(self.ticks);
@@ -249,10 +254,237 @@ fn test_seq_picker() {
     }
 
     for i in 0..10 {
-        assert_eq!(p.completed(Block::new(i, 0)), Ok((true, vec![0])));
+        assert_eq!(p.completed(Block::new(i, 0), localhost()), Ok((true, vec![0])));
     }
 
     p.invalidate_piece(5);
 
     assert_eq!(p.pick(&peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_pick_whole() {
+    let i = Info::with_pieces(10);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb);
+
+    assert_eq!(p.pick_whole(100), Some(0));
+    assert_eq!(p.pick(&peer), Some(Block::new(1, 0)));
+    assert_eq!(p.pick_whole(100), Some(2));
+
+    // Abandoned pieces may be picked by peers again
+    p.abandon_whole(0);
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+
+    assert!(p.completed_whole(2));
+    assert!(!p.completed_whole(2));
+}
+
+#[test]
+fn test_suggest_reject() {
+    let i = Info::with_pieces(10);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    peer.suggested_mut().push(5);
+
+    assert_eq!(p.pick(&peer), Some(Block::new(5, 0)));
+    // Once fully picked, the suggestion should be ignored
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+
+    p.rejected(Block::new(5, 0), 0);
+    assert_eq!(p.completed(Block::new(5, 0), localhost()), Ok((true, vec![])));
+}
+
+#[test]
+fn test_restore_partial() {
+    let mut i = Info::with_pieces(10);
+    i.piece_len = 16384 * 4;
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb);
+
+    for _ in 0..3 {
+        p.pick(&peer);
+    }
+    p.completed(Block::new(0, 0), localhost()).unwrap();
+    p.completed(Block::new(0, 32768), localhost()).unwrap();
+    let partial = p.partial();
+    assert_eq!(partial, vec![(0, vec![0, 32768])]);
+
+    let mut p = Picker::new_sequential(&i, &b);
+    p.restore_partial(&partial);
+    assert_eq!(p.partial(), partial);
+    // Missing blocks of the restored piece are picked before new ones
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 16384)));
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 49152)));
+    assert_eq!(p.pick(&peer), Some(Block::new(1, 0)));
+    assert_eq!(p.completed(Block::new(0, 16384), localhost()), Ok((false, vec![0])));
+    assert_eq!(p.completed(Block::new(0, 49152), localhost()), Ok((true, vec![0])));
+}
+
+#[test]
+fn test_priorities() {
+    let i = Info::with_pieces(4);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb);
+    p.set_priorities(vec![1, 0, 5, 3]);
+
+    // Higher priority pieces are picked first
+    assert_eq!(p.pick(&peer), Some(Block::new(2, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(3, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+    // and unwanted pieces never are
+    assert_eq!(p.pick_whole(100), None);
+    assert!(!p.wanted(1));
+}
+
+#[test]
+fn test_critical() {
+    let i = Info::with_pieces(10);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb.clone());
+    let other = TPeer::test_from_pieces(1, pb);
+    p.set_critical(vec![7, 3]);
+
+    assert_eq!(p.pick(&peer), Some(Block::new(7, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(3, 0)));
+    // Outstanding critical blocks are requested from other peers as well
+    assert_eq!(p.pick(&other), Some(Block::new(7, 0)));
+    assert_eq!(p.pick(&other), Some(Block::new(3, 0)));
+    let b = p.pick(&peer).unwrap();
+    assert!(b.index != 7 && b.index != 3);
+
+    assert_eq!(p.completed(Block::new(7, 0), localhost()), Ok((true, vec![0, 1])));
+}
+
+#[test]
+fn test_endgame() {
+    let i = Info::with_pieces(2);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(2);
+    for i in 0..2 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb.clone());
+    let other = TPeer::test_from_pieces(1, pb.clone());
+    let third = TPeer::test_from_pieces(2, pb);
+
+    assert!(p.pick(&peer).is_some());
+    assert!(p.pick(&peer).is_some());
+    // Blocks are never requested twice from the same peer
+    assert_eq!(p.pick(&peer), None);
+    // but once everything is requested, other peers are asked for them too
+    assert_eq!(p.pick(&other), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&third), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&third), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&other), Some(Block::new(1, 0)));
+
+    assert_eq!(p.completed(Block::new(0, 0), localhost()), Ok((true, vec![0, 1, 2])));
+    // A duplicate arriving late is reported as unrequested
+    assert_eq!(p.completed(Block::new(0, 0), localhost()), Err(()));
+    assert_eq!(p.completed(Block::new(1, 0), localhost()), Ok((true, vec![0, 2, 1])));
+}
+
+#[test]
+fn test_block_sources() {
+    let mut i = Info::with_pieces(2);
+    i.piece_len = 16384 * 2;
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(2);
+    for i in 0..2 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb);
+    let other: IpAddr = "10.0.0.1".parse().unwrap();
+
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 16384)));
+    p.completed(Block::new(0, 0), localhost()).unwrap();
+    p.completed(Block::new(0, 16384), other).unwrap();
+    let sources = vec![(0, Some(localhost())), (16384, Some(other))];
+    assert_eq!(p.hash_failed(0), sources);
+    // The failed piece is downloaded again
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+    assert_eq!(p.verified(0), vec![]);
+}
+
+#[test]
+fn test_endgame_skipped() {
+    let i = Info::with_pieces(3);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_rarest(&i, &b);
+    p.set_priorities(vec![3, 0, 3]);
+    let mut pb = Bitfield::new(3);
+    for i in 0..3 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb.clone());
+    let other = TPeer::test_from_pieces(1, pb);
+
+    assert!(p.pick(&peer).is_some());
+    assert!(p.pick(&peer).is_some());
+    // The skipped piece doesn't hold up the endgame
+    let b = p.pick(&other).unwrap();
+    assert!(b.index != 1);
+    // Pieces which become wanted again leave it
+    p.set_priorities(vec![3, 3, 3]);
+    assert_eq!(p.pick(&other), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_pinned() {
+    let mut i = Info::with_pieces(2);
+    i.piece_len = 16384 * 2;
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(2);
+    for i in 0..2 {
+        pb.set_bit(i);
+    }
+    let peer = TPeer::test_from_pieces(0, pb.clone());
+    let other = TPeer::test_from_pieces(1, pb);
+
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&other), Some(Block::new(0, 16384)));
+    p.completed(Block::new(0, 0), localhost()).unwrap();
+    p.completed(Block::new(0, 16384), localhost()).unwrap();
+    p.hash_failed(0);
+    p.pin(0);
+
+    // The piece is downloaded again entirely from whichever peer picks it first
+    assert_eq!(p.pick(&other), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&other), Some(Block::new(0, 16384)));
+
+    // and passed on to another peer if that one leaves
+    p.release_pinned(other.id());
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&other), Some(Block::new(1, 16384)));
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 16384)));
+}
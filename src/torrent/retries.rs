@@ -0,0 +1,69 @@
+//! Addresses of peers to reconnect to with different connection settings,
+//! e.g. without encryption. Entries expire and their number is capped, so
+//! that peers which come and go don't grow the set without bound.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Time after which an address may be retried again
+const EXPIRY_SECS: u64 = 30 * 60;
+/// Maximum number of addresses kept
+const MAX_ADDRS: usize = 512;
+
+pub struct Retries {
+    addrs: HashMap<SocketAddr, Instant>,
+}
+
+impl Retries {
+    pub fn new() -> Retries {
+        Retries { addrs: HashMap::new() }
+    }
+
+    /// Adds an address, returning false if it's already present or
+    /// there's no room for it.
+    pub fn insert(&mut self, addr: SocketAddr) -> bool {
+        let expiry = Duration::from_secs(EXPIRY_SECS);
+        self.addrs.retain(|_, added| added.elapsed() < expiry);
+        if self.addrs.len() >= MAX_ADDRS || self.addrs.contains_key(&addr) {
+            return false;
+        }
+        self.addrs.insert(addr, Instant::now());
+        true
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.addrs.contains_key(addr)
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.addrs.remove(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Retries, MAX_ADDRS};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_insert() {
+        let mut r = Retries::new();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert!(r.insert(addr));
+        assert!(!r.insert(addr));
+        assert!(r.contains(&addr));
+        r.remove(&addr);
+        assert!(!r.contains(&addr));
+        assert!(r.insert(addr));
+    }
+
+    #[test]
+    fn test_capped() {
+        let mut r = Retries::new();
+        for port in 0..MAX_ADDRS {
+            assert!(r.insert(SocketAddr::new("127.0.0.1".parse().unwrap(), port as u16)));
+        }
+        assert!(!r.insert("127.0.0.2:1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,320 @@
+//! Versioned encoding of torrent session files. Files written in an
+//! older layout are decoded as such and migrated to the current one.
+
+use bincode;
+use serde::de::DeserializeOwned;
+use super::{Bitfield, Info, Status, TorrentData};
+use super::info::File;
+use super::picker::DEFAULT_PRIORITY;
+
+/// Prefix of versioned session files. Unversioned files can't start
+/// with it, as it would be the length of an implausibly long name.
+const MAGIC: &'static [u8] = b"SYNS";
+/// Version of the current session layout
+const VERSION: u32 = 5;
+
+/// Session layout prior to versioning
+#[derive(Serialize, Deserialize)]
+struct TorrentDataV0 {
+    info: InfoV0,
+    pieces: Bitfield,
+    uploaded: u64,
+    downloaded: u64,
+    status: Status,
+    path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InfoV0 {
+    name: String,
+    announce: String,
+    piece_len: u32,
+    total_len: u64,
+    hashes: Vec<Vec<u8>>,
+    hash: [u8; 20],
+    files: Vec<File>,
+    private: bool,
+}
+
+impl From<TorrentDataV0> for TorrentData {
+    fn from(d: TorrentDataV0) -> TorrentData {
+        TorrentDataV1 {
+            info: InfoV1 {
+                name: d.info.name,
+                announce: d.info.announce,
+                piece_len: d.info.piece_len,
+                total_len: d.info.total_len,
+                hashes: d.info.hashes,
+                hash: d.info.hash,
+                files: d.info.files,
+                private: d.info.private,
+                announce_list: Vec::new(),
+                url_list: Vec::new(),
+                comment: None,
+                creator: None,
+                creation_date: None,
+            },
+            pieces: d.pieces,
+            uploaded: d.uploaded,
+            downloaded: d.downloaded,
+            status: d.status,
+            path: d.path,
+        }.into()
+    }
+}
+
+/// Session layout of version 1, adding the metainfo's optional fields
+#[derive(Serialize, Deserialize)]
+struct TorrentDataV1 {
+    info: InfoV1,
+    pieces: Bitfield,
+    uploaded: u64,
+    downloaded: u64,
+    status: Status,
+    path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InfoV1 {
+    name: String,
+    announce: String,
+    piece_len: u32,
+    total_len: u64,
+    hashes: Vec<Vec<u8>>,
+    hash: [u8; 20],
+    files: Vec<File>,
+    private: bool,
+    announce_list: Vec<Vec<String>>,
+    url_list: Vec<String>,
+    comment: Option<String>,
+    creator: Option<String>,
+    creation_date: Option<i64>,
+}
+
+impl From<TorrentDataV1> for TorrentData {
+    fn from(d: TorrentDataV1) -> TorrentData {
+        TorrentDataV2 {
+            info: Info {
+                name: d.info.name,
+                announce: d.info.announce,
+                piece_len: d.info.piece_len,
+                total_len: d.info.total_len,
+                hashes: d.info.hashes,
+                hash: d.info.hash,
+                files: d.info.files,
+                private: d.info.private,
+                announce_list: d.info.announce_list,
+                url_list: d.info.url_list,
+                comment: d.info.comment,
+                creator: d.info.creator,
+                creation_date: d.info.creation_date,
+                // The info dictionary is reencoded when needed
+                raw_info: Vec::new(),
+            },
+            pieces: d.pieces,
+            uploaded: d.uploaded,
+            downloaded: d.downloaded,
+            status: d.status,
+            path: d.path,
+        }.into()
+    }
+}
+
+/// Session layout of version 2, adding the raw info dictionary
+#[derive(Serialize, Deserialize)]
+struct TorrentDataV2 {
+    info: Info,
+    pieces: Bitfield,
+    uploaded: u64,
+    downloaded: u64,
+    status: Status,
+    path: Option<String>,
+}
+
+impl From<TorrentDataV2> for TorrentData {
+    fn from(d: TorrentDataV2) -> TorrentData {
+        TorrentDataV3 {
+            info: d.info,
+            pieces: d.pieces,
+            uploaded: d.uploaded,
+            downloaded: d.downloaded,
+            status: d.status,
+            path: d.path,
+            partial: Vec::new(),
+        }.into()
+    }
+}
+
+/// Session layout of version 3, adding partially downloaded pieces
+#[derive(Serialize, Deserialize)]
+struct TorrentDataV3 {
+    info: Info,
+    pieces: Bitfield,
+    uploaded: u64,
+    downloaded: u64,
+    status: Status,
+    path: Option<String>,
+    partial: Vec<(u32, Vec<u32>)>,
+}
+
+impl From<TorrentDataV3> for TorrentData {
+    fn from(d: TorrentDataV3) -> TorrentData {
+        let priorities = vec![DEFAULT_PRIORITY; d.info.files.len()];
+        TorrentDataV4 {
+            info: d.info,
+            pieces: d.pieces,
+            uploaded: d.uploaded,
+            downloaded: d.downloaded,
+            status: d.status,
+            path: d.path,
+            partial: d.partial,
+            priorities,
+        }.into()
+    }
+}
+
+/// Session layout of version 4, adding file priorities
+#[derive(Serialize, Deserialize)]
+struct TorrentDataV4 {
+    info: Info,
+    pieces: Bitfield,
+    uploaded: u64,
+    downloaded: u64,
+    status: Status,
+    path: Option<String>,
+    partial: Vec<(u32, Vec<u32>)>,
+    priorities: Vec<u8>,
+}
+
+impl From<TorrentDataV4> for TorrentData {
+    fn from(d: TorrentDataV4) -> TorrentData {
+        TorrentData {
+            info: d.info,
+            pieces: d.pieces,
+            uploaded: d.uploaded,
+            downloaded: d.downloaded,
+            wasted: 0,
+            status: d.status,
+            path: d.path,
+            partial: d.partial,
+            priorities: d.priorities,
+        }
+    }
+}
+
+/// Encodes torrent data in the current session layout.
+pub fn encode(d: &TorrentData) -> Result<Vec<u8>, bincode::Error> {
+    let mut data = MAGIC.to_vec();
+    data.extend(bincode::serialize(&VERSION, bincode::Infinite)?);
+    data.extend(bincode::serialize(d, bincode::Infinite)?);
+    Ok(data)
+}
+
+/// Decodes torrent data from the start of a session file in any known
+/// layout, leaving whatever follows it in `data`.
+pub fn decode(data: &mut &[u8]) -> Result<TorrentData, bincode::Error> {
+    if !data.starts_with(MAGIC) {
+        return read::<TorrentDataV0>(data).map(Into::into);
+    }
+    *data = &data[MAGIC.len()..];
+    let version: u32 = read(data)?;
+    match version {
+        VERSION => read(data),
+        4 => read::<TorrentDataV4>(data).map(Into::into),
+        3 => read::<TorrentDataV3>(data).map(Into::into),
+        2 => read::<TorrentDataV2>(data).map(Into::into),
+        1 => read::<TorrentDataV1>(data).map(Into::into),
+        v => Err(Box::new(bincode::ErrorKind::Custom(
+            format!("Unknown session version {}", v),
+        ))),
+    }
+}
+
+fn read<D: DeserializeOwned>(data: &mut &[u8]) -> Result<D, bincode::Error> {
+    bincode::deserialize_from(data, bincode::Infinite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, InfoV0, TorrentDataV0, MAGIC, VERSION};
+    use std::path::PathBuf;
+    use torrent::{Bitfield, Status, TorrentData};
+    use torrent::info::File;
+    use torrent::picker::DEFAULT_PRIORITY;
+    use bincode;
+
+    fn data_v0() -> TorrentDataV0 {
+        TorrentDataV0 {
+            info: InfoV0 {
+                name: "test".to_owned(),
+                announce: "http://localhost:8000/announce".to_owned(),
+                piece_len: 16384,
+                total_len: 10,
+                hashes: vec![vec![0u8; 20]],
+                hash: [1u8; 20],
+                files: vec![
+                    File {
+                        path: PathBuf::from("a"),
+                        length: 4,
+                    },
+                    File {
+                        path: PathBuf::from("b"),
+                        length: 6,
+                    },
+                ],
+                private: false,
+            },
+            pieces: Bitfield::new(1),
+            uploaded: 5,
+            downloaded: 10,
+            status: Status::Paused,
+            path: Some("/tmp".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_decode_v0() {
+        let mut data = bincode::serialize(&data_v0(), bincode::Infinite).unwrap();
+        data.extend_from_slice(b"stats");
+        let mut s = &data[..];
+        let d = decode(&mut s).unwrap();
+        assert_eq!(s, &b"stats"[..]);
+        assert_eq!(d.info.name, "test");
+        assert_eq!(d.info.announce, "http://localhost:8000/announce");
+        assert!(d.info.announce_list.is_empty());
+        assert!(d.info.raw_info.is_empty());
+        assert_eq!(d.uploaded, 5);
+        assert_eq!(d.downloaded, 10);
+        assert_eq!(d.wasted, 0);
+        assert_eq!(d.status, Status::Paused);
+        assert_eq!(d.path, Some("/tmp".to_owned()));
+        assert!(d.partial.is_empty());
+        assert_eq!(d.priorities, vec![DEFAULT_PRIORITY; 2]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut d: TorrentData = data_v0().into();
+        d.info.comment = Some("comment".to_owned());
+        d.partial = vec![(0, vec![1])];
+        d.priorities = vec![0, 5];
+        d.wasted = 3;
+        let mut data = encode(&d).unwrap();
+        assert!(data.starts_with(MAGIC));
+        data.extend_from_slice(b"stats");
+        let mut s = &data[..];
+        let d = decode(&mut s).unwrap();
+        assert_eq!(s, &b"stats"[..]);
+        assert_eq!(d.info.comment, Some("comment".to_owned()));
+        assert_eq!(d.partial, vec![(0, vec![1])]);
+        assert_eq!(d.priorities, vec![0, 5]);
+        assert_eq!(d.wasted, 3);
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let mut data = MAGIC.to_vec();
+        data.extend(bincode::serialize(&(VERSION + 1), bincode::Infinite).unwrap());
+        assert!(decode(&mut &data[..]).is_err());
+    }
+}
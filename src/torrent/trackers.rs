@@ -0,0 +1,440 @@
+//! Multitracker support(BEP 12). Trackers are grouped into tiers which
+//! are tried in order, with the order within a tier being shuffled.
+//! Once a tracker responds it's moved to the front of its tier.
+//! Trackers which fail are skipped until an exponentially growing
+//! backoff has elapsed.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rand::{self, Rng};
+
+use tracker::{self, ScrapeResponse, TrackerResponse};
+use super::{Info, TrackerStatus};
+
+/// Delay before retrying a tracker after its first failure
+const BACKOFF_BASE_SECS: u64 = 30;
+/// Longest delay before retrying a failing tracker
+const BACKOFF_MAX_SECS: u64 = 3600;
+
+pub struct Tracker {
+    pub url: String,
+    pub tier: usize,
+    pub status: TrackerStatus,
+    pub last_report: DateTime<Utc>,
+    /// Swarm statistics from the latest scrape or announce
+    pub stats: ScrapeResponse,
+    /// Number of consecutive failed announces
+    failures: u32,
+    /// Time before which the tracker shouldn't be announced to
+    retry: Option<Instant>,
+}
+
+pub struct Trackers {
+    /// Trackers ordered by tier, then preference
+    list: Vec<Tracker>,
+    /// The tracker currently being announced to and the event sent to it
+    pending: Option<(String, Option<tracker::Event>)>,
+    /// When the next regular announce is due
+    next: Option<Instant>,
+    /// Event which couldn't be sent since every tracker was failing
+    deferred: Option<tracker::Event>,
+}
+
+impl Trackers {
+    pub fn new(info: &Info) -> Trackers {
+        let mut list = Vec::new();
+        let mut rng = rand::thread_rng();
+        for (tier, mut urls) in info.tiers().into_iter().enumerate() {
+            rng.shuffle(&mut urls);
+            for url in urls {
+                list.push(Tracker::new(url, tier));
+            }
+        }
+        Trackers {
+            list,
+            pending: None,
+            next: None,
+            deferred: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<Tracker> {
+        self.list.iter()
+    }
+
+    /// Trackers which can be scraped, i.e. which aren't backing off
+    /// and haven't failed their latest announce.
+    pub fn scrapable<'a>(&'a self) -> Box<Iterator<Item = &'a Tracker> + 'a> {
+        let now = Instant::now();
+        Box::new(self.list.iter().filter(move |t| match t.status {
+            TrackerStatus::Failure(_) |
+            TrackerStatus::Error => false,
+            _ => t.ready(now),
+        }))
+    }
+
+    pub fn get(&self, url: &str) -> Option<&Tracker> {
+        self.list.iter().find(|t| t.url == url)
+    }
+
+    /// Whether or not an announce is in progress or
+    /// some tracker has responded successfully.
+    pub fn active(&self) -> bool {
+        self.pending.is_some() || self.list.iter().any(|t| t.status.ok())
+    }
+
+    /// Whether or not the regular announce interval has elapsed.
+    pub fn due(&self) -> bool {
+        self.pending.is_none() && self.next.map(|n| Instant::now() >= n).unwrap_or(false)
+    }
+
+    /// Starts an announce, returning the url of the first
+    /// tracker which should be contacted. If every tracker is
+    /// backing off the announce is retried once one can be used.
+    pub fn start(&mut self, event: Option<tracker::Event>) -> Option<String> {
+        self.next = None;
+        self.pending = None;
+        let event = event.or(self.deferred.take());
+        let url = self.announce_to(0, event);
+        if url.is_none() {
+            self.defer(event);
+        }
+        url
+    }
+
+    /// Stops announcing, returning the trackers which should
+    /// be sent a stopped event.
+    pub fn stop(&mut self) -> Vec<String> {
+        self.next = None;
+        self.pending = None;
+        self.deferred = None;
+        self.list
+            .iter()
+            .filter(|t| t.status.ok())
+            .map(|t| t.url.clone())
+            .collect()
+    }
+
+    /// Processes a tracker's response, returning the next
+    /// tracker to fail over to and the event to send it if the
+    /// announce failed.
+    pub fn handle_response(
+        &mut self,
+        url: &str,
+        resp: &tracker::Result<TrackerResponse>,
+    ) -> Option<(String, Option<tracker::Event>)> {
+        let idx = match self.list.iter().position(|t| t.url == url) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        {
+            let t = &mut self.list[idx];
+            t.last_report = Utc::now();
+            t.status = TrackerStatus::from_response(resp);
+            match *resp {
+                Ok(ref r) => {
+                    t.stats.complete = r.seeders;
+                    t.stats.incomplete = r.leechers;
+                    t.failures = 0;
+                    t.retry = None;
+                }
+                Err(_) => {
+                    t.failures += 1;
+                    t.retry = Some(Instant::now() + backoff(t.failures));
+                }
+            }
+        }
+        if resp.is_ok() {
+            self.promote(idx);
+        }
+        let event = match self.pending {
+            Some((ref u, event)) if u == url => event,
+            // Responses to stopped events or outdated announces
+            _ => return None,
+        };
+        self.pending = None;
+        match *resp {
+            Ok(ref r) => {
+                let interval = cmp::max(r.interval, r.min_interval.unwrap_or(0));
+                self.next = Some(Instant::now() + Duration::from_secs(interval as u64));
+                None
+            }
+            Err(_) => {
+                let next = self.announce_to(idx + 1, event);
+                if next.is_none() {
+                    self.defer(event);
+                }
+                next.map(|u| (u, event))
+            }
+        }
+    }
+
+    /// Records a tracker's scrape results, returning false if it doesn't exist.
+    pub fn set_stats(&mut self, url: &str, stats: ScrapeResponse) -> bool {
+        match self.list.iter_mut().find(|t| t.url == url) {
+            Some(t) => {
+                t.stats = stats;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Best known swarm statistics across all trackers.
+    pub fn stats(&self) -> ScrapeResponse {
+        self.list.iter().fold(ScrapeResponse::default(), |s, t| {
+            ScrapeResponse {
+                complete: cmp::max(s.complete, t.stats.complete),
+                incomplete: cmp::max(s.incomplete, t.stats.incomplete),
+                downloaded: cmp::max(s.downloaded, t.stats.downloaded),
+            }
+        })
+    }
+
+    /// Adds a tracker in a new tier, returning false if it already exists.
+    pub fn add(&mut self, url: String) -> bool {
+        if self.get(&url).is_some() {
+            return false;
+        }
+        let tier = self.list.last().map(|t| t.tier + 1).unwrap_or(0);
+        self.list.push(Tracker::new(url, tier));
+        true
+    }
+
+    /// Removes a tracker, returning false if it doesn't exist.
+    pub fn remove(&mut self, url: &str) -> bool {
+        let len = self.list.len();
+        self.list.retain(|t| t.url != url);
+        let pending = match self.pending {
+            Some((ref u, _)) => u == url,
+            None => false,
+        };
+        if pending {
+            // Retry at the next interval check rather than waiting on a
+            // response that will be ignored
+            self.pending = None;
+            self.next = Some(Instant::now());
+        }
+        self.list.len() != len
+    }
+
+    /// Announces to the first tracker from idx onwards which isn't backing off.
+    fn announce_to(&mut self, idx: usize, event: Option<tracker::Event>) -> Option<String> {
+        let now = Instant::now();
+        match self.list.iter_mut().skip(idx).find(|t| t.ready(now)) {
+            Some(t) => {
+                t.status = TrackerStatus::Updating;
+                self.pending = Some((t.url.clone(), event));
+                Some(t.url.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Keeps an event which couldn't be announced, scheduling
+    /// a retry for when the first tracker's backoff ends.
+    fn defer(&mut self, event: Option<tracker::Event>) {
+        if self.list.is_empty() {
+            return;
+        }
+        self.deferred = event;
+        self.next = Some(self.list.iter().filter_map(|t| t.retry).min().unwrap_or_else(
+            || {
+                Instant::now() + Duration::from_secs(BACKOFF_BASE_SECS)
+            },
+        ));
+    }
+
+    /// Moves a tracker to the front of its tier.
+    fn promote(&mut self, idx: usize) {
+        let tier = self.list[idx].tier;
+        let first = self.list.iter().position(|t| t.tier == tier).unwrap();
+        let t = self.list.remove(idx);
+        self.list.insert(first, t);
+    }
+}
+
+impl Tracker {
+    fn new(url: String, tier: usize) -> Tracker {
+        Tracker {
+            url,
+            tier,
+            status: TrackerStatus::NotContacted,
+            last_report: Utc::now(),
+            stats: ScrapeResponse::default(),
+            failures: 0,
+            retry: None,
+        }
+    }
+
+    /// Whether or not the tracker's backoff has elapsed.
+    fn ready(&self, now: Instant) -> bool {
+        self.retry.map(|r| r <= now).unwrap_or(true)
+    }
+
+    pub fn warning(&self) -> Option<String> {
+        match self.status {
+            TrackerStatus::Ok { ref warning, .. } => warning.clone(),
+            _ => None,
+        }
+    }
+
+    pub fn error(&self) -> Option<String> {
+        match self.status {
+            TrackerStatus::Failure(ref r) => Some(r.clone()),
+            TrackerStatus::Error => Some("Failed to query tracker for an unknown reason.".to_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// Delay before retrying a tracker which has failed the given number of
+/// times, jittered so trackers shared by many torrents aren't all retried
+/// at once.
+pub fn backoff(failures: u32) -> Duration {
+    let shift = cmp::min(failures.saturating_sub(1), 16);
+    let secs = cmp::min(BACKOFF_BASE_SECS << shift, BACKOFF_MAX_SECS);
+    let jitter = rand::random::<u64>() % (secs / 2 + 1);
+    Duration::from_secs(secs - secs / 4 + jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::{backoff, Trackers, BACKOFF_BASE_SECS, BACKOFF_MAX_SECS};
+    use torrent::{Info, TrackerStatus};
+    use tracker::{ErrorKind, Event, Result, ScrapeResponse, TrackerResponse};
+
+    fn tiers() -> Trackers {
+        let mut info = Info::with_pieces(1);
+        info.announce_list = vec![
+            vec!["http://a".to_owned(), "http://b".to_owned()],
+            vec!["http://c".to_owned()],
+        ];
+        Trackers::new(&info)
+    }
+
+    #[test]
+    fn test_failover() {
+        let mut t = tiers();
+        assert_eq!(t.len(), 3);
+        assert_eq!(t.iter().last().unwrap().url, "http://c");
+        let first = t.start(Some(Event::Started)).unwrap();
+        assert!(t.active());
+        assert!(!t.due());
+
+        // Failures move on to the next tracker, keeping the event
+        let err: Result<TrackerResponse> = Err(ErrorKind::Timeout.into());
+        let (second, ev) = t.handle_response(&first, &err).unwrap();
+        assert!(second != first && second != "http://c");
+        assert_eq!(ev, Some(Event::Started));
+        let (third, _) = t.handle_response(&second, &err).unwrap();
+        assert_eq!(third, "http://c");
+        assert!(t.get(&first).unwrap().error().is_some());
+        assert_eq!(t.scrapable().count(), 1);
+
+        // Responding trackers are promoted within their tier
+        assert!(t.handle_response(&third, &Ok(TrackerResponse::empty())).is_none());
+        assert!(t.get("http://c").unwrap().status.ok());
+        assert!(t.handle_response(&second, &Ok(TrackerResponse::empty())).is_none());
+        assert_eq!(t.iter().next().unwrap().url, second);
+        assert_eq!(t.stop().len(), 2);
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let mut t = tiers();
+        let mut url = t.start(None).unwrap();
+        let err: Result<TrackerResponse> = Err(ErrorKind::TrackerError("bad".to_owned()).into());
+        while let Some((next, _)) = t.handle_response(&url, &err) {
+            url = next;
+        }
+        assert!(!t.active());
+        assert_eq!(
+            t.get("http://c").unwrap().status,
+            TrackerStatus::Failure("bad".to_owned())
+        );
+        assert!(t.stop().is_empty());
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut t = tiers();
+        assert!(t.add("udp://d:1".to_owned()));
+        assert!(!t.add("udp://d:1".to_owned()));
+        assert_eq!(t.iter().last().unwrap().tier, 2);
+        let url = t.start(None).unwrap();
+        assert!(t.remove(&url));
+        assert!(!t.remove(&url));
+        assert!(t.due());
+        assert_eq!(t.len(), 3);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut t = tiers();
+        let url = t.start(None).unwrap();
+        let mut r = TrackerResponse::empty();
+        r.seeders = 3;
+        r.leechers = 7;
+        t.handle_response(&url, &Ok(r));
+        assert!(t.set_stats(
+            "http://c",
+            ScrapeResponse {
+                complete: 5,
+                incomplete: 2,
+                downloaded: 40,
+            },
+        ));
+        assert!(!t.set_stats("http://d", ScrapeResponse::default()));
+        assert_eq!(
+            t.stats(),
+            ScrapeResponse {
+                complete: 5,
+                incomplete: 7,
+                downloaded: 40,
+            }
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        for i in 1..40 {
+            let d = backoff(i);
+            assert!(d >= Duration::from_secs(BACKOFF_BASE_SECS * 3 / 4));
+            assert!(d <= Duration::from_secs(BACKOFF_MAX_SECS * 5 / 4));
+        }
+
+        // Failing trackers are skipped and the event kept until they can be retried
+        let mut t = tiers();
+        let mut url = t.start(Some(Event::Started)).unwrap();
+        let err: Result<TrackerResponse> = Err(ErrorKind::Timeout.into());
+        while let Some((next, _)) = t.handle_response(&url, &err) {
+            url = next;
+        }
+        assert!(!t.due());
+        assert!(t.start(None).is_none());
+        assert!(t.list.iter().all(|tr| tr.failures == 1));
+
+        t.list[2].retry = Some(Instant::now());
+        t.next = Some(Instant::now());
+        assert!(t.due());
+        assert_eq!(t.start(None), Some(t.list[2].url.clone()));
+        assert_eq!(t.pending.as_ref().unwrap().1, Some(Event::Started));
+
+        // A successful announce clears the backoff and respects min interval
+        let url = t.list[2].url.clone();
+        let mut r = TrackerResponse::empty();
+        r.interval = 10;
+        r.min_interval = Some(1000);
+        t.handle_response(&url, &Ok(r));
+        assert!(t.get(&url).unwrap().retry.is_none());
+        assert!(t.next.unwrap() > Instant::now() + Duration::from_secs(900));
+    }
+}
@@ -0,0 +1,207 @@
+//! Web seeds(BEP 19), HTTP servers hosting the torrent's files. Each acts
+//! as a peer with every piece, downloading one whole piece at a time with
+//! a ranged request for each file the piece spans.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::path::Path;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use torrent::Info;
+use torrent::trackers::backoff;
+use tracker::WebSeedRange;
+
+pub struct WebSeed {
+    pub url: String,
+    /// Id the seed's requests are made under in the picker
+    pub id: usize,
+    download: Option<Download>,
+    failures: u32,
+    retry: Option<Instant>,
+    downloaded_bytes: u64,
+    last_flush: DateTime<Utc>,
+}
+
+/// Piece being downloaded from a web seed
+struct Download {
+    index: u32,
+    data: Vec<u8>,
+    /// File URL, offset and length of each range left to request
+    ranges: VecDeque<(String, u64, u64)>,
+}
+
+impl WebSeed {
+    pub fn new(url: String, id: usize) -> WebSeed {
+        WebSeed {
+            url,
+            id,
+            download: None,
+            failures: 0,
+            retry: None,
+            downloaded_bytes: 0,
+            last_flush: Utc::now(),
+        }
+    }
+
+    /// Returns whether or not a piece can be downloaded from the seed.
+    pub fn idle(&self) -> bool {
+        self.download.is_none() && self.retry.map(|r| r <= Instant::now()).unwrap_or(true)
+    }
+
+    /// Stops downloading the current piece, returning its index.
+    pub fn reset(&mut self) -> Option<u32> {
+        self.download.take().map(|d| d.index)
+    }
+
+    /// Begins downloading a piece, returning the first request to make.
+    pub fn start(&mut self, tid: usize, index: u32, info: &Info) -> WebSeedRange {
+        let ranges = info.piece_disk_locs(index)
+            .into_iter()
+            .map(|loc| {
+                let url = file_url(&self.url, info, &loc.file);
+                (url, loc.offset, (loc.end - loc.start) as u64)
+            })
+            .collect();
+        self.download = Some(Download {
+            index,
+            data: Vec::with_capacity(info.piece_len(index) as usize),
+            ranges,
+        });
+        self.next_request(tid).unwrap()
+    }
+
+    /// Returns the next range of the piece being downloaded to request.
+    pub fn next_request(&mut self, tid: usize) -> Option<WebSeedRange> {
+        let seed = self.url.clone();
+        self.download
+            .as_mut()
+            .and_then(|d| d.ranges.pop_front())
+            .map(|(url, offset, len)| {
+                WebSeedRange {
+                    id: tid,
+                    seed,
+                    url,
+                    offset,
+                    len,
+                }
+            })
+    }
+
+    /// Handles a received range, returning the piece's index and
+    /// data once all of it has been received.
+    pub fn received(&mut self, data: Vec<u8>) -> Option<(u32, Vec<u8>)> {
+        self.downloaded_bytes += data.len() as u64;
+        let done = match self.download {
+            Some(ref mut d) => {
+                d.data.extend_from_slice(&data);
+                d.ranges.is_empty()
+            }
+            None => return None,
+        };
+        if done {
+            self.download.take().map(|d| (d.index, d.data))
+        } else {
+            None
+        }
+    }
+
+    /// Records a successfully downloaded piece, clearing any backoff.
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry = None;
+    }
+
+    /// Records a failure and backs off from the seed, returning the
+    /// index of the piece which was being downloaded.
+    pub fn failed(&mut self) -> Option<u32> {
+        self.failures += 1;
+        self.retry = Some(Instant::now() + backoff(self.failures));
+        self.reset()
+    }
+
+    pub fn get_rate(&mut self) -> u64 {
+        let dur = Utc::now()
+            .signed_duration_since(self.last_flush)
+            .num_milliseconds() as u64;
+        let db = mem::replace(&mut self.downloaded_bytes, 0);
+        self.last_flush = Utc::now();
+        if dur == 0 {
+            0
+        } else {
+            (1000 * db) / dur
+        }
+    }
+}
+
+/// Returns the URL of one of the torrent's files on a web seed. Single
+/// file torrents use the seed URL as is, unless it names a directory.
+fn file_url(base: &str, info: &Info, file: &Path) -> String {
+    let single = info.files.len() == 1 && info.files[0].path == Path::new(&info.name);
+    if single && !base.ends_with('/') {
+        return base.to_owned();
+    }
+    let mut url = base.to_owned();
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    let parts: Vec<String> = file.components()
+        .map(|c| {
+            utf8_percent_encode(&c.as_os_str().to_string_lossy(), PATH_SEGMENT_ENCODE_SET)
+                .to_string()
+        })
+        .collect();
+    url.push_str(&parts.join("/"));
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::{file_url, WebSeed};
+    use torrent::info::{File, Info};
+
+    fn info(files: Vec<(&str, usize)>) -> Info {
+        let mut info = Info::with_pieces(2);
+        info.name = "dir".to_owned();
+        info.total_len = files.iter().map(|f| f.1 as u64).sum();
+        info.files = files
+            .into_iter()
+            .map(|(p, length)| File { path: PathBuf::from(p), length })
+            .collect();
+        info
+    }
+
+    #[test]
+    fn test_file_url() {
+        let single = info(vec![("dir", 32768)]);
+        let p = Path::new("dir");
+        assert_eq!(file_url("http://a.com/f.iso", &single, p), "http://a.com/f.iso");
+        assert_eq!(file_url("http://a.com/", &single, p), "http://a.com/dir");
+
+        let multi = info(vec![("dir/a b", 10000), ("dir/c", 22768)]);
+        let p = Path::new("dir/a b");
+        assert_eq!(file_url("http://a.com/x", &multi, p), "http://a.com/x/dir/a%20b");
+        assert_eq!(file_url("http://a.com/x/", &multi, p), "http://a.com/x/dir/a%20b");
+    }
+
+    #[test]
+    fn test_piece_ranges() {
+        let multi = info(vec![("dir/a", 10000), ("dir/c", 22768)]);
+        let mut ws = WebSeed::new("http://a.com/".to_owned(), 0);
+        let r = ws.start(1, 0, &multi);
+        assert_eq!((r.url.as_str(), r.offset, r.len), ("http://a.com/dir/a", 0, 10000));
+        assert_eq!(ws.received(vec![0; 10000]), None);
+        let r = ws.next_request(1).unwrap();
+        assert_eq!((r.url.as_str(), r.offset, r.len), ("http://a.com/dir/c", 0, 6384));
+        let (idx, data) = ws.received(vec![0; 6384]).unwrap();
+        assert_eq!((idx, data.len()), (0, 16384));
+        assert!(ws.idle());
+
+        ws.start(1, 1, &multi);
+        assert_eq!(ws.failed(), Some(1));
+        assert!(!ws.idle());
+    }
+}
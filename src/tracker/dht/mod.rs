@@ -1,12 +1,14 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::net::SocketAddr;
 use std::io;
 use self::io::{Read, Write};
 use num::bigint::BigUint;
-use {amy, tracker, CONFIG};
+use {amy, ipfilter, tracker, CONFIG};
 use std::{thread, time};
 use slog::Logger;
 use std::fs::OpenOptions;
 use std::path::Path;
+use socket::proxy::UdpSocket;
+use config::ProxyClass;
 
 mod rt;
 mod proto;
@@ -16,12 +18,15 @@ type ID = BigUint;
 const BUCKET_MAX: usize = 8;
 const VERSION: &'static str = "SY";
 const SESSION_FILE: &'static str = "dht_data";
+const SESSION_FILE6: &'static str = "dht_data6";
 const MIN_BOOTSTRAP_BKTS: usize = 3;
 const TX_TIMEOUT_SECS: i64 = 20;
 
 pub struct Manager {
     id: usize,
     table: rt::RoutingTable,
+    /// Routing table of IPv6 nodes(BEP 32)
+    table6: rt::RoutingTable,
     dht_flush: time::Instant,
     sock: UdpSocket,
     buf: Vec<u8>,
@@ -30,49 +35,73 @@ pub struct Manager {
 
 impl Manager {
     pub fn new(reg: &amy::Registrar, l: Logger) -> io::Result<Manager> {
-        let sock = UdpSocket::bind(("0.0.0.0", CONFIG.dht.port))?;
+        let sock = UdpSocket::bind(CONFIG.dht.port, ProxyClass::Dht)?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
 
-        let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-        let mut data = Vec::new();
-        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
-            f.read_to_end(&mut data)?;
-        }
-        let table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
-            info!(l, "DHT table loaded from disk!");
-            t
-        } else {
-            info!(
-                l,
-                "DHT table could not be read from disk, creating new table!"
-            );
-            let mut t = rt::RoutingTable::new();
-            if let Some(addr) = CONFIG.dht.bootstrap_node {
+        let mut table = Manager::load_table(SESSION_FILE, &l)?;
+        let mut table6 = Manager::load_table(SESSION_FILE6, &l)?;
+        if let Some(addr) = CONFIG.dht.bootstrap_node {
+            let t = if addr.is_ipv4() { &mut table } else { &mut table6 };
+            if t.is_empty() {
                 info!(l, "Using bootstrap node!");
                 let (msg, _) = t.add_addr(addr.clone());
-                sock.send_to(&msg.encode(), addr)?;
+                send_msg(&sock, &l, &msg.encode(), addr);
             }
-            t
-        };
+        }
 
         Ok(Manager {
             table,
+            table6,
             sock,
             id,
-            buf: vec![0u8; 500],
+            buf: vec![0u8; 1500],
             dht_flush: time::Instant::now(),
             l,
         })
     }
 
+    fn load_table(file: &str, l: &Logger) -> io::Result<rt::RoutingTable> {
+        let p = Path::new(&CONFIG.disk.session[..]).join(file);
+        let mut data = Vec::new();
+        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+            f.read_to_end(&mut data)?;
+        }
+        if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
+            info!(l, "DHT table {} loaded from disk!", file);
+            Ok(t)
+        } else {
+            info!(
+                l,
+                "DHT table {} could not be read from disk, creating new table!",
+                file
+            );
+            Ok(rt::RoutingTable::new())
+        }
+    }
+
     pub fn init(&mut self) {
         debug!(self.l, "Initializing DHT nodes!");
-        for (q, a) in self.table.init() {
+        let mut reqs = self.table.init();
+        reqs.extend(self.table6.init());
+        for (q, a) in reqs {
             self.send_msg(&q.encode(), a);
         }
     }
 
+    /// Replaces the socket after the local address changed,
+    /// refreshing the routing tables from the new address.
+    pub fn rebind(&mut self, reg: &amy::Registrar) -> io::Result<()> {
+        let sock = UdpSocket::bind(CONFIG.dht.port, ProxyClass::Dht)?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        let _ = reg.deregister(&self.sock);
+        self.sock = sock;
+        self.id = id;
+        self.init();
+        Ok(())
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -81,17 +110,26 @@ impl Manager {
         let mut resps = Vec::new();
         loop {
             match self.sock.recv_from(&mut self.buf[..]) {
+                // Blocked nodes aren't answered or learned from
+                Ok((_, addr)) if ipfilter::blocked(&addr.ip()) => {
+                    trace!(self.l, "Ignoring msg from blocked address {:?}!", addr);
+                }
                 Ok((v, addr)) => {
                     trace!(self.l, "Processing msg from {:?}!", addr);
+                    let table = if addr.is_ipv4() {
+                        &mut self.table
+                    } else {
+                        &mut self.table6
+                    };
                     if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
-                        let resp = self.table.handle_req(req, addr).encode();
-                        self.send_msg(&resp, addr);
+                        let resp = table.handle_req(req, addr).encode();
+                        send_msg(&self.sock, &self.l, &resp, addr);
                     } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
-                        match self.table.handle_resp(resp, addr) {
+                        match table.handle_resp(resp, addr) {
                             Ok(r) => resps.push(r),
                             Err(q) => {
                                 for (req, a) in q {
-                                    self.send_msg(&req.encode(), a);
+                                    send_msg(&self.sock, &self.l, &req.encode(), a);
                                 }
                             }
                         }
@@ -117,46 +155,65 @@ impl Manager {
     }
 
     pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
-        for (req, a) in self.table.get_peers(tid, hash) {
+        let mut reqs = self.table.get_peers(tid, hash);
+        reqs.extend(self.table6.get_peers(tid, hash));
+        for (req, a) in reqs {
             self.send_msg(&req.encode(), a);
         }
     }
 
     pub fn add_addr(&mut self, addr: SocketAddr) {
-        self.table.add_addr(addr);
+        let (req, a) = if addr.is_ipv4() {
+            self.table.add_addr(addr)
+        } else {
+            self.table6.add_addr(addr)
+        };
+        self.send_msg(&req.encode(), a);
     }
 
     pub fn announce(&mut self, hash: [u8; 20]) {
-        for (req, a) in self.table.announce(hash) {
+        let mut reqs = self.table.announce(hash);
+        reqs.extend(self.table6.announce(hash));
+        for (req, a) in reqs {
             self.send_msg(&req.encode(), a);
         }
     }
 
     pub fn tick(&mut self) {
+        self.sock.tick();
         if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
             let data = self.table.serialize();
+            let data6 = self.table6.serialize();
             thread::spawn(move || {
-                let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-                if let Err(e) = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&p)
-                    .and_then(|mut f| f.write(&data[..]))
-                {
-                    // TODO: properly log
-                    println!("DHT serialization failed: {:?}!", e);
+                for &(file, ref data) in &[(SESSION_FILE, data), (SESSION_FILE6, data6)] {
+                    let p = Path::new(&CONFIG.disk.session[..]).join(file);
+                    if let Err(e) = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .open(&p)
+                        .and_then(|mut f| f.write(&data[..]))
+                    {
+                        // TODO: properly log
+                        println!("DHT serialization failed: {:?}!", e);
+                    }
                 }
             });
             self.dht_flush = time::Instant::now();
         }
-        for (req, a) in self.table.tick() {
+        let mut reqs = self.table.tick();
+        reqs.extend(self.table6.tick());
+        for (req, a) in reqs {
             self.send_msg(&req.encode(), a);
         }
     }
 
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
-        if let Err(e) = self.sock.send_to(msg, addr) {
-            warn!(self.l, "Failed to send message on UDP socket: {:?}", e);
-        }
+        send_msg(&self.sock, &self.l, msg, addr);
+    }
+}
+
+fn send_msg(sock: &UdpSocket, l: &Logger, msg: &[u8], addr: SocketAddr) {
+    if let Err(e) = sock.send_to(msg, addr) {
+        warn!(l, "Failed to send message on UDP socket: {:?}", e);
     }
 }
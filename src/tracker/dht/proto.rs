@@ -378,11 +378,7 @@ impl Response {
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::FindNode { id, nodes } => {
-                let mut data = Vec::new();
-                for node in nodes {
-                    data.extend(node.to_bytes())
-                }
-                args.insert(String::from("nodes"), BEncode::String(data));
+                encode_nodes(&mut args, nodes);
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::GetPeers { id, token, resp } => {
@@ -390,18 +386,14 @@ impl Response {
                 args.insert(String::from("token"), BEncode::String(token));
                 match resp {
                     PeerResp::Values(addrs) => {
-                        let mut data = Vec::new();
-                        for addr in addrs {
-                            data.extend_from_slice(&addr_to_bytes(&addr)[..]);
-                        }
-                        args.insert(String::from("values"), BEncode::String(data));
+                        let values = addrs
+                            .iter()
+                            .map(|addr| BEncode::String(addr_to_bytes(addr)))
+                            .collect();
+                        args.insert(String::from("values"), BEncode::List(values));
                     }
                     PeerResp::Nodes(nodes) => {
-                        let mut data = Vec::new();
-                        for node in nodes {
-                            data.extend(node.to_bytes())
-                        }
-                        args.insert(String::from("nodes"), BEncode::String(data));
+                        encode_nodes(&mut args, nodes);
                     }
                 }
             }
@@ -511,21 +503,13 @@ impl Response {
 
                 let kind =
                     if let Some(token) = r.remove("token").and_then(|b| b.to_bytes()) {
-                        if let Some(data) = r.remove("values").and_then(|b| b.to_bytes()) {
-                            let mut peers = Vec::new();
-                            for d in data.chunks(6) {
-                                peers.push(bytes_to_addr(d));
-                            }
+                        if let Some(values) = r.remove("values") {
                             ResponseKind::GetPeers {
                                 id,
                                 token,
-                                resp: PeerResp::Values(peers),
-                            }
-                        } else if let Some(ns) = r.remove("nodes").and_then(|b| b.to_bytes()) {
-                            let mut nodes = Vec::new();
-                            for n in ns.chunks(26) {
-                                nodes.push(Node::new(n));
+                                resp: PeerResp::Values(decode_values(values)),
                             }
+                        } else if let Some(nodes) = decode_nodes(&mut r) {
                             ResponseKind::GetPeers {
                                 id,
                                 token,
@@ -534,11 +518,7 @@ impl Response {
                         } else {
                             return Err(ErrorKind::InvalidResponse("Invalid BEncoded data(get_peers resp has no values/nodes fields)").into());
                         }
-                    } else if let Some(ns) = r.remove("nodes").and_then(|b| b.to_bytes()) {
-                        let mut nodes = Vec::new();
-                        for n in ns.chunks(26) {
-                            nodes.push(Node::new(n));
-                        }
+                    } else if let Some(nodes) = decode_nodes(&mut r) {
                         ResponseKind::FindNode { id, nodes }
                     } else {
                         ResponseKind::ID(id)
@@ -562,6 +542,8 @@ impl Response {
 }
 
 impl Node {
+    /// Decodes compact node info, 26 bytes for IPv4 nodes
+    /// or 38 bytes for IPv6 nodes.
     pub fn new(data: &[u8]) -> Node {
         let id = BigUint::from_bytes_be(&data[0..20]);
         Node {
@@ -571,15 +553,73 @@ impl Node {
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut data = self.id.to_bytes_be();
+        // IDs with leading zeros must still take up 20 bytes
+        let id = self.id.to_bytes_be();
+        let mut data = vec![0u8; 20usize.saturating_sub(id.len())];
+        data.extend_from_slice(&id);
         data.extend_from_slice(&addr_to_bytes(&self.addr)[..]);
         data
     }
 }
 
+/// Encodes nodes into the nodes and nodes6(BEP 32) fields by address family.
+fn encode_nodes(args: &mut BTreeMap<String, BEncode>, nodes: Vec<Node>) {
+    let mut data = Vec::new();
+    let mut data6 = Vec::new();
+    for node in nodes {
+        if node.addr.is_ipv4() {
+            data.extend(node.to_bytes())
+        } else {
+            data6.extend(node.to_bytes())
+        }
+    }
+    if !data.is_empty() || data6.is_empty() {
+        args.insert(String::from("nodes"), BEncode::String(data));
+    }
+    if !data6.is_empty() {
+        args.insert(String::from("nodes6"), BEncode::String(data6));
+    }
+}
+
+fn decode_nodes(r: &mut BTreeMap<String, BEncode>) -> Option<Vec<Node>> {
+    let ns = r.remove("nodes").and_then(|b| b.to_bytes());
+    let ns6 = r.remove("nodes6").and_then(|b| b.to_bytes());
+    if ns.is_none() && ns6.is_none() {
+        return None;
+    }
+    let mut nodes = Vec::new();
+    for n in ns.unwrap_or_default().chunks(26).filter(|n| n.len() == 26) {
+        nodes.push(Node::new(n));
+    }
+    for n in ns6.unwrap_or_default().chunks(38).filter(|n| n.len() == 38) {
+        nodes.push(Node::new(n));
+    }
+    Some(nodes)
+}
+
+fn decode_values(values: BEncode) -> Vec<SocketAddr> {
+    match values {
+        BEncode::List(l) => {
+            l.into_iter()
+                .filter_map(|b| b.to_bytes())
+                .filter(|d| d.len() == 6 || d.len() == 18)
+                .map(|d| bytes_to_addr(&d))
+                .collect()
+        }
+        // Some nodes send a single string of concatenated IPv4 peers
+        BEncode::String(data) => {
+            data.chunks(6)
+                .filter(|d| d.len() == 6)
+                .map(bytes_to_addr)
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Response, ResponseKind, Request};
+    use super::{Node, PeerResp, Response, ResponseKind, Request};
     use num::bigint::BigUint;
 
     #[test]
@@ -636,4 +676,36 @@ mod tests {
             String::from_utf8(r).unwrap()
         );
     }
+
+    #[test]
+    fn test_encode_decode_nodes6() {
+        let nodes = vec![
+            Node {
+                id: BigUint::from_bytes_be(b"abcdefghij0123456789"),
+                addr: "1.2.3.4:6881".parse().unwrap(),
+            },
+            Node {
+                id: BigUint::from_bytes_be(b"mnopqrstuvwxyz123456"),
+                addr: "[2001:db8::1]:6881".parse().unwrap(),
+            },
+        ];
+        let id = BigUint::from_bytes_be(b"abcdefghij0123456789");
+        let r = Response::find_node(b"aa".to_vec(), id.clone(), nodes.clone()).encode();
+        match Response::decode(&r).unwrap().kind {
+            ResponseKind::FindNode { nodes: n, .. } => {
+                assert_eq!(n.len(), 2);
+                assert_eq!(n[0].addr, nodes[0].addr);
+                assert_eq!(n[1].addr, nodes[1].addr);
+                assert_eq!(n[1].id, nodes[1].id);
+            }
+            _ => panic!("Should decode to FindNode!"),
+        }
+
+        let peers = vec!["1.2.3.4:6881".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap()];
+        let r = Response::peers(b"aa".to_vec(), id, b"tok".to_vec(), peers.clone()).encode();
+        match Response::decode(&r).unwrap().kind {
+            ResponseKind::GetPeers { resp: PeerResp::Values(p), .. } => assert_eq!(p, peers),
+            _ => panic!("Should decode to GetPeers!"),
+        }
+    }
 }
@@ -6,7 +6,7 @@ use num::bigint::BigUint;
 use rand::{self, Rng};
 use super::{proto, ID, BUCKET_MAX, MIN_BOOTSTRAP_BKTS, TX_TIMEOUT_SECS};
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-use {tracker, bincode};
+use {tracker, bincode, ipfilter};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RoutingTable {
@@ -95,8 +95,19 @@ impl RoutingTable {
         self.refresh_tokens()
     }
 
+    /// Deserializes a saved table, dropping nodes which have since been blocked.
     pub fn deserialize(data: &[u8]) -> Option<RoutingTable> {
-        bincode::deserialize(data).ok()
+        bincode::deserialize(data).ok().map(|mut t: RoutingTable| {
+            for b in t.buckets.iter_mut() {
+                b.nodes.retain(|n| !ipfilter::blocked(&n.addr.ip()));
+                b.queue.retain(|n| !ipfilter::blocked(&n.addr.ip()));
+            }
+            t
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.nodes.is_empty())
     }
 
     pub fn add_addr(&mut self, addr: SocketAddr) -> (proto::Request, SocketAddr) {
@@ -286,7 +297,8 @@ impl RoutingTable {
                     return Err(reqs);
                 }
                 self.get_node_mut(id1).update();
-                for node in nodes.drain(..) {
+                // Nodes of the other address family belong in the other table
+                for node in nodes.drain(..).filter(|n| n.addr.is_ipv4() == addr.is_ipv4()) {
                     if !self.contains_id(&node.id) {
                         let id = node.id.clone();
                         let addr = node.addr.clone();
@@ -341,11 +353,14 @@ impl RoutingTable {
                 }
 
                 if let proto::PeerResp::Values(ref mut addrs) = *pr {
-                    let mut r = tracker::TrackerResponse::empty();
-                    mem::swap(&mut r.peers, addrs);
-                    return Ok((torrent, Ok(r)));
+                    let mut peers = Vec::new();
+                    mem::swap(&mut peers, addrs);
+                    return Ok(tracker::Response::DHT {
+                        tid: torrent,
+                        peers,
+                    });
                 } else if let proto::PeerResp::Nodes(ref mut nodes) = *pr {
-                    for node in nodes.drain(..) {
+                    for node in nodes.drain(..).filter(|n| n.addr.is_ipv4() == addr.is_ipv4()) {
                         if !self.contains_id(&node.id) {
                             let id = node.id.clone();
                             let addr = node.addr.clone();
@@ -517,6 +532,9 @@ impl RoutingTable {
     }
 
     fn add_node(&mut self, node: Node) -> Result<(), ()> {
+        if ipfilter::blocked(&node.addr.ip()) {
+            return Err(());
+        }
         let idx = self.bucket_idx(&node.id);
         if self.buckets[idx].full() {
             if self.buckets[idx].could_hold(&self.id) {
@@ -1,5 +1,5 @@
 use std::collections::{HashSet, HashMap};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 use std::sync::{Arc, Mutex};
 use tracker::{Result, ResultExt, ErrorKind};
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -20,6 +20,14 @@ pub struct Resolver {
     marked: HashSet<usize>,
 }
 
+/// State of a query, which is resolved for both address families
+struct Lookup {
+    sender: Arc<Mutex<amy::Sender<QueryResponse>>>,
+    pending: u8,
+    done: bool,
+    v6: Option<Ipv6Addr>,
+}
+
 struct CSockWrapper(c_ares::Socket);
 
 impl AsRawFd for CSockWrapper {
@@ -88,21 +96,56 @@ impl Resolver {
         });
     }
 
+    /// Resolves the host, preferring IPv4 addresses and
+    /// only using IPv6 if the host has no IPv4 address.
     pub fn new_query(&mut self, id: usize, host: &str) {
-        // TODO: handle ipv6 too
-        let s = self.sender.clone();
-        self.chan.get_host_by_name(
-            host,
-            c_ares::AddressFamily::INET,
-            move |res| {
+        let lookup = Arc::new(Mutex::new(Lookup {
+            sender: self.sender.clone(),
+            pending: 2,
+            done: false,
+            v6: None,
+        }));
+        for family in vec![c_ares::AddressFamily::INET, c_ares::AddressFamily::INET6] {
+            let lookup = lookup.clone();
+            self.chan.get_host_by_name(host, family, move |res| {
                 let res = res.chain_err(|| ErrorKind::DNS).and_then(|ips| {
                     ips.addresses().next().ok_or(ErrorKind::DNS.into())
                 });
-                let resp = QueryResponse { id, res };
-                if s.lock().unwrap().send(resp).is_err() {
-                    // Other end was shutdown, ignore
+                lookup.lock().unwrap().resolved(id, res);
+            });
+        }
+    }
+}
+
+impl Lookup {
+    fn resolved(&mut self, id: usize, res: Result<IpAddr>) {
+        self.pending -= 1;
+        if self.done {
+            return;
+        }
+        let res = match res {
+            Ok(IpAddr::V6(ip)) => {
+                self.v6 = Some(ip);
+                if self.pending > 0 {
+                    return;
+                }
+                Ok(IpAddr::V6(ip))
+            }
+            Ok(ip) => Ok(ip),
+            Err(e) => {
+                if self.pending > 0 {
+                    return;
                 }
-            },
-        );
+                match self.v6 {
+                    Some(ip) => Ok(IpAddr::V6(ip)),
+                    None => Err(e),
+                }
+            }
+        };
+        self.done = true;
+        let resp = QueryResponse { id, res };
+        if self.sender.lock().unwrap().send(resp).is_err() {
+            // Other end was shutdown, ignore
+        }
     }
 }
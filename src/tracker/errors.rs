@@ -10,6 +10,11 @@ error_chain! {
             display("invalid tracker response: {}", r)
         }
 
+        HTTPStatus(s: u16) {
+            description("unexpected HTTP status")
+            display("unexpected HTTP status: {}", s)
+        }
+
         TrackerError(e: String) {
             description("tracker error response")
             display("tracker error: {}", e)
@@ -0,0 +1,152 @@
+//! Connection to an HTTP tracker, optionally over TLS and through a
+//! proxy. TLS records and the proxy handshake are exchanged with the
+//! socket as it becomes readable or writable, so the connection can be
+//! used like a plain nonblocking stream.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientSession, Session};
+use slog::Logger;
+use webpki_roots;
+
+use socket::TSocket;
+use socket::proxy::Handshake;
+use CONFIG;
+
+pub(super) struct Conn {
+    sock: TSocket,
+    tls: Option<ClientSession>,
+    proxy: Option<Handshake>,
+}
+
+impl Conn {
+    pub fn plain(sock: TSocket) -> Conn {
+        Conn {
+            sock,
+            tls: None,
+            proxy: None,
+        }
+    }
+
+    pub fn tls(sock: TSocket, config: &Arc<ClientConfig>, host: &str) -> Conn {
+        Conn {
+            sock,
+            tls: Some(ClientSession::new(config, host)),
+            proxy: None,
+        }
+    }
+
+    /// Tunnels the connection through a proxy, which must be connected to
+    /// rather than the tracker.
+    pub fn set_proxy(&mut self, hs: Handshake) {
+        self.proxy = Some(hs);
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.sock.connect(addr)
+    }
+
+    /// Progresses the proxy handshake, returning WouldBlock until it's done.
+    fn proxy_handshake(&mut self) -> io::Result<()> {
+        let done = match self.proxy {
+            Some(ref mut hs) => hs.drive(&mut self.sock.conn)?,
+            None => return Ok(()),
+        };
+        if !done {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, ""));
+        }
+        // Trackers only respond after the request is sent, so anything
+        // received along with the proxy reply is invalid.
+        if !self.proxy.take().unwrap().finish().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected data after proxy handshake",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.proxy_handshake()?;
+        let session = match self.tls {
+            Some(ref mut s) => s,
+            None => return self.sock.conn.read(buf),
+        };
+        loop {
+            // Return any data which has already been decrypted
+            match session.read(buf) {
+                Ok(0) => {}
+                res => return res,
+            }
+            // Handshake messages may need to be sent before more data arrives
+            write_tls(session, &mut self.sock.conn)?;
+            if session.read_tls(&mut self.sock.conn)? == 0 {
+                return Ok(0);
+            }
+            session.process_new_packets().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+            })?;
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.proxy_handshake()?;
+        match self.tls {
+            Some(ref mut s) => {
+                // Data is buffered by the session until the handshake completes
+                let amnt = s.write(buf)?;
+                write_tls(s, &mut self.sock.conn)?;
+                Ok(amnt)
+            }
+            None => self.sock.conn.write(buf),
+        }
+    }
+
+    /// Sends any TLS records which couldn't be written previously.
+    fn flush(&mut self) -> io::Result<()> {
+        self.proxy_handshake()?;
+        match self.tls {
+            Some(ref mut s) => write_tls(s, &mut self.sock.conn),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_tls(session: &mut ClientSession, conn: &mut TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        match session.write_tls(conn) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Creates the TLS configuration used for HTTPS trackers, trusting
+/// the Mozilla root certificates and any configured CA certificates.
+pub fn tls_config(l: &Logger) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store.add_server_trust_anchors(
+        &webpki_roots::TLS_SERVER_ROOTS,
+    );
+    for path in &CONFIG.trk.ca_certs {
+        let res = File::open(path).map_err(|_| ()).and_then(|f| {
+            config.root_store.add_pem_file(&mut BufReader::new(f))
+        });
+        match res {
+            Ok((added, _)) if added > 0 => {
+                debug!(l, "Loaded {} CA certificate(s) from {}", added, path);
+            }
+            _ => warn!(l, "Failed to load CA certificates from {}", path),
+        }
+    }
+    Arc::new(config)
+}
@@ -1,24 +1,32 @@
+mod conn;
 mod reader;
 mod writer;
 
-use tracker::{self, Announce, Response, TrackerResponse, Result, ResultExt, Error, ErrorKind, dns};
+use tracker::{self, Announce, Response, ScrapeBatch, ScrapeResponse, TrackerResponse,
+              WebSeedRange, Result, ResultExt, Error, ErrorKind, dns};
 use std::time::{Instant, Duration};
 use std::mem;
-use {PEER_ID, bencode, amy};
+use {CONFIG, PEER_ID, bencode, amy};
+use config::ProxyClass;
+use self::conn::Conn;
 use self::writer::Writer;
 use self::reader::Reader;
-use std::io;
+use std::io::{self, Write};
+use std::sync::Arc;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use url::percent_encoding::percent_encode_byte;
 use url::Url;
 use slog::Logger;
 use socket::TSocket;
+use socket::proxy;
+use rustls::ClientConfig;
 
 const TIMEOUT_MS: u64 = 2500;
 
 pub struct Handler {
     reg: amy::Registrar,
+    tls: Arc<ClientConfig>,
     connections: HashMap<usize, Tracker>,
     l: Logger,
 }
@@ -30,7 +38,8 @@ enum Event {
 }
 
 struct Tracker {
-    torrent: usize,
+    kind: Kind,
+    url: String,
     last_updated: Instant,
     state: TrackerState,
 }
@@ -38,23 +47,83 @@ struct Tracker {
 enum TrackerState {
     Error,
     ResolvingDNS {
-        sock: TSocket,
+        sock: Conn,
         req: Vec<u8>,
         port: u16,
     },
-    Writing { sock: TSocket, writer: Writer },
-    Reading { sock: TSocket, reader: Reader },
-    Complete(TrackerResponse),
+    Writing { sock: Conn, writer: Writer },
+    Reading { sock: Conn, reader: Reader },
+    Complete(Reader),
+}
+
+enum Kind {
+    Announce(usize),
+    Scrape(Vec<(usize, [u8; 20])>),
+    /// Range of a file from a torrent's web seed
+    WebSeed { tid: usize, offset: u64, len: u64 },
+}
+
+impl Tracker {
+    fn handle(&mut self, event: Event) -> Result<Option<Reader>> {
+        // Web seeds should only send the range requested
+        let max = match self.kind {
+            Kind::WebSeed { len, .. } => Some(len as usize),
+            _ => None,
+        };
+        self.state.handle(event, max)
+    }
+
+    /// Produces a response from the body returned by the server.
+    fn response(&self, data: Result<Reader>) -> Response {
+        let url = self.url.clone();
+        match self.kind {
+            Kind::WebSeed { tid, offset, len } => {
+                let resp = data.and_then(|r| match r.status() {
+                    // Servers may ignore the range and send the whole file, which
+                    // is only of use if it's the range, larger bodies are aborted
+                    Some(s) if s == 206 || (s == 200 && offset == 0) => {
+                        let body = r.consume();
+                        if body.len() as u64 != len {
+                            bail!(ErrorKind::InvalidResponse("Range has the wrong length"));
+                        }
+                        Ok(body)
+                    }
+                    Some(s) => bail!(ErrorKind::HTTPStatus(s)),
+                    None => bail!(ErrorKind::InvalidResponse("Invalid HTTP status line")),
+                });
+                Response::WebSeed { tid, url, resp }
+            }
+            Kind::Announce(tid) => {
+                let resp = data.map(Reader::consume).and_then(|d| {
+                    let content = bencode::decode_buf(&d).chain_err(|| {
+                        ErrorKind::InvalidResponse("Invalid BEncoded response!")
+                    })?;
+                    TrackerResponse::from_bencode(content)
+                });
+                Response::Tracker { tid, url, resp }
+            }
+            Kind::Scrape(ref torrents) => {
+                let resp = data.map(Reader::consume).and_then(|d| {
+                    let content = bencode::decode_buf_binary_keys(&d).chain_err(|| {
+                        ErrorKind::InvalidResponse("Invalid BEncoded response!")
+                    })?;
+                    ScrapeResponse::from_bencode(content, torrents)
+                });
+                Response::Scrape { url, resp }
+            }
+        }
+    }
 }
 
 impl TrackerState {
-    fn new(sock: TSocket, req: Vec<u8>, port: u16) -> TrackerState {
+    fn new(sock: Conn, req: Vec<u8>, port: u16) -> TrackerState {
         TrackerState::ResolvingDNS { sock, req, port }
     }
 
-    fn handle(&mut self, event: Event) -> Result<Option<TrackerResponse>> {
+    /// Handles an event, failing if the response body exceeds `max` bytes.
+    fn handle(&mut self, event: Event, max: Option<usize>) -> Result<Option<Reader>> {
         let s = mem::replace(self, TrackerState::Error);
-        let n = s.next(event)?;
+        let n = s.next(event, max)?;
         if let TrackerState::Complete(r) = n {
             Ok(Some(r))
         } else {
@@ -63,7 +132,7 @@ impl TrackerState {
         }
     }
 
-    fn next(self, event: Event) -> Result<TrackerState> {
+    fn next(self, event: Event, max: Option<usize>) -> Result<TrackerState> {
         match (self, event) {
             (TrackerState::ResolvingDNS { sock, req, port }, Event::DNSResolved(r)) => {
                 let addr = SocketAddr::new(r.res?, port);
@@ -71,17 +140,23 @@ impl TrackerState {
                 Ok(TrackerState::Writing {
                     sock,
                     writer: Writer::new(req),
-                }.next(Event::Writable)?)
+                }.next(Event::Writable, max)?)
             }
+            // Readable events progress the proxy handshake, if there is one
             (TrackerState::Writing {
                  mut sock,
                  mut writer,
              },
-             Event::Writable) => {
-                match writer.writable(&mut sock.conn)? {
+             Event::Writable) |
+            (TrackerState::Writing {
+                 mut sock,
+                 mut writer,
+             },
+             Event::Readable) => {
+                match writer.writable(&mut sock)? {
                     Some(()) => {
                         let r = Reader::new();
-                        Ok(TrackerState::Reading { sock, reader: r }.next(Event::Readable)?)
+                        Ok(TrackerState::Reading { sock, reader: r }.next(Event::Readable, max)?)
                     }
                     None => Ok(TrackerState::Writing { sock, writer }),
                 }
@@ -91,17 +166,21 @@ impl TrackerState {
                  mut reader,
              },
              Event::Readable) => {
-                if reader.readable(&mut sock.conn)? {
-                    let data = reader.consume();
-                    let content = bencode::decode_buf(&data).chain_err(|| {
-                        ErrorKind::InvalidResponse("Invalid BEncoded response!")
-                    })?;
-                    let resp = TrackerResponse::from_bencode(content)?;
-                    Ok(TrackerState::Complete(resp))
+                if reader.readable(&mut sock, max)? {
+                    Ok(TrackerState::Complete(reader))
                 } else {
                     Ok(TrackerState::Reading { sock, reader })
                 }
             }
+            // TLS records may still need to be sent after the request was written
+            (TrackerState::Reading { mut sock, reader }, Event::Writable) => {
+                match sock.flush() {
+                    Err(ref e) if e.kind() != io::ErrorKind::WouldBlock => {
+                        bail!(ErrorKind::IO)
+                    }
+                    _ => Ok(TrackerState::Reading { sock, reader }),
+                }
+            }
             (s @ TrackerState::Writing { .. }, _) => Ok(s),
             (s @ TrackerState::Reading { .. }, _) => Ok(s),
             (s @ TrackerState::ResolvingDNS { .. }, _) => Ok(s),
@@ -114,6 +193,7 @@ impl Handler {
     pub fn new(reg: &amy::Registrar, l: Logger) -> io::Result<Handler> {
         Ok(Handler {
             reg: reg.try_clone()?,
+            tls: conn::tls_config(&l),
             connections: HashMap::new(),
             l,
         })
@@ -136,9 +216,9 @@ impl Handler {
         debug!(self.l, "Received a DNS resp for {:?}", id);
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
-            match trk.state.handle(Event::DNSResolved(resp)) {
+            match trk.handle(Event::DNSResolved(resp)) {
                 Ok(_) => None,
-                Err(e) => Some((trk.torrent, Err(e))),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -152,9 +232,9 @@ impl Handler {
     pub fn writable(&mut self, id: usize) -> Option<Response> {
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
-            match trk.state.handle(Event::Writable) {
+            match trk.handle(Event::Writable) {
                 Ok(_) => None,
-                Err(e) => Some((trk.torrent, Err(e))),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -168,17 +248,17 @@ impl Handler {
     pub fn readable(&mut self, id: usize) -> Option<Response> {
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
-            match trk.state.handle(Event::Readable) {
+            match trk.handle(Event::Readable) {
                 Ok(Some(r)) => {
                     debug!(
                         self.l,
-                        "Announce response received for {:?} succesfully",
+                        "Tracker response received for {:?} succesfully",
                         id
                     );
-                    Some(((trk.torrent, Ok(r))))
+                    Some(trk.response(Ok(r)))
                 }
                 Ok(None) => None,
-                Err(e) => Some((trk.torrent, Err(e))),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -196,8 +276,8 @@ impl Handler {
             |id, trk| if trk.last_updated.elapsed() >
                 Duration::from_millis(TIMEOUT_MS)
             {
-                resps.push((trk.torrent, Err(ErrorKind::Timeout.into())));
-                debug!(l, "Announce {:?} timed out", id);
+                resps.push(trk.response(Err(ErrorKind::Timeout.into())));
+                debug!(l, "Tracker request {:?} timed out", id);
                 false
             } else {
                 true
@@ -214,35 +294,92 @@ impl Handler {
         dns: &mut dns::Resolver,
     ) -> Result<()> {
         debug!(self.l, "Received a new announce req for {:?}", url);
-        let mut http_req = Vec::with_capacity(50);
-        // Encode GET req
-        http_req.extend_from_slice(b"GET ");
-
-        // Encode the URL
-        http_req.extend_from_slice(url.path().as_bytes());
-        http_req.extend_from_slice("?".as_bytes());
-        append_query_pair(&mut http_req, "info_hash", &encode_param(&req.hash));
-        append_query_pair(&mut http_req, "peer_id", &encode_param(&PEER_ID[..]));
-        append_query_pair(&mut http_req, "uploaded", &req.uploaded.to_string());
-        append_query_pair(&mut http_req, "downloaded", &req.downloaded.to_string());
-        append_query_pair(&mut http_req, "left", &req.left.to_string());
-        append_query_pair(&mut http_req, "compact", "1");
-        append_query_pair(&mut http_req, "port", &req.port.to_string());
+        let mut query = Vec::with_capacity(50);
+        append_query_pair(&mut query, "info_hash", &encode_param(&req.hash));
+        append_query_pair(&mut query, "peer_id", &encode_param(&PEER_ID[..]));
+        append_query_pair(&mut query, "uploaded", &req.uploaded.to_string());
+        append_query_pair(&mut query, "downloaded", &req.downloaded.to_string());
+        append_query_pair(&mut query, "left", &req.left.to_string());
+        append_query_pair(&mut query, "compact", "1");
+        append_query_pair(&mut query, "port", &req.port.to_string());
         if let Some(nw) = req.num_want {
-            append_query_pair(&mut http_req, "numwant", &nw.to_string());
+            append_query_pair(&mut query, "numwant", &nw.to_string());
         }
         match req.event {
             Some(tracker::Event::Started) => {
-                append_query_pair(&mut http_req, "event", "started");
+                append_query_pair(&mut query, "event", "started");
             }
             Some(tracker::Event::Stopped) => {
-                append_query_pair(&mut http_req, "event", "started");
+                append_query_pair(&mut query, "event", "stopped");
             }
             Some(tracker::Event::Completed) => {
-                append_query_pair(&mut http_req, "event", "completed");
+                append_query_pair(&mut query, "event", "completed");
             }
             None => {}
         }
+        let kind = Kind::Announce(req.id);
+        self.new_request(url, url.path(), &query, &[], req.url, kind, dns)
+    }
+
+    pub fn new_scrape(
+        &mut self,
+        req: ScrapeBatch,
+        url: &Url,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        debug!(self.l, "Received a new scrape req for {:?}", url);
+        let path = scrape_path(url.path()).ok_or::<Error>(
+            ErrorKind::InvalidRequest(
+                format!("Tracker does not support scraping!"),
+            ).into(),
+        )?;
+        let mut query = Vec::with_capacity(50);
+        for &(_, ref hash) in &req.torrents {
+            append_query_pair(&mut query, "info_hash", &encode_param(hash));
+        }
+        let kind = Kind::Scrape(req.torrents);
+        self.new_request(url, &path, &query, &[], req.url, kind, dns)
+    }
+
+    pub fn new_webseed(
+        &mut self,
+        req: WebSeedRange,
+        url: &Url,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        debug!(self.l, "Received a new web seed req for {:?}", url);
+        let range = format!("bytes={}-{}", req.offset, req.offset + req.len - 1);
+        // The connection isn't reused, so don't wait for the server to time it out
+        let headers = [("Range", range.as_str()), ("Connection", "close")];
+        let query = url.query().unwrap_or("").as_bytes().to_owned();
+        let kind = Kind::WebSeed {
+            tid: req.id,
+            offset: req.offset,
+            len: req.len,
+        };
+        self.new_request(url, url.path(), &query, &headers, req.seed, kind, dns)
+    }
+
+    fn new_request(
+        &mut self,
+        url: &Url,
+        path: &str,
+        query: &[u8],
+        headers: &[(&str, &str)],
+        trk_url: String,
+        kind: Kind,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        let mut http_req = Vec::with_capacity(50 + query.len());
+        // Encode GET req
+        http_req.extend_from_slice(b"GET ");
+
+        // Encode the URL
+        http_req.extend_from_slice(path.as_bytes());
+        if !query.is_empty() {
+            http_req.extend_from_slice("?".as_bytes());
+            http_req.extend_from_slice(query);
+        }
 
         // Encode HTTP protocol
         http_req.extend_from_slice(b" HTTP/1.1\r\n");
@@ -253,30 +390,71 @@ impl Handler {
                 format!("Tracker announce url has no host!"),
             ).into(),
         )?;
-        let port = url.port().unwrap_or(80);
+        let port = url.port_or_known_default().unwrap_or(80);
         http_req.extend_from_slice(host.as_bytes());
         http_req.extend_from_slice(b"\r\n");
+        for &(k, v) in headers {
+            http_req.extend_from_slice(k.as_bytes());
+            http_req.extend_from_slice(b": ");
+            http_req.extend_from_slice(v.as_bytes());
+            http_req.extend_from_slice(b"\r\n");
+        }
         // Encode empty line to terminate request
         http_req.extend_from_slice(b"\r\n");
 
         // Setup actual connection and start DNS query
-        let (id, sock) = TSocket::new_v4(&self.reg).chain_err(|| ErrorKind::IO)?;
+        let (id, sock) = TSocket::new(&self.reg).chain_err(|| ErrorKind::IO)?;
+        let mut sock = if url.scheme() == "https" {
+            Conn::tls(sock, &self.tls, host)
+        } else {
+            Conn::plain(sock)
+        };
+        let class = match kind {
+            Kind::WebSeed { .. } => ProxyClass::WebSeeds,
+            _ => ProxyClass::Trackers,
+        };
+        // The proxy resolves the server's hostname, so connect to it directly
+        let state = if CONFIG.proxy.enabled(class) {
+            let target = proxy::Target::Host(host.to_owned(), port);
+            let (addr, hs) = proxy::connect(target).chain_err(|| ErrorKind::IO)?;
+            sock.set_proxy(hs);
+            sock.connect(addr).chain_err(|| ErrorKind::IO)?;
+            TrackerState::Writing {
+                sock,
+                writer: Writer::new(http_req),
+            }
+        } else {
+            debug!(self.l, "Dispatching DNS req, id {:?}", id);
+            dns.new_query(id, host);
+            TrackerState::new(sock, http_req, port)
+        };
         self.connections.insert(
             id,
             Tracker {
                 last_updated: Instant::now(),
-                torrent: req.id,
-                state: TrackerState::new(sock, http_req, port),
+                kind,
+                url: trk_url,
+                state,
             },
         );
 
-        debug!(self.l, "Dispatching DNS req, id {:?}", id);
-        dns.new_query(id, host);
-
         Ok(())
     }
 }
 
+/// Converts an announce path to its scrape equivalent, following the
+/// convention of replacing "announce" at the start of the last path
+/// component with "scrape". Trackers whose path doesn't follow it
+/// don't support scraping.
+fn scrape_path(path: &str) -> Option<String> {
+    let idx = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    if path[idx..].starts_with("announce") {
+        Some(format!("{}scrape{}", &path[..idx], &path[idx + 8..]))
+    } else {
+        None
+    }
+}
+
 fn append_query_pair(s: &mut Vec<u8>, k: &str, v: &str) {
     s.extend_from_slice(k.as_bytes());
     s.extend_from_slice("=".as_bytes());
@@ -291,3 +469,16 @@ fn encode_param(data: &[u8]) -> String {
     }
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::scrape_path;
+
+    #[test]
+    fn test_scrape_path() {
+        assert_eq!(scrape_path("/announce"), Some("/scrape".to_owned()));
+        assert_eq!(scrape_path("/x/announce.php"), Some("/x/scrape.php".to_owned()));
+        assert_eq!(scrape_path("/announce/x"), None);
+        assert_eq!(scrape_path("/a"), None);
+    }
+}
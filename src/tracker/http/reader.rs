@@ -1,4 +1,4 @@
-use std::io;
+use std::{cmp, io, str};
 use std::mem;
 use tracker::errors::{Result, ErrorKind};
 
@@ -6,6 +6,9 @@ pub(super) struct Reader {
     data: Vec<u8>,
     idx: usize,
     state: ReadState,
+    /// Status line of the response, collected until its end
+    status: Vec<u8>,
+    status_done: bool,
 }
 
 enum ReadState {
@@ -59,11 +62,26 @@ impl Reader {
             data: vec![0; 75],
             idx: 0,
             state: ReadState::ParsingHeaderN1,
+            status: Vec::new(),
+            status_done: false,
         }
     }
 
-    pub fn readable<R: io::Read>(&mut self, conn: &mut R) -> Result<bool> {
-        while let ReadRes::Again = self.read(conn)? {}
+    /// Returns the status code of the response, if it had a valid status line.
+    pub fn status(&self) -> Option<u16> {
+        str::from_utf8(&self.status)
+            .ok()
+            .and_then(|l| l.split(' ').nth(1))
+            .and_then(|c| c.parse().ok())
+    }
+
+    /// Reads what's available, failing once the body exceeds `max` bytes.
+    pub fn readable<R: io::Read>(&mut self, conn: &mut R, max: Option<usize>) -> Result<bool> {
+        while let ReadRes::Again = self.read(conn)? {
+            if self.state.ready() && max.map(|m| self.idx > m).unwrap_or(false) {
+                return Err(ErrorKind::InvalidResponse("Response body is too large").into());
+            }
+        }
         Ok(self.state.ready())
     }
 
@@ -76,6 +94,12 @@ impl Reader {
                     self.idx += v;
                 } else {
                     for i in 0..v {
+                        if !self.status_done {
+                            match self.data[i] {
+                                b'\r' | b'\n' => self.status_done = true,
+                                b => self.status.push(b),
+                            }
+                        }
                         if self.state.handle(self.data[i]) {
                             self.data = self.data.split_off(i + 1);
                             self.idx = v - (i + 1);
@@ -84,7 +108,9 @@ impl Reader {
                     }
                 }
                 if self.idx == self.data.len() {
-                    self.data.resize(self.idx + 30, 0);
+                    // Grow geometrically, since bodies may be large
+                    let len = self.idx + cmp::max(self.idx, 30);
+                    self.data.resize(len, 0);
                 }
                 Ok(ReadRes::Again)
             }
@@ -114,7 +140,7 @@ mod tests {
         let mut r = Reader::new();
         let data = "SomeHeader: Foo\r\nConnection: Close\r\n\r\n";
         let mut c = Cursor::new(data);
-        assert_eq!(r.readable(&mut c).unwrap(), true);
+        assert_eq!(r.readable(&mut c, None).unwrap(), true);
         assert_eq!(r.consume(), Vec::<u8>::new());
     }
 
@@ -123,7 +149,35 @@ mod tests {
         let mut r = Reader::new();
         let data = "SomeHeader: Foo\r\nConnection: C";
         let mut c = Cursor::new(data);
-        assert_eq!(r.readable(&mut c).is_err(), true);
+        assert_eq!(r.readable(&mut c, None).is_err(), true);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut r = Reader::new();
+        let data = "HTTP/1.1 206 Partial Content\r\nConnection: Close\r\n\r\nhello";
+        let mut c = Cursor::new(data);
+        assert_eq!(r.readable(&mut c, None).unwrap(), true);
+        assert_eq!(r.status(), Some(206));
+        assert_eq!(r.consume(), b"hello");
+
+        let mut r = Reader::new();
+        let mut c = Cursor::new("SomeHeader: Foo\r\n\r\n");
+        assert_eq!(r.readable(&mut c, None).unwrap(), true);
+        assert_eq!(r.status(), None);
+    }
+
+    #[test]
+    fn test_max_body() {
+        let data = "SomeHeader: Foo\r\n\r\nhello";
+        let mut r = Reader::new();
+        let mut c = Cursor::new(data);
+        assert_eq!(r.readable(&mut c, Some(5)).unwrap(), true);
+        assert_eq!(r.consume(), b"hello");
+
+        let mut r = Reader::new();
+        let mut c = Cursor::new(data);
+        assert!(r.readable(&mut c, Some(4)).is_err());
     }
 
     #[test]
@@ -131,7 +185,7 @@ mod tests {
         let mut r = Reader::new();
         let data = "SomeHeader: Foo\r\nConnection: Close\r\n\r\nhello world spam";
         let mut c = Cursor::new(data);
-        assert_eq!(r.readable(&mut c).unwrap(), true);
+        assert_eq!(r.readable(&mut c, None).unwrap(), true);
         assert_eq!(r.consume(), b"hello world spam");
     }
 }
@@ -4,18 +4,18 @@ mod errors;
 mod dns;
 mod dht;
 
-use std::collections::VecDeque;
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::{result, io};
 
-use byteorder::{BigEndian, ReadBytesExt};
 use slog::Logger;
 use url::Url;
 
 pub use self::errors::{Result, ResultExt, Error, ErrorKind};
 use torrent::Torrent;
-use bencode::BEncode;
+use bencode::{self, BEncode};
 use control::cio;
+use util::bytes_to_addr;
 use handle;
 use amy;
 use {CONFIG, LOG};
@@ -26,6 +26,8 @@ pub struct Tracker {
     dns_res: amy::Receiver<dns::QueryResponse>,
     http: http::Handler,
     queue: VecDeque<Announce>,
+    /// Torrents waiting to be scraped, grouped by tracker url
+    scrapes: HashMap<String, Vec<(usize, [u8; 20])>>,
     udp: udp::Handler,
     dht: dht::Manager,
     dns: dns::Resolver,
@@ -37,9 +39,13 @@ pub struct Tracker {
 #[derive(Debug)]
 pub enum Request {
     Announce(Announce),
+    Scrape(Scrape),
     GetPeers(GetPeers),
     AddNode(SocketAddr),
     DHTAnnounce([u8; 20]),
+    WebSeed(WebSeedRange),
+    /// Rebind the UDP and DHT sockets after the local address changed
+    Rebind,
     Shutdown,
 }
 
@@ -56,6 +62,32 @@ pub struct Announce {
     event: Option<Event>,
 }
 
+#[derive(Debug)]
+pub struct Scrape {
+    id: usize,
+    url: String,
+    hash: [u8; 20],
+}
+
+/// Scrape of several torrents on the same tracker
+#[derive(Debug)]
+pub struct ScrapeBatch {
+    url: String,
+    torrents: Vec<(usize, [u8; 20])>,
+}
+
+/// Request for a byte range of a file from a web seed
+#[derive(Debug)]
+pub struct WebSeedRange {
+    pub id: usize,
+    /// URL of the web seed, which identifies it in the response
+    pub seed: String,
+    /// URL of the file the range is in
+    pub url: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
 #[derive(Debug)]
 pub struct GetPeers {
     pub id: usize,
@@ -63,24 +95,57 @@ pub struct GetPeers {
 }
 
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Event {
     Started,
     Stopped,
     Completed,
 }
 
-pub type Response = (usize, Result<TrackerResponse>);
+#[derive(Debug)]
+pub enum Response {
+    /// Result of an announce to one of a torrent's trackers
+    Tracker {
+        tid: usize,
+        url: String,
+        resp: Result<TrackerResponse>,
+    },
+    /// Result of scraping a batch of torrents on a tracker
+    Scrape {
+        url: String,
+        resp: Result<Vec<(usize, ScrapeResponse)>>,
+    },
+    /// Peers found through the DHT
+    DHT { tid: usize, peers: Vec<SocketAddr> },
+    /// Data requested from a web seed
+    WebSeed {
+        tid: usize,
+        url: String,
+        resp: Result<Vec<u8>>,
+    },
+}
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub peers: Vec<SocketAddr>,
     pub interval: u32,
+    /// Minimum interval between regular announces, if given
+    pub min_interval: Option<u32>,
     pub leechers: u32,
     pub seeders: u32,
+    pub warning: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScrapeResponse {
+    pub complete: u32,
+    pub incomplete: u32,
+    pub downloaded: u32,
 }
 
 const POLL_INT_MS: usize = 1000;
+/// Most info hashes a UDP tracker will accept in one scrape
+const MAX_SCRAPE_HASHES: usize = 74;
 
 impl Tracker {
     pub fn new(
@@ -105,6 +170,7 @@ impl Tracker {
             dns_res,
             timer,
             queue: VecDeque::new(),
+            scrapes: HashMap::new(),
             shutting_down: false,
         }
     }
@@ -152,6 +218,12 @@ impl Tracker {
         while let Ok(r) = self.ch.recv() {
             match r {
                 Request::Announce(req) => self.handle_announce(req),
+                Request::Scrape(req) => {
+                    // Batched and dispatched on the next tick
+                    self.scrapes.entry(req.url).or_insert_with(Vec::new).push(
+                        (req.id, req.hash),
+                    );
+                }
                 Request::GetPeers(gp) => {
                     debug!(self.l, "Handling dht peer find req!");
                     self.dht.get_peers(gp.id, gp.hash);
@@ -164,6 +236,12 @@ impl Tracker {
                     debug!(self.l, "Handling dht node addition req!");
                     self.dht.announce(hash);
                 }
+                Request::WebSeed(req) => self.handle_webseed(req),
+                Request::Rebind => {
+                    if let Err(e) = self.rebind() {
+                        error!(self.l, "Failed to rebind sockets: {}", e);
+                    }
+                }
                 Request::Shutdown => {
                     return Err(());
                 }
@@ -172,6 +250,12 @@ impl Tracker {
         Ok(())
     }
 
+    fn rebind(&mut self) -> io::Result<()> {
+        let reg = self.poll.get_registrar()?;
+        self.udp.rebind(&reg)?;
+        self.dht.rebind(&reg)
+    }
+
     fn handle_announce(&mut self, req: Announce) {
         debug!(self.l, "Handling announce request!");
         if self.udp.active_requests() + self.http.active_requests() >
@@ -180,9 +264,10 @@ impl Tracker {
             self.queue.push_back(req);
         } else {
             let id = req.id;
+            let url = req.url.clone();
             let response = if let Ok(url) = Url::parse(&req.url) {
                 match url.scheme() {
-                    "http" => self.http.new_announce(req, &url, &mut self.dns),
+                    "http" | "https" => self.http.new_announce(req, &url, &mut self.dns),
                     "udp" => self.udp.new_announce(req, &url, &mut self.dns),
                     s => Err(
                         ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s))
@@ -195,8 +280,77 @@ impl Tracker {
                 )
             };
             if let Err(e) = response {
-                self.send_response((id, Err(e)));
+                self.send_response(Response::Tracker {
+                    tid: id,
+                    url,
+                    resp: Err(e),
+                });
+            }
+        }
+    }
+
+    fn handle_scrape(&mut self, req: ScrapeBatch) {
+        debug!(self.l, "Handling scrape request!");
+        let url = req.url.clone();
+        let response = if let Ok(url) = Url::parse(&req.url) {
+            match url.scheme() {
+                "http" | "https" => self.http.new_scrape(req, &url, &mut self.dns),
+                "udp" => self.udp.new_scrape(req, &url, &mut self.dns),
+                s => Err(
+                    ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s))
+                        .into(),
+                ),
+            }
+        } else {
+            Err(
+                ErrorKind::InvalidRequest(format!("Invalid url: {}", req.url)).into(),
+            )
+        };
+        if let Err(e) = response {
+            self.send_response(Response::Scrape { url, resp: Err(e) });
+        }
+    }
+
+    fn handle_webseed(&mut self, req: WebSeedRange) {
+        debug!(self.l, "Handling web seed request!");
+        let (tid, seed) = (req.id, req.seed.clone());
+        let response = match Url::parse(&req.url) {
+            Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {
+                self.http.new_webseed(req, url, &mut self.dns)
+            }
+            _ => Err(
+                ErrorKind::InvalidRequest(format!("Invalid web seed url: {}", req.url)).into(),
+            ),
+        };
+        if let Err(e) = response {
+            self.send_response(Response::WebSeed {
+                tid,
+                url: seed,
+                resp: Err(e),
+            });
+        }
+    }
+
+    /// Dispatches pending scrapes, each tracker receiving as few
+    /// requests as possible while respecting the connection limit.
+    fn dispatch_scrapes(&mut self) {
+        let urls: Vec<_> = self.scrapes.keys().cloned().collect();
+        for url in urls {
+            if self.udp.active_requests() + self.http.active_requests() >
+                CONFIG.net.max_open_announces
+            {
+                return;
+            }
+            let mut torrents = self.scrapes.remove(&url).unwrap();
+            if torrents.len() > MAX_SCRAPE_HASHES {
+                let rest = torrents.split_off(MAX_SCRAPE_HASHES);
+                self.scrapes.insert(url.clone(), rest);
             }
+            let req = ScrapeBatch {
+                url: url.clone(),
+                torrents,
+            };
+            self.handle_scrape(req);
         }
     }
 
@@ -226,6 +380,7 @@ impl Tracker {
         for r in self.http.tick().into_iter().chain(self.udp.tick().into_iter()) {
             self.send_response(r);
         }
+        self.dispatch_scrapes();
 
         self.dns.tick();
         self.dht.tick();
@@ -275,10 +430,14 @@ impl Tracker {
 }
 
 impl Request {
-    pub fn new_announce<T: cio::CIO>(torrent: &Torrent<T>, event: Option<Event>) -> Request {
+    pub fn new_announce<T: cio::CIO>(
+        torrent: &Torrent<T>,
+        url: String,
+        event: Option<Event>,
+    ) -> Request {
         Request::Announce(Announce {
             id: torrent.id(),
-            url: torrent.info().announce.clone(),
+            url,
             hash: torrent.info().hash,
             port: CONFIG.port,
             uploaded: torrent.uploaded(),
@@ -295,20 +454,33 @@ impl Request {
         })
     }
 
-    pub fn started<T: cio::CIO>(torrent: &Torrent<T>) -> Request {
-        Request::new_announce(torrent, Some(Event::Started))
+    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>, url: String) -> Request {
+        Request::new_announce(torrent, url, Some(Event::Stopped))
     }
 
-    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Request {
-        Request::new_announce(torrent, Some(Event::Stopped))
+    pub fn scrape<T: cio::CIO>(torrent: &Torrent<T>, url: String) -> Request {
+        Request::Scrape(Scrape {
+            id: torrent.id(),
+            url,
+            hash: torrent.info().hash,
+        })
     }
+}
 
-    pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Request {
-        Request::new_announce(torrent, Some(Event::Completed))
+impl Response {
+    pub fn tracker(a: &Announce, resp: Result<TrackerResponse>) -> Response {
+        Response::Tracker {
+            tid: a.id,
+            url: a.url.clone(),
+            resp,
+        }
     }
 
-    pub fn interval<T: cio::CIO>(torrent: &Torrent<T>) -> Request {
-        Request::new_announce(torrent, None)
+    pub fn scrape(s: &ScrapeBatch, resp: Result<Vec<(usize, ScrapeResponse)>>) -> Response {
+        Response::Scrape {
+            url: s.url.clone(),
+            resp,
+        }
     }
 }
 
@@ -317,8 +489,10 @@ impl TrackerResponse {
         TrackerResponse {
             peers: vec![],
             interval: 900,
+            min_interval: None,
             leechers: 0,
             seeders: 0,
+            warning: None,
         }
     }
 
@@ -335,10 +509,16 @@ impl TrackerResponse {
         let mut resp = TrackerResponse::empty();
         match d.remove("peers") {
             Some(BEncode::String(ref data)) => {
-                for p in data.chunks(6) {
-                    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                    let socket = SocketAddrV4::new(ip, (&p[4..]).read_u16::<BigEndian>().unwrap());
-                    resp.peers.push(SocketAddr::V4(socket));
+                for p in data.chunks(6).filter(|p| p.len() == 6) {
+                    resp.peers.push(bytes_to_addr(p));
+                }
+            }
+            // Non compact responses are still used by some trackers for IPv6 peers
+            Some(BEncode::List(peers)) => {
+                for p in peers {
+                    if let Some(addr) = p.to_dict().and_then(parse_peer) {
+                        resp.peers.push(addr);
+                    }
                 }
             }
             _ => {
@@ -347,6 +527,11 @@ impl TrackerResponse {
                 );
             }
         };
+        if let Some(BEncode::String(ref data)) = d.remove("peers6") {
+            for p in data.chunks(18).filter(|p| p.len() == 18) {
+                resp.peers.push(bytes_to_addr(p));
+            }
+        }
         match d.remove("interval") {
             Some(BEncode::Int(ref i)) => {
                 resp.interval = *i as u32;
@@ -357,10 +542,77 @@ impl TrackerResponse {
                 );
             }
         };
+        if let Some(BEncode::Int(i)) = d.remove("min interval") {
+            resp.min_interval = Some(i as u32);
+        }
+        resp.warning = d.remove("warning message").and_then(|w| w.to_string());
+        if let Some(BEncode::Int(i)) = d.remove("complete") {
+            resp.seeders = i as u32;
+        }
+        if let Some(BEncode::Int(i)) = d.remove("incomplete") {
+            resp.leechers = i as u32;
+        }
+        Ok(resp)
+    }
+}
+
+impl ScrapeResponse {
+    /// Parses an HTTP scrape response, which should be decoded with
+    /// binary keys since files are keyed by info hash. Torrents the
+    /// tracker doesn't know about are omitted.
+    pub fn from_bencode(
+        data: BEncode,
+        torrents: &[(usize, [u8; 20])],
+    ) -> Result<Vec<(usize, ScrapeResponse)>> {
+        let mut d = data.to_dict().ok_or(ErrorKind::InvalidResponse(
+            "Scrape response must be a dictionary type!",
+        ))?;
+        if let Some(BEncode::String(data)) = d.remove("failure reason") {
+            let reason = String::from_utf8(data).chain_err(|| {
+                ErrorKind::InvalidResponse("Failure reason must be UTF8!")
+            })?;
+            return Err(ErrorKind::TrackerError(reason).into());
+        }
+        let mut files = d.remove("files").and_then(|f| f.to_dict()).ok_or(
+            ErrorKind::InvalidResponse(
+                "Scrape response must have files field!",
+            ),
+        )?;
+        let mut resp = Vec::new();
+        for &(id, ref hash) in torrents {
+            let mut f = match files.remove(&bencode::binary_key(hash)).and_then(
+                |f| f.to_dict(),
+            ) {
+                Some(f) => f,
+                None => continue,
+            };
+            let mut field = |k: &str| f.remove(k).and_then(|v| v.to_int()).unwrap_or(0) as u32;
+            resp.push((
+                id,
+                ScrapeResponse {
+                    complete: field("complete"),
+                    incomplete: field("incomplete"),
+                    downloaded: field("downloaded"),
+                },
+            ));
+        }
         Ok(resp)
     }
 }
 
+fn parse_peer(mut d: BTreeMap<String, BEncode>) -> Option<SocketAddr> {
+    let ip = d.remove("ip").and_then(|b| b.to_string()).and_then(
+        |s| s.parse::<IpAddr>().ok(),
+    );
+    let port = d.remove("port").and_then(|b| b.to_int());
+    match (ip, port) {
+        (Some(ip), Some(port)) if port > 0 && port <= 65535 => {
+            Some(SocketAddr::new(ip, port as u16))
+        }
+        _ => None,
+    }
+}
+
 pub fn start(creg: &mut amy::Registrar) -> io::Result<handle::Handle<Response, Request>> {
     let poll = amy::Poller::new()?;
     let mut reg = poll.get_registrar()?;
@@ -376,3 +628,62 @@ pub fn start(creg: &mut amy::Registrar) -> io::Result<handle::Handle<Response, R
     });
     Ok(ch)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ScrapeResponse, TrackerResponse};
+    use bencode;
+
+    #[test]
+    fn test_response_peers() {
+        let data = b"d8:intervali900e5:peers6:\x01\x02\x03\x04\x1a\xe16:peers618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e";
+        let r = TrackerResponse::from_bencode(bencode::decode_buf(&data[..]).unwrap()).unwrap();
+        assert_eq!(
+            r.peers,
+            vec![
+                "1.2.3.4:6881".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+
+        let data = b"d8:intervali900e5:peersld2:ip11:2001:db8::14:porti6881eeee";
+        let r = TrackerResponse::from_bencode(bencode::decode_buf(&data[..]).unwrap()).unwrap();
+        assert_eq!(r.peers, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_response_intervals() {
+        let data = b"d8:intervali1800e12:min intervali900e5:peers0:15:warning message4:slowe";
+        let r = TrackerResponse::from_bencode(bencode::decode_buf(&data[..]).unwrap()).unwrap();
+        assert_eq!(r.interval, 1800);
+        assert_eq!(r.min_interval, Some(900));
+        assert_eq!(r.warning, Some("slow".to_owned()));
+    }
+
+    #[test]
+    fn test_scrape_response() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"d5:filesd20:");
+        data.extend_from_slice(&[0xffu8; 20]);
+        data.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let content = bencode::decode_buf_binary_keys(&data).unwrap();
+        let r = ScrapeResponse::from_bencode(content, &[(1, [0xff; 20]), (2, [0; 20])]).unwrap();
+        assert_eq!(
+            r,
+            vec![
+                (
+                    1,
+                    ScrapeResponse {
+                        complete: 5,
+                        incomplete: 10,
+                        downloaded: 50,
+                    },
+                ),
+            ]
+        );
+
+        let data = b"d14:failure reason4:nopee";
+        let content = bencode::decode_buf_binary_keys(&data[..]).unwrap();
+        assert!(ScrapeResponse::from_bencode(content, &[]).is_err());
+    }
+}
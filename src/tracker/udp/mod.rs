@@ -1,17 +1,20 @@
-use std::net::{UdpSocket, SocketAddr};
+use std::net::SocketAddr;
 use std::time;
-use tracker::{Announce, Result, ResultExt, Response, TrackerResponse, Event, Error, ErrorKind, dns};
+use tracker::{Announce, Result, ResultExt, Response, ScrapeBatch, ScrapeResponse, TrackerResponse,
+              Event, Error, ErrorKind, dns};
 use std::collections::HashMap;
-use {CONFIG, PEER_ID, amy};
+use {PEER_ID, amy};
 use util::bytes_to_addr;
+use socket::proxy::UdpSocket;
+use config::ProxyClass;
 use std::io::{self, Write, Read, Cursor};
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 use slog::Logger;
 use url::Url;
 use rand::random;
 
-// We're not going to bother with backoff, if the tracker/network aren't working now
-// the torrent can just resend a request later.
+// Requests aren't retried past the timeout, failing trackers are instead
+// backed off by the torrent before it announces to them again.
 const TIMEOUT_MS: u64 = 15000;
 const RETRANS_MS: u64 = 5000;
 const MAGIC_NUM: u64 = 0x41727101980;
@@ -27,23 +30,38 @@ pub struct Handler {
 }
 
 struct Connection {
-    torrent: usize,
     last_updated: time::Instant,
     last_retrans: time::Instant,
     state: State,
-    announce: Announce,
+    req: Req,
+}
+
+enum Req {
+    Announce(Announce),
+    Scrape(ScrapeBatch),
 }
 
 enum State {
     ResolvingDNS { port: u16 },
     Connecting { addr: SocketAddr, data: [u8; 16] },
     Announcing { addr: SocketAddr, data: [u8; 98] },
+    Scraping { addr: SocketAddr, data: Vec<u8> },
+}
+
+impl Connection {
+    fn error(&self, e: Error) -> Response {
+        match self.req {
+            Req::Announce(ref a) => Response::tracker(a, Err(e)),
+            Req::Scrape(ref s) => Response::scrape(s, Err(e)),
+        }
+    }
 }
 
 impl Handler {
     pub fn new(reg: &amy::Registrar, l: Logger) -> io::Result<Handler> {
-        let port = CONFIG.port;
-        let sock = UdpSocket::bind(("0.0.0.0", port))?;
+        // The peer port is taken by the uTP endpoint, and trackers
+        // reply to whatever port we send from anyways
+        let sock = UdpSocket::bind(0, ProxyClass::Trackers)?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
         Ok(Handler {
@@ -53,10 +71,23 @@ impl Handler {
             transactions: HashMap::new(),
             l,
             conn_count: 0,
-            buf: vec![0u8; 350],
+            // Large enough for 50 IPv6 peers
+            buf: vec![0u8; 1024],
         })
     }
 
+    /// Replaces the socket after the local address changed, requests
+    /// in flight on the old one time out.
+    pub fn rebind(&mut self, reg: &amy::Registrar) -> io::Result<()> {
+        let sock = UdpSocket::bind(0, ProxyClass::Trackers)?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        let _ = reg.deregister(&self.sock);
+        self.sock = sock;
+        self.id = id;
+        Ok(())
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -79,8 +110,22 @@ impl Handler {
         url: &Url,
         dns: &mut dns::Resolver,
     ) -> Result<()> {
-        // TODO: Attempt to parse into an IP address first, then perform dns res
         debug!(self.l, "Received a new announce req for {:?}", url);
+        self.new_request(Req::Announce(req), url, dns)
+    }
+
+    pub fn new_scrape(
+        &mut self,
+        req: ScrapeBatch,
+        url: &Url,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        debug!(self.l, "Received a new scrape req for {:?}", url);
+        self.new_request(Req::Scrape(req), url, dns)
+    }
+
+    fn new_request(&mut self, req: Req, url: &Url, dns: &mut dns::Resolver) -> Result<()> {
+        // TODO: Attempt to parse into an IP address first, then perform dns res
         let host = url.host_str().ok_or::<Error>(
             ErrorKind::InvalidRequest(
                 format!("Tracker announce url has no host!"),
@@ -96,11 +141,10 @@ impl Handler {
         self.connections.insert(
             id,
             Connection {
-                torrent: req.id,
                 last_updated: time::Instant::now(),
                 last_retrans: time::Instant::now(),
                 state: State::ResolvingDNS { port },
-                announce: req,
+                req,
             },
         );
         debug!(self.l, "Dispatching DNS req for {:?}, url: {:?}", id, host);
@@ -134,7 +178,7 @@ impl Handler {
                             self.transactions.insert(tid, id);
                             None
                         }
-                        Err(e) => Some((conn.torrent, Err(e))),
+                        Err(e) => Some(conn.error(e)),
                     }
                 }
                 _ => None,
@@ -169,6 +213,11 @@ impl Handler {
                                 resps.push(r);
                             }
                         }
+                        2 if v >= 8 => {
+                            if let Some(r) = self.process_scrape(v) {
+                                resps.push(r);
+                            }
+                        }
                         3 if v >= 8 => {
                             if let Some(r) = self.process_error(v) {
                                 resps.push(r);
@@ -191,6 +240,7 @@ impl Handler {
     }
 
     pub fn tick(&mut self) -> Vec<Response> {
+        self.sock.tick();
         let mut resps = Vec::new();
         let mut retrans = Vec::new();
         {
@@ -202,8 +252,8 @@ impl Handler {
                         TIMEOUT_MS,
                     )
                 {
-                    resps.push((conn.torrent, Err(ErrorKind::Timeout.into())));
-                    debug!(l, "Announce {:?} timed out", id);
+                    resps.push(conn.error(ErrorKind::Timeout.into()));
+                    debug!(l, "Request {:?} timed out", id);
                     false
                 } else {
                     if conn.last_retrans.elapsed() > time::Duration::from_millis(RETRANS_MS) {
@@ -239,7 +289,6 @@ impl Handler {
             None => return None,
         };
 
-        let mut data = [0u8; 98];
         {
             let conn = match self.connections.get_mut(&id) {
                 Some(conn) => conn,
@@ -249,56 +298,19 @@ impl Handler {
                 State::Connecting { addr, .. } => addr,
                 _ => return None,
             };
+            let tid = random::<u32>();
+            self.transactions.insert(tid, id);
 
-            {
-                let mut announce_req = Cursor::new(&mut data[..]);
-                announce_req.write_u64::<BigEndian>(connection_id).unwrap();
-                // announce action
-                announce_req.write_u32::<BigEndian>(1).unwrap();
-
-                let tid = random::<u32>();
-                announce_req.write_u32::<BigEndian>(tid).unwrap();
-                self.transactions.insert(tid, id);
-
-                announce_req.write_all(&conn.announce.hash).unwrap();
-                announce_req.write_all(&PEER_ID[..]).unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.downloaded as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.left as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.uploaded as u64)
-                    .unwrap();
-                match conn.announce.event {
-                    Some(Event::Started) => {
-                        announce_req.write_u32::<BigEndian>(2).unwrap();
-                    }
-                    Some(Event::Stopped) => {
-                        announce_req.write_u32::<BigEndian>(3).unwrap();
-                    }
-                    Some(Event::Completed) => {
-                        announce_req.write_u32::<BigEndian>(1).unwrap();
-                    }
-                    None => {
-                        announce_req.write_u32::<BigEndian>(0).unwrap();
-                    }
-                }
-
-                // IP
-                announce_req.write_u32::<BigEndian>(0).unwrap();
-                // Key - TODO: randomly generate this
-                announce_req.write_u32::<BigEndian>(0xF00BA).unwrap();
-                // Num want
-                let nw = conn.announce.num_want.map(|nw| nw as i32).unwrap_or(-1);
-                announce_req.write_i32::<BigEndian>(nw).unwrap();
-                // port
-                announce_req
-                    .write_u16::<BigEndian>(conn.announce.port)
-                    .unwrap();
-            }
-            conn.state = State::Announcing { addr, data };
+            conn.state = match conn.req {
+                Req::Announce(ref a) => State::Announcing {
+                    addr,
+                    data: announce_req(connection_id, tid, a),
+                },
+                Req::Scrape(ref s) => State::Scraping {
+                    addr,
+                    data: scrape_req(connection_id, tid, s),
+                },
+            };
             conn.last_updated = time::Instant::now();
         }
         self.send_data(id)
@@ -318,17 +330,66 @@ impl Handler {
             Some(c) => c,
             None => return None,
         };
+        let a = match conn.req {
+            Req::Announce(ref a) => a,
+            _ => return None,
+        };
 
         resp.interval = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.leechers = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.seeders = announce_resp.read_u32::<BigEndian>().unwrap();
         if len > 20 {
+            // Trackers contacted over IPv6 respond with IPv6 peers
+            let plen = match conn.state {
+                State::Announcing { addr: SocketAddr::V6(_), .. } => 18,
+                _ => 6,
+            };
             let pos = announce_resp.position() as usize;
-            for p in announce_resp.get_ref()[pos..].chunks(6) {
+            for p in announce_resp.get_ref()[pos..].chunks(plen).filter(|p| p.len() == plen) {
                 resp.peers.push(bytes_to_addr(p));
             }
         }
-        Some((conn.torrent, Ok(resp)))
+        Some(Response::tracker(a, Ok(resp)))
+    }
+
+    fn process_scrape(&mut self, len: usize) -> Option<Response> {
+        let mut scrape_resp = Cursor::new(&self.buf[4..len]);
+        let transaction_id = scrape_resp.read_u32::<BigEndian>().unwrap();
+
+        let id = match self.transactions.remove(&transaction_id) {
+            Some(id) => id,
+            None => return None,
+        };
+
+        let conn = match self.connections.remove(&id) {
+            Some(c) => c,
+            None => return None,
+        };
+        let s = match conn.req {
+            Req::Scrape(ref s) => s,
+            _ => return None,
+        };
+
+        // Stats are given in the same order as the requested hashes
+        let resp = s.torrents
+            .iter()
+            .map(|&(tid, _)| tid)
+            .zip(scrape_resp.get_ref()[4..].chunks(12).filter(|c| c.len() == 12))
+            .map(|(tid, mut c)| {
+                let complete = c.read_u32::<BigEndian>().unwrap();
+                let downloaded = c.read_u32::<BigEndian>().unwrap();
+                let incomplete = c.read_u32::<BigEndian>().unwrap();
+                (
+                    tid,
+                    ScrapeResponse {
+                        complete,
+                        incomplete,
+                        downloaded,
+                    },
+                )
+            })
+            .collect();
+        Some(Response::scrape(s, Ok(resp)))
     }
 
     fn process_error(&mut self, len: usize) -> Option<Response> {
@@ -347,16 +408,13 @@ impl Handler {
         };
 
         if connect_resp.read_to_string(&mut s).is_err() {
-            Some((
-                conn.torrent,
-                Err(
-                    ErrorKind::InvalidResponse(
-                        "Tracker error response was invalid UTF8",
-                    ).into(),
-                ),
+            Some(conn.error(
+                ErrorKind::InvalidResponse(
+                    "Tracker error response was invalid UTF8",
+                ).into(),
             ))
         } else {
-            Some((conn.torrent, Err(ErrorKind::TrackerError(s).into())))
+            Some(conn.error(ErrorKind::TrackerError(s).into()))
         }
     }
 
@@ -367,20 +425,28 @@ impl Handler {
     }
 
     fn send_data(&mut self, id: usize) -> Option<Response> {
-        let tid;
         let res = {
             let conn = self.connections.get_mut(&id).unwrap();
-            tid = conn.torrent;
             // If this actually blocks, something is really fucked(prob with the NIC)
             // and i dont think we need to care
             match conn.state {
                 State::Connecting { ref addr, ref data } => {
                     conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                    self.sock
+                        .send_to(data, *addr)
+                        .chain_err(|| ErrorKind::IO)
                 }
                 State::Announcing { ref addr, ref data } => {
                     conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                    self.sock
+                        .send_to(data, *addr)
+                        .chain_err(|| ErrorKind::IO)
+                }
+                State::Scraping { ref addr, ref data } => {
+                    conn.last_retrans = time::Instant::now();
+                    self.sock
+                        .send_to(data, *addr)
+                        .chain_err(|| ErrorKind::IO)
                 }
                 _ => Ok(0),
             }
@@ -388,10 +454,71 @@ impl Handler {
 
         match res {
             Err(e) => {
-                self.connections.remove(&id);
-                Some((tid, Err(e)))
+                self.connections
+                    .remove(&id)
+                    .map(|conn| conn.error(e))
             }
             Ok(_) => None,
         }
     }
 }
+
+fn announce_req(connection_id: u64, tid: u32, announce: &Announce) -> [u8; 98] {
+    let mut data = [0u8; 98];
+    {
+        let mut announce_req = Cursor::new(&mut data[..]);
+        announce_req.write_u64::<BigEndian>(connection_id).unwrap();
+        // announce action
+        announce_req.write_u32::<BigEndian>(1).unwrap();
+        announce_req.write_u32::<BigEndian>(tid).unwrap();
+
+        announce_req.write_all(&announce.hash).unwrap();
+        announce_req.write_all(&PEER_ID[..]).unwrap();
+        announce_req
+            .write_u64::<BigEndian>(announce.downloaded as u64)
+            .unwrap();
+        announce_req
+            .write_u64::<BigEndian>(announce.left as u64)
+            .unwrap();
+        announce_req
+            .write_u64::<BigEndian>(announce.uploaded as u64)
+            .unwrap();
+        match announce.event {
+            Some(Event::Started) => {
+                announce_req.write_u32::<BigEndian>(2).unwrap();
+            }
+            Some(Event::Stopped) => {
+                announce_req.write_u32::<BigEndian>(3).unwrap();
+            }
+            Some(Event::Completed) => {
+                announce_req.write_u32::<BigEndian>(1).unwrap();
+            }
+            None => {
+                announce_req.write_u32::<BigEndian>(0).unwrap();
+            }
+        }
+
+        // IP
+        announce_req.write_u32::<BigEndian>(0).unwrap();
+        // Key - TODO: randomly generate this
+        announce_req.write_u32::<BigEndian>(0xF00BA).unwrap();
+        // Num want
+        let nw = announce.num_want.map(|nw| nw as i32).unwrap_or(-1);
+        announce_req.write_i32::<BigEndian>(nw).unwrap();
+        // port
+        announce_req.write_u16::<BigEndian>(announce.port).unwrap();
+    }
+    data
+}
+
+fn scrape_req(connection_id: u64, tid: u32, scrape: &ScrapeBatch) -> Vec<u8> {
+    let mut data = Vec::with_capacity(16 + 20 * scrape.torrents.len());
+    data.write_u64::<BigEndian>(connection_id).unwrap();
+    // scrape action
+    data.write_u32::<BigEndian>(2).unwrap();
+    data.write_u32::<BigEndian>(tid).unwrap();
+    for &(_, ref hash) in &scrape.torrents {
+        data.extend_from_slice(hash);
+    }
+    data
+}
@@ -2,8 +2,16 @@ use std::io;
 use rand::{self, Rng};
 use std::fmt::Write as FWrite;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener,
+               UdpSocket};
+use std::ffi::CStr;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::RwLock;
+use net2::{TcpBuilder, UdpBuilder};
 use ring::digest;
+use libc;
+use CONFIG;
 
 pub fn io_err<T>(reason: &'static str) -> io::Result<T> {
     Err(io::Error::new(io::ErrorKind::Other, reason))
@@ -110,6 +118,23 @@ pub fn file_rpc_id(torrent: &[u8; 20], file: &str) -> String {
     hash_to_id(ctx.finish().as_ref())
 }
 
+pub fn webseed_rpc_id(torrent: &[u8; 20], url: &str) -> String {
+    const WEBSEED_ID: &'static [u8] = b"WEBSEED";
+    let mut ctx = digest::Context::new(&digest::SHA1);
+    ctx.update(torrent);
+    ctx.update(WEBSEED_ID);
+    ctx.update(url.as_bytes());
+    hash_to_id(ctx.finish().as_ref())
+}
+
+pub fn ban_rpc_id(ip: &IpAddr) -> String {
+    const BAN_ID: &'static [u8] = b"BAN";
+    let mut ctx = digest::Context::new(&digest::SHA1);
+    ctx.update(BAN_ID);
+    ctx.update(ip.to_string().as_bytes());
+    hash_to_id(ctx.finish().as_ref())
+}
+
 pub fn trk_rpc_id(torrent: &[u8; 20], url: &str) -> String {
     const TRK_ID: &'static [u8] = b"TRK";
     let mut ctx = digest::Context::new(&digest::SHA1);
@@ -166,7 +191,18 @@ fn hex_to_bit(c: char) -> Option<u8> {
     Some(r)
 }
 
+/// Decodes a compact address, 6 bytes for IPv4 or 18 bytes for IPv6.
 pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
+    if p.len() == 18 {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&p[..16]);
+        return SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(ip),
+            (&p[16..]).read_u16::<BigEndian>().unwrap(),
+            0,
+            0,
+        ));
+    }
     let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
     SocketAddr::V4(SocketAddrV4::new(
         ip,
@@ -174,20 +210,270 @@ pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
     ))
 }
 
-pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
-    let mut data = [0u8; 6];
+/// Encodes an address in compact form, 6 bytes for IPv4 or 18 bytes for IPv6.
+pub fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = Vec::with_capacity(18);
     match *addr {
+        SocketAddr::V4(s) => data.extend_from_slice(&s.ip().octets()),
+        SocketAddr::V6(s) => data.extend_from_slice(&s.ip().octets()),
+    }
+    data.write_u16::<BigEndian>(addr.port()).unwrap();
+    data
+}
+
+/// Converts IPv4 mapped IPv6 addresses, as reported by
+/// dual stack sockets, back into plain IPv4 addresses.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(s) = addr {
+        let seg = s.ip().segments();
+        if seg[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            let ip = Ipv4Addr::new(
+                (seg[6] >> 8) as u8,
+                seg[6] as u8,
+                (seg[7] >> 8) as u8,
+                seg[7] as u8,
+            );
+            return SocketAddr::V4(SocketAddrV4::new(ip, s.port()));
+        }
+    }
+    addr
+}
+
+/// Converts IPv4 addresses into IPv4 mapped IPv6 addresses,
+/// for use with dual stack sockets.
+pub fn mapped_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
         SocketAddr::V4(s) => {
-            let oct = s.ip().octets();
-            data[0] = oct[0];
-            data[1] = oct[1];
-            data[2] = oct[2];
-            data[3] = oct[3];
-            (&mut data[4..]).write_u16::<BigEndian>(s.port()).unwrap();
+            SocketAddr::V6(SocketAddrV6::new(s.ip().to_ipv6_mapped(), s.port(), 0, 0))
         }
-        _ => unimplemented!(),
+        a => a,
+    }
+}
+
+/// Returns the addresses of all local network interfaces, by name.
+pub fn interface_addrs() -> io::Result<Vec<(String, IpAddr)>> {
+    let mut addrs = Vec::new();
+    unsafe {
+        let mut ifap: *mut libc::ifaddrs = ptr::null_mut();
+        if libc::getifaddrs(&mut ifap) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cur = ifap;
+        while !cur.is_null() {
+            let ifa = &*cur;
+            cur = ifa.ifa_next;
+            if ifa.ifa_addr.is_null() {
+                continue;
+            }
+            let ip = match (*ifa.ifa_addr).sa_family as i32 {
+                libc::AF_INET => {
+                    let sa = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let sa = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned();
+            addrs.push((name, ip));
+        }
+        libc::freeifaddrs(ifap);
+    }
+    Ok(addrs)
+}
+
+lazy_static! {
+    /// Address of the configured bind interface as of the last refresh
+    static ref INTERFACE_IP: RwLock<Option<IpAddr>> = RwLock::new(interface_ip());
+}
+
+/// Returns the local address sockets should be bound to, if one is
+/// configured. If the configured interface has no usable address an
+/// error is returned, so traffic is never sent from another interface.
+pub fn bind_ip() -> io::Result<Option<IpAddr>> {
+    if let Some(ip) = CONFIG.net.bind_address {
+        return Ok(Some(ip));
+    }
+    if CONFIG.net.interface.is_none() {
+        return Ok(None);
+    }
+    match *INTERFACE_IP.read().unwrap() {
+        Some(ip) => Ok(Some(ip)),
+        None => io_err("Bind interface has no usable address"),
+    }
+}
+
+/// Resolves the configured bind interface's address again, as it
+/// may have changed since the last refresh, e.g. when a VPN reconnects.
+pub fn refresh_bind_ip() {
+    if CONFIG.net.interface.is_some() {
+        *INTERFACE_IP.write().unwrap() = interface_ip();
+    }
+}
+
+fn interface_ip() -> Option<IpAddr> {
+    let name = match CONFIG.net.interface {
+        Some(ref n) => n,
+        None => return None,
+    };
+    let mut ips: Vec<_> = interface_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&(ref n, ref ip)| n == name && !link_local(ip))
+        .map(|(_, ip)| ip)
+        .collect();
+    // IPv4 is preferred as most peers are only reachable over it
+    ips.sort_by_key(|ip| ip.is_ipv6());
+    ips.into_iter().next()
+}
+
+/// Restricts a socket to the configured bind interface, so that its
+/// traffic isn't routed out of another interface when the routes change.
+#[cfg(target_os = "linux")]
+pub fn bind_device<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    let name = match CONFIG.net.interface {
+        Some(ref n) if CONFIG.net.bind_address.is_none() => n,
+        _ => return Ok(()),
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // Without CAP_NET_RAW only the bound address restricts the socket
+        Some(libc::EPERM) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_device<S: AsRawFd>(_: &S) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns whether or not the configured bind address or interface
+/// is currently available.
+pub fn bind_available() -> bool {
+    match CONFIG.net.bind_address {
+        Some(ip) => {
+            interface_addrs()
+                .map(|addrs| addrs.iter().any(|&(_, a)| a == ip))
+                .unwrap_or(false)
+        }
+        None => bind_ip().is_ok(),
+    }
+}
+
+/// Returns the local address long lived sockets should listen on. While
+/// the configured address is unavailable loopback is used instead, so
+/// nothing is sent until the sockets are rebound.
+pub fn listen_ip() -> Option<IpAddr> {
+    if !bind_available() {
+        return Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+    }
+    bind_ip().unwrap_or(None)
+}
+
+fn link_local(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => ip.is_link_local(),
+        IpAddr::V6(ref ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Creates a TCP socket in the address family of ip.
+pub fn ip_tcp_builder(ip: IpAddr) -> io::Result<TcpBuilder> {
+    match ip {
+        IpAddr::V4(..) => TcpBuilder::new_v4(),
+        IpAddr::V6(..) => TcpBuilder::new_v6(),
+    }
+}
+
+/// Creates a TCP socket for an outgoing connection to addr, bound to the
+/// configured local address if there is one.
+pub fn tcp_builder(addr: &SocketAddr) -> io::Result<TcpBuilder> {
+    match bind_ip()? {
+        Some(ip) => {
+            let b = ip_tcp_builder(ip)?;
+            bind_device(&b)?;
+            b.bind((ip, 0))?;
+            Ok(b)
+        }
+        None => {
+            match *addr {
+                SocketAddr::V4(..) => TcpBuilder::new_v4(),
+                SocketAddr::V6(..) => TcpBuilder::new_v6(),
+            }
+        }
+    }
+}
+
+/// Binds a TCP listener on all interfaces, accepting both IPv6 and IPv4
+/// connections where possible, and falling back to IPv4 otherwise. If a
+/// local address is configured, only that address is used.
+pub fn bind_tcp(port: u16) -> io::Result<TcpListener> {
+    if let Some(ip) = listen_ip() {
+        let b = ip_tcp_builder(ip)?;
+        if !ip.is_loopback() {
+            bind_device(&b)?;
+        }
+        b.reuse_address(true)?;
+        b.bind((ip, port))?;
+        return b.listen(128);
+    }
+    let v6 = TcpBuilder::new_v6().and_then(|b| {
+        b.only_v6(false)?;
+        b.reuse_address(true)?;
+        b.bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port))?;
+        b.listen(128)
+    });
+    match v6 {
+        Ok(l) => Ok(l),
+        Err(_) => TcpListener::bind((Ipv4Addr::new(0, 0, 0, 0), port)),
+    }
+}
+
+/// Binds a UDP socket on all interfaces, accepting both IPv6 and IPv4
+/// traffic where possible, and falling back to IPv4 otherwise. Addresses
+/// sent to should be passed through `udp_addr` and received addresses
+/// through `canonical_addr`. If a local address is configured, only that
+/// address is used.
+pub fn bind_udp(port: u16) -> io::Result<UdpSocket> {
+    if let Some(ip) = listen_ip() {
+        let sock = UdpSocket::bind((ip, port))?;
+        if !ip.is_loopback() {
+            bind_device(&sock)?;
+        }
+        return Ok(sock);
+    }
+    let v6 = UdpBuilder::new_v6().and_then(|b| {
+        b.only_v6(false)?;
+        b.bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port))
+    });
+    match v6 {
+        Ok(s) => Ok(s),
+        Err(_) => UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), port)),
+    }
+}
+
+/// Converts an address into the form expected by the socket,
+/// mapping IPv4 addresses when the socket is IPv6.
+pub fn udp_addr(sock: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match sock.local_addr() {
+        Ok(SocketAddr::V6(_)) => mapped_addr(addr),
+        _ => addr,
     }
-    data
 }
 
 #[test]
@@ -196,3 +482,33 @@ fn test_hash_enc() {
     let s = hash_to_id(&hash);
     assert_eq!(id_to_hash(&s).unwrap(), hash);
 }
+
+#[test]
+fn test_addr_enc() {
+    let a: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+    assert_eq!(addr_to_bytes(&a), vec![1, 2, 3, 4, 0x1A, 0xE1]);
+    assert_eq!(bytes_to_addr(&addr_to_bytes(&a)), a);
+
+    let a: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+    assert_eq!(addr_to_bytes(&a).len(), 18);
+    assert_eq!(bytes_to_addr(&addr_to_bytes(&a)), a);
+}
+
+#[test]
+fn test_mapped_addr() {
+    let a: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+    let m = mapped_addr(a);
+    assert_eq!(m, "[::ffff:1.2.3.4]:6881".parse().unwrap());
+    assert_eq!(canonical_addr(m), a);
+    let a: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+    assert_eq!(canonical_addr(a), a);
+    assert_eq!(mapped_addr(a), a);
+}
+
+#[test]
+fn test_link_local() {
+    assert!(link_local(&"169.254.1.2".parse().unwrap()));
+    assert!(link_local(&"fe80::1".parse().unwrap()));
+    assert!(!link_local(&"10.8.0.2".parse().unwrap()));
+    assert!(!link_local(&"2001:db8::1".parse().unwrap()));
+}
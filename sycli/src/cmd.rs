@@ -250,6 +250,134 @@ pub fn dl<S: Stream>(mut c: WClient<S>, url: &str, name: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn create<S: Stream>(
+    mut c: WClient<S>,
+    url: &str,
+    msg: CMessage,
+    output: &str,
+) -> Result<()> {
+    let msg_data = serde_json::to_string(&msg).chain_err(
+        || ErrorKind::Serialization,
+    )?;
+    c.send_message(&WSMessage::Text(msg_data)).chain_err(|| {
+        ErrorKind::Websocket
+    })?;
+    // Hashing may take a while, so keep the connection alive until
+    // the server is done.
+    recv_torrent_file(&mut c, url, output)
+}
+
+pub fn export<S: Stream>(mut c: WClient<S>, url: &str, name: &str, output: Option<&str>) -> Result<()> {
+    let mut serial = Serial(0);
+    let resources = search_torrent_name(&mut c, &mut serial, name)?;
+    if resources.len() == 1 {
+        let msg = CMessage::DownloadTorrent {
+            serial: serial.next(),
+            id: resources[0].id().to_owned(),
+        };
+        let msg_data = serde_json::to_string(&msg).chain_err(
+            || ErrorKind::Serialization,
+        )?;
+        c.send_message(&WSMessage::Text(msg_data)).chain_err(|| {
+            ErrorKind::Websocket
+        })?;
+        let path = match output {
+            Some(o) => o.to_owned(),
+            None => format!("{}.torrent", resources[0].as_torrent().name),
+        };
+        recv_torrent_file(&mut c, url, &path)
+    } else if resources.is_empty() {
+        eprintln!("Could not find any matching torrents for {}", name);
+        Ok(())
+    } else {
+        eprintln!(
+            "Ambiguous results searching for {}. Potential alternatives include: ",
+            name
+        );
+        for res in resources.into_iter().take(3) {
+            if let Resource::Torrent(t) = res {
+                eprintln!("{}", t.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn reload_blocklist<S: Stream>(mut c: WClient<S>) -> Result<()> {
+    let mut serial = Serial(0);
+    // Subscribe to the server so its blocklist update is received
+    search(&mut c, &mut serial, ResourceKind::Server, vec![])?;
+    let msg = CMessage::ReloadBlocklist { serial: serial.next() };
+    let msg_data = serde_json::to_string(&msg).chain_err(
+        || ErrorKind::Serialization,
+    )?;
+    c.send_message(&WSMessage::Text(msg_data)).chain_err(|| {
+        ErrorKind::Websocket
+    })?;
+    loop {
+        let smsg = match c.recv_message().chain_err(|| ErrorKind::Websocket)? {
+            WSMessage::Text(s) => {
+                serde_json::from_str(&s).chain_err(
+                    || ErrorKind::Deserialization,
+                )?
+            }
+            WSMessage::Close(_) => bail!("Connection closed by synapse!"),
+            _ => continue,
+        };
+        match smsg {
+            SMessage::UpdateResources { resources } => {
+                for r in resources {
+                    if let SResourceUpdate::Blocklist { blocklist_ranges, .. } = r {
+                        println!("Loaded {} blocked address ranges", blocklist_ranges);
+                        return Ok(());
+                    }
+                }
+            }
+            SMessage::ServerError(e) => bail!("{}", e.reason),
+            _ => continue,
+        }
+    }
+}
+
+/// Waits for a transfer offer for a .torrent file and writes it to output.
+fn recv_torrent_file<S: Stream>(c: &mut WClient<S>, url: &str, output: &str) -> Result<()> {
+    let token = loop {
+        let smsg = match c.recv_message().chain_err(|| ErrorKind::Websocket)? {
+            WSMessage::Text(s) => {
+                serde_json::from_str(&s).chain_err(
+                    || ErrorKind::Deserialization,
+                )?
+            }
+            WSMessage::Ping(p) => {
+                c.send_message(&WSMessage::Pong(p)).chain_err(|| {
+                    ErrorKind::Websocket
+                })?;
+                continue;
+            }
+            WSMessage::Close(_) => bail!("Connection closed by synapse!"),
+            _ => continue,
+        };
+        match smsg {
+            SMessage::TransferOffer { token, .. } => break token,
+            SMessage::ServerError(e) |
+            SMessage::InvalidSchema(e) |
+            SMessage::InvalidResource(e) => bail!("{}", e.reason),
+            _ => continue,
+        }
+    };
+
+    let client = HClient::new().chain_err(|| ErrorKind::HTTP)?;
+    let mut resp = client
+        .get(url)
+        .chain_err(|| ErrorKind::HTTP)?
+        .header(header::Authorization(header::Bearer { token }))
+        .send()
+        .chain_err(|| ErrorKind::HTTP)?;
+    let mut f = fs::File::create(output).chain_err(|| ErrorKind::FileIO)?;
+    io::copy(&mut resp, &mut f).chain_err(|| ErrorKind::FileIO)?;
+    Ok(())
+}
+
 pub fn list<S: Stream>(
     mut c: WClient<S>,
     kind: &str,
@@ -263,6 +391,7 @@ pub fn list<S: Stream>(
         "piece" => ResourceKind::Piece,
         "file" => ResourceKind::File,
         "server" => ResourceKind::Server,
+        "ban" => ResourceKind::Ban,
         _ => bail!("Unexpected resource kind {}", kind),
     };
     let mut serial = Serial(0);
@@ -288,6 +417,9 @@ pub fn list<S: Stream>(
             ResourceKind::Server => {
                 table.add_row(row!["DL RT", "UL RT"]);
             }
+            ResourceKind::Ban => {
+                table.add_row(row!["IP", "Banned"]);
+            }
         }
 
         #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -339,6 +471,10 @@ pub fn list<S: Stream>(
                     let ru = fmt_bytes(s.rate_up as f64) + "/s";
                     table.add_row(row![rd, ru]);
                 }
+                ResourceKind::Ban => {
+                    let b = res.as_ban();
+                    table.add_row(row![b.ip, b.created]);
+                }
             }
         }
         table.printstd();
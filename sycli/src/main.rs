@@ -11,10 +11,12 @@ extern crate url;
 extern crate websocket;
 
 use std::process;
+use std::path::Path;
 
 use url::Url;
 use clap::{App, AppSettings, Arg, SubCommand};
 use websocket::ClientBuilder;
+use rpc::message::CMessage;
 
 mod cmd;
 
@@ -68,7 +70,7 @@ fn main() {
                          .takes_value(true))
                     .arg(Arg::with_name("kind")
                         .help("The kind of resource to list.")
-                        .possible_values(&["torrent", "peer", "file", "server", "tracker", "piece"])
+                        .possible_values(&["torrent", "peer", "file", "server", "tracker", "piece", "ban"])
                         .default_value("torrent")
                         .short("k")
                         .long("kind"))
@@ -98,6 +100,60 @@ fn main() {
                          .index(1)
                          .required(true))
                    )
+        .subcommand(SubCommand::with_name("export")
+                    .about("Exports the .torrent file of a torrent.")
+                    .arg(Arg::with_name("torrent")
+                         .help("Name of torrent to export. A fuzzy match will be attempted and ambiguities displayed.")
+                         .index(1)
+                         .required(true))
+                    .arg(Arg::with_name("output")
+                         .help("Path to write the .torrent file to, defaults to the torrent's name.")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true))
+                   )
+        .subcommand(SubCommand::with_name("create")
+                    .about("Creates a torrent from files on the synapse server.")
+                    .arg(Arg::with_name("path")
+                         .help("File or directory to create the torrent from, relative paths are taken to be under the download directory.")
+                         .index(1)
+                         .required(true))
+                    .arg(Arg::with_name("piece-size")
+                         .help("Piece size in bytes, must be a power of two. Chosen automatically if not given.")
+                         .short("p")
+                         .long("piece-size")
+                         .takes_value(true))
+                    .arg(Arg::with_name("trackers")
+                         .help("Tracker URLs to announce to.")
+                         .short("t")
+                         .long("trackers")
+                         .multiple(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("webseeds")
+                         .help("Web seed URLs.")
+                         .short("w")
+                         .long("webseeds")
+                         .multiple(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("comment")
+                         .help("Comment to embed in the torrent.")
+                         .short("c")
+                         .long("comment")
+                         .takes_value(true))
+                    .arg(Arg::with_name("private")
+                         .help("Marks the torrent as private.")
+                         .long("private"))
+                    .arg(Arg::with_name("start")
+                         .help("Starts seeding the torrent once it's created.")
+                         .long("start"))
+                    .arg(Arg::with_name("output")
+                         .help("Path to write the .torrent file to, defaults to the torrent's name.")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true))
+                   )
+        .subcommand(SubCommand::with_name("reload-blocklist")
+                    .about("Reloads the IP blocklists configured on the synapse server."))
         .get_matches();
 
     let mut url = match Url::parse(matches.value_of("server").unwrap()) {
@@ -152,6 +208,66 @@ fn main() {
                 process::exit(1);
             }
         }
+        "export" => {
+            let args = matches.subcommand_matches("export").unwrap();
+            let res = cmd::export(
+                client,
+                url.as_str(),
+                args.value_of("torrent").unwrap(),
+                args.value_of("output"),
+            );
+            if let Err(e) = res {
+                eprintln!("Failed to export torrent: {:?}", e);
+                process::exit(1);
+            }
+        }
+        "create" => {
+            let args = matches.subcommand_matches("create").unwrap();
+            let path = args.value_of("path").unwrap();
+            let piece_size = match args.value_of("piece-size").map(|p| p.parse()) {
+                Some(Ok(p)) => Some(p),
+                Some(Err(_)) => {
+                    eprintln!("Piece size must be a number!");
+                    process::exit(1);
+                }
+                None => None,
+            };
+            let output = match args.value_of("output") {
+                Some(o) => o.to_owned(),
+                None => {
+                    let name = Path::new(path)
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| "out".to_owned());
+                    format!("{}.torrent", name)
+                }
+            };
+            let msg = CMessage::CreateTorrent {
+                serial: 0,
+                path: path.to_owned(),
+                piece_size,
+                trackers: args.values_of("trackers")
+                    .map(|v| v.map(|t| t.to_owned()).collect())
+                    .unwrap_or(vec![]),
+                webseeds: args.values_of("webseeds")
+                    .map(|v| v.map(|w| w.to_owned()).collect())
+                    .unwrap_or(vec![]),
+                private: args.is_present("private"),
+                comment: args.value_of("comment").map(|c| c.to_owned()),
+                start: args.is_present("start"),
+            };
+            let res = cmd::create(client, url.as_str(), msg, &output);
+            if let Err(e) = res {
+                eprintln!("Failed to create torrent: {:?}", e);
+                process::exit(1);
+            }
+        }
+        "reload-blocklist" => {
+            if let Err(e) = cmd::reload_blocklist(client) {
+                eprintln!("Failed to reload blocklists: {:?}", e);
+                process::exit(1);
+            }
+        }
         "list" => {
             let args = matches.subcommand_matches("list").unwrap();
             let crit = args.value_of("filter").and_then(|f| {